@@ -0,0 +1,66 @@
+/* The C interface to newtext's replacement engine
+ *
+ * Link against the cdylib that `cargo build --release` builds, as
+ * libnewtext.so, libnewtext.dylib or newtext.dll. Functions that return int
+ * return 0 on success, or -1 with newtext_last_error() saying what went
+ * wrong, or -2 if newtext panicked. Strings are UTF-8.
+ */
+
+#ifndef NEWTEXT_H
+#define NEWTEXT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Flags for newtext_plan and newtext_run */
+
+/* Treat OLD as a regex */
+#define NEWTEXT_PATTERN ((uint32_t)1 << 0)
+/* Match OLD ignoring case, keeping the case of each match */
+#define NEWTEXT_IGNORE_CASE ((uint32_t)1 << 1)
+/* Don't record changes in the journal, so they can't be undone */
+#define NEWTEXT_NO_JOURNAL ((uint32_t)1 << 2)
+
+/* The changes worked out by newtext_plan */
+typedef struct NewtextChangeSet NewtextChangeSet;
+
+/* Called with the path of each file that changes, its new content and the
+ * caller's data. The pointers are only valid during the call. Returning
+ * anything but 0 stops the iteration. */
+typedef int (*newtext_change_cb)(const char *path, const uint8_t *content,
+                                 size_t len, void *data);
+
+/* The message for the last call on this thread that failed, or NULL */
+const char *newtext_last_error(void);
+
+/* Work out the changes that replacing OLD with NEW would make to the files
+ * under ROOT, without making them. Free *OUT with newtext_free. */
+int newtext_plan(const char *root, const char *old, const char *new_,
+                 uint32_t flags, NewtextChangeSet **out);
+
+/* Call CALLBACK with each file that CHANGES rewrites */
+int newtext_changes(const NewtextChangeSet *changes,
+                    newtext_change_cb callback, void *data);
+
+/* Make the changes. Files that have changed since they were worked out are
+ * left alone. */
+int newtext_apply(const NewtextChangeSet *changes);
+
+/* Free a change set. NULL is ignored. */
+void newtext_free(NewtextChangeSet *changes);
+
+/* Replace OLD with NEW in the files under ROOT, calling CALLBACK, which may
+ * be NULL, with each file that changes before the changes are made. If
+ * CALLBACK stops the iteration, nothing is changed. */
+int newtext_run(const char *root, const char *old, const char *new_,
+                uint32_t flags, newtext_change_cb callback, void *data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,149 @@
+//! The `newtext` Python module, over the same engine as the command line tool
+//!
+//! ```python
+//! import newtext
+//!
+//! changes = newtext.plan("old_name", "new_name", "src", ignore_case=True)
+//! for path, content in changes.changes():
+//!     print(path)
+//! changes.apply()
+//! ```
+
+use newtext::{Engine, Options};
+use pyo3::exceptions::{PyOSError, PyValueError};
+use pyo3::prelude::*;
+use std::path::PathBuf;
+
+/// The changes to a tree of files worked out by `plan`
+#[pyclass(name = "ChangeSet", module = "newtext")]
+struct ChangeSet {
+    changes: newtext::ChangeSet,
+}
+
+#[pymethods]
+impl ChangeSet {
+    /// The files that would be rewritten, as (path, new content) pairs
+    fn changes(&self) -> Vec<(PathBuf, Vec<u8>)> {
+        self.changes
+            .changes()
+            .into_iter()
+            .map(|change| (change.path, change.content))
+            .collect()
+    }
+
+    /// The files that couldn't be read or processed, as (path, message) pairs
+    fn problems(&self) -> Vec<(PathBuf, String)> {
+        self.changes
+            .problems()
+            .iter()
+            .map(|problem| (problem.path.clone(), problem.message.clone()))
+            .collect()
+    }
+
+    /// Make the changes, returning how many files changed
+    ///
+    /// The changes are recorded in the journal so that `newtext undo` can
+    /// revert them, unless `journal` is false. Files that have changed since
+    /// the changes were worked out are left alone.
+    #[pyo3(signature = (journal = true))]
+    fn apply(&self, py: Python<'_>, journal: bool) -> PyResult<usize> {
+        let (applied, problems) = py
+            .allow_threads(|| self.changes.apply(journal))
+            .map_err(PyOSError::new_err)?;
+        match problems.first() {
+            Some(problem) => Err(PyOSError::new_err(format!(
+                "Could not change {}: {}",
+                problem.path.display(),
+                problem.message
+            ))),
+            None => Ok(applied),
+        }
+    }
+
+    fn __len__(&self) -> usize {
+        self.changes.changes().len()
+    }
+
+    fn __bool__(&self) -> bool {
+        !self.changes.is_empty()
+    }
+}
+
+fn engine(old: &str, new: &str, pattern: bool, ignore_case: bool) -> PyResult<Engine> {
+    let options = Options {
+        old: old.to_string(),
+        new: new.to_string(),
+        pattern,
+        ignore_case,
+        ..Options::default()
+    };
+    Engine::new(&options).map_err(PyValueError::new_err)
+}
+
+/// Work out the changes that replacing `old` with `new` would make to the
+/// files under `root`, or to `paths` within it, without making them
+///
+/// Files are walked as the command line tool walks them, leaving out ignored
+/// files.
+#[pyfunction]
+#[pyo3(signature = (old, new, root = PathBuf::from("."), paths = Vec::new(), *, pattern = false, ignore_case = false))]
+#[pyo3(text_signature = "(old, new, root=\".\", paths=(), *, pattern=False, ignore_case=False)")]
+fn plan(
+    py: Python<'_>,
+    old: &str,
+    new: &str,
+    root: PathBuf,
+    paths: Vec<PathBuf>,
+    pattern: bool,
+    ignore_case: bool,
+) -> PyResult<ChangeSet> {
+    let mut engine = engine(old, new, pattern, ignore_case)?;
+    let changes = py.allow_threads(|| engine.plan(&root, &paths));
+    Ok(ChangeSet { changes })
+}
+
+/// Replace `old` with `new` in the files under `root`, or in `paths` within
+/// it, returning how many files changed
+#[pyfunction]
+#[pyo3(signature = (old, new, root = PathBuf::from("."), paths = Vec::new(), *, pattern = false, ignore_case = false, journal = true))]
+#[pyo3(
+    text_signature = "(old, new, root=\".\", paths=(), *, pattern=False, ignore_case=False, journal=True)"
+)]
+#[allow(clippy::too_many_arguments)]
+fn apply(
+    py: Python<'_>,
+    old: &str,
+    new: &str,
+    root: PathBuf,
+    paths: Vec<PathBuf>,
+    pattern: bool,
+    ignore_case: bool,
+    journal: bool,
+) -> PyResult<usize> {
+    plan(py, old, new, root, paths, pattern, ignore_case)?.apply(py, journal)
+}
+
+/// `text` with `old` replaced by `new`, or None if nothing in it changes
+#[pyfunction]
+#[pyo3(signature = (old, new, text, *, pattern = false, ignore_case = false))]
+fn preview(
+    old: &str,
+    new: &str,
+    text: &str,
+    pattern: bool,
+    ignore_case: bool,
+) -> PyResult<Option<String>> {
+    engine(old, new, pattern, ignore_case)?
+        .replace(text)
+        .map_err(PyValueError::new_err)
+}
+
+#[pymodule]
+#[pyo3(name = "newtext")]
+fn newtext_python(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<ChangeSet>()?;
+    m.add_function(wrap_pyfunction!(plan, m)?)?;
+    m.add_function(wrap_pyfunction!(apply, m)?)?;
+    m.add_function(wrap_pyfunction!(preview, m)?)?;
+    Ok(())
+}
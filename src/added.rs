@@ -0,0 +1,126 @@
+//! --only-added, which limits replacements to the lines that a branch added
+//! or changed, as git sees them
+//!
+//! The lines are read from `git diff` against where the branch forked from a
+//! ref, taking in commits, staged and unstaged changes alike, and every line
+//! of a file git doesn't track yet counts as added.
+
+use crate::commit::git;
+use crate::scope::{Escape, Segment};
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// The lines added to each file
+#[derive(Clone, Debug, Default)]
+pub struct AddedLines {
+    /// The ranges of line numbers, from 1, with the files' canonical paths
+    files: HashMap<PathBuf, Vec<Range<usize>>>,
+}
+
+impl AddedLines {
+    /// The lines added since the commit where HEAD forked from `reference`
+    pub fn since(reference: &str) -> Result<AddedLines, String> {
+        let top = PathBuf::from(git(&["rev-parse", "--show-toplevel"])?.trim());
+        // A ref that starts with - is still a ref, not an option
+        let base = git(&["merge-base", "--end-of-options", reference, "HEAD"])?;
+        // The prefixes are given as the paths are read, whatever the user's
+        // diff.noprefix or diff.mnemonicPrefix say
+        let diff = git(&[
+            "-c",
+            "core.quotePath=false",
+            "diff",
+            "-U0",
+            "--no-color",
+            "--no-ext-diff",
+            "--src-prefix=a/",
+            "--dst-prefix=b/",
+            base.trim(),
+            "--",
+        ])?;
+
+        let mut files: HashMap<PathBuf, Vec<Range<usize>>> = HashMap::new();
+        let mut file = None;
+        for line in diff.lines() {
+            if let Some(name) = line.strip_prefix("+++ ") {
+                // Deleted files are `+++ /dev/null`
+                file = name.strip_prefix("b/").map(|name| top.join(name));
+            } else if let (Some(hunk), Some(file)) = (line.strip_prefix("@@ "), &file) {
+                if let Some(lines) = hunk_lines(hunk) {
+                    files.entry(file.clone()).or_default().push(lines);
+                }
+            }
+        }
+        let untracked = git(&["ls-files", "--others", "--exclude-standard", "--full-name"])?;
+        for name in untracked.lines() {
+            files.insert(top.join(name), std::iter::once(1..usize::MAX).collect());
+        }
+
+        // Looked up by their canonical paths, as the walk can reach them by
+        // other ones
+        let files = files
+            .into_iter()
+            .filter_map(|(path, lines)| Some((fs::canonicalize(path).ok()?, lines)))
+            .collect();
+        Ok(AddedLines { files })
+    }
+
+    /// The lines added to the file at `path`, which are none if it
+    /// didn't change
+    pub fn lines(&self, path: &Path) -> &[Range<usize>] {
+        fs::canonicalize(path)
+            .ok()
+            .and_then(|path| self.files.get(&path))
+            .map_or(&[], Vec::as_slice)
+    }
+}
+
+/// The new lines of a hunk header such as `-12,3 +14,2 @@`, or None if it
+/// only removes lines
+fn hunk_lines(hunk: &str) -> Option<Range<usize>> {
+    let new = hunk.split(' ').find_map(|part| part.strip_prefix('+'))?;
+    let (start, count) = match new.split_once(',') {
+        Some((start, count)) => (start.parse().ok()?, count.parse().ok()?),
+        None => (new.parse().ok()?, 1),
+    };
+    (count > 0).then_some(start..start + count)
+}
+
+/// The whole lines of `content` among `lines`
+pub fn segments(content: &str, lines: &[Range<usize>]) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut start = 0;
+    for (number, line) in content.split_inclusive('\n').enumerate() {
+        let end = start + line.len();
+        if lines.iter().any(|lines| lines.contains(&(number + 1))) {
+            // Lines added together are replaced within together
+            match segments.last_mut() {
+                Some(last) if last.range.end == start => {
+                    last.range.end = end;
+                    last.text.push_str(line);
+                }
+                _ => segments.push(Segment {
+                    range: start..end,
+                    text: line.to_string(),
+                    escape: Escape::None,
+                }),
+            }
+        }
+        start = end;
+    }
+    segments
+}
+
+/// Keep the `segments` of `content` that start on one of `lines`
+pub fn keep(content: &str, segments: &mut Vec<Segment>, lines: &[Range<usize>]) {
+    let (mut line, mut counted_to) = (1, 0);
+    segments.retain(|segment| {
+        // Counted as it goes, as segments are in order
+        line += content[counted_to..segment.range.start]
+            .matches('\n')
+            .count();
+        counted_to = segment.range.start;
+        segment.escape == Escape::Added || lines.iter().any(|lines| lines.contains(&line))
+    });
+}
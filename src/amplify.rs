@@ -0,0 +1,101 @@
+use crate::matcher::Matcher;
+use crate::{Replacement, Replacer};
+
+/// Describe the ways that the output of the replacement can be matched by it
+/// again
+///
+/// Such replacements aren't idempotent: running them again changes the
+/// files again, and within one run sed commands can feed into each other.
+/// The checks look at the literal text of each replacement, so they can't
+/// see what scripts or capture groups will produce.
+pub fn check(replacement: &Replacement) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if let Some(sed) = &replacement.sed {
+        let substitutions: Vec<_> = sed.substitutions().collect();
+        for (i, (_, output)) in substitutions.iter().enumerate() {
+            let output = literal_text(output);
+            for (j, (pattern, _)) in substitutions.iter().enumerate() {
+                if pattern.is_match(&output) {
+                    problems.push(if i == j {
+                        format!(
+                            "the replacement of sed command {} matches its own pattern",
+                            i + 1
+                        )
+                    } else {
+                        format!(
+                            "the replacement of sed command {} matches the pattern of command {}",
+                            i + 1,
+                            j + 1
+                        )
+                    });
+                }
+            }
+        }
+    } else {
+        match &replacement.replacer {
+            Some(Replacer::Map(map)) => {
+                for (key, value, next) in map.chains() {
+                    problems.push(format!(
+                        "the map replaces '{}' with '{}', which it replaces with '{}'",
+                        key, value, next
+                    ));
+                }
+            }
+            // There's no telling what a script or plugin returns
+            Some(Replacer::Script(_) | Replacer::Plugin(_)) => {}
+            None => match &replacement.matcher {
+                Some(Matcher::Regex(re)) if re.is_match(&literal_text(&replacement.new)) => {
+                    problems.push("the pattern matches NEW".to_string());
+                }
+                Some(Matcher::Regex(_)) | None => {}
+                Some(Matcher::IgnoreCase(_)) => {
+                    let new = replacement.new.to_lowercase();
+                    if new.contains(&replacement.old.to_lowercase()) {
+                        problems.push("NEW contains OLD, ignoring case".to_string());
+                    }
+                }
+                Some(Matcher::Literal(_)) => {
+                    if replacement.new.contains(&replacement.old) {
+                        problems.push("NEW contains OLD".to_string());
+                    }
+                }
+            },
+        }
+    }
+
+    problems
+}
+
+/// The text a replacement string always produces, leaving out references to
+/// capture groups such as `$1` and `${name}`
+fn literal_text(replacement: &str) -> String {
+    let mut text = String::new();
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            text.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('$') => {
+                text.push('$');
+                chars.next();
+            }
+            Some('{') => {
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                while chars
+                    .next_if(|c| c.is_alphanumeric() || *c == '_')
+                    .is_some()
+                {}
+            }
+        }
+    }
+    text
+}
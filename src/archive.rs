@@ -0,0 +1,231 @@
+use crate::contents::SNIFF_LEN;
+use crate::encoding;
+use crate::throttle;
+use crate::write::AtomicFile;
+use crate::{rewrite_bytes, Replacement, Rewrite};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// The archive formats whose members can be rewritten
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Kind {
+    /// Recognise an archive by its file name
+    pub fn for_path(path: &Path) -> Option<Kind> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(Kind::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Kind::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Kind::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Apply the replacement to the text members of an archive, and repack it
+/// if any of them changed
+///
+/// Members are reported to scripts as paths inside the archive, such as
+/// `bundle.zip/config/app.ini`. Other members are copied over unchanged.
+pub fn process_archive(path: &Path, kind: Kind, replacement: &Replacement) -> io::Result<bool> {
+    let input = BufReader::new(throttle::open(path)?);
+    let output = AtomicFile::create(path, &replacement.write)?;
+
+    // The new archive only replaces the original if a member changed
+    let (changed, output) = match kind {
+        Kind::Zip => rewrite_zip(input, output, path, replacement)?,
+        Kind::Tar => rewrite_tar(input, output, path, replacement)?,
+        Kind::TarGz => {
+            let encoder = GzEncoder::new(output, flate2::Compression::default());
+            let (changed, encoder) =
+                rewrite_tar(GzDecoder::new(input), encoder, path, replacement)?;
+            (changed, encoder.finish()?)
+        }
+    };
+
+    if changed {
+        output.commit()?;
+    }
+    Ok(changed)
+}
+
+fn rewrite_zip(
+    input: BufReader<File>,
+    output: AtomicFile,
+    path: &Path,
+    replacement: &Replacement,
+) -> io::Result<(bool, AtomicFile)> {
+    let mut archive = ZipArchive::new(input).map_err(io::Error::other)?;
+
+    // The members that change are found first, so archives with none are
+    // left without writing anything
+    let mut new_contents = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let readable = {
+            let member = archive.by_index_raw(i).map_err(io::Error::other)?;
+            member.is_file() && !member.encrypted()
+        };
+        new_contents.push(if readable {
+            rewrite_zip_member(&mut archive, i, path, replacement)?
+        } else {
+            None
+        });
+    }
+    if new_contents.iter().all(Option::is_none) {
+        return Ok((false, output));
+    }
+
+    let mut writer = ZipWriter::new(output);
+    writer.set_raw_comment(archive.comment().into());
+    for (i, new_content) in new_contents.into_iter().enumerate() {
+        match new_content {
+            Some((name, options, content)) => {
+                writer.start_file(name, options).map_err(io::Error::other)?;
+                writer.write_all(&content)?;
+            }
+            None => {
+                // Copy the compressed data as it is
+                let member = archive.by_index_raw(i).map_err(io::Error::other)?;
+                writer.raw_copy_file(member).map_err(io::Error::other)?;
+            }
+        }
+    }
+
+    let output = writer.finish().map_err(io::Error::other)?;
+    Ok((true, output))
+}
+
+/// The new content of a zip member and the options to write it with, if the
+/// replacement changed it
+fn rewrite_zip_member(
+    archive: &mut ZipArchive<BufReader<File>>,
+    index: usize,
+    path: &Path,
+    replacement: &Replacement,
+) -> io::Result<Option<(String, SimpleFileOptions, Vec<u8>)>> {
+    let mut member = archive.by_index(index).map_err(io::Error::other)?;
+    let mut content = Vec::new();
+    member.read_to_end(&mut content)?;
+
+    let new_content = match rewrite_member(&content, &path.join(member.name()), replacement)? {
+        Some(new_content) => new_content,
+        None => return Ok(None),
+    };
+
+    // Keep the member's metadata, other than its size
+    let mut options = SimpleFileOptions::default()
+        .compression_method(match member.compression() {
+            CompressionMethod::Stored => CompressionMethod::Stored,
+            _ => CompressionMethod::Deflated,
+        })
+        .large_file(new_content.len() >= u32::MAX as usize);
+    if let Some(modified) = member.last_modified() {
+        options = options.last_modified_time(modified);
+    }
+    if let Some(mode) = member.unix_mode() {
+        options = options.unix_permissions(mode);
+    }
+
+    Ok(Some((member.name().to_string(), options, new_content)))
+}
+
+fn rewrite_tar<R: Read, W: Write>(
+    input: R,
+    output: W,
+    path: &Path,
+    replacement: &Replacement,
+) -> io::Result<(bool, W)> {
+    let mut archive = tar::Archive::new(input);
+    let mut builder = tar::Builder::new(output);
+
+    let mut changed = false;
+    for member in archive.entries()? {
+        let mut member = member?;
+        // The PAX extensions go with the member, as they hold its xattrs,
+        // precise times and long names
+        let mut pax: Vec<(String, Vec<u8>)> = match member.pax_extensions()? {
+            Some(extensions) => extensions
+                .map(|extension| {
+                    let extension = extension?;
+                    let key = extension.key().map_err(io::Error::other)?;
+                    Ok((key.to_string(), extension.value_bytes().to_vec()))
+                })
+                .collect::<io::Result<_>>()?,
+            None => Vec::new(),
+        };
+        let has_pax = |key: &str| pax.iter().any(|(name, _)| name == key);
+        let mut header = member.header().clone();
+        // Names too long for the header are otherwise stored in extra
+        // entries, which the builder writes again when it's given the full
+        // names
+        let short_names = (member.path_bytes() == header.path_bytes() || has_pax("path"))
+            && (member.link_name_bytes() == header.link_name_bytes() || has_pax("linkpath"));
+        let member_path = member.path()?.into_owned();
+        let link_name = member.link_name()?.map(|link| link.into_owned());
+
+        let mut content = Vec::new();
+        member.read_to_end(&mut content)?;
+
+        if header.entry_type().is_file() {
+            if let Some(new_content) =
+                rewrite_member(&content, &path.join(&member_path), replacement)?
+            {
+                content = new_content;
+                header.set_size(content.len() as u64);
+                for (key, value) in &mut pax {
+                    if key == "size" {
+                        *value = content.len().to_string().into_bytes();
+                    }
+                }
+                changed = true;
+            }
+        }
+
+        builder.append_pax_extensions(
+            pax.iter()
+                .map(|(key, value)| (key.as_str(), value.as_slice())),
+        )?;
+        // The header keeps the member's mode, owner and times
+        if short_names {
+            header.set_cksum();
+            builder.append(&header, content.as_slice())?;
+        } else if let Some(link_name) = link_name {
+            builder.append_link(&mut header, &member_path, &link_name)?;
+        } else {
+            builder.append_data(&mut header, &member_path, content.as_slice())?;
+        }
+    }
+
+    let output = builder.into_inner()?;
+    Ok((changed, output))
+}
+
+/// The new content of an archive member or compressed file, if it's text and
+/// the replacement changed it
+pub fn rewrite_member(
+    content: &[u8],
+    member_path: &Path,
+    replacement: &Replacement,
+) -> io::Result<Option<Vec<u8>>> {
+    if encoding::looks_binary(&content[..content.len().min(SNIFF_LEN)]) {
+        return Ok(None);
+    }
+
+    match rewrite_bytes(content, member_path, replacement)? {
+        Rewrite::Changed(new_content) => Ok(Some(new_content)),
+        Rewrite::Unchanged | Rewrite::NotText => Ok(None),
+    }
+}
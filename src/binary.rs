@@ -0,0 +1,108 @@
+use memchr::memmem;
+use regex::bytes::{Captures, Regex};
+
+/// Find and replace on raw bytes, for files that can't be decoded as text
+pub enum BinaryReplacer {
+    Literal {
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+    Regex {
+        regex: Regex,
+        new: Vec<u8>,
+        /// Apply the case of each match to the replacement, as for text
+        preserve_case: bool,
+    },
+}
+
+impl BinaryReplacer {
+    /// Build a byte-level equivalent of the text replacement
+    ///
+    /// Patterns can use `(?-u)` to match arbitrary bytes, such as `\xFF`,
+    /// rather than UTF-8 encoded characters.
+    pub fn new(
+        old: &str,
+        new: &str,
+        pattern: bool,
+        ignore_case: bool,
+    ) -> Result<BinaryReplacer, String> {
+        if !pattern && !ignore_case {
+            return Ok(BinaryReplacer::Literal {
+                old: old.as_bytes().to_vec(),
+                new: new.as_bytes().to_vec(),
+            });
+        }
+
+        let source = if pattern {
+            old.to_string()
+        } else {
+            regex::escape(old)
+        };
+        let source = if ignore_case {
+            format!("(?i){}", source)
+        } else {
+            source
+        };
+        let regex = Regex::new(&source).map_err(|e| e.to_string())?;
+
+        Ok(BinaryReplacer::Regex {
+            regex,
+            new: new.as_bytes().to_vec(),
+            preserve_case: ignore_case && !pattern,
+        })
+    }
+
+    /// How many times the pattern matches in `haystack`
+    pub fn count(&self, haystack: &[u8]) -> u64 {
+        let count = match self {
+            BinaryReplacer::Literal { old, .. } => memmem::find_iter(haystack, old).count(),
+            BinaryReplacer::Regex { regex, .. } => regex.find_iter(haystack).count(),
+        };
+        count as u64
+    }
+
+    /// Return the replaced bytes, or None if nothing matched
+    pub fn replace(&self, haystack: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            BinaryReplacer::Literal { old, new } => {
+                let mut matches = memmem::find_iter(haystack, old).peekable();
+                matches.peek()?;
+
+                let mut result = Vec::with_capacity(haystack.len());
+                let mut last = 0;
+                for start in matches {
+                    result.extend_from_slice(&haystack[last..start]);
+                    result.extend_from_slice(new);
+                    last = start + old.len();
+                }
+                result.extend_from_slice(&haystack[last..]);
+                Some(result)
+            }
+            BinaryReplacer::Regex {
+                regex,
+                new,
+                preserve_case,
+            } => {
+                if !regex.is_match(haystack) {
+                    return None;
+                }
+
+                let result = if *preserve_case {
+                    regex.replace_all(haystack, |caps: &Captures| {
+                        let matched = &caps[0];
+                        // Case can only be carried over from matches that are text
+                        match (std::str::from_utf8(matched), std::str::from_utf8(new)) {
+                            (Ok(matched), Ok(new)) => {
+                                crate::case::apply_case_pattern(matched, new).into_bytes()
+                            }
+                            _ => new.clone(),
+                        }
+                    })
+                } else {
+                    regex.replace_all(haystack, new.as_slice())
+                };
+                Some(result.into_owned())
+            }
+        }
+    }
+}
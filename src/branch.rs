@@ -0,0 +1,66 @@
+//! --branch, which makes a run's changes on a new branch off HEAD and
+//! commits them there, leaving the current branch and working tree alone
+//!
+//! The branch is checked out in a worktree of its own in a temporary
+//! directory, which the run is made in and which is removed once it's done.
+
+use crate::commit::git;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A new branch, checked out in a temporary worktree
+pub struct Worktree {
+    /// Where the worktree is
+    pub path: PathBuf,
+    /// The top of the working tree the branch was made from
+    top: PathBuf,
+    branch: String,
+    /// The commit the branch starts at
+    base: String,
+}
+
+impl Worktree {
+    /// Make the branch `name` off HEAD, and a worktree for it
+    pub fn create(name: &str) -> Result<Worktree, String> {
+        let top = PathBuf::from(git(&["rev-parse", "--show-toplevel"])?.trim());
+        let top = fs::canonicalize(&top).unwrap_or(top);
+        let base = git(&["rev-parse", "HEAD"])?.trim().to_string();
+        let path = tempfile::Builder::new()
+            .prefix("newtext-")
+            .tempdir()
+            .map_err(|e| format!("couldn't make a directory for the worktree: {}", e))?
+            .keep();
+        let worktree = path.to_string_lossy().into_owned();
+        if let Err(e) = git(&["worktree", "add", "--quiet", "-b", name, &worktree, &base]) {
+            let _ = fs::remove_dir_all(&path);
+            return Err(e);
+        }
+        Ok(Worktree {
+            path,
+            top,
+            branch: name.to_string(),
+            base,
+        })
+    }
+
+    /// Where `path`, in the working tree the branch was made from, is in the
+    /// worktree, or None if it's outside it
+    pub fn path_for(&self, path: &Path) -> Option<PathBuf> {
+        let path = fs::canonicalize(path).ok()?;
+        let relative = path.strip_prefix(&self.top).ok()?;
+        Some(self.path.join(relative))
+    }
+
+    /// Remove the worktree, and the branch too if nothing was committed to
+    /// it. Returns whether the branch was kept.
+    pub fn finish(self) -> Result<bool, String> {
+        let worktree = self.path.to_string_lossy().into_owned();
+        git(&["worktree", "remove", "--force", &worktree])?;
+        let head = git(&["rev-parse", &format!("refs/heads/{}", self.branch)])?;
+        if head.trim() != self.base {
+            return Ok(true);
+        }
+        git(&["branch", "--quiet", "-D", &self.branch])?;
+        Ok(false)
+    }
+}
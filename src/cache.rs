@@ -0,0 +1,179 @@
+use crate::journal;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use tempfile::NamedTempFile;
+
+/// The directory in the journal directory that holds the caches
+const CACHE_DIR: &str = "cache";
+
+/// The files that earlier runs of the same replacement found needed no
+/// changes, so that later runs can skip them without reading them
+///
+/// Each replacement has its own cache under `.newtext/cache`, named by a hash
+/// of everything that decides whether a file needs changing. A file is
+/// skipped if its size and modification time are as they were, or if only
+/// its modification time differs and its content hashes the same.
+pub struct Cache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, Entry>,
+    /// The entries from this run, or None for files looked up but not found
+    /// to need no changes
+    found: Mutex<HashMap<PathBuf, Option<Entry>>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    /// Nanoseconds since the Unix epoch
+    modified: u64,
+    hash: u64,
+}
+
+/// What the cache knows about a file
+pub enum Lookup {
+    /// It needed no changes, and hasn't been modified since
+    Unchanged,
+    /// It has to be read to tell, as of its size and modification time
+    Unknown(Stat),
+}
+
+#[derive(Clone, Copy)]
+pub struct Stat {
+    size: u64,
+    modified: u64,
+}
+
+impl Cache {
+    /// The cache for the replacement described by `key`, in `root`
+    ///
+    /// A missing or unreadable cache is started afresh.
+    pub fn load(root: &Path, key: &str) -> Cache {
+        let name = format!("{:016x}.jsonl", journal::hash(key.as_bytes()));
+        let path = root.join(journal::JOURNAL_DIR).join(CACHE_DIR).join(name);
+        let entries = File::open(&path)
+            .map(|file| read_entries(BufReader::new(file)))
+            .unwrap_or_default();
+        Cache {
+            path,
+            entries,
+            found: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Look up `path`, before reading it, or None if its size and
+    /// modification time can't be read for it to be cached
+    pub fn look_up(&self, path: &Path) -> Option<Lookup> {
+        let stat = stat(path)?;
+        Some(match self.entries.get(path) {
+            Some(entry) if entry.size == stat.size && entry.modified == stat.modified => {
+                Lookup::Unchanged
+            }
+            _ => {
+                self.found.lock().unwrap().insert(path.to_path_buf(), None);
+                Lookup::Unknown(stat)
+            }
+        })
+    }
+
+    /// Whether `bytes`, read from `path` after it was looked up, are what
+    /// they were when the file needed no changes. They're recorded again if
+    /// so, as of `stat`.
+    pub fn is_unchanged(&self, path: &Path, stat: Stat, bytes: &[u8]) -> bool {
+        let entry = match self.entries.get(path) {
+            Some(entry) if entry.size == stat.size => entry,
+            _ => return false,
+        };
+        let hash = journal::hash(bytes);
+        if entry.hash != hash {
+            return false;
+        }
+        self.record(path, stat, hash);
+        true
+    }
+
+    /// Note that `bytes`, read from `path` after it was looked up, need no
+    /// changes
+    pub fn record_unchanged(&self, path: &Path, stat: Stat, bytes: &[u8]) {
+        self.record(path, stat, journal::hash(bytes));
+    }
+
+    fn record(&self, path: &Path, stat: Stat, hash: u64) {
+        let entry = Entry {
+            size: stat.size,
+            modified: stat.modified,
+            hash,
+        };
+        self.found
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), Some(entry));
+    }
+
+    /// Write the cache out with what this run found
+    ///
+    /// It's replaced in one go, so a run that's interrupted, or another run
+    /// saving the same cache, leaves a whole cache behind.
+    pub fn save(&self, root: &Path) -> io::Result<()> {
+        let mut entries = self.entries.clone();
+        for (path, entry) in self.found.lock().unwrap().drain() {
+            match entry {
+                Some(entry) => entries.insert(path, entry),
+                None => entries.remove(&path),
+            };
+        }
+
+        let dir = journal::create_journal_dir(root)?.join(CACHE_DIR);
+        fs::create_dir_all(&dir)?;
+        let mut temp = NamedTempFile::new_in(&dir)?;
+        {
+            let mut out = io::BufWriter::new(temp.as_file_mut());
+            // Paths that aren't UTF-8 are looked up afresh each time
+            for (path, entry) in entries
+                .iter()
+                .filter_map(|(path, entry)| Some((path.to_str()?, entry)))
+            {
+                let line = json!([path, entry.size, entry.modified, entry.hash]);
+                writeln!(out, "{}", line)?;
+            }
+            out.flush()?;
+        }
+        temp.persist(&self.path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+/// The entries in a cache file, leaving out any lines that can't be read
+fn read_entries(reader: impl BufRead) -> HashMap<PathBuf, Entry> {
+    let mut entries = HashMap::new();
+    for line in reader.lines().map_while(Result::ok) {
+        let value: Value = match serde_json::from_str(&line) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        let entry = (|| {
+            let path = PathBuf::from(value.get(0)?.as_str()?);
+            let entry = Entry {
+                size: value.get(1)?.as_u64()?,
+                modified: value.get(2)?.as_u64()?,
+                hash: value.get(3)?.as_u64()?,
+            };
+            Some((path, entry))
+        })();
+        entries.extend(entry);
+    }
+    entries
+}
+
+fn stat(path: &Path) -> Option<Stat> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(Stat {
+        size: metadata.len(),
+        modified: u64::try_from(modified.as_nanos()).ok()?,
+    })
+}
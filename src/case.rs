@@ -0,0 +1,72 @@
+/// Apply the case pattern from the matched text to the replacement text
+pub fn apply_case_pattern(matched: &str, replacement: &str) -> String {
+    // If the matched text has no letters, just return the replacement as-is
+    if !matched.chars().any(|c| c.is_alphabetic()) {
+        return replacement.to_string();
+    }
+
+    let matched_chars: Vec<char> = matched.chars().collect();
+
+    // Determine case pattern of matched text
+    let alphabetic_chars: Vec<char> = matched_chars
+        .iter()
+        .filter(|c| c.is_alphabetic())
+        .copied()
+        .collect();
+
+    if alphabetic_chars.is_empty() {
+        return replacement.to_string();
+    }
+
+    let all_upper = alphabetic_chars.iter().all(|c| c.is_uppercase());
+    let all_lower = alphabetic_chars.iter().all(|c| c.is_lowercase());
+    let first_upper = alphabetic_chars[0].is_uppercase()
+        && alphabetic_chars[1..].iter().all(|c| c.is_lowercase());
+
+    if all_upper {
+        // All uppercase: BAR -> BAR
+        replacement.to_uppercase()
+    } else if all_lower {
+        // All lowercase: bar -> bar
+        replacement.to_lowercase()
+    } else if first_upper {
+        // Title case: Bar -> Bar
+        let mut result = String::new();
+        let mut first_letter = true;
+        for c in replacement.chars() {
+            if c.is_alphabetic() {
+                if first_letter {
+                    result.push_str(&c.to_uppercase().to_string());
+                    first_letter = false;
+                } else {
+                    result.push_str(&c.to_lowercase().to_string());
+                }
+            } else {
+                result.push(c);
+            }
+        }
+        result
+    } else {
+        // Mixed case (including camelCase): try to preserve pattern character by character
+        let mut result = String::new();
+        let mut matched_alpha_iter = alphabetic_chars.iter();
+
+        for c in replacement.chars() {
+            if c.is_alphabetic() {
+                if let Some(&matched_c) = matched_alpha_iter.next() {
+                    if matched_c.is_uppercase() {
+                        result.push_str(&c.to_uppercase().to_string());
+                    } else {
+                        result.push_str(&c.to_lowercase().to_string());
+                    }
+                } else {
+                    // If we run out of matched characters, keep the original case
+                    result.push(c);
+                }
+            } else {
+                result.push(c);
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,66 @@
+use clap::ValueEnum;
+use std::env;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether what's printed to stdout is colored
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// When to color the paths, line numbers and changes that are printed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum When {
+    /// When stdout is a terminal and NO_COLOR isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+pub fn set(when: When) {
+    let enabled = match when {
+        When::Auto => io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+        When::Always => true,
+        When::Never => false,
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// `path:line:` as printed for a matching line, colored as ripgrep colors it
+pub fn location(path: &str, line: usize) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[35m{}\x1b[0m:\x1b[32m{}\x1b[0m:", path, line)
+    } else {
+        format!("{}:{}:", path, line)
+    }
+}
+
+/// A path as printed by itself, for a file with no matching lines to show
+pub fn path(path: &str) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[35m{}\x1b[0m", path)
+    } else {
+        path.to_string()
+    }
+}
+
+/// Whether output is colored, for output with colors of its own
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Text that a change removes, in red
+pub fn removed(text: &str) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[31m{}\x1b[0m", text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Text that a change adds, in green
+pub fn added(text: &str) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[32m{}\x1b[0m", text)
+    } else {
+        text.to_string()
+    }
+}
@@ -0,0 +1,72 @@
+//! --commit, which commits the files a run changed once it's done, and
+//! nothing else, so the replacement is kept apart from other work in
+//! progress
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Commit `paths`, the files a run modified and the paths of those it
+/// renamed, with `message`, leaving everything else that's staged or changed
+/// as it was. Returns the new commit's short hash.
+pub fn commit(paths: &[String], message: &str) -> Result<String, String> {
+    check()?;
+    // The files that are there are staged first, so that new ones can be
+    // committed
+    let (present, missing): (Vec<&String>, Vec<&String>) =
+        paths.iter().partition(|path| Path::new(path).exists());
+    let mut add = vec!["add", "--all", "--"];
+    add.extend(present.iter().map(|path| path.as_str()));
+    git(&add)?;
+
+    // The old paths of renamed files are gone, and are committed as removed
+    // if git has them
+    let mut paths: Vec<&str> = present.into_iter().map(String::as_str).collect();
+    if !missing.is_empty() {
+        let mut args = vec!["ls-files", "--with-tree=HEAD", "--"];
+        args.extend(missing.iter().map(|path| path.as_str()));
+        let known = git(&args).unwrap_or_default();
+        paths.extend(
+            missing
+                .into_iter()
+                .filter(|path| {
+                    known
+                        .lines()
+                        .any(|known| Path::new(known) == Path::new(path))
+                })
+                .map(String::as_str),
+        );
+    }
+
+    // Only these paths are committed, whatever else is staged
+    let mut commit = vec!["commit", "--quiet", "--message", message, "--only", "--"];
+    commit.extend(&paths);
+    git(&commit)?;
+    Ok(git(&["rev-parse", "--short", "HEAD"])?.trim().to_string())
+}
+
+/// Check that the current directory is in a git repository, so that there's
+/// somewhere to commit to
+pub fn check() -> Result<(), String> {
+    git(&["rev-parse", "--is-inside-work-tree"])
+        .map(|_| ())
+        .map_err(|_| "it isn't inside a git repository".to_string())
+}
+
+/// Run git with `args`, returning what it prints
+pub(crate) fn git(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("couldn't run git: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.iter()
+                .find(|arg| !arg.starts_with('-') && !arg.contains('='))
+                .unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,650 @@
+//! Defaults for options, from the user's config file, the project's and the
+//! environment
+//!
+//! Each setting is named after an option's long name, as in `jobs = 4` or
+//! `exclude = ["vendor/"]`, or `NEWTEXT_JOBS=4` in the environment. The
+//! settings are given to newtext ahead of the options on the command line,
+//! which take precedence over them. A project's config file can only set
+//! a few options that don't run commands, read or write outside the tree or
+//! skip safeguards, since it comes with a checkout that may not be trusted.
+//!
+//! Recipes are named lists of replacements for `newtext run`, under
+//! `[recipes.NAME]`, and rules for `newtext hook` to check the files about to
+//! be committed against are under `[rules.NAME]`.
+
+use crate::Cli;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, Command, CommandFactory};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// The project's config file, in the current directory or one above it
+pub const PROJECT_FILE: &str = ".newtext.toml";
+
+/// Environment variables that start with the prefix for settings, but that
+/// newtext sets itself for hooks
+const NOT_SETTINGS: &[&str] = &["NEWTEXT_FILES_MODIFIED"];
+
+/// The settings a project's config file can give. Everything else, such as
+/// options that run commands, write logs or patches elsewhere or turn off
+/// the journal or confirmation, can only come from the command line, the
+/// user's config file or the environment.
+const PROJECT_SETTINGS: &[&str] = &[
+    "exclude",
+    "no-ignore",
+    "color",
+    "jobs",
+    "encoding",
+    "detect-encoding",
+    "eol",
+    "final-newline",
+    "strip-bom",
+];
+
+/// The options a project's recipes can give their steps, which say what's
+/// replaced and in which files, and otherwise leave the run to the command
+/// line
+const PROJECT_RECIPE_OPTIONS: &[&str] = &[
+    "pattern",
+    "ignore-case",
+    "reverse",
+    "sed",
+    "sed-extended",
+    "fix",
+    "eol",
+    "final-newline",
+    "strip-bom",
+    "encoding",
+    "detect-encoding",
+    "exclude",
+    "no-ignore",
+    "json-path",
+    "yaml-path",
+    "toml-key",
+    "selector",
+    "xpath",
+    "xml-text",
+    "csv-column",
+    "csv-delimiter",
+    "csv-quote",
+    "csv-no-header",
+    "markdown",
+    "front-matter",
+    "key",
+    "ensure",
+    "notebook-cells",
+    "po",
+    "binary",
+    "archives",
+    "compressed",
+    "office",
+    "rename",
+    "fail-if-no-matches",
+];
+
+/// Options that run commands, which an environment variable can't give
+/// either, so that a variable left set in a shell doesn't run whatever it
+/// says
+const RUN_COMMANDS: &[&str] = &[
+    "pre-hook",
+    "post-hook",
+    "validate",
+    "format-cmd",
+    "plugin",
+    "script",
+];
+
+/// A default for an option, and where it came from
+pub struct Setting {
+    pub name: String,
+    pub value: toml::Value,
+    pub source: Source,
+}
+
+pub enum Source {
+    File(PathBuf),
+    /// An environment variable, with its name
+    Env(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File(file) => write!(f, "{}", file.display()),
+            Source::Env(var) => write!(f, "the {} environment variable", var),
+        }
+    }
+}
+
+/// The settings, recipes and rules from the config files
+#[derive(Default)]
+pub struct Config {
+    /// The config files that were read
+    files: Vec<PathBuf>,
+    pub settings: Vec<Setting>,
+    recipes: BTreeMap<String, (toml::Table, PathBuf)>,
+    rules: BTreeMap<String, (toml::Table, PathBuf)>,
+}
+
+/// A named list of replacements, each made by a run of its own
+pub struct Recipe {
+    pub description: Option<String>,
+    /// The arguments to `newtext replace` for each replacement
+    pub steps: Vec<Vec<OsString>>,
+}
+
+/// Something that `newtext hook` checks for in the files about to be
+/// committed, and either replaces or rejects
+pub struct Rule {
+    pub name: String,
+    /// What the rule finds, as OLD is found
+    pub old: String,
+    /// What it's replaced with, or None if it isn't allowed at all
+    pub new: Option<String>,
+    pub pattern: bool,
+    pub ignore_case: bool,
+    /// Globs for the files the rule applies to, or none for every file
+    pub paths: Vec<String>,
+    pub message: Option<String>,
+}
+
+/// The user's config file, whether or not it exists
+fn user_file() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_dir.join("newtext").join("config.toml"))
+}
+
+/// An error if `setting` can't be given where it comes from: a project's
+/// config file, described by `place`, can only give the settings in
+/// `project_allows`, and the environment can't give options that run
+/// commands
+fn check_allowed(setting: &Setting, project_allows: &[&str], place: &str) -> Result<(), String> {
+    let name = setting.name.as_str();
+    match &setting.source {
+        Source::File(file) if user_file().as_ref() == Some(file) => Ok(()),
+        Source::File(_) if project_allows.contains(&name) => Ok(()),
+        Source::File(_) => Err(format!(
+            "{} can't be set in {}, which can only set {}",
+            name,
+            place,
+            project_allows.join(", ")
+        )),
+        Source::Env(_) if !RUN_COMMANDS.contains(&name) => Ok(()),
+        source => Err(format!(
+            "{} in {} runs a command, so it can only be given on the command line or in the user's config file",
+            name, source
+        )),
+    }
+}
+
+/// The nearest project config file to `dir`
+fn project_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|file| file.is_file())
+}
+
+/// The settings and recipes in the user's and the project's config files,
+/// and the settings in the environment, each taking precedence over the
+/// last
+pub fn load(current_dir: &Path) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut settings = BTreeMap::new();
+    for file in [user_file(), project_file(current_dir)]
+        .into_iter()
+        .flatten()
+    {
+        let table = match fs::read_to_string(&file) {
+            Ok(text) => parse(&text, &file)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("Could not read {}: {}", file.display(), e)),
+        };
+        for setting in config.add(table, &file)? {
+            let place = format!("the project's config file {}", file.display());
+            check_allowed(&setting, PROJECT_SETTINGS, &place)?;
+            settings.insert(setting.name.clone(), setting);
+        }
+    }
+    for (var, value) in env::vars_os() {
+        let Some(var) = var.to_str().filter(|var| !NOT_SETTINGS.contains(var)) else {
+            continue;
+        };
+        let Some(name) = var.strip_prefix("NEWTEXT_") else {
+            continue;
+        };
+        let value = value
+            .into_string()
+            .map_err(|_| format!("{} isn't UTF-8", var))?;
+        let setting = Setting {
+            name: long_name(&name.to_lowercase().replace('_', "-")),
+            value: toml::Value::String(value),
+            source: Source::Env(var.to_string()),
+        };
+        settings.insert(setting.name.clone(), setting);
+    }
+    config.settings = settings.into_values().collect();
+    Ok(config)
+}
+
+/// The config file `file` alone, without the environment
+pub fn load_file(file: &Path) -> Result<Config, String> {
+    let text = fs::read_to_string(file)
+        .map_err(|e| format!("Could not read {}: {}", file.display(), e))?;
+    let mut config = Config::default();
+    config.settings = config.add(parse(&text, file)?, file)?;
+    Ok(config)
+}
+
+fn parse(text: &str, file: &Path) -> Result<toml::Table, String> {
+    text.parse()
+        .map_err(|e| format!("Could not parse {}: {}", file.display(), e))
+}
+
+impl Config {
+    /// Take the recipes and rules in the table from a config file, returning
+    /// its settings
+    fn add(&mut self, table: toml::Table, file: &Path) -> Result<Vec<Setting>, String> {
+        self.files.push(file.to_path_buf());
+        let mut settings = Vec::new();
+        for (name, value) in table {
+            let named = match name.as_str() {
+                "recipes" => &mut self.recipes,
+                "rules" => &mut self.rules,
+                _ => {
+                    settings.push(Setting {
+                        name: long_name(&name),
+                        value,
+                        source: Source::File(file.to_path_buf()),
+                    });
+                    continue;
+                }
+            };
+            let toml::Value::Table(table) = value else {
+                return Err(format!("{} in {} should be a table", name, file.display()));
+            };
+            for (entry, value) in table {
+                let toml::Value::Table(value) = value else {
+                    return Err(format!(
+                        "{}.{} in {} should be a table",
+                        name,
+                        entry,
+                        file.display()
+                    ));
+                };
+                named.insert(entry, (value, file.to_path_buf()));
+            }
+        }
+        Ok(settings)
+    }
+
+    /// The names of the recipes, with their descriptions
+    pub fn recipes(&self) -> Vec<(&str, Option<&str>)> {
+        self.recipes
+            .iter()
+            .map(|(name, (recipe, _))| {
+                let description = recipe.get("description").and_then(toml::Value::as_str);
+                (name.as_str(), description)
+            })
+            .collect()
+    }
+
+    /// The recipe called `name`
+    ///
+    /// Options given for the whole recipe come before those for each step,
+    /// so a step's take precedence.
+    pub fn recipe(&self, name: &str) -> Result<Recipe, String> {
+        let (table, file) = self
+            .recipes
+            .get(name)
+            .ok_or_else(|| format!("There's no recipe called {}", name))?;
+        let context = format!("recipe {} in {}", name, file.display());
+        let replace = Cli::command();
+        let replace = replace.find_subcommand("replace").expect("replace command");
+
+        let description = match table.get("description") {
+            Some(toml::Value::String(description)) => Some(description.clone()),
+            Some(_) => return Err(format!("description in {} should be a string", context)),
+            None => None,
+        };
+        let steps = match table.get("steps") {
+            Some(toml::Value::Array(steps)) if !steps.is_empty() => steps,
+            _ => return Err(format!("steps in {} should be a list of tables", context)),
+        };
+        let shared = recipe_args(table, &["description", "steps"], replace, file, &context)?;
+
+        let mut recipe = Recipe {
+            description,
+            steps: Vec::new(),
+        };
+        for (i, step) in steps.iter().enumerate() {
+            let context = format!("step {} of {}", i + 1, context);
+            let toml::Value::Table(step) = step else {
+                return Err(format!("{} should be a table", context));
+            };
+            let mut args = shared.options.clone();
+            let step = recipe_args(step, &[], replace, file, &context)?;
+            args.extend(step.options);
+            // Anything after -- is OLD, NEW or a path, even if it starts
+            // with -
+            args.push("--".into());
+            args.extend(step.old.or_else(|| shared.old.clone()));
+            args.extend(step.new.or_else(|| shared.new.clone()));
+            let paths = if step.paths.is_empty() {
+                &shared.paths
+            } else {
+                &step.paths
+            };
+            args.extend(paths.iter().cloned());
+            recipe.steps.push(args);
+        }
+        Ok(recipe)
+    }
+}
+
+impl Config {
+    /// The rules for `newtext hook`, in order of their names
+    pub fn rules(&self) -> Result<Vec<Rule>, String> {
+        self.rules
+            .iter()
+            .map(|(name, (table, file))| rule(name, table, file))
+            .collect()
+    }
+}
+
+fn rule(name: &str, table: &toml::Table, file: &Path) -> Result<Rule, String> {
+    let context = format!("rule {} in {}", name, file.display());
+    let string = |key: &str| match table.get(key) {
+        Some(toml::Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(format!("{} in {} should be a string", key, context)),
+        None => Ok(None),
+    };
+    let flag = |key: &str| match table.get(key) {
+        Some(toml::Value::Boolean(set)) => Ok(*set),
+        Some(_) => Err(format!("{} in {} should be true or false", key, context)),
+        None => Ok(false),
+    };
+    if let Some(key) = table.keys().find(|key| {
+        ![
+            "deny",
+            "old",
+            "new",
+            "pattern",
+            "ignore-case",
+            "paths",
+            "message",
+        ]
+        .contains(&key.as_str())
+    }) {
+        return Err(format!("Unknown setting {} in {}", key, context));
+    }
+
+    let (old, new) = match (string("deny")?, string("old")?, string("new")?) {
+        (Some(old), None, None) => (old, None),
+        (None, Some(old), Some(new)) => (old, Some(new)),
+        _ => {
+            return Err(format!(
+                "{} should have either deny, or old and new",
+                context
+            ))
+        }
+    };
+    if old.is_empty() {
+        return Err(format!("What {} finds can't be empty", context));
+    }
+    let paths = match table.get("paths") {
+        Some(toml::Value::Array(paths)) => paths
+            .iter()
+            .map(|path| path.as_str().map(str::to_string))
+            .collect::<Option<_>>()
+            .ok_or_else(|| format!("paths in {} should be a list of globs", context))?,
+        Some(_) => return Err(format!("paths in {} should be a list of globs", context)),
+        None => Vec::new(),
+    };
+    Ok(Rule {
+        name: name.to_string(),
+        old,
+        new,
+        pattern: flag("pattern")?,
+        ignore_case: flag("ignore-case")?,
+        paths,
+        message: string("message")?,
+    })
+}
+
+/// The arguments that a recipe, or a step of one, gives
+#[derive(Default)]
+struct RecipeArgs {
+    old: Option<OsString>,
+    new: Option<OsString>,
+    paths: Vec<OsString>,
+    options: Vec<OsString>,
+}
+
+fn recipe_args(
+    table: &toml::Table,
+    skip: &[&str],
+    command: &Command,
+    file: &Path,
+    context: &str,
+) -> Result<RecipeArgs, String> {
+    let string = |name: &str, value: &toml::Value| match value {
+        toml::Value::String(s) => Ok(OsString::from(s)),
+        _ => Err(format!("{} in {} should be a string", name, context)),
+    };
+    let mut args = RecipeArgs::default();
+    for (name, value) in table {
+        match name.as_str() {
+            name if skip.contains(&name) => {}
+            "old" => args.old = Some(string(name, value)?),
+            "new" => args.new = Some(string(name, value)?),
+            "paths" => {
+                let toml::Value::Array(paths) = value else {
+                    return Err(format!("paths in {} should be a list", context));
+                };
+                for path in paths {
+                    args.paths.push(string("paths", path)?);
+                }
+            }
+            _ => {
+                let arg = command
+                    .get_arguments()
+                    .find(|arg| is_named(arg, name))
+                    .ok_or_else(|| format!("Unknown option {} in {}", name, context))?;
+                let setting = Setting {
+                    name: long_name(name),
+                    value: value.clone(),
+                    source: Source::File(file.to_path_buf()),
+                };
+                check_allowed(&setting, PROJECT_RECIPE_OPTIONS, context)?;
+                args.options
+                    .extend(option_args(arg.get_action(), &setting)?);
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// `args`, the command line, with the options that `settings` give put
+/// ahead of those given on it
+///
+/// Settings for options that the command doesn't take are left out, so the
+/// same config can set `exclude` for runs and leave `newtext undo` alone.
+pub fn with_defaults(args: Vec<OsString>, settings: &[Setting]) -> Result<Vec<OsString>, String> {
+    let command = Cli::command();
+    // Errors and --help are left for parsing the command line itself to show
+    let Ok(matches) = command.clone().try_get_matches_from(&args) else {
+        return Ok(args);
+    };
+    // Commands can't follow options, so they're always first
+    let (at, target) = match matches.subcommand_name() {
+        Some(name) => (2, command.find_subcommand(name).expect("parsed command")),
+        None => (1, &command),
+    };
+
+    let mut defaults = Vec::new();
+    for setting in settings {
+        // Global options are only defined on newtext itself
+        let arg = target
+            .get_arguments()
+            .chain(command.get_arguments().filter(|arg| arg.is_global_set()))
+            .find(|arg| is_named(arg, &setting.name));
+        match arg {
+            Some(arg) => {
+                let place = format!("the project's config file {}", setting.source);
+                check_allowed(setting, PROJECT_SETTINGS, &place)?;
+                defaults.extend(option_args(arg.get_action(), setting)?)
+            }
+            None if find_option(&command, &setting.name).is_none() => {
+                warn!("Unknown setting {} in {}", setting.name, setting.source)
+            }
+            None => {}
+        }
+    }
+
+    let mut args = args;
+    let rest = args.split_off(at.min(args.len()));
+    args.extend(defaults);
+    args.extend(rest);
+    Ok(args)
+}
+
+/// Whether an option is called `name`, by its long name or an alias
+fn is_named(arg: &Arg, name: &str) -> bool {
+    arg.get_long() == Some(name) || arg.get_all_aliases().is_some_and(|a| a.contains(&name))
+}
+
+/// The option called `name` to newtext or to any of its commands
+fn find_option<'a>(command: &'a Command, name: &str) -> Option<&'a Arg> {
+    command
+        .get_arguments()
+        .find(|arg| is_named(arg, name))
+        .or_else(|| {
+            command
+                .get_subcommands()
+                .find_map(|subcommand| find_option(subcommand, name))
+        })
+}
+
+/// The long name of the option called `name`, which may be an alias
+fn long_name(name: &str) -> String {
+    find_option(&Cli::command(), name)
+        .and_then(Arg::get_long)
+        .unwrap_or(name)
+        .to_string()
+}
+
+/// The arguments that give an option the value of a setting
+fn option_args(action: &ArgAction, setting: &Setting) -> Result<Vec<OsString>, String> {
+    let name = &setting.name;
+    let invalid = |expected: &str| format!("{} in {} should be {}", name, setting.source, expected);
+    if !action.takes_values() {
+        // Environment variables can only be strings
+        let set = match &setting.value {
+            toml::Value::Boolean(set) => *set,
+            toml::Value::String(s) if ["1", "true", "yes"].contains(&s.as_str()) => true,
+            toml::Value::String(s) if ["", "0", "false", "no"].contains(&s.as_str()) => false,
+            _ => return Err(invalid("true or false")),
+        };
+        return Ok(if set {
+            vec![format!("--{}", name).into()]
+        } else {
+            Vec::new()
+        });
+    }
+
+    let values = match &setting.value {
+        toml::Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    };
+    values
+        .into_iter()
+        .map(|value| {
+            let value = match value {
+                toml::Value::String(s) => s.clone(),
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                    value.to_string()
+                }
+                _ => return Err(invalid("a string, a number or a list of them")),
+            };
+            Ok(format!("--{}={}", name, value).into())
+        })
+        .collect()
+}
+
+/// Print the value of each option that `args`, the command line with the
+/// defaults put in, gives, and where it came from: a setting, `given` on the
+/// command line itself, or the option's default
+pub fn print(given: &[OsString], args: &[OsString], config: &Config) {
+    let command = Cli::command();
+    let given = command.clone().get_matches_from(given);
+    let matches = command.clone().get_matches_from(args);
+    let (target, given, matches) = match matches.subcommand() {
+        Some((name, matches)) => (
+            command.find_subcommand(name).expect("parsed command"),
+            given.subcommand_matches(name).expect("given command"),
+            matches,
+        ),
+        None => (&command, &given, &matches),
+    };
+
+    if config.files.is_empty() {
+        println!("# No config files");
+    }
+    for file in &config.files {
+        println!("# Read {}", file.display());
+    }
+    let mut lines = Vec::new();
+    for arg in target.get_arguments() {
+        let id = arg.get_id().as_str();
+        let (Some(name), false) = (arg.get_long(), arg.is_hide_set() || id == "print_config")
+        else {
+            continue;
+        };
+        let values: Vec<_> = matches
+            .get_raw(id)
+            .map(|values| values.map(|value| value.to_string_lossy()).collect())
+            .unwrap_or_default();
+        let value = match arg.get_action() {
+            ArgAction::Append => format!("[{}]", values.join(", ")),
+            _ if values.is_empty() => "(not set)".to_string(),
+            _ => values.join(", "),
+        };
+
+        let on_command_line = given.value_source(id) == Some(ValueSource::CommandLine);
+        let setting = config.settings.iter().find(|setting| setting.name == name);
+        let mut sources = Vec::new();
+        // Lists take values from everywhere, but otherwise the command line
+        // wins
+        if let Some(setting) = setting {
+            if !on_command_line || matches!(arg.get_action(), ArgAction::Append) {
+                sources.push(setting.source.to_string());
+            }
+        }
+        if on_command_line {
+            sources.push("the command line".to_string());
+        }
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => format!("from {}", sources.join(" and ")),
+            Some(ValueSource::DefaultValue) => "default".to_string(),
+            _ => String::new(),
+        };
+        lines.push((format!("{} = {}", name, value), source));
+    }
+
+    let width = lines.iter().map(|(line, _)| line.len()).max().unwrap_or(0);
+    for (line, source) in lines {
+        if source.is_empty() {
+            println!("{}", line);
+        } else {
+            println!("{:width$}  # {}", line, source, width = width);
+        }
+    }
+}
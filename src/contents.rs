@@ -0,0 +1,71 @@
+use crate::throttle;
+use memmap2::Mmap;
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::Path;
+
+/// Files at least this large are memory-mapped instead of read into a buffer
+const MMAP_THRESHOLD: u64 = 1 << 20;
+
+/// How much of a file is read to guess whether it's binary
+pub const SNIFF_LEN: usize = 8192;
+
+/// The raw bytes of a file, either read into memory or memory-mapped
+///
+/// Mapping large files means checking them for matches doesn't need to copy
+/// their content, which matters on big trees where most files don't match.
+pub enum Contents {
+    Read(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Contents {
+    pub fn read(path: &Path) -> io::Result<Contents> {
+        Ok(Contents::open(path, false)?.expect("only sniffed reads are skipped"))
+    }
+
+    /// Read a file, or return None if its first few KB show it's binary
+    pub fn read_unless_binary(path: &Path) -> io::Result<Option<Contents>> {
+        Contents::open(path, true)
+    }
+
+    fn open(path: &Path, sniff: bool) -> io::Result<Option<Contents>> {
+        let mut file = throttle::open(path)?;
+        let len = file.metadata()?.len();
+
+        if len >= MMAP_THRESHOLD {
+            // SAFETY: the mapping is only read, and is dropped before the file
+            // is rewritten. Another process truncating the file while it's
+            // mapped could still cause a fault, which ripgrep accepts too.
+            if let Ok(mmap) = unsafe { Mmap::map(&file) } {
+                if sniff && crate::encoding::looks_binary(&mmap[..mmap.len().min(SNIFF_LEN)]) {
+                    return Ok(None);
+                }
+                return Ok(Some(Contents::Mapped(mmap)));
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(len as usize);
+        if sniff {
+            // Binary files can be large, so look at the start before reading on
+            (&mut file).take(SNIFF_LEN as u64).read_to_end(&mut bytes)?;
+            if crate::encoding::looks_binary(&bytes) {
+                return Ok(None);
+            }
+        }
+        file.read_to_end(&mut bytes)?;
+
+        Ok(Some(Contents::Read(bytes)))
+    }
+}
+
+impl Deref for Contents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Contents::Read(bytes) => bytes,
+            Contents::Mapped(mmap) => mmap,
+        }
+    }
+}
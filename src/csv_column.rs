@@ -0,0 +1,193 @@
+//! --csv-column, which limits replacements to the values in some columns of
+//! CSV and TSV files
+//!
+//! Fields are found by scanning rather than by reading records into
+//! values, so that everything but the fields that change, including how
+//! every other field is quoted, is written back as it was.
+
+use crate::scope::{Escape, Segment};
+
+/// The columns to replace within, and how the files are written
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Csv {
+    pub columns: Vec<Column>,
+    /// The character between fields, or None for a tab in `.tsv` files and
+    /// a comma otherwise
+    pub delimiter: Option<char>,
+    /// The character that quotes fields, and that's doubled inside them
+    pub quote: char,
+    /// Whether the first row names the columns, and so isn't replaced within
+    pub header: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Column {
+    /// The column with this name in the header
+    Name(String),
+    /// The column at this index, from 0
+    Index(usize),
+}
+
+/// Parse a column's name, or its number counting from 1
+pub fn parse_column(column: &str) -> Result<Column, String> {
+    match column.parse::<usize>() {
+        Ok(0) => Err("columns are numbered from 1".to_string()),
+        Ok(number) => Ok(Column::Index(number - 1)),
+        Err(_) if column.is_empty() => Err("a column's name is empty".to_string()),
+        Err(_) => Ok(Column::Name(column.to_string())),
+    }
+}
+
+/// Parse a delimiter or a quote, which can be written as `\t` or `tab`
+pub fn parse_char(c: &str) -> Result<char, String> {
+    match c {
+        "\\t" | "tab" => Ok('\t'),
+        _ => {
+            let mut chars = c.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c != '\n' && c != '\r' => Ok(c),
+                _ => Err(format!("'{}' isn't a single character", c)),
+            }
+        }
+    }
+}
+
+/// The fields of the selected columns in `text`, whose fields are
+/// separated by `delimiter`
+pub fn segments(text: &str, csv: &Csv, delimiter: char) -> Result<Vec<Segment>, String> {
+    let mut scanner = Scanner {
+        text,
+        at: 0,
+        delimiter,
+        quote: csv.quote,
+        line: 1,
+    };
+    let mut wanted: Vec<usize> = Vec::new();
+    let mut names = Vec::new();
+    for column in &csv.columns {
+        match column {
+            Column::Index(index) => wanted.push(*index),
+            Column::Name(name) => names.push(name),
+        }
+    }
+    if csv.header {
+        let header = scanner.record()?;
+        for name in names {
+            let index = header
+                .iter()
+                .position(|field| field.text == *name)
+                .ok_or_else(|| format!("it has no column named '{}'", name))?;
+            wanted.push(index);
+        }
+    } else if let Some(name) = names.first() {
+        return Err(format!(
+            "it has no header to find a column named '{}' in",
+            name
+        ));
+    }
+
+    let mut segments = Vec::new();
+    while scanner.at < text.len() {
+        let record = scanner.record()?;
+        for (index, field) in record.into_iter().enumerate() {
+            if wanted.contains(&index) {
+                segments.push(field);
+            }
+        }
+    }
+    Ok(segments)
+}
+
+struct Scanner<'a> {
+    text: &'a str,
+    at: usize,
+    delimiter: char,
+    quote: char,
+    /// The line the scanner is on, for errors
+    line: usize,
+}
+
+impl Scanner<'_> {
+    /// The fields of the record starting here, up to and past the end of
+    /// its line
+    fn record(&mut self) -> Result<Vec<Segment>, String> {
+        let mut fields = Vec::new();
+        loop {
+            fields.push(self.field()?);
+            let rest = &self.text[self.at..];
+            if rest.starts_with(self.delimiter) {
+                self.at += self.delimiter.len_utf8();
+                continue;
+            }
+            // The end of the line, or of the file
+            let newline = if rest.starts_with("\r\n") {
+                2
+            } else {
+                usize::from(rest.starts_with('\n'))
+            };
+            self.at += newline;
+            self.line += 1;
+            return Ok(fields);
+        }
+    }
+
+    fn field(&mut self) -> Result<Segment, String> {
+        let start = self.at;
+        let rest = &self.text[start..];
+        let escape = Escape::Csv {
+            delimiter: self.delimiter,
+            quote: self.quote,
+            quoted: rest.starts_with(self.quote),
+        };
+        if !rest.starts_with(self.quote) {
+            let mut end = rest
+                .find([self.delimiter, '\n'])
+                .map_or(self.text.len(), |end| start + end);
+            if self.text[end..].starts_with('\n') && self.text[..end].ends_with('\r') {
+                end -= 1;
+            }
+            self.at = end;
+            return Ok(Segment {
+                range: start..end,
+                text: self.text[start..end].to_string(),
+                escape,
+            });
+        }
+
+        let quote_len = self.quote.len_utf8();
+        let mut value = String::new();
+        let mut at = start + quote_len;
+        loop {
+            let inside = &self.text[at..];
+            let Some(found) = inside.find(self.quote) else {
+                return Err(format!("a quote on line {} isn't closed", self.line));
+            };
+            value.push_str(&inside[..found]);
+            self.line += inside[..found].matches('\n').count();
+            at += found + quote_len;
+            if self.text[at..].starts_with(self.quote) {
+                value.push(self.quote);
+                at += quote_len;
+            } else {
+                break;
+            }
+        }
+        let rest = &self.text[at..];
+        if !(rest.is_empty()
+            || rest.starts_with(self.delimiter)
+            || rest.starts_with('\n')
+            || rest.starts_with("\r\n"))
+        {
+            return Err(format!(
+                "there's more after a quoted field on line {}",
+                self.line
+            ));
+        }
+        self.at = at;
+        Ok(Segment {
+            range: start..at,
+            text: value,
+            escape,
+        })
+    }
+}
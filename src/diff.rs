@@ -0,0 +1,213 @@
+//! Diffs of the changes a run would make, printed to preview them
+//!
+//! A structural diff compares the tokens of the old and the new text rather
+//! than their lines, as difftastic does, so that changes to layout are left
+//! out and each changed line shows exactly which tokens changed.
+
+use crate::color;
+use crate::edits;
+use crate::patch;
+use clap::ValueEnum;
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+/// The lines of the old and the new text that a change is to
+type Lines = (BTreeSet<usize>, BTreeSet<usize>);
+
+/// How --diff shows the changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Style {
+    /// A unified diff of the lines that change
+    Lines,
+    /// The old and new forms of each changed line, with the tokens that
+    /// change marked
+    Structural,
+}
+
+/// The diff from `old` to `new` of the file at `path`, in `style`
+pub fn diff(style: Style, path: &str, old: &str, new: &str) -> String {
+    match style {
+        Style::Lines => lines(path, old, new),
+        Style::Structural => structural(path, old, new),
+    }
+}
+
+fn lines(path: &str, old: &str, new: &str) -> String {
+    let mut diff = String::new();
+    for line in patch::diff(path, old, new).lines() {
+        let line = match line.as_bytes().first() {
+            _ if line.starts_with("---") || line.starts_with("+++") => color::path(line),
+            Some(b'-') => color::removed(line),
+            Some(b'+') => color::added(line),
+            _ => line.to_string(),
+        };
+        diff.push_str(&line);
+        diff.push('\n');
+    }
+    diff
+}
+
+fn structural(path: &str, old: &str, new: &str) -> String {
+    let old_tokens = tokens(old);
+    let new_tokens = tokens(new);
+    let old_texts: Vec<&str> = old_tokens.iter().map(|token| &old[token.clone()]).collect();
+    let new_texts: Vec<&str> = new_tokens.iter().map(|token| &new[token.clone()]).collect();
+
+    // The tokens that change, and the lines they're on
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    let mut changes: Vec<Lines> = Vec::new();
+    let old_lines = line_starts(old);
+    let new_lines = line_starts(new);
+    let ops = capture_diff_slices(Algorithm::Myers, &old_texts, &new_texts);
+    for (tag, old_range, new_range) in edits::ranges(&ops) {
+        if tag == DiffTag::Equal {
+            continue;
+        }
+        let old_changed = &old_tokens[old_range];
+        let new_changed = &new_tokens[new_range];
+        removed.extend_from_slice(old_changed);
+        added.extend_from_slice(new_changed);
+        let change: Lines = (
+            old_changed
+                .iter()
+                .map(|token| line_of(&old_lines, token.start))
+                .collect(),
+            new_changed
+                .iter()
+                .map(|token| line_of(&new_lines, token.start))
+                .collect(),
+        );
+        // Changes to the same lines are shown together
+        match changes.last_mut() {
+            Some(last) if overlaps(last, &change) => {
+                last.0.extend(change.0);
+                last.1.extend(change.1);
+            }
+            _ => changes.push(change),
+        }
+    }
+
+    let mut diff = format!("{}\n", color::path(path));
+    if changes.is_empty() {
+        diff.push_str("  Only the layout changes\n");
+        return diff;
+    }
+    for (old_changed, new_changed) in changes {
+        for line in old_changed {
+            let text = marked(old, &old_lines, line, &removed, color::removed, "[-", "-]");
+            diff.push_str(&format!("{:>5} - {}\n", line + 1, text));
+        }
+        for line in new_changed {
+            let text = marked(new, &new_lines, line, &added, color::added, "{+", "+}");
+            diff.push_str(&format!("{:>5} + {}\n", line + 1, text));
+        }
+    }
+    diff
+}
+
+/// `new` with the tokens that differ from those of `old` marked, as
+/// `newtext try` shows the result of a replacement
+pub fn highlighted(old: &str, new: &str) -> String {
+    let old_tokens = tokens(old);
+    let new_tokens = tokens(new);
+    let old_texts: Vec<&str> = old_tokens.iter().map(|token| &old[token.clone()]).collect();
+    let new_texts: Vec<&str> = new_tokens.iter().map(|token| &new[token.clone()]).collect();
+    let ops = capture_diff_slices(Algorithm::Myers, &old_texts, &new_texts);
+    let added: Vec<Range<usize>> = edits::ranges(&ops)
+        .into_iter()
+        .filter(|(tag, _, _)| *tag != DiffTag::Equal)
+        .flat_map(|(_, _, new_range)| new_tokens[new_range].iter().cloned())
+        .collect();
+
+    let starts = line_starts(new);
+    let mut shown = String::new();
+    for line in 0..new.split_inclusive('\n').count() {
+        shown.push_str(&marked(
+            new,
+            &starts,
+            line,
+            &added,
+            color::added,
+            "{+",
+            "+}",
+        ));
+        shown.push('\n');
+    }
+    shown
+}
+
+/// The tokens in `text` that aren't whitespace
+fn tokens(text: &str) -> Vec<Range<usize>> {
+    edits::tokens(text)
+        .into_iter()
+        .filter(|token| !text[token.clone()].trim().is_empty())
+        .collect()
+}
+
+/// The byte offset that each line of `text` starts at
+fn line_starts(text: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+    starts
+}
+
+/// The index of the line that the byte `offset` is on
+fn line_of(starts: &[usize], offset: usize) -> usize {
+    starts.partition_point(|&start| start <= offset) - 1
+}
+
+/// Whether two changes are to any of the same or neighbouring lines
+fn overlaps(a: &Lines, b: &Lines) -> bool {
+    let near = |a: &BTreeSet<usize>, b: &BTreeSet<usize>| {
+        let (Some(&last), Some(&first)) = (a.last(), b.first()) else {
+            return false;
+        };
+        first <= last + 1
+    };
+    near(&a.0, &b.0) || near(&a.1, &b.1)
+}
+
+/// Line `line` of `text`, with the tokens in `changed` marked by `mark`, or
+/// between `open` and `close` when output isn't colored
+fn marked(
+    text: &str,
+    starts: &[usize],
+    line: usize,
+    changed: &[Range<usize>],
+    mark: fn(&str) -> String,
+    open: &str,
+    close: &str,
+) -> String {
+    let start = starts[line];
+    let end = starts.get(line + 1).map_or(text.len(), |&next| next);
+    let line_text = text[start..end].trim_end_matches(['\n', '\r']);
+    let end = start + line_text.len();
+
+    let mut shown = String::new();
+    let mut at = start;
+    // Neighbouring changed tokens are marked together, with the space
+    // between them
+    let mut runs: Vec<Range<usize>> = Vec::new();
+    for token in changed
+        .iter()
+        .filter(|token| token.start >= start && token.end <= end)
+    {
+        match runs.last_mut() {
+            Some(run) if text[run.end..token.start].trim().is_empty() => run.end = token.end,
+            _ => runs.push(token.clone()),
+        }
+    }
+    for run in runs {
+        shown.push_str(&text[at..run.start]);
+        let token = &text[run.clone()];
+        if color::enabled() {
+            shown.push_str(&mark(token));
+        } else {
+            shown.push_str(&format!("{}{}{}", open, token, close));
+        }
+        at = run.end;
+    }
+    shown.push_str(&text[at..end]);
+    shown
+}
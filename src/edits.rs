@@ -0,0 +1,172 @@
+use clap::ValueEnum;
+use similar::{capture_diff_slices, Algorithm, DiffOp, DiffTag, TextDiff};
+use std::ops::Range;
+
+/// How a run that leaves the files alone prints the changes it would make,
+/// with --format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// An LSP WorkspaceEdit, for an editor to apply with workspace/applyEdit
+    Lsp,
+    /// Reviewdog's diagnostic format, for CI to comment on each change
+    Rdjson,
+}
+
+/// A change to a file's text: the text between two byte offsets in the old
+/// text is replaced
+pub struct Edit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+/// The edits that turn `old` into `new`
+///
+/// Runs of changed lines are found first, then the runs of tokens within
+/// them that change, so that an edit to a word leaves the rest of its line
+/// alone.
+pub fn edits(old: &str, new: &str) -> Vec<Edit> {
+    let old_starts = line_starts(old);
+    let new_starts = line_starts(new);
+    let lines = ranges(TextDiff::from_lines(old, new).ops());
+    let mut edits = Vec::new();
+    for (old_lines, new_lines) in changed_runs(lines) {
+        let old_start = old_starts[old_lines.start];
+        let old_text = &old[old_start..old_starts[old_lines.end]];
+        let new_text = &new[new_starts[new_lines.start]..new_starts[new_lines.end]];
+        edits.extend(
+            token_edits(old_text, new_text)
+                .into_iter()
+                .map(|edit| Edit {
+                    start: old_start + edit.start,
+                    end: old_start + edit.end,
+                    text: edit.text,
+                }),
+        );
+    }
+    edits
+}
+
+/// The edits that turn `old` into `new`, one for each run of tokens that
+/// changes
+fn token_edits(old: &str, new: &str) -> Vec<Edit> {
+    let old_tokens = tokens(old);
+    let new_tokens = tokens(new);
+    let old_texts: Vec<&str> = old_tokens.iter().map(|token| &old[token.clone()]).collect();
+    let new_texts: Vec<&str> = new_tokens.iter().map(|token| &new[token.clone()]).collect();
+    let ops = ranges(&capture_diff_slices(
+        Algorithm::Myers,
+        &old_texts,
+        &new_texts,
+    ));
+
+    // The tokens cover all of the text, so their spans are exact
+    let span = |tokens: &[Range<usize>], range: Range<usize>, len: usize| match range.is_empty() {
+        true => {
+            let at = tokens.get(range.start).map_or(len, |token| token.start);
+            at..at
+        }
+        false => tokens[range.start].start..tokens[range.end - 1].end,
+    };
+    changed_runs(ops)
+        .into_iter()
+        .map(|(old_range, new_range)| {
+            let removed = span(&old_tokens, old_range, old.len());
+            let added = span(&new_tokens, new_range, new.len());
+            Edit {
+                start: removed.start,
+                end: removed.end,
+                text: new[added].to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Each diff op, with the ranges of the old and the new that it covers
+///
+/// The ranges are worked out from the lengths of the ops that come before,
+/// as the index that an op which only deletes gives in the new, or one that
+/// only inserts in the old, isn't always where it is.
+pub(crate) fn ranges(ops: &[DiffOp]) -> Vec<(DiffTag, Range<usize>, Range<usize>)> {
+    let (mut old, mut new) = (0, 0);
+    ops.iter()
+        .map(|op| {
+            let old_range = old..old + op.old_range().len();
+            let new_range = new..new + op.new_range().len();
+            (old, new) = (old_range.end, new_range.end);
+            (op.tag(), old_range, new_range)
+        })
+        .collect()
+}
+
+/// The ranges of the old and the new that each run of diff ops that aren't
+/// equal covers
+pub(crate) fn changed_runs(
+    ops: Vec<(DiffTag, Range<usize>, Range<usize>)>,
+) -> Vec<(Range<usize>, Range<usize>)> {
+    let mut runs = Vec::new();
+    let mut changed: Option<(Range<usize>, Range<usize>)> = None;
+    for (tag, old_range, new_range) in ops {
+        if tag == DiffTag::Equal {
+            runs.extend(changed.take());
+            continue;
+        }
+        changed = Some(match changed {
+            Some((old, new)) => (old.start..old_range.end, new.start..new_range.end),
+            None => (old_range, new_range),
+        });
+    }
+    runs.extend(changed);
+    runs
+}
+
+/// The byte ranges of the tokens in `text`, which between them cover all of
+/// it: words, runs of whitespace, and each other character
+pub fn tokens(text: &str) -> Vec<Range<usize>> {
+    let mut tokens: Vec<Range<usize>> = Vec::new();
+    let mut last = None;
+    for (i, c) in text.char_indices() {
+        let kind = if c.is_alphanumeric() || c == '_' {
+            Some(0)
+        } else if c.is_whitespace() {
+            Some(1)
+        } else {
+            None
+        };
+        match tokens.last_mut() {
+            Some(token) if kind.is_some() && kind == last => token.end = i + c.len_utf8(),
+            _ => tokens.push(i..i + c.len_utf8()),
+        }
+        last = kind;
+    }
+    tokens
+}
+
+/// The byte offset that each line of `text` starts at, then its length
+fn line_starts(text: &str) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+    if starts.last() != Some(&text.len()) {
+        starts.push(text.len());
+    }
+    starts
+}
+
+/// `text` with `edits` made, which mustn't overlap
+///
+/// Edits at the same offset are made in the order they're given.
+pub fn apply(text: &str, mut edits: Vec<Edit>) -> Result<String, String> {
+    edits.sort_by_key(|edit| (edit.start, edit.end));
+    let mut new = String::with_capacity(text.len());
+    let mut at = 0;
+    for edit in &edits {
+        if edit.start < at || edit.end < edit.start {
+            return Err("its edits overlap".to_string());
+        }
+        new.push_str(&text[at..edit.start]);
+        new.push_str(&edit.text);
+        at = edit.end;
+    }
+    new.push_str(&text[at..]);
+    Ok(new)
+}
@@ -0,0 +1,278 @@
+/// The character encodings newtext can read and write
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// A legacy encoding such as Windows-1252 or Shift_JIS
+    Legacy(&'static encoding_rs::Encoding),
+}
+
+impl Encoding {
+    /// Look up an encoding by one of its WHATWG labels, e.g. "latin1" or "sjis"
+    pub fn for_label(label: &str) -> Option<Encoding> {
+        let encoding = encoding_rs::Encoding::for_label(label.trim().as_bytes())?;
+        Some(if encoding == encoding_rs::UTF_8 {
+            Encoding::Utf8
+        } else if encoding == encoding_rs::UTF_16LE {
+            Encoding::Utf16Le
+        } else if encoding == encoding_rs::UTF_16BE {
+            Encoding::Utf16Be
+        } else {
+            Encoding::Legacy(encoding)
+        })
+    }
+}
+
+/// How to decode content that isn't valid UTF-8 (or UTF-16 with a BOM)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fallback {
+    /// Treat it as binary and skip it
+    Skip,
+    /// Decode it with a fixed encoding
+    Fixed(Encoding),
+    /// Guess the encoding from the content
+    Detect,
+}
+
+/// A file's content decoded to UTF-8, with what's needed to write it back
+pub struct Decoded {
+    /// The text without any byte order mark
+    pub text: String,
+    pub encoding: Encoding,
+    /// Whether the file started with a byte order mark
+    pub bom: bool,
+}
+
+pub const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+pub const UTF16LE_BOM: &[u8] = b"\xFF\xFE";
+pub const UTF16BE_BOM: &[u8] = b"\xFE\xFF";
+
+/// How many bytes to inspect when guessing whether BOM-less content is UTF-16
+const SNIFF_LEN: usize = 4096;
+
+/// Decode file content, or return None if it doesn't look like text
+///
+/// UTF-16 is recognised by its byte order mark, or for files without one by
+/// the NUL bytes that mostly-ASCII UTF-16 text has in every other position.
+/// Content that isn't valid UTF-8 is decoded according to `fallback`, so
+/// trees mixing UTF-8 and legacy encodings can be processed together.
+pub fn decode(bytes: &[u8], fallback: Fallback) -> Option<Decoded> {
+    if let Some((bom, text)) = borrow_utf8(bytes) {
+        return Some(Decoded {
+            text: text.to_string(),
+            encoding: Encoding::Utf8,
+            bom,
+        });
+    }
+
+    if let Some(rest) = bytes.strip_prefix(UTF8_BOM) {
+        let text = String::from_utf8(rest.to_vec()).ok()?;
+        return Some(Decoded {
+            text,
+            encoding: Encoding::Utf8,
+            bom: true,
+        });
+    }
+    if let Some(rest) = bytes.strip_prefix(UTF16LE_BOM) {
+        return decode_utf16(rest, Encoding::Utf16Le, true);
+    }
+    if let Some(rest) = bytes.strip_prefix(UTF16BE_BOM) {
+        return decode_utf16(rest, Encoding::Utf16Be, true);
+    }
+
+    // NULs are valid UTF-8, so content with them might really be UTF-16
+    if bytes.contains(&0) {
+        if let Some(decoded) = guess_utf16(bytes).and_then(|e| decode_utf16(bytes, e, false)) {
+            return Some(decoded);
+        }
+    }
+
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return Some(Decoded {
+            text: text.to_string(),
+            encoding: Encoding::Utf8,
+            bom: false,
+        });
+    }
+
+    let fallback = match fallback {
+        Fallback::Skip => return None,
+        Fallback::Fixed(encoding) => encoding,
+        Fallback::Detect => detect(bytes),
+    };
+
+    match fallback {
+        Encoding::Utf8 => None,
+        encoding @ (Encoding::Utf16Le | Encoding::Utf16Be) => decode_utf16(bytes, encoding, false),
+        Encoding::Legacy(legacy) => {
+            let (text, had_errors) = legacy.decode_without_bom_handling(bytes);
+            if had_errors || text.contains('\0') {
+                return None;
+            }
+            Some(Decoded {
+                text: text.into_owned(),
+                encoding: Encoding::Legacy(legacy),
+                bom: false,
+            })
+        }
+    }
+}
+
+/// Guess the encoding of content that isn't valid UTF-8
+fn detect(bytes: &[u8]) -> Encoding {
+    let mut detector = chardetng::EncodingDetector::new(chardetng::Iso2022JpDetection::Deny);
+    detector.feed(bytes, true);
+    let encoding = detector.guess(None, chardetng::Utf8Detection::Deny);
+    Encoding::for_label(encoding.name()).unwrap_or(Encoding::Legacy(encoding))
+}
+
+/// View content as UTF-8 text without copying it, if `decode` would treat it
+/// as UTF-8. Also returns whether there was a byte order mark.
+///
+/// Content with NUL bytes is never borrowed, as it might be UTF-16.
+pub fn borrow_utf8(bytes: &[u8]) -> Option<(bool, &str)> {
+    let (bom, rest) = match bytes.strip_prefix(UTF8_BOM) {
+        Some(rest) => (true, rest),
+        None => (false, bytes),
+    };
+    if !bom && (bytes.starts_with(UTF16LE_BOM) || bytes.starts_with(UTF16BE_BOM)) {
+        return None;
+    }
+    if memchr::memchr(0, rest).is_some() {
+        return None;
+    }
+    std::str::from_utf8(rest).ok().map(|text| (bom, text))
+}
+
+/// File signatures of common binary formats
+const BINARY_MAGIC: &[&[u8]] = &[
+    b"\x89PNG\r\n\x1a\n",
+    b"\xFF\xD8\xFF", // JPEG
+    b"GIF87a",
+    b"GIF89a",
+    b"PK\x03\x04",       // zip, jar, docx
+    b"\x1F\x8B",         // gzip
+    b"\xFD7zXZ\x00",     // xz
+    b"\x28\xB5\x2F\xFD", // zstd
+    b"7z\xBC\xAF\x27\x1C",
+    b"\x7FELF",
+    b"\xCE\xFA\xED\xFE", // Mach-O
+    b"\xCF\xFA\xED\xFE", // Mach-O 64-bit
+    b"\xCA\xFE\xBA\xBE", // Java class, Mach-O fat binary
+    b"\x00asm",          // WebAssembly
+    b"SQLite format 3\x00",
+];
+
+/// Guess from the first few KB of a file whether it is binary
+///
+/// This is a cheap check so binaries can be skipped without reading them in
+/// full. Content with NUL bytes counts as binary unless it looks like UTF-16.
+pub fn looks_binary(sample: &[u8]) -> bool {
+    if BINARY_MAGIC.iter().any(|magic| sample.starts_with(magic)) {
+        return true;
+    }
+    if sample.starts_with(UTF16LE_BOM) || sample.starts_with(UTF16BE_BOM) {
+        return false;
+    }
+
+    // An odd-length sample may just end halfway through a UTF-16 code unit
+    let even = &sample[..sample.len() & !1];
+    memchr::memchr(0, sample).is_some() && guess_utf16(even).is_none()
+}
+
+/// Encode text back into the file's original encoding
+///
+/// Fails if the text contains characters the encoding can't represent, which
+/// can happen when the replacement introduces them.
+pub fn encode(text: &str, encoding: Encoding, bom: bool) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(text.len() + 3);
+
+    match encoding {
+        Encoding::Utf8 => {
+            if bom {
+                bytes.extend_from_slice(UTF8_BOM);
+            }
+            bytes.extend_from_slice(text.as_bytes());
+        }
+        Encoding::Utf16Le => {
+            if bom {
+                bytes.extend_from_slice(UTF16LE_BOM);
+            }
+            for unit in text.encode_utf16() {
+                bytes.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+        Encoding::Utf16Be => {
+            if bom {
+                bytes.extend_from_slice(UTF16BE_BOM);
+            }
+            for unit in text.encode_utf16() {
+                bytes.extend_from_slice(&unit.to_be_bytes());
+            }
+        }
+        Encoding::Legacy(legacy) => {
+            let (encoded, _, had_unmappable) = legacy.encode(text);
+            if had_unmappable {
+                return Err(format!(
+                    "text contains characters that can't be written as {}",
+                    legacy.name()
+                ));
+            }
+            bytes.extend_from_slice(&encoded);
+        }
+    }
+
+    Ok(bytes)
+}
+
+fn decode_utf16(bytes: &[u8], encoding: Encoding, bom: bool) -> Option<Decoded> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let units = bytes.chunks_exact(2).map(|pair| match encoding {
+        Encoding::Utf16Be => u16::from_be_bytes([pair[0], pair[1]]),
+        _ => u16::from_le_bytes([pair[0], pair[1]]),
+    });
+    let text = char::decode_utf16(units)
+        .collect::<Result<String, _>>()
+        .ok()?;
+
+    // Text files don't contain NUL characters even when encoded as UTF-16
+    if text.contains('\0') {
+        return None;
+    }
+
+    Some(Decoded {
+        text,
+        encoding,
+        bom,
+    })
+}
+
+/// Guess the byte order of BOM-less UTF-16 from where its NUL bytes fall
+fn guess_utf16(bytes: &[u8]) -> Option<Encoding> {
+    let sample = &bytes[..bytes.len().min(SNIFF_LEN)];
+    if sample.len() < 2 || !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let pairs = sample.len() / 2;
+    let even_nuls = sample.iter().step_by(2).filter(|&&b| b == 0).count();
+    let odd_nuls = sample
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .filter(|&&b| b == 0)
+        .count();
+
+    // Require most high bytes to be NUL and almost no low bytes
+    if odd_nuls * 10 >= pairs * 4 && even_nuls * 20 < pairs {
+        Some(Encoding::Utf16Le)
+    } else if even_nuls * 10 >= pairs * 4 && odd_nuls * 20 < pairs {
+        Some(Encoding::Utf16Be)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,252 @@
+use crate::added::AddedLines;
+use crate::encoding::Encoding;
+use crate::eol::Eol;
+use crate::fix::Fix;
+use crate::journal::{self, Journal};
+use crate::map::MissingKey;
+use crate::plan::Plan;
+use crate::report::{self, Code, Problem, Problems};
+use crate::scope::Scope;
+use crate::write::{self, Attribute, WriteOptions};
+use crate::{pool, process_file, rewrite_input, walk, Replacement, FILES_AHEAD_PER_JOB};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What to replace and how, as given on the command line
+///
+/// The defaults are those of the command line tool.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The text to search for, or a regex with `pattern`
+    pub old: String,
+    /// The text to replace with, unless a script, map or sed script gives it
+    pub new: String,
+    pub pattern: bool,
+    /// Match OLD ignoring case, keeping the case of each match
+    pub ignore_case: bool,
+    /// A Rhai script that computes the replacement for each match
+    pub script: Option<PathBuf>,
+    /// A file mapping each match to its replacement
+    pub map: Option<PathBuf>,
+    /// A command that computes the replacement for each match, see `Plugin`
+    pub plugin: Option<String>,
+    pub map_missing: MissingKey,
+    /// Use the map from values to keys
+    pub reverse: bool,
+    /// The parts of files that replacements are limited to
+    pub scope: Option<Scope>,
+    /// The lines a branch added, which replacements are limited to
+    pub only_added: Option<AddedLines>,
+    /// A sed script, in place of OLD and NEW
+    pub sed: Option<String>,
+    /// A file with a sed script, in place of OLD and NEW
+    pub sed_file: Option<PathBuf>,
+    pub sed_extended: bool,
+    /// Built-in transforms made to each file, in place of OLD and NEW
+    pub fixes: Vec<Fix>,
+    pub eol: Eol,
+    pub final_newline: bool,
+    pub strip_bom: bool,
+    /// How to read files that aren't UTF-8 or UTF-16, if at all
+    pub encoding: Option<Encoding>,
+    pub detect_encoding: bool,
+    /// Replace within binary files as well, matching their bytes
+    pub binary: bool,
+    pub archives: bool,
+    pub compressed: bool,
+    /// Replace within the text of Word, Excel and PowerPoint documents
+    pub office: bool,
+    /// The attributes of each file that its rewritten copy keeps
+    pub preserve: Vec<Attribute>,
+    /// The attributes it keeps where it can
+    pub preserve_if_possible: Vec<Attribute>,
+    pub fsync: bool,
+    /// A command to run on each file once it's written, with `{}` for its path
+    pub format_cmd: Option<String>,
+    /// Count the matches, in `Replacement::matches`
+    pub count_matches: bool,
+    /// How many files to process at once, by default one per CPU
+    pub jobs: Option<usize>,
+    /// Files at least this large are streamed, if the replacement allows it
+    pub stream_threshold: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            old: String::new(),
+            new: String::new(),
+            pattern: false,
+            ignore_case: false,
+            script: None,
+            map: None,
+            plugin: None,
+            map_missing: MissingKey::Skip,
+            reverse: false,
+            scope: None,
+            only_added: None,
+            sed: None,
+            sed_file: None,
+            sed_extended: false,
+            fixes: Vec::new(),
+            eol: Eol::Preserve,
+            final_newline: false,
+            strip_bom: false,
+            encoding: None,
+            detect_encoding: false,
+            binary: false,
+            archives: false,
+            compressed: false,
+            office: false,
+            preserve: vec![Attribute::Mode],
+            preserve_if_possible: vec![Attribute::Ownership, Attribute::Xattr],
+            fsync: false,
+            format_cmd: None,
+            count_matches: false,
+            jobs: None,
+            stream_threshold: Some(64 * 1024 * 1024),
+        }
+    }
+}
+
+/// A replacement, ready to make in text or throughout trees of files
+pub struct Engine {
+    replacement: Replacement,
+    /// What the runs are recorded as in the journal
+    args: Vec<String>,
+}
+
+impl Engine {
+    pub fn new(options: &Options) -> Result<Engine, String> {
+        let args = match (&options.sed, &options.sed_file) {
+            (Some(script), _) => vec!["--sed".to_string(), script.clone()],
+            (None, Some(file)) => vec!["--sed-file".to_string(), file.display().to_string()],
+            (None, None) => vec![options.old.clone(), options.new.clone()],
+        };
+        Ok(Engine {
+            replacement: Replacement::new(options)?,
+            args: ["newtext".to_string()].into_iter().chain(args).collect(),
+        })
+    }
+
+    /// `text` with the replacement made, or None if nothing in it changes
+    pub fn replace(&self, text: &str) -> Result<Option<String>, String> {
+        let output = rewrite_input(text.as_bytes(), Path::new("-"), &self.replacement)
+            .map_err(|e| e.to_string())?;
+        Ok(output.map(|output| String::from_utf8_lossy(&output).into_owned()))
+    }
+
+    /// The changes that replacing within the files under `paths`, or under
+    /// `root` if there are none, would make
+    ///
+    /// Files are walked as the command line tool walks them, leaving out
+    /// ignored files and the journal. Nothing is changed until the changes
+    /// are applied.
+    pub fn plan(&mut self, root: &Path, paths: &[PathBuf]) -> ChangeSet {
+        let roots = if paths.is_empty() {
+            vec![root.to_path_buf()]
+        } else {
+            paths.iter().map(|path| root.join(path)).collect()
+        };
+        let plan = Arc::new(Plan::new(root));
+        let problems = Arc::new(Problems::default());
+        self.replacement.write.plan = Some(Arc::clone(&plan));
+        self.replacement.write.problems = Some(Arc::clone(&problems));
+
+        let files = walk(&roots, None)
+            .filter_map(|entry| {
+                entry
+                    .map_err(|e| report::add_walk_error(Some(&problems), &e))
+                    .ok()
+            })
+            .filter(|entry| entry.file_type().is_some_and(|ft| ft.is_file()));
+        let jobs = self.replacement.jobs;
+        let replacement = &self.replacement;
+        pool::run(
+            jobs,
+            jobs * FILES_AHEAD_PER_JOB,
+            files,
+            |entry| process_file(entry.path(), replacement),
+            |entry, result| {
+                if let Err(e) = result {
+                    let code = if write::is_write_error(&e) {
+                        Code::Write
+                    } else {
+                        Code::Process
+                    };
+                    report::add_to(Some(&problems), code, entry.path(), e);
+                }
+                true
+            },
+        );
+
+        self.replacement.write.plan = None;
+        self.replacement.write.problems = None;
+        ChangeSet {
+            root: root.to_path_buf(),
+            plan,
+            write: self.replacement.write.clone(),
+            args: self.args.clone(),
+            problems: problems.take(),
+        }
+    }
+}
+
+/// A file that a change set rewrites
+pub struct Change {
+    pub path: PathBuf,
+    pub content: Vec<u8>,
+}
+
+/// The changes to a tree of files worked out by [`Engine::plan`]
+pub struct ChangeSet {
+    root: PathBuf,
+    plan: Arc<Plan>,
+    write: WriteOptions,
+    args: Vec<String>,
+    problems: Vec<Problem>,
+}
+
+impl ChangeSet {
+    /// The files that would be rewritten, with their new content
+    pub fn changes(&self) -> Vec<Change> {
+        self.plan
+            .writes()
+            .into_iter()
+            .map(|(path, content)| Change { path, content })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plan.is_empty()
+    }
+
+    /// The files that couldn't be read or processed
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+
+    /// Make the changes, recording them in the journal so that `newtext
+    /// undo` can revert them, unless `journal` is false
+    ///
+    /// Files that have changed since the changes were worked out are left
+    /// alone. Returns how many changes were made, and the problems with those
+    /// that couldn't be.
+    pub fn apply(&self, journal: bool) -> Result<(usize, Vec<Problem>), String> {
+        let _lock = journal::lock(&self.root, true)?;
+        let journal = journal.then(|| Arc::new(Journal::new(&self.root, self.args.clone())));
+        let problems = Arc::new(Problems::default());
+        let write = WriteOptions {
+            journal: journal.clone(),
+            problems: Some(Arc::clone(&problems)),
+            ..self.write.clone()
+        };
+        let (applied, _, _) = self.plan.apply(&write);
+        if let Some(journal) = journal {
+            journal
+                .finish()
+                .map_err(|e| format!("Could not update the journal: {}", e))?;
+        }
+        Ok((applied, problems.take()))
+    }
+}
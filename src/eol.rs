@@ -0,0 +1,71 @@
+use clap::ValueEnum;
+
+/// How line endings are written in rewritten files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Eol {
+    /// Keep the line ending style the file already uses
+    Preserve,
+    /// Write LF line endings
+    Lf,
+    /// Write CRLF line endings
+    Crlf,
+}
+
+/// Fix up the line endings of `updated`, the replaced version of `original`
+///
+/// In preserve mode a file that consistently used one style keeps using it,
+/// so replacements containing `\n` don't mix LF into a CRLF file (or the
+/// reverse). Files that already mixed styles are left as they are.
+pub fn apply(original: &str, updated: String, eol: Eol) -> String {
+    match eol {
+        Eol::Lf => to_lf(&updated),
+        Eol::Crlf => to_crlf(&updated),
+        Eol::Preserve => {
+            let crlf = original.matches("\r\n").count();
+            let bare_lf = original.bytes().filter(|&b| b == b'\n').count() - crlf;
+
+            if crlf > 0 && bare_lf == 0 {
+                to_crlf(&updated)
+            } else if bare_lf > 0 && crlf == 0 {
+                to_lf(&updated)
+            } else {
+                updated
+            }
+        }
+    }
+}
+
+/// Make `updated` end with a newline exactly when `original` did
+///
+/// Replacements that touch the last line can otherwise add or drop the final
+/// newline. With `force`, a final newline is always added to non-empty files.
+pub fn fix_final_newline(original: &str, updated: String, force: bool) -> String {
+    if updated.is_empty() {
+        return updated;
+    }
+
+    let wanted = force || original.ends_with('\n');
+    let has = updated.ends_with('\n');
+
+    if wanted && !has {
+        let ending = if original.ends_with("\r\n") || (force && updated.contains("\r\n")) {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        updated + ending
+    } else if !wanted && has {
+        let trimmed = updated.strip_suffix('\n').unwrap();
+        trimmed.strip_suffix('\r').unwrap_or(trimmed).to_string()
+    } else {
+        updated
+    }
+}
+
+fn to_lf(text: &str) -> String {
+    text.replace("\r\n", "\n")
+}
+
+fn to_crlf(text: &str) -> String {
+    to_lf(text).replace('\n', "\r\n")
+}
@@ -0,0 +1,458 @@
+//! `newtext explain`, which checks a pattern, describes what each part of it
+//! matches, and warns about the mistakes that make a run match far more or
+//! less than was meant
+//!
+//! The description is worked out from the pattern's syntax tree rather than
+//! from what it compiles to, so it follows the pattern as it was written.
+
+use crate::matcher;
+use regex_syntax::ast::{
+    self, Assertion, AssertionKind, Ast, ClassPerlKind, ClassSet, ClassSetItem, Flag,
+    FlagsItemKind, GroupKind, RepetitionKind, RepetitionRange,
+};
+
+/// What a pattern matches, part by part, and what might be wrong with it
+pub struct Explanation {
+    /// Each part of the pattern, with how deeply it's nested and what it
+    /// matches
+    pub parts: Vec<Part>,
+    pub warnings: Vec<String>,
+}
+
+pub struct Part {
+    pub depth: usize,
+    /// The part as it's written in the pattern
+    pub source: String,
+    pub description: String,
+}
+
+/// The flags in effect at a point in the pattern, which change what `^`, `$`
+/// and `.` match
+#[derive(Clone, Copy, Default)]
+struct Flags {
+    multi_line: bool,
+    dot_matches_new_line: bool,
+}
+
+struct Explainer<'a> {
+    pattern: &'a str,
+    parts: Vec<Part>,
+    warnings: Vec<String>,
+}
+
+/// Check `pattern` as OLD is compiled, and explain it, or say why it's
+/// invalid
+pub fn explain(pattern: &str, ignore_case: bool) -> Result<Explanation, String> {
+    matcher::compile(pattern, ignore_case).map_err(|e| e.to_string())?;
+    let tree = ast::parse::Parser::new()
+        .parse(pattern)
+        .map_err(|e| e.to_string())?;
+
+    let mut explainer = Explainer {
+        pattern,
+        parts: Vec::new(),
+        warnings: Vec::new(),
+    };
+    explainer.describe(&tree, 0, &mut Flags::default());
+
+    let hir = regex_syntax::ParserBuilder::new()
+        .case_insensitive(ignore_case)
+        .build()
+        .parse(pattern)
+        .map_err(|e| e.to_string())?;
+    if hir.properties().minimum_len() == Some(0) {
+        explainer.warnings.push(
+            "it can match empty text, so it also matches where there's nothing to \
+             replace, such as between every two characters"
+                .to_string(),
+        );
+    }
+    if has_literal(&tree, '\n') && !has_literal(&tree, '\r') {
+        explainer.warnings.push(
+            "`\\n` doesn't match the `\\r` before it in files with CRLF line endings, \
+             write `\\r?\\n` to match both"
+                .to_string(),
+        );
+    }
+
+    Ok(Explanation {
+        parts: explainer.parts,
+        warnings: explainer.warnings,
+    })
+}
+
+impl Explainer<'_> {
+    fn source(&self, span: &ast::Span) -> String {
+        self.pattern[span.start.offset..span.end.offset].to_string()
+    }
+
+    fn push(&mut self, depth: usize, span: &ast::Span, description: String) {
+        // Spaces are shown quoted, so that they can be seen
+        let source = self.source(span);
+        let source = if source.trim().is_empty() {
+            format!("{:?}", source)
+        } else {
+            source
+        };
+        self.parts.push(Part {
+            depth,
+            source,
+            description,
+        });
+    }
+
+    fn warn(&mut self, span: &ast::Span, why: &str) {
+        self.warnings.push(format!(
+            "`{}` at column {} {}",
+            self.source(span),
+            span.start.column,
+            why
+        ));
+    }
+
+    /// Describe `ast` and what's in it, with `flags` in effect, which flags
+    /// set along the way change for the rest of the group
+    fn describe(&mut self, ast: &Ast, depth: usize, flags: &mut Flags) {
+        match ast {
+            Ast::Empty(span) => {
+                self.push(depth, span, "nothing, which matches anywhere".to_string())
+            }
+            Ast::Flags(set) => {
+                let description = set_flags(&set.flags, flags);
+                self.push(depth, &set.span, format!("from here on, {}", description));
+            }
+            Ast::Literal(literal) => self.push(
+                depth,
+                &literal.span,
+                format!("the text {:?}", literal.c.to_string()),
+            ),
+            Ast::Dot(span) => {
+                let description = if flags.dot_matches_new_line {
+                    "any character"
+                } else {
+                    "any character but a newline"
+                };
+                self.push(depth, span, description.to_string());
+            }
+            Ast::Assertion(assertion) => {
+                let description = assertion_description(assertion, *flags);
+                self.push(depth, &assertion.span, description.to_string());
+                match assertion.kind {
+                    AssertionKind::StartLine if !flags.multi_line => self.warn(
+                        &assertion.span,
+                        "matches only at the start of the whole file, add (?m) to match at the \
+                         start of each line",
+                    ),
+                    AssertionKind::EndLine if !flags.multi_line => self.warn(
+                        &assertion.span,
+                        "matches only at the end of the whole file, add (?m) to match at the \
+                         end of each line, before \\n or \\r\\n",
+                    ),
+                    _ => {}
+                }
+            }
+            Ast::ClassUnicode(class) => {
+                let description = unicode_class(class);
+                self.push(depth, &class.span, description);
+            }
+            Ast::ClassPerl(class) => self.push(
+                depth,
+                &class.span,
+                perl_class(&class.kind, class.negated).to_string(),
+            ),
+            Ast::ClassBracketed(class) => {
+                let description = self.bracketed(class);
+                self.push(depth, &class.span, description);
+            }
+            Ast::Repetition(repetition) => {
+                let mut times = match &repetition.op.kind {
+                    RepetitionKind::ZeroOrOne => "optionally".to_string(),
+                    RepetitionKind::ZeroOrMore => "zero or more times".to_string(),
+                    RepetitionKind::OneOrMore => "one or more times".to_string(),
+                    RepetitionKind::Range(RepetitionRange::Exactly(n)) => times(*n),
+                    RepetitionKind::Range(RepetitionRange::AtLeast(n)) => {
+                        format!("{} or more times", n)
+                    }
+                    RepetitionKind::Range(RepetitionRange::Bounded(min, max)) => {
+                        format!("{} to {} times", min, max)
+                    }
+                };
+                if !repetition.greedy {
+                    times.push_str(", as few as it can");
+                }
+                // What's repeated is described on the same line when it's
+                // only one part
+                let at = self.parts.len();
+                self.describe(&repetition.ast, depth + 1, flags);
+                if self.parts.len() == at + 1 {
+                    let part = self.parts.pop().expect("a part was described");
+                    let description = format!("{}, {}", part.description, times);
+                    self.push(depth, &repetition.span, description);
+                } else {
+                    let part = Part {
+                        depth,
+                        source: self.source(&repetition.span),
+                        description: format!("{}:", capitalized(&times)),
+                    };
+                    self.parts.insert(at, part);
+                }
+            }
+            Ast::Group(group) => {
+                let mut inner = *flags;
+                let description = match &group.kind {
+                    GroupKind::CaptureIndex(index) => {
+                        format!("group {}, which NEW can refer to as ${}", index, index)
+                    }
+                    GroupKind::CaptureName { name, .. } => format!(
+                        "group {}, which NEW can refer to as ${{{}}} or ${}",
+                        name.index, name.name, name.index
+                    ),
+                    GroupKind::NonCapturing(group_flags) if group_flags.items.is_empty() => {
+                        "a group, which isn't captured".to_string()
+                    }
+                    GroupKind::NonCapturing(group_flags) => {
+                        format!("a group, {}", set_flags(group_flags, &mut inner))
+                    }
+                };
+                self.push(depth, &group.span, description);
+                self.describe(&group.ast, depth + 1, &mut inner);
+            }
+            Ast::Alternation(alternation) => {
+                self.push(
+                    depth,
+                    &alternation.span,
+                    format!(
+                        "the first of these {} that matches:",
+                        alternation.asts.len()
+                    ),
+                );
+                for alternative in &alternation.asts {
+                    let mut inner = *flags;
+                    self.describe(alternative, depth + 1, &mut inner);
+                }
+                self.check_alternatives(&alternation.asts);
+            }
+            Ast::Concat(concat) => {
+                let mut asts = concat.asts.iter().peekable();
+                while let Some(ast) = asts.next() {
+                    // Literals one after another are described as one text
+                    if let Ast::Literal(first) = ast {
+                        let (mut text, mut end) = (first.c.to_string(), first.span.end);
+                        while let Some(Ast::Literal(next)) = asts.peek() {
+                            text.push(next.c);
+                            end = next.span.end;
+                            asts.next();
+                        }
+                        let span = ast::Span::new(first.span.start, end);
+                        self.push(depth, &span, format!("the text {:?}", text));
+                    } else {
+                        self.describe(ast, depth, flags);
+                    }
+                }
+                self.check_dots(&concat.asts);
+            }
+        }
+    }
+
+    fn bracketed(&self, class: &ast::ClassBracketed) -> String {
+        let listed = match &class.kind {
+            ClassSet::Item(ClassSetItem::Union(union)) => union
+                .items
+                .iter()
+                .map(|item| self.class_item(item))
+                .collect::<Vec<_>>(),
+            ClassSet::Item(item) => vec![self.class_item(item)],
+            ClassSet::BinaryOp(op) => vec![format!("those that {} gives", self.source(&op.span))],
+        };
+        let listed = match listed.as_slice() {
+            [] => String::new(),
+            [only] => only.clone(),
+            [rest @ .., last] => format!("{} or {}", rest.join(", "), last),
+        };
+        if class.negated {
+            format!("any character but {}", listed)
+        } else {
+            format!("one of {}", listed)
+        }
+    }
+
+    fn class_item(&self, item: &ClassSetItem) -> String {
+        match item {
+            ClassSetItem::Literal(literal) => format!("{:?}", literal.c),
+            ClassSetItem::Range(range) => format!("{:?} to {:?}", range.start.c, range.end.c),
+            ClassSetItem::Perl(class) => perl_class(&class.kind, class.negated).to_string(),
+            ClassSetItem::Unicode(class) => unicode_class(class),
+            ClassSetItem::Ascii(_) | ClassSetItem::Bracketed(_) | ClassSetItem::Union(_) => {
+                self.source(item.span())
+            }
+            ClassSetItem::Empty(_) => "nothing".to_string(),
+        }
+    }
+
+    /// Warn about dots between literals, as in `example.com`, which were
+    /// most likely meant to match only a dot
+    fn check_dots(&mut self, asts: &[Ast]) {
+        let is_literal = |ast: Option<&Ast>| matches!(ast, Some(Ast::Literal(literal)) if literal.c.is_alphanumeric());
+        for (i, ast) in asts.iter().enumerate() {
+            if let Ast::Dot(span) = ast {
+                let before = i.checked_sub(1).and_then(|before| asts.get(before));
+                if is_literal(before) && is_literal(asts.get(i + 1)) {
+                    self.warn(
+                        span,
+                        "matches any character, not just a dot, write `\\.` to match only a dot",
+                    );
+                }
+            }
+        }
+    }
+
+    /// Warn about alternatives that can never match, because an earlier one
+    /// is the same or starts the same way and is tried first
+    fn check_alternatives(&mut self, asts: &[Ast]) {
+        let texts: Vec<Option<String>> = asts.iter().map(literal_text).collect();
+        for (later, text) in texts.iter().enumerate() {
+            let Some(text) = text else { continue };
+            let earlier = texts[..later]
+                .iter()
+                .flatten()
+                .find(|earlier| text.starts_with(earlier.as_str()));
+            if let Some(earlier) = earlier {
+                let why = if earlier == text {
+                    "is also an earlier alternative, so it's never used".to_string()
+                } else {
+                    format!(
+                        "never matches, as the earlier alternative `{}` matches its start and is \
+                         tried first, put the longer alternative first",
+                        earlier
+                    )
+                };
+                self.warn(asts[later].span(), &why);
+            }
+        }
+    }
+}
+
+/// The text that `ast` matches, if it's only literals
+fn literal_text(ast: &Ast) -> Option<String> {
+    match ast {
+        Ast::Literal(literal) => Some(literal.c.to_string()),
+        Ast::Concat(concat) => concat.asts.iter().map(literal_text).collect(),
+        _ => None,
+    }
+}
+
+/// Whether `ast` has `c` in it as a literal, as written or escaped
+fn has_literal(ast: &Ast, c: char) -> bool {
+    match ast {
+        Ast::Literal(literal) => literal.c == c,
+        Ast::Repetition(repetition) => has_literal(&repetition.ast, c),
+        Ast::Group(group) => has_literal(&group.ast, c),
+        Ast::Alternation(alternation) => alternation.asts.iter().any(|ast| has_literal(ast, c)),
+        Ast::Concat(concat) => concat.asts.iter().any(|ast| has_literal(ast, c)),
+        Ast::ClassBracketed(class) => match &class.kind {
+            ClassSet::Item(ClassSetItem::Union(union)) => union
+                .items
+                .iter()
+                .any(|item| matches!(item, ClassSetItem::Literal(literal) if literal.c == c)),
+            ClassSet::Item(ClassSetItem::Literal(literal)) => literal.c == c,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Apply the flags in `set` to `flags`, describing what they do
+fn set_flags(set: &ast::Flags, flags: &mut Flags) -> String {
+    let mut described = Vec::new();
+    let mut on = true;
+    for item in &set.items {
+        let flag = match item.kind {
+            FlagsItemKind::Negation => {
+                on = false;
+                continue;
+            }
+            FlagsItemKind::Flag(flag) => flag,
+        };
+        let (does, doesnt) = match flag {
+            Flag::CaseInsensitive => ("ignoring case", "matching case"),
+            Flag::MultiLine => (
+                "^ and $ match at each line",
+                "^ and $ match only at the ends of the file",
+            ),
+            Flag::DotMatchesNewLine => (". matches newlines too", ". doesn't match newlines"),
+            Flag::SwapGreed => (
+                "repeats are as short as they can be",
+                "repeats are as long as they can be",
+            ),
+            Flag::Unicode => ("classes are Unicode", "classes are ASCII"),
+            Flag::CRLF => ("lines end in \\n or \\r\\n", "lines end in \\n"),
+            Flag::IgnoreWhitespace => (
+                "spaces in the pattern are ignored",
+                "spaces in the pattern match",
+            ),
+        };
+        match flag {
+            Flag::MultiLine => flags.multi_line = on,
+            Flag::DotMatchesNewLine => flags.dot_matches_new_line = on,
+            _ => {}
+        }
+        described.push(if on { does } else { doesnt });
+    }
+    described.join(", ")
+}
+
+fn assertion_description(assertion: &Assertion, flags: Flags) -> &'static str {
+    match assertion.kind {
+        AssertionKind::StartLine if flags.multi_line => "the start of a line",
+        AssertionKind::EndLine if flags.multi_line => "the end of a line",
+        AssertionKind::StartLine | AssertionKind::StartText => "the start of the file",
+        AssertionKind::EndLine | AssertionKind::EndText => "the end of the file",
+        AssertionKind::WordBoundary => "the start or end of a word",
+        AssertionKind::NotWordBoundary => "anywhere but the start or end of a word",
+        AssertionKind::WordBoundaryStart | AssertionKind::WordBoundaryStartAngle => {
+            "the start of a word"
+        }
+        AssertionKind::WordBoundaryEnd | AssertionKind::WordBoundaryEndAngle => "the end of a word",
+        AssertionKind::WordBoundaryStartHalf => "where a word could start",
+        AssertionKind::WordBoundaryEndHalf => "where a word could end",
+    }
+}
+
+fn perl_class(kind: &ClassPerlKind, negated: bool) -> &'static str {
+    match (kind, negated) {
+        (ClassPerlKind::Digit, false) => "a digit",
+        (ClassPerlKind::Digit, true) => "anything but a digit",
+        (ClassPerlKind::Space, false) => "whitespace",
+        (ClassPerlKind::Space, true) => "anything but whitespace",
+        (ClassPerlKind::Word, false) => "a word character, a letter, digit or _",
+        (ClassPerlKind::Word, true) => "anything but a letter, digit or _",
+    }
+}
+
+fn unicode_class(class: &ast::ClassUnicode) -> String {
+    let name = match &class.kind {
+        ast::ClassUnicodeKind::OneLetter(letter) => letter.to_string(),
+        ast::ClassUnicodeKind::Named(name) => name.clone(),
+        ast::ClassUnicodeKind::NamedValue { name, value, .. } => format!("{}={}", name, value),
+    };
+    if class.is_negated() {
+        format!("a character outside the Unicode class {}", name)
+    } else {
+        format!("a character in the Unicode class {}", name)
+    }
+}
+
+fn times(n: u32) -> String {
+    match n {
+        1 => "once".to_string(),
+        2 => "twice".to_string(),
+        n => format!("{} times", n),
+    }
+}
+
+fn capitalized(text: &str) -> String {
+    let mut chars = text.chars();
+    chars.next().map_or_else(String::new, |first| {
+        first.to_uppercase().chain(chars).collect()
+    })
+}
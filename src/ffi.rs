@@ -0,0 +1,243 @@
+//! The C interface, declared in `include/newtext.h`
+//!
+//! Each function returns 0 on success, or -1 with a message for
+//! `newtext_last_error`. A panic isn't let through to C, and returns -2
+//! instead.
+
+use crate::{ChangeSet, Engine, Options};
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, c_void, CStr, CString, OsStr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+/// Treat OLD as a regex
+pub const NEWTEXT_PATTERN: u32 = 1;
+/// Match OLD ignoring case, keeping the case of each match
+pub const NEWTEXT_IGNORE_CASE: u32 = 1 << 1;
+/// Don't record changes in the journal, so they can't be undone
+pub const NEWTEXT_NO_JOURNAL: u32 = 1 << 2;
+
+/// The status returned when newtext panicked
+const PANICKED: c_int = -2;
+
+/// Called with the path of each file that changes, its new content and the
+/// caller's data. Returning anything but 0 stops the iteration.
+pub type ChangeCallback =
+    Option<unsafe extern "C" fn(*const c_char, *const u8, usize, *mut c_void) -> c_int>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// The changes worked out by `newtext_plan`, for C to hold
+pub struct NewtextChangeSet {
+    changes: ChangeSet,
+    journal: bool,
+}
+
+/// The message for the last call on this thread that failed, or NULL
+///
+/// The message stays valid until the next call that fails on this thread.
+#[no_mangle]
+pub extern "C" fn newtext_last_error() -> *const c_char {
+    panic::catch_unwind(|| {
+        LAST_ERROR.with(|error| {
+            error
+                .borrow()
+                .as_ref()
+                .map_or(ptr::null(), |error| error.as_ptr())
+        })
+    })
+    .unwrap_or(ptr::null())
+}
+
+/// Work out the changes that replacing `old` with `new` would make to the
+/// files under `root`, without making them. The change set is stored in
+/// `out`, to be freed with `newtext_free`.
+///
+/// # Safety
+///
+/// `root`, `old` and `new` must be NUL-terminated strings, and `out` must
+/// point to writable memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn newtext_plan(
+    root: *const c_char,
+    old: *const c_char,
+    new: *const c_char,
+    flags: u32,
+    out: *mut *mut NewtextChangeSet,
+) -> c_int {
+    guarded(|| {
+        if out.is_null() {
+            return Err("out is NULL".to_string());
+        }
+        let changes = plan(root, old, new, flags)?;
+        *out = Box::into_raw(Box::new(changes));
+        Ok(())
+    })
+}
+
+/// Call `callback` with each file that a change set rewrites
+///
+/// # Safety
+///
+/// `changes` must have come from `newtext_plan` and not been freed. The
+/// pointers passed to `callback` are only valid during the call.
+#[no_mangle]
+pub unsafe extern "C" fn newtext_changes(
+    changes: *const NewtextChangeSet,
+    callback: ChangeCallback,
+    data: *mut c_void,
+) -> c_int {
+    guarded(|| match changes.as_ref() {
+        Some(changes) => for_each_change(&changes.changes, callback, data).map(|_| ()),
+        None => Err("changes is NULL".to_string()),
+    })
+}
+
+/// Make the changes in a change set. Files that have changed since it was
+/// worked out are left alone.
+///
+/// # Safety
+///
+/// `changes` must have come from `newtext_plan` and not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn newtext_apply(changes: *const NewtextChangeSet) -> c_int {
+    guarded(|| match changes.as_ref() {
+        Some(changes) => apply(changes),
+        None => Err("changes is NULL".to_string()),
+    })
+}
+
+/// Free a change set from `newtext_plan`. NULL is ignored.
+///
+/// # Safety
+///
+/// `changes` must have come from `newtext_plan` and not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn newtext_free(changes: *mut NewtextChangeSet) {
+    if !changes.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(changes))));
+    }
+}
+
+/// Replace `old` with `new` in the files under `root`, calling `callback`
+/// with each file that changes before the changes are made. If `callback`
+/// stops the iteration, nothing is changed.
+///
+/// # Safety
+///
+/// As for `newtext_plan` and `newtext_changes`. `callback` may be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn newtext_run(
+    root: *const c_char,
+    old: *const c_char,
+    new: *const c_char,
+    flags: u32,
+    callback: ChangeCallback,
+    data: *mut c_void,
+) -> c_int {
+    guarded(|| {
+        let changes = plan(root, old, new, flags)?;
+        if !for_each_change(&changes.changes, callback, data)? {
+            return Ok(());
+        }
+        apply(&changes)
+    })
+}
+
+unsafe fn plan(
+    root: *const c_char,
+    old: *const c_char,
+    new: *const c_char,
+    flags: u32,
+) -> Result<NewtextChangeSet, String> {
+    let root = PathBuf::from(string(root, "root")?);
+    let options = Options {
+        old: string(old, "old")?,
+        new: string(new, "new")?,
+        pattern: flags & NEWTEXT_PATTERN != 0,
+        ignore_case: flags & NEWTEXT_IGNORE_CASE != 0,
+        ..Options::default()
+    };
+    let mut engine = Engine::new(&options)?;
+    Ok(NewtextChangeSet {
+        changes: engine.plan(&root, &[]),
+        journal: flags & NEWTEXT_NO_JOURNAL == 0,
+    })
+}
+
+/// Call `callback` with each change, returning false if it stopped before
+/// the last
+unsafe fn for_each_change(
+    changes: &ChangeSet,
+    callback: ChangeCallback,
+    data: *mut c_void,
+) -> Result<bool, String> {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return Ok(true),
+    };
+    for change in changes.changes() {
+        let path = c_path(&change.path)?;
+        let content = &change.content;
+        if callback(path.as_ptr(), content.as_ptr(), content.len(), data) != 0 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn apply(changes: &NewtextChangeSet) -> Result<(), String> {
+    let (_, problems) = changes.changes.apply(changes.journal)?;
+    match problems.first() {
+        Some(problem) => Err(format!(
+            "Could not change {}: {}",
+            problem.path.display(),
+            problem.message
+        )),
+        None => Ok(()),
+    }
+}
+
+unsafe fn string(s: *const c_char, name: &str) -> Result<String, String> {
+    if s.is_null() {
+        return Err(format!("{} is NULL", name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(str::to_string)
+        .map_err(|_| format!("{} isn't UTF-8", name))
+}
+
+fn c_path(path: &Path) -> Result<CString, String> {
+    let bytes = OsStr::as_encoded_bytes(path.as_os_str());
+    CString::new(bytes).map_err(|_| format!("{} has a NUL in it", path.display()))
+}
+
+/// The status to return for the result of `body`, noting the error if there
+/// is one. Panics are caught, as unwinding into C is undefined.
+fn guarded(body: impl FnOnce() -> Result<(), String>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(&e);
+            -1
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            set_last_error(&format!("newtext panicked: {}", message));
+            PANICKED
+        }
+    }
+}
+
+fn set_last_error(e: &str) {
+    let message = CString::new(e.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(message));
+}
@@ -0,0 +1,812 @@
+use crate::added::{self, AddedLines};
+use crate::binary::BinaryReplacer;
+use crate::cache::{Cache, Lookup};
+use crate::contents::Contents;
+use crate::encoding::{self, Fallback};
+use crate::eol::{self, Eol};
+use crate::fix::{self, Fix};
+use crate::key_value;
+use crate::map::ValueMap;
+use crate::matcher::{self, Matcher};
+use crate::notebook::{self, Cells};
+use crate::plugin::Plugin;
+use crate::prefilter::Prefilter;
+use crate::report::{self, Code};
+use crate::scope::{self, Scope, Segment};
+use crate::script::Script;
+use crate::sed::SedScript;
+use crate::timings::{self, Phase};
+use crate::write::{self, WriteOptions};
+use crate::{archive, filters, gzip, journal, memory, office, stream, text, Options};
+use ignore::WalkBuilder;
+use regex::{Captures, Regex};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use tracing::{debug, info, trace};
+
+/// Computes replacements per match, for modes where NEW isn't a fixed string
+pub(crate) enum Replacer {
+    Script(Box<Script>),
+    Map(ValueMap),
+    Plugin(Plugin),
+}
+
+impl Replacer {
+    /// Returns the replacement for a match, or None to leave it unchanged
+    fn replace(&self, caps: &Captures, path: &Path, line: usize) -> Result<Option<String>, String> {
+        match self {
+            Replacer::Script(script) => script.replace(caps, path, line),
+            Replacer::Map(map) => map.replace(caps),
+            Replacer::Plugin(plugin) => plugin.replace(caps, path, line),
+        }
+    }
+}
+
+/// Everything needed to compute the new content of a file
+pub struct Replacement {
+    pub(crate) old: String,
+    pub(crate) new: String,
+    /// None for sed scripts, which have their own patterns, byte patterns and
+    /// runs that only make fixes
+    pub(crate) matcher: Option<Matcher>,
+    pub(crate) replacer: Option<Replacer>,
+    pub(crate) sed: Option<SedScript>,
+    /// The built-in transforms made after the replacement, with --fix
+    pub(crate) fixes: Vec<Fix>,
+    pub(crate) scope: Option<Scope>,
+    /// The lines replacements are limited to, with --only-added
+    pub(crate) only_added: Option<AddedLines>,
+    pub(crate) eol: Eol,
+    pub(crate) final_newline: bool,
+    pub(crate) strip_bom: bool,
+    pub(crate) fallback: Fallback,
+    pub write: WriteOptions,
+    pub(crate) binary: Option<BinaryReplacer>,
+    pub(crate) bytes_only: bool,
+    /// Files at least this large are streamed, if the replacement allows it
+    pub(crate) stream_threshold: Option<u64>,
+    pub(crate) archives: bool,
+    pub(crate) compressed: bool,
+    pub(crate) office: bool,
+    /// How many times OLD has matched, when that's being counted
+    pub matches: Option<AtomicU64>,
+    /// How many threads to process files on, and to split large files across
+    pub jobs: usize,
+    /// A quick test for files that can't match, when the replacement has one
+    pub(crate) prefilter: Option<Prefilter>,
+    pub cache: Option<Cache>,
+}
+
+impl Replacement {
+    /// The replacement `options` ask for, without an output directory, journal,
+    /// plan or cache, which depend on the run
+    pub fn new(options: &Options) -> Result<Replacement, String> {
+        let sed_script = if let Some(script) = &options.sed {
+            Some(script.clone())
+        } else if let Some(path) = &options.sed_file {
+            let script = fs::read_to_string(path)
+                .map_err(|e| format!("Could not read sed script {}: {}", path.display(), e))?;
+            Some(script)
+        } else {
+            None
+        };
+        let sed = sed_script
+            .map(|script| SedScript::parse(&script, options.sed_extended, options.ignore_case))
+            .transpose()
+            .map_err(|e| format!("Invalid sed script: {}", e))?;
+
+        let old = options.old.clone();
+        if sed.is_none() && options.fixes.is_empty() && old.is_empty() {
+            return Err("old string cannot be empty".to_string());
+        }
+
+        let replacer = if let Some(path) = &options.script {
+            let script = Script::load(path)
+                .map_err(|e| format!("Could not load script {}: {}", path.display(), e))?;
+            Some(Replacer::Script(Box::new(script)))
+        } else if let Some(path) = &options.map {
+            let map = ValueMap::load(path, options.map_missing, options.reverse)
+                .map_err(|e| format!("Could not load map {}: {}", path.display(), e))?;
+            Some(Replacer::Map(map))
+        } else if let Some(command) = &options.plugin {
+            Some(Replacer::Plugin(Plugin::start(command)?))
+        } else {
+            None
+        };
+
+        let new = options.new.clone();
+
+        let binary = if options.binary {
+            let binary = BinaryReplacer::new(&old, &new, options.pattern, options.ignore_case)
+                .map_err(|e| format!("Invalid regex pattern: {}", e))?;
+            Some(binary)
+        } else {
+            None
+        };
+
+        // Byte patterns such as `(?-u)\xFF` can't match text, so with --binary
+        // they are applied to every file as raw bytes
+        let mut bytes_only = false;
+
+        // If using regex mode, compile the regex pattern. Replacers always go
+        // through a regex so they can be handed the match and its captures.
+        let regex = if options.pattern || replacer.is_some() {
+            let pattern = if options.pattern {
+                old.clone()
+            } else {
+                regex::escape(&old)
+            };
+            match matcher::compile(&pattern, options.ignore_case) {
+                Ok(re) => Some(re),
+                Err(_) if binary.is_some() => {
+                    bytes_only = true;
+                    None
+                }
+                Err(e) => return Err(format!("Invalid regex pattern: {}", e)),
+            }
+        } else {
+            None
+        };
+
+        let fallback = match options.encoding {
+            Some(encoding) => Fallback::Fixed(encoding),
+            None if options.detect_encoding => Fallback::Detect,
+            None => Fallback::Skip,
+        };
+        let matcher = if let Some(re) = regex {
+            Some(Matcher::Regex(re))
+        } else if sed.is_some() || bytes_only || old.is_empty() {
+            None
+        } else {
+            let matcher = Matcher::literal(&old, options.ignore_case)
+                .map_err(|e| format!("Could not search for OLD: {}", e))?;
+            Some(matcher)
+        };
+        let prefilter = if let Some(sed) = &sed {
+            Prefilter::patterns(sed.substitutions().map(|(re, _)| re.as_str()), fallback)
+        } else {
+            match &matcher {
+                Some(Matcher::Regex(re) | Matcher::IgnoreCase(re)) => {
+                    Prefilter::patterns([re.as_str()], fallback)
+                }
+                Some(Matcher::Literal(_)) => Prefilter::literal(&old, fallback),
+                None => None,
+            }
+        };
+
+        let replacement = Replacement {
+            old,
+            new,
+            matcher,
+            replacer,
+            sed,
+            fixes: options.fixes.clone(),
+            scope: options.scope.clone(),
+            only_added: options.only_added.clone(),
+            eol: options.eol,
+            final_newline: options.final_newline,
+            strip_bom: options.strip_bom,
+            fallback,
+            write: WriteOptions {
+                preserve: options.preserve.clone(),
+                preserve_if_possible: options.preserve_if_possible.clone(),
+                fsync: options.fsync,
+                format: options.format_cmd.clone(),
+                ..WriteOptions::default()
+            },
+            binary,
+            bytes_only,
+            stream_threshold: None,
+            archives: options.archives,
+            compressed: options.compressed,
+            office: options.office,
+            matches: options.count_matches.then(|| AtomicU64::new(0)),
+            jobs: jobs(options.jobs),
+            prefilter,
+            cache: None,
+        };
+        Ok(Replacement {
+            stream_threshold: options
+                .stream_threshold
+                .filter(|_| stream::is_streamable(&replacement)),
+            ..replacement
+        })
+    }
+}
+
+/// Whether the walk of `roots` would take in `path`, going by the directories
+/// above it. They're added to `walked_dirs` as they're found to be walked.
+pub fn is_walked(
+    path: &Path,
+    roots: &[PathBuf],
+    output: Option<&Path>,
+    walked_dirs: &mut HashSet<PathBuf>,
+) -> bool {
+    if roots.iter().any(|root| root == path) {
+        return true;
+    }
+    let parent = match path.parent() {
+        Some(parent) if roots.iter().any(|root| parent.starts_with(root)) => parent,
+        _ => return false,
+    };
+    if !walked_dirs.contains(parent) {
+        if !is_walked(parent, roots, output, walked_dirs) {
+            return false;
+        }
+        walked_dirs.insert(parent.to_path_buf());
+    }
+    walk_builder(&[parent.to_path_buf()], output)
+        .max_depth(Some(1))
+        .build()
+        .flatten()
+        .any(|entry| entry.path() == path)
+}
+
+/// The number and text of each line of `content` that OLD matches, in order
+pub fn matching_lines<'a>(
+    content: &'a str,
+    path: &Path,
+    replacement: &Replacement,
+) -> Vec<(usize, &'a str)> {
+    let mut lines = Vec::new();
+    for start in match_starts(content, path, replacement) {
+        let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = content[start..]
+            .find('\n')
+            .map_or(content.len(), |i| start + i);
+        let number = content[..start].matches('\n').count() + 1;
+        if lines.last().is_none_or(|(last, _)| *last != number) {
+            lines.push((number, content[line_start..line_end].trim_end_matches('\r')));
+        }
+    }
+    lines
+}
+
+/// `path` relative to `root` for showing to the user, or as it is if it's
+/// outside the root
+pub fn display_path(path: &Path, root: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// `count` of `noun`, which takes an s unless there's one, as in "1 change"
+/// or "3 changes"
+pub fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("1 {}", noun),
+        count => format!("{} {}s", count, noun),
+    }
+}
+
+/// How many files each thread can get ahead of the oldest one still being
+/// processed
+pub const FILES_AHEAD_PER_JOB: usize = 64;
+
+/// How many files to process at once, given `--jobs`
+fn jobs(jobs: Option<usize>) -> usize {
+    match jobs {
+        Some(jobs) => jobs.max(1),
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    }
+}
+
+/// Replace every match of `re` with the text computed by `replacer`
+fn replace_with(
+    content: &str,
+    re: &Regex,
+    replacer: &Replacer,
+    path: &Path,
+    first_line: usize,
+) -> io::Result<String> {
+    let mut error: Option<String> = None;
+    let mut line = first_line;
+    let mut line_counted_to = 0;
+
+    let result = re.replace_all(content, |caps: &regex::Captures| {
+        let matched = caps.get(0).unwrap();
+
+        // Count newlines incrementally so large files stay linear
+        line += content[line_counted_to..matched.start()]
+            .bytes()
+            .filter(|&b| b == b'\n')
+            .count();
+        line_counted_to = matched.start();
+
+        if error.is_some() {
+            return matched.as_str().to_string();
+        }
+        match replacer.replace(caps, path, line) {
+            Ok(Some(replacement)) => replacement,
+            Ok(None) => matched.as_str().to_string(),
+            Err(e) => {
+                error = Some(e);
+                matched.as_str().to_string()
+            }
+        }
+    });
+
+    match error {
+        Some(e) => Err(io::Error::other(e)),
+        None => Ok(result.to_string()),
+    }
+}
+
+pub fn process_file(path: &Path, replacement: &Replacement) -> io::Result<bool> {
+    if replacement.archives {
+        if let Some(kind) = archive::Kind::for_path(path) {
+            debug!(path = %path.display(), "Replacing within the archive {}", path.display());
+            let _memory = memory::reserve_for(path);
+            return archive::process_archive(path, kind, replacement);
+        }
+    }
+    if replacement.office && office::is_document(path) {
+        debug!(path = %path.display(), "Replacing within the Office document {}", path.display());
+        let _memory = memory::reserve_for(path);
+        return archive::process_archive(path, archive::Kind::Zip, replacement);
+    }
+    if replacement.compressed && gzip::is_gzip(path) {
+        debug!(path = %path.display(), "Replacing within the compressed file {}", path.display());
+        let _memory = memory::reserve_for(path);
+        return gzip::process_gzip_file(path, replacement);
+    }
+
+    // Files that are always scoped need the whole document to find what's
+    // in the scope
+    let threshold = replacement
+        .stream_threshold
+        .filter(|_| scope_of(path, replacement).is_none());
+    if let Some(threshold) = threshold {
+        let large = fs::metadata(path)
+            .map(|m| m.len() >= threshold)
+            .unwrap_or(false);
+        if large {
+            debug!(path = %path.display(), "Streaming {}, as it's at least {} bytes", path.display(), threshold);
+            let streamed = timings::time(Phase::Replace, || {
+                stream::process_file_streaming(path, replacement)
+            });
+            if let Some(modified) = streamed? {
+                return Ok(modified);
+            }
+        }
+    }
+
+    let stat = match replacement
+        .cache
+        .as_ref()
+        .and_then(|cache| cache.look_up(path))
+    {
+        Some(Lookup::Unchanged) => {
+            debug!(path = %path.display(), "Skipped {}, as the cache has it needing no changes", path.display());
+            return Ok(false);
+        }
+        Some(Lookup::Unknown(stat)) => Some(stat),
+        None => None,
+    };
+
+    // Held until the file is done, along with the copies made of its content
+    let _memory = memory::reserve_for(path);
+    // Without --binary, binaries can be skipped after reading just their start
+    let bytes = timings::time(Phase::Read, || {
+        if replacement.binary.is_some() {
+            Contents::read(path).map(Some)
+        } else {
+            Contents::read_unless_binary(path)
+        }
+    });
+    let bytes = match bytes {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => {
+            report::add_to(
+                replacement.write.problems.as_deref(),
+                Code::BinarySkip,
+                path,
+                "it's binary, pass --binary to replace within it",
+            );
+            return Ok(false);
+        }
+        Err(e) => {
+            report::add_to(replacement.write.problems.as_deref(), Code::Read, path, e);
+            return Ok(false);
+        }
+    };
+
+    let cache = replacement.cache.as_ref().zip(stat);
+    if let Some((cache, stat)) = cache {
+        if cache.is_unchanged(path, stat, &bytes) {
+            debug!(path = %path.display(), "Skipped {}, as the cache has it needing no changes", path.display());
+            return Ok(false);
+        }
+    }
+
+    let new_bytes = match rewrite_bytes(&bytes, path, replacement)? {
+        Rewrite::Changed(new_bytes) => new_bytes,
+        Rewrite::Unchanged => {
+            if let Some((cache, stat)) = cache {
+                cache.record_unchanged(path, stat, &bytes);
+            }
+            return Ok(false);
+        }
+        // If we can't read it as text, it's probably binary, skip it unless
+        // binary files were asked for
+        Rewrite::NotText => {
+            return match &replacement.binary {
+                Some(binary) => process_binary_file(path, bytes, binary, replacement),
+                None => {
+                    if let Fallback::Skip = replacement.fallback {
+                        report::add_to(
+                            replacement.write.problems.as_deref(),
+                            Code::Encoding,
+                            path,
+                            "it isn't UTF-8 or UTF-16, pass --encoding or --detect-encoding to read it",
+                        );
+                    }
+                    Ok(false)
+                }
+            };
+        }
+    };
+    // Release any memory map before the file is replaced
+    drop(bytes);
+
+    timings::time(Phase::Write, || {
+        write::write_atomic(path, &new_bytes, &replacement.write)
+    })?;
+
+    Ok(true)
+}
+
+/// The outcome of replacing within some content
+pub enum Rewrite {
+    Changed(Vec<u8>),
+    Unchanged,
+    /// The content couldn't be decoded as text
+    NotText,
+}
+
+/// Apply the replacement to the content of a text file, given as bytes
+///
+/// The content is returned in the same encoding, with the line ending and
+/// byte order mark options applied. `path` is only used to tell scripts
+/// which file they're working on.
+pub fn rewrite_bytes(bytes: &[u8], path: &Path, replacement: &Replacement) -> io::Result<Rewrite> {
+    // Most files don't match, so check that before copying them into a String
+    if timings::time(Phase::Match, || cannot_change(bytes, path, replacement)) {
+        return Ok(Rewrite::Unchanged);
+    }
+
+    // Try to read the file as text. The byte order mark is kept out of
+    // matching so anchored patterns still work.
+    let decoded = timings::time(Phase::Read, || {
+        encoding::decode(bytes, replacement.fallback)
+    });
+    let decoded = match decoded {
+        Some(decoded) if !replacement.bytes_only => decoded,
+        _ => return Ok(Rewrite::NotText),
+    };
+
+    timings::time(Phase::Match, || {
+        count_matches(&decoded.text, path, replacement)
+    });
+
+    timings::time(Phase::Replace, || replace_text(&decoded, path, replacement))
+}
+
+/// Work out the new content of a file from its decoded text
+fn replace_text(
+    decoded: &encoding::Decoded,
+    path: &Path,
+    replacement: &Replacement,
+) -> io::Result<Rewrite> {
+    let text = decoded.text.as_str();
+    let strip_bom = decoded.bom && replacement.strip_bom;
+    let replaced = rewrite_scoped(text, path, replacement)?
+        .map(|new_text| eol::fix_final_newline(text, new_text, replacement.final_newline));
+    let fixed = fix::apply(
+        replaced.as_deref().unwrap_or(text),
+        path,
+        &replacement.fixes,
+    );
+    let new_text = match fixed.or(replaced) {
+        Some(new_text) => eol::apply(text, new_text, replacement.eol),
+        None if strip_bom => text.to_string(),
+        None => return Ok(Rewrite::Unchanged),
+    };
+
+    // Only write if content actually changed
+    if new_text == text && !strip_bom {
+        return Ok(Rewrite::Unchanged);
+    }
+
+    // Write back in the original encoding
+    let bom = decoded.bom && !strip_bom;
+    let bytes = encoding::encode(&new_text, decoded.encoding, bom).map_err(io::Error::other)?;
+    Ok(Rewrite::Changed(bytes))
+}
+
+/// Apply the replacement to `input`, such as stdin or a file's planned
+/// content, whether it's text or binary, or None if it doesn't match
+pub fn rewrite_input(
+    input: &[u8],
+    path: &Path,
+    replacement: &Replacement,
+) -> io::Result<Option<Vec<u8>>> {
+    Ok(match rewrite_bytes(input, path, replacement)? {
+        Rewrite::Changed(output) => Some(output),
+        Rewrite::Unchanged => None,
+        Rewrite::NotText => match &replacement.binary {
+            Some(binary) => binary.replace(input),
+            None => None,
+        },
+    })
+}
+
+/// Walk the files to process under `roots`, leaving out the journal and any
+/// output directory
+pub fn walk(roots: &[PathBuf], output: Option<&Path>) -> ignore::Walk {
+    walk_builder(roots, output).build()
+}
+
+pub fn walk_builder(roots: &[PathBuf], output: Option<&Path>) -> WalkBuilder {
+    // An output directory inside the tree mustn't be processed itself
+    let output_dir = output.and_then(|dir| fs::canonicalize(dir).ok());
+
+    let mut builder = WalkBuilder::new(&roots[0]);
+    for root in &roots[1..] {
+        builder.add(root);
+    }
+    builder
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .hidden(false) // Don't automatically skip hidden files/dirs
+        .standard_filters(true) // Use standard VCS filters (ignores .git, etc)
+        .filter_entry(move |entry| {
+            let is_journal = entry.file_name() == journal::JOURNAL_DIR
+                && entry.file_type().is_some_and(|ft| ft.is_dir());
+            let is_output = match &output_dir {
+                Some(output_dir) => is_same_dir(entry, output_dir),
+                None => false,
+            };
+            !is_journal && !is_output
+        });
+    filters::apply(&mut builder);
+    builder
+}
+
+fn is_same_dir(entry: &ignore::DirEntry, dir: &Path) -> bool {
+    entry.file_type().is_some_and(|ft| ft.is_dir())
+        && fs::canonicalize(entry.path()).is_ok_and(|path| path == dir)
+}
+
+/// Repoint a symlink if its target path matches
+pub fn process_symlink(path: &Path, replacement: &Replacement) -> io::Result<bool> {
+    let target = fs::read_link(path)?;
+    let target = match target.to_str() {
+        Some(target) => target,
+        // Only UTF-8 targets can be matched
+        None => return Ok(false),
+    };
+
+    let new_target = match rewrite_content(target, path, replacement)? {
+        Some(new_target) if new_target != target => new_target,
+        _ => return Ok(false),
+    };
+
+    write::replace_symlink(path, Path::new(&new_target), &replacement.write)?;
+    Ok(true)
+}
+
+/// Patch a file that isn't text by matching on its raw bytes
+fn process_binary_file(
+    path: &Path,
+    bytes: Contents,
+    binary: &BinaryReplacer,
+    replacement: &Replacement,
+) -> io::Result<bool> {
+    if let Some(matches) = &replacement.matches {
+        matches.fetch_add(binary.count(&bytes), Ordering::Relaxed);
+    }
+    let new_bytes = match binary.replace(&bytes) {
+        Some(new_bytes) if new_bytes != *bytes => new_bytes,
+        _ => return Ok(false),
+    };
+    drop(bytes);
+
+    write::write_atomic(path, &new_bytes, &replacement.write)?;
+
+    // Patching binaries is risky enough that each one is called out
+    info!("Modified binary file {}", path.display());
+
+    Ok(true)
+}
+
+/// Add how many times OLD matches in `content` to the count of matches, if
+/// they're being counted
+pub fn count_matches(content: &str, path: &Path, replacement: &Replacement) {
+    let matches = match &replacement.matches {
+        Some(matches) => matches,
+        None => return,
+    };
+
+    let count = match_starts(content, path, replacement).len();
+    matches.fetch_add(count as u64, Ordering::Relaxed);
+}
+
+/// The byte offsets in `content` where OLD, or any of the sed patterns,
+/// matches, in order, within the run's scope if it has one
+fn match_starts(content: &str, path: &Path, replacement: &Replacement) -> Vec<usize> {
+    match segments(content, path, replacement) {
+        Ok(None) => unscoped_starts(content, replacement),
+        Ok(Some(segments)) => scope::starts(&segments, |text| unscoped_starts(text, replacement)),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn unscoped_starts(content: &str, replacement: &Replacement) -> Vec<usize> {
+    if let Some(sed) = &replacement.sed {
+        // sed patterns apply to one line at a time
+        let mut starts = Vec::new();
+        let mut offset = 0;
+        for line in content.split_inclusive('\n') {
+            let text = line.trim_end_matches(['\n', '\r']);
+            let mut line_starts: Vec<usize> = sed
+                .substitutions()
+                .flat_map(|(re, _)| re.find_iter(text).map(|m| offset + m.start()))
+                .collect();
+            line_starts.sort_unstable();
+            line_starts.dedup();
+            starts.extend(line_starts);
+            offset += line.len();
+        }
+        starts
+    } else if let Some(matcher) = &replacement.matcher {
+        matcher.starts(content)
+    } else {
+        Vec::new()
+    }
+}
+
+/// Whether `bytes` certainly need no changes, checked without copying them
+fn cannot_change(bytes: &[u8], path: &Path, replacement: &Replacement) -> bool {
+    if replacement.bytes_only || (replacement.strip_bom && bytes.starts_with(encoding::UTF8_BOM)) {
+        return false;
+    }
+    if scope_of(path, replacement).is_some_and(|scope| scope.adds() || scope.decodes()) {
+        return false;
+    }
+    if !replacement.fixes.is_empty() {
+        return false;
+    }
+    // Scanning the bytes is quicker than checking they're UTF-8
+    if let Some(prefilter) = &replacement.prefilter {
+        if prefilter.rules_out(bytes) {
+            return true;
+        }
+    }
+
+    let text = match encoding::borrow_utf8(bytes) {
+        Some((_, text)) => text,
+        None => return false,
+    };
+    if replacement.sed.is_some() {
+        return false;
+    }
+
+    replacement
+        .matcher
+        .as_ref()
+        .is_some_and(|matcher| !matcher.is_match(text))
+}
+
+/// Notebooks are only ever replaced within in their cells
+static NOTEBOOK_CELLS: Scope = Scope::Notebook(Cells::All);
+
+/// The parts of Office documents are only replaced within in their text runs
+static OFFICE_RUNS: Scope = Scope::Office;
+
+/// `.properties` files are always read with their escapes
+static PROPERTIES: Scope = Scope::Properties;
+
+/// The scope replacements in the file at `path` are limited to: the run's,
+/// or if it has none the text runs of a part of an Office document, the
+/// cells of a notebook or the keys and values of a `.properties` file
+fn scope_of<'a>(path: &Path, replacement: &'a Replacement) -> Option<&'a Scope> {
+    match &replacement.scope {
+        Some(scope) => Some(scope),
+        None if replacement.office && office::is_part(path) => Some(&OFFICE_RUNS),
+        None if notebook::is_notebook(path) => Some(&NOTEBOOK_CELLS),
+        None if key_value::Format::of(path) == Some(key_value::Format::Properties) => {
+            Some(&PROPERTIES)
+        }
+        None => None,
+    }
+}
+
+/// The parts of `content` that replacements in the file at `path` are
+/// limited to, by its scope and by --only-added, or None if they're not
+fn segments(
+    content: &str,
+    path: &Path,
+    replacement: &Replacement,
+) -> Result<Option<Vec<Segment>>, String> {
+    let segments = match scope_of(path, replacement) {
+        Some(scope) => match scope.segments(path, content)? {
+            Some(segments) => Some(segments),
+            None => {
+                trace!(path = %path.display(), "Left out {}, as it isn't in the scope", path.display());
+                return Ok(Some(Vec::new()));
+            }
+        },
+        None => None,
+    };
+    let Some(added) = &replacement.only_added else {
+        return Ok(segments);
+    };
+    let lines = added.lines(path);
+    Ok(Some(match segments {
+        Some(mut segments) => {
+            added::keep(content, &mut segments, lines);
+            segments
+        }
+        None => added::segments(content, lines),
+    }))
+}
+
+/// Compute the replaced content of the file at `path`, or None if nothing
+/// in `content` matches within the run's scope
+fn rewrite_scoped(
+    content: &str,
+    path: &Path,
+    replacement: &Replacement,
+) -> io::Result<Option<String>> {
+    let Some(segments) = segments(content, path, replacement).map_err(io::Error::other)? else {
+        return rewrite_content(content, path, replacement);
+    };
+    scope::rewrite(content, &segments, |text, line| {
+        rewrite_content_from(text, path, replacement, line)
+    })
+}
+
+/// Compute the replaced content, or None if nothing in `content` matches
+pub fn rewrite_content(
+    content: &str,
+    path: &Path,
+    replacement: &Replacement,
+) -> io::Result<Option<String>> {
+    rewrite_content_from(content, path, replacement, 1)
+}
+
+/// Compute the replaced content of text starting on line `first_line` of
+/// the file at `path`
+fn rewrite_content_from(
+    content: &str,
+    path: &Path,
+    replacement: &Replacement,
+    first_line: usize,
+) -> io::Result<Option<String>> {
+    let old = replacement.old.as_str();
+    let new = replacement.new.as_str();
+
+    // Perform replacement based on mode
+    let new_content = if let Some(sed) = &replacement.sed {
+        // sed mode works line by line, so there is no cheap whole-file test
+        sed.apply(content)
+    } else {
+        let matcher = match &replacement.matcher {
+            Some(matcher) if matcher.is_match(content) => matcher,
+            _ => return Ok(None),
+        };
+        match (matcher, &replacement.replacer) {
+            (Matcher::Regex(re), Some(replacer)) => {
+                replace_with(content, re, replacer, path, first_line)?
+            }
+            _ => text::replace_all(content, matcher, old, new),
+        }
+    };
+
+    Ok(Some(new_content))
+}
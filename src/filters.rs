@@ -0,0 +1,47 @@
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::WalkBuilder;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// What the walk leaves out beyond what it always does, as set by --exclude
+/// and --no-ignore
+static FILTERS: OnceLock<Filters> = OnceLock::new();
+
+struct Filters {
+    exclude: Override,
+    no_ignore: bool,
+}
+
+/// Leave out the files and directories that match any of `globs`, which are
+/// gitignore-style globs relative to `root`, and if `no_ignore` is set don't
+/// leave out the files that .gitignore and .ignore files do
+///
+/// Only the first call has any effect, as the walks of a run all use the
+/// same filters.
+pub fn set(root: &Path, globs: &[String], no_ignore: bool) -> Result<(), String> {
+    let mut exclude = OverrideBuilder::new(root);
+    for glob in globs {
+        exclude
+            .add(&format!("!{}", glob))
+            .map_err(|e| format!("Invalid --exclude glob '{}': {}", glob, e))?;
+    }
+    let exclude = exclude.build().map_err(|e| e.to_string())?;
+    let _ = FILTERS.set(Filters { exclude, no_ignore });
+    Ok(())
+}
+
+/// Make `builder` leave out what the filters say to
+pub(crate) fn apply(builder: &mut WalkBuilder) {
+    let Some(filters) = FILTERS.get() else {
+        return;
+    };
+    builder.overrides(filters.exclude.clone());
+    if filters.no_ignore {
+        builder
+            .ignore(false)
+            .git_ignore(false)
+            .git_global(false)
+            .git_exclude(false)
+            .parents(false);
+    }
+}
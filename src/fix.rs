@@ -0,0 +1,234 @@
+//! --fix, built-in transforms that clean up the text of files, made with the
+//! same walk, previews and reports as a replacement
+//!
+//! Fixes are made to the whole of each file, whatever the scope of the run,
+//! after the replacement if there is one.
+
+use std::fmt;
+use std::path::Path;
+
+/// A built-in transform
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fix {
+    /// Make smart quotes, odd spaces, zero-width characters and letters
+    /// that only look Latin plain ASCII
+    Normalize,
+    /// Remove spaces and tabs from the ends of lines
+    TrailingWhitespace,
+    /// Replace tabs with spaces up to the next tab stop, every this many
+    /// columns
+    TabsToSpaces(usize),
+    /// End files that have text with a newline
+    FinalNewline,
+}
+
+/// Parse a fix's name, such as `normalize` or `tabs-to-spaces=4`
+pub fn parse(fix: &str) -> Result<Fix, String> {
+    match fix.split_once('=') {
+        None if fix == "normalize" => Ok(Fix::Normalize),
+        None if fix == "trailing-whitespace" => Ok(Fix::TrailingWhitespace),
+        None if fix == "final-newline" => Ok(Fix::FinalNewline),
+        None if fix == "tabs-to-spaces" => {
+            Err("tabs-to-spaces needs a width, as in tabs-to-spaces=4".to_string())
+        }
+        Some(("tabs-to-spaces", width)) => match width.parse() {
+            Ok(0) | Err(_) => Err(format!("'{}' isn't a width of at least 1", width)),
+            Ok(width) => Ok(Fix::TabsToSpaces(width)),
+        },
+        _ => Err(format!(
+            "there's no fix '{}', the fixes are normalize, trailing-whitespace, \
+             tabs-to-spaces=N and final-newline",
+            fix
+        )),
+    }
+}
+
+impl fmt::Display for Fix {
+    /// The fix as it's given to --fix
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fix::Normalize => f.write_str("normalize"),
+            Fix::TrailingWhitespace => f.write_str("trailing-whitespace"),
+            Fix::TabsToSpaces(width) => write!(f, "tabs-to-spaces={}", width),
+            Fix::FinalNewline => f.write_str("final-newline"),
+        }
+    }
+}
+
+/// The text of the file at `path` with `fixes` made, or None if they change
+/// nothing
+pub fn apply(text: &str, path: &Path, fixes: &[Fix]) -> Option<String> {
+    let mut fixed: Option<String> = None;
+    for fix in fixes {
+        let from = fixed.as_deref().unwrap_or(text);
+        let changed = match fix {
+            Fix::Normalize => normalize(from),
+            Fix::TrailingWhitespace => trim_lines(from),
+            // make needs the tabs in makefiles, and they separate the
+            // fields of TSV files
+            Fix::TabsToSpaces(_) if needs_tabs(path) => None,
+            Fix::TabsToSpaces(width) => expand_tabs(from, *width),
+            Fix::FinalNewline => final_newline(from),
+        };
+        if let Some(changed) = changed {
+            fixed = Some(changed);
+        }
+    }
+    fixed
+}
+
+/// `text` with the spaces and tabs at the ends of its lines removed, or None
+/// if there are none
+fn trim_lines(text: &str) -> Option<String> {
+    let mut trimmed = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\n', '\r']);
+        trimmed.push_str(content.trim_end_matches([' ', '\t']));
+        trimmed.push_str(&line[content.len()..]);
+    }
+    (trimmed.len() != text.len()).then_some(trimmed)
+}
+
+/// Whether the file at `path` is one whose tabs mean something
+fn needs_tabs(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    matches!(name, "Makefile" | "makefile" | "GNUmakefile")
+        || path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| matches!(extension, "mk" | "tsv"))
+}
+
+/// `text` with each tab replaced by spaces up to the next multiple of
+/// `width` columns, or None if it has no tabs
+fn expand_tabs(text: &str, width: usize) -> Option<String> {
+    if !text.contains('\t') {
+        return None;
+    }
+    let mut expanded = String::with_capacity(text.len());
+    let mut column = 0;
+    for c in text.chars() {
+        match c {
+            '\t' => {
+                let spaces = width - column % width;
+                expanded.extend(std::iter::repeat_n(' ', spaces));
+                column += spaces;
+            }
+            '\n' | '\r' => {
+                expanded.push(c);
+                column = 0;
+            }
+            c => {
+                expanded.push(c);
+                column += 1;
+            }
+        }
+    }
+    Some(expanded)
+}
+
+/// `text` ending with a newline, or None if it does already or is empty
+fn final_newline(text: &str) -> Option<String> {
+    if text.is_empty() || text.ends_with('\n') {
+        return None;
+    }
+    let ending = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    Some(format!("{}{}", text, ending))
+}
+
+/// Characters that stand in for ASCII ones, and what they're replaced with
+fn typography(c: char) -> Option<&'static str> {
+    Some(match c {
+        // Smart quotes and primes
+        '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' | '\u{2032}' => "'",
+        '\u{201C}' | '\u{201D}' | '\u{201E}' | '\u{201F}' | '\u{2033}' => "\"",
+        // Non-breaking, fixed width and other odd spaces
+        '\u{00A0}' | '\u{2000}'..='\u{200A}' | '\u{202F}' | '\u{205F}' => " ",
+        // Hyphens and the minus sign
+        '\u{2010}' | '\u{2011}' | '\u{2212}' => "-",
+        // Zero-width spaces, word joiners and soft hyphens, which can't be
+        // seen. Zero-width joiners are kept, as emoji sequences need them.
+        '\u{200B}' | '\u{2060}' | '\u{FEFF}' | '\u{00AD}' => "",
+        _ => return None,
+    })
+}
+
+/// The Latin letter that a Cyrillic or Greek one looks the same as
+fn homoglyph(c: char) -> Option<char> {
+    Some(match c {
+        'а' => 'a',
+        'е' => 'e',
+        'о' | 'ο' => 'o',
+        'р' | 'ρ' => 'p',
+        'с' => 'c',
+        'у' => 'y',
+        'х' => 'x',
+        'ѕ' => 's',
+        'і' => 'i',
+        'ј' => 'j',
+        'А' | 'Α' => 'A',
+        'В' | 'Β' => 'B',
+        'Е' | 'Ε' => 'E',
+        'Ζ' => 'Z',
+        'Н' | 'Η' => 'H',
+        'І' | 'Ι' => 'I',
+        'К' | 'Κ' => 'K',
+        'М' | 'Μ' => 'M',
+        'Ν' => 'N',
+        'О' | 'Ο' => 'O',
+        'Р' | 'Ρ' => 'P',
+        'С' => 'C',
+        'Т' | 'Τ' => 'T',
+        'Υ' => 'Y',
+        'Х' | 'Χ' => 'X',
+        _ => return None,
+    })
+}
+
+/// `text` with typography made ASCII, or None if there's none to change
+///
+/// Lookalike letters are only replaced in words that are otherwise Latin,
+/// so Russian and Greek text is left as it is.
+fn normalize(text: &str) -> Option<String> {
+    if text.is_ascii() {
+        return None;
+    }
+    let mut normalized = String::with_capacity(text.len());
+    let mut changed = false;
+    let mut word_start = None;
+    for (i, c) in text.char_indices() {
+        if c.is_alphanumeric() {
+            word_start.get_or_insert(i);
+            continue;
+        }
+        if let Some(start) = word_start.take() {
+            changed |= push_word(&mut normalized, &text[start..i]);
+        }
+        match typography(c) {
+            Some(ascii) => {
+                normalized.push_str(ascii);
+                changed = true;
+            }
+            None => normalized.push(c),
+        }
+    }
+    if let Some(start) = word_start {
+        changed |= push_word(&mut normalized, &text[start..]);
+    }
+    changed.then_some(normalized)
+}
+
+/// Add `word` to `normalized` with its lookalike letters replaced, if it
+/// has Latin letters as well, returning whether any were
+fn push_word(normalized: &mut String, word: &str) -> bool {
+    let latin = word.chars().any(|c| c.is_ascii_alphabetic());
+    if !latin || !word.chars().any(|c| homoglyph(c).is_some()) {
+        normalized.push_str(word);
+        return false;
+    }
+    normalized.extend(word.chars().map(|c| homoglyph(c).unwrap_or(c)));
+    true
+}
@@ -0,0 +1,48 @@
+use crate::archive;
+use crate::throttle;
+use crate::write::AtomicFile;
+use crate::Replacement;
+use flate2::read::MultiGzDecoder;
+use flate2::{Compression, GzBuilder};
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+/// Whether `path` is a gzip compressed file, rather than a compressed tarball
+pub fn is_gzip(path: &Path) -> bool {
+    let is_gz = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("gz"));
+    is_gz && archive::Kind::for_path(path).is_none()
+}
+
+/// Decompress a gzip file, apply the replacement to its content and compress
+/// it again if anything changed
+///
+/// The name and modification time recorded in the gzip header are kept.
+pub fn process_gzip_file(path: &Path, replacement: &Replacement) -> io::Result<bool> {
+    let mut decoder = MultiGzDecoder::new(BufReader::new(throttle::open(path)?));
+    let mut content = Vec::new();
+    decoder.read_to_end(&mut content)?;
+
+    // The text inside is named without the .gz, e.g. for scripts
+    let inner_path = path.with_extension("");
+    let new_content = match archive::rewrite_member(&content, &inner_path, replacement)? {
+        Some(new_content) => new_content,
+        None => return Ok(false),
+    };
+
+    let mut builder = GzBuilder::new();
+    if let Some(header) = decoder.header() {
+        builder = builder.mtime(header.mtime());
+        if let Some(filename) = header.filename() {
+            builder = builder.filename(filename);
+        }
+    }
+
+    let output = AtomicFile::create(path, &replacement.write)?;
+    let mut encoder = builder.write(output, Compression::default());
+    encoder.write_all(&new_content)?;
+    encoder.finish()?.commit()?;
+
+    Ok(true)
+}
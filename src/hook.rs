@@ -0,0 +1,96 @@
+use crate::journal::Journal;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use tracing::{error, info};
+
+/// A command given on the command line, run by the shell so that it can be
+/// quoted and piped as usual
+pub fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    #[cfg(not(windows))]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command).stdin(Stdio::null());
+    shell
+}
+
+/// Run `command` once the changes have been made, rolling the run back if it
+/// fails. Returns whether the changes were kept.
+pub fn validate(command: &str, journal: &Journal) -> bool {
+    info!("Validating with `{}`", command);
+    match check(command, shell(command).status()) {
+        Ok(()) => return true,
+        Err(e) => error!("{}", e),
+    }
+
+    match journal.roll_back() {
+        Ok(reverted) => info!("Rolled back {} changes", reverted),
+        Err(e) => error!(
+            "Could not roll back the changes: {}, run `newtext undo` to revert them",
+            e
+        ),
+    }
+    false
+}
+
+/// Run the hook `command` before anything is changed
+pub fn pre(command: &str) -> Result<(), String> {
+    check(command, shell(command).status())
+}
+
+/// Run the hook `command` after the changes have been made, with the paths of
+/// the modified files on its stdin, one per line
+pub fn post(command: &str, modified: &[String]) -> Result<(), String> {
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .env("NEWTEXT_FILES_MODIFIED", modified.len().to_string())
+        .spawn()
+        .map_err(|e| format!("Could not run `{}`: {}", command, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let list: String = modified.iter().map(|path| format!("{}\n", path)).collect();
+        // A hook that doesn't read the list closes the pipe early
+        let _ = stdin.write_all(list.as_bytes());
+    }
+    check(command, child.wait())
+}
+
+/// Run the formatter `command` on the file at `path`, which replaces each `{}`
+/// in it, or is added to the end if there's none
+pub fn format(command: &str, path: &Path) -> Result<(), String> {
+    let quoted = quote(&path.to_string_lossy());
+    let command = if command.contains("{}") {
+        command.replace("{}", &quoted)
+    } else {
+        format!("{} {}", command, quoted)
+    };
+    check(&command, shell(&command).status())
+}
+
+/// `arg` quoted for the shell, so it's passed as a single argument
+#[cfg(not(windows))]
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+#[cfg(windows)]
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg)
+}
+
+/// The outcome of running `command`, as an error unless it succeeded
+fn check(command: &str, status: io::Result<ExitStatus>) -> Result<(), String> {
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("`{}` failed ({})", command, status)),
+        Err(e) => Err(format!("Could not run `{}`: {}", command, e)),
+    }
+}
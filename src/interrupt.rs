@@ -0,0 +1,28 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The exit status for a run stopped by Ctrl-C, as shells use for SIGINT
+pub const EXIT_STATUS: i32 = 130;
+
+/// Catch Ctrl-C, so that the file being written can be finished and the run
+/// stopped cleanly between files
+///
+/// Pressing Ctrl-C a second time exits straight away.
+pub fn install() {
+    let result = ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(EXIT_STATUS);
+        }
+        info!("Interrupted, stopping after the current file (press Ctrl-C again to quit now)");
+    });
+    if let Err(e) = result {
+        warn!("Could not catch Ctrl-C: {}", e);
+    }
+}
+
+/// Whether Ctrl-C has been pressed
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
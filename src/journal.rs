@@ -0,0 +1,672 @@
+use crate::plural;
+use crate::write::{self, Attribute, WriteOptions};
+use serde_json::{json, Value};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// The directory, relative to where newtext runs, that holds the journal
+pub const JOURNAL_DIR: &str = ".newtext";
+
+/// The log of a run's changes, while they are in effect
+const CHANGES: &str = "changes.jsonl";
+/// The log of the changes that undid a run, so they can be reverted in turn
+const REDO: &str = "redo.jsonl";
+/// How far an unfinished run got, so it can be resumed
+const CHECKPOINT: &str = "checkpoint.json";
+/// The file locked while a run is changing the files under the directory
+const LOCK: &str = "lock";
+
+/// A record of every change a run makes, so that the run can be undone
+///
+/// Each run gets a directory under `.newtext/history` holding a description
+/// of the run, one JSON line per change, and a copy of every file as it was
+/// before the run changed it. Lines are written as changes are made, so even
+/// an interrupted run can be undone.
+#[derive(Debug)]
+pub struct Journal {
+    root: PathBuf,
+    args: Vec<String>,
+    log: Mutex<Option<Log>>,
+}
+
+impl Journal {
+    /// A journal for a run in `root`. Nothing is written until the first
+    /// change is recorded.
+    pub fn new(root: &Path, args: Vec<String>) -> Journal {
+        Journal {
+            root: root.to_path_buf(),
+            args,
+            log: Mutex::new(None),
+        }
+    }
+
+    /// Replace the file at `path` with `replace`, having first saved a copy
+    /// of it. A file that doesn't exist yet is recorded as created.
+    pub fn record_write(
+        &self,
+        path: &Path,
+        replace: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        self.start(&mut log)?.record_write(path, replace)
+    }
+
+    /// Record that the symlink at `path` was repointed from `old_target`
+    pub fn record_symlink(
+        &self,
+        path: &Path,
+        old_target: &Path,
+        new_target: &Path,
+    ) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        self.start(&mut log)?
+            .record_symlink(path, old_target, new_target)
+    }
+
+    /// Record that `from` was renamed to `to`
+    pub fn record_rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        self.start(&mut log)?.record_rename(from, to)
+    }
+
+    /// The journal of the most recent unfinished run in `root` made with the
+    /// same arguments, and the last checkpoint it saved
+    ///
+    /// Changes made when the run carries on are added to the same run, so
+    /// it's still undone in one go.
+    pub fn resume(root: &Path, args: Vec<String>) -> Result<(Journal, Value), String> {
+        let run = runs(root)
+            .into_iter()
+            .rev()
+            .filter(|run| !run.undone && run.dir.join(CHECKPOINT).exists())
+            // The binary may have been run through a different path
+            .find(|run| {
+                let run_args = run.info["args"].as_array();
+                run_args.is_some_and(|run_args| run_args.iter().skip(1).eq(args.iter().skip(1)))
+            })
+            .ok_or("there is no unfinished run of this command to resume")?;
+
+        let checkpoint = fs::read_to_string(run.dir.join(CHECKPOINT)).map_err(|e| e.to_string())?;
+        let checkpoint = serde_json::from_str(&checkpoint).map_err(|e| e.to_string())?;
+        let log = Log::reopen(&run.dir, CHANGES).map_err(|e| e.to_string())?;
+        info!("Resuming run {}", run.id);
+
+        let journal = Journal {
+            root: root.to_path_buf(),
+            args,
+            log: Mutex::new(Some(log)),
+        };
+        Ok((journal, checkpoint))
+    }
+
+    /// The ID of the run, once it has recorded a change
+    pub fn id(&self) -> Option<String> {
+        let log = self.log.lock().unwrap();
+        let dir = &log.as_ref()?.dir;
+        Some(dir.file_name()?.to_string_lossy().into_owned())
+    }
+
+    /// Save how far the run has got, so that it can be resumed if it doesn't
+    /// finish
+    pub fn checkpoint(&self, progress: &Value) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let dir = &self.start(&mut log)?.dir;
+
+        // Written under another name first, so a crash can't leave it partial
+        let temp = dir.join(format!("{}.tmp", CHECKPOINT));
+        fs::write(&temp, format!("{}\n", progress))?;
+        fs::rename(temp, dir.join(CHECKPOINT))
+    }
+
+    /// Mark the run as finished. A run that made no changes is removed, as
+    /// there's nothing to undo.
+    pub fn finish(&self) -> io::Result<()> {
+        let log = self.log.lock().unwrap();
+        let log = match log.as_ref() {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+
+        if log.file.metadata()?.len() == 0 {
+            return fs::remove_dir_all(&log.dir);
+        }
+        match fs::remove_file(log.dir.join(CHECKPOINT)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        // A resumed run has now finished
+        let path = log.dir.join("run.json");
+        let mut info: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        if let Some(info) = info.as_object_mut() {
+            info.remove("interrupted");
+        }
+        fs::write(path, format!("{:#}\n", info))
+    }
+
+    /// Note in the run's description that it was stopped part way through
+    pub fn record_interrupted(&self) -> io::Result<()> {
+        let log = self.log.lock().unwrap();
+        let log = match log.as_ref() {
+            Some(log) => log,
+            // Nothing was changed, so there's no run to describe
+            None => return Ok(()),
+        };
+
+        let path = log.dir.join("run.json");
+        let mut info: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        info["interrupted"] = true.into();
+        fs::write(path, format!("{:#}\n", info))
+    }
+
+    /// Revert every change recorded so far, for a run that shouldn't be left
+    /// half done. The run is then shown as undone. Returns how many changes
+    /// were reverted.
+    pub fn roll_back(&self) -> Result<usize, String> {
+        let dir = match self.log.lock().unwrap().take() {
+            Some(log) => log.dir,
+            None => return Ok(0),
+        };
+        revert_log(&dir, CHANGES, REDO)
+    }
+
+    /// The current run's log, creating the run if this is the first change
+    fn start<'a>(&self, log: &'a mut Option<Log>) -> io::Result<&'a mut Log> {
+        if let Some(log) = log {
+            return Ok(log);
+        }
+
+        let journal_dir = create_journal_dir(&self.root)?;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let history = journal_dir.join("history");
+        fs::create_dir_all(&history)?;
+
+        // Run IDs sort in the order the runs happened
+        let (id, dir) = (0..)
+            .map(|n| {
+                let id = format!("{}-{}", time, n);
+                let dir = history.join(&id);
+                (id, dir)
+            })
+            .find(|(_, dir)| fs::create_dir(dir).is_ok())
+            .unwrap();
+
+        let info = json!({
+            "id": id,
+            "time": time,
+            "args": self.args,
+            "dir": self.root.to_string_lossy(),
+        });
+        fs::write(dir.join("run.json"), format!("{:#}\n", info))?;
+
+        Ok(log.insert(Log::create(&dir, CHANGES)?))
+    }
+}
+
+/// A log of changes as they're made, with copies of the files they replaced
+#[derive(Debug)]
+struct Log {
+    dir: PathBuf,
+    file: File,
+    next_backup: usize,
+}
+
+impl Log {
+    /// Start the log `name` in the run directory `dir`
+    fn create(dir: &Path, name: &str) -> io::Result<Log> {
+        Log::open(dir, name, OpenOptions::new().truncate(true))
+    }
+
+    /// Add to the existing log `name`, for a run that's being resumed
+    fn reopen(dir: &Path, name: &str) -> io::Result<Log> {
+        Log::open(dir, name, OpenOptions::new().append(true))
+    }
+
+    fn open(dir: &Path, name: &str, options: &mut OpenOptions) -> io::Result<Log> {
+        let files = dir.join("files");
+        fs::create_dir_all(&files)?;
+
+        // Copies made for other logs of the run may still be needed
+        let next_backup = fs::read_dir(&files)?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<usize>().ok())
+            .max()
+            .map_or(0, |n| n + 1);
+
+        let file = options.create(true).write(true).open(dir.join(name))?;
+        Ok(Log {
+            dir: dir.to_path_buf(),
+            file,
+            next_backup,
+        })
+    }
+
+    fn record_write(
+        &mut self,
+        path: &Path,
+        replace: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let is_file = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file());
+        let backup = if is_file {
+            let backup = format!("files/{}", self.next_backup);
+            fs::copy(path, self.dir.join(&backup))?;
+            self.next_backup += 1;
+            Some(backup)
+        } else {
+            None
+        };
+
+        replace()?;
+
+        // What was written, so a revert can tell if the file has changed since
+        let (size, hash) = stamp(path).unzip();
+        self.append(json!({
+            "op": "write",
+            "path": path.to_string_lossy(),
+            "backup": backup,
+            "size": size,
+            "hash": hash,
+        }))
+    }
+
+    fn record_symlink(
+        &mut self,
+        path: &Path,
+        old_target: &Path,
+        new_target: &Path,
+    ) -> io::Result<()> {
+        self.append(json!({
+            "op": "symlink",
+            "path": path.to_string_lossy(),
+            "target": old_target.to_string_lossy(),
+            "new_target": new_target.to_string_lossy(),
+        }))
+    }
+
+    fn record_rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        self.append(json!({
+            "op": "rename",
+            "from": from.to_string_lossy(),
+            "to": to.to_string_lossy(),
+        }))
+    }
+
+    fn append(&mut self, change: Value) -> io::Result<()> {
+        writeln!(self.file, "{}", change)
+    }
+}
+
+/// The size and a hash of the content of a file, or None if it doesn't exist
+///
+/// Checking content rather than modification times lets runs be undone in
+/// any order, as files put back by undoing a later run still match.
+pub fn stamp(path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if !metadata.is_file() {
+        return Some((metadata.len(), 0));
+    }
+
+    let mut hash = FNV_OFFSET;
+    let mut reader = io::BufReader::new(File::open(path).ok()?);
+    loop {
+        let buf = reader.fill_buf().ok()?;
+        if buf.is_empty() {
+            break;
+        }
+        hash = fnv(hash, buf);
+        let len = buf.len();
+        reader.consume(len);
+    }
+    Some((metadata.len(), hash))
+}
+
+/// A hash of `bytes` that is stable between newtext versions
+pub fn hash(bytes: &[u8]) -> u64 {
+    fnv(FNV_OFFSET, bytes)
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// 64-bit FNV-1a, carrying on from `hash`
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A run recorded in the journal
+struct Run {
+    id: String,
+    dir: PathBuf,
+    info: Value,
+    /// Whether the run has been undone, rather than being in effect
+    undone: bool,
+}
+
+impl Run {
+    /// The log of the changes currently in effect for this run
+    fn log(&self) -> PathBuf {
+        self.dir.join(if self.undone { REDO } else { CHANGES })
+    }
+}
+
+/// Every run recorded in `root`, oldest first
+fn runs(root: &Path) -> Vec<Run> {
+    let history = root.join(JOURNAL_DIR).join("history");
+    let mut runs: Vec<Run> = match fs::read_dir(history) {
+        Ok(entries) => entries
+            .filter_map(|entry| {
+                let dir = entry.ok()?.path();
+                let info = fs::read_to_string(dir.join("run.json")).ok()?;
+                let undone = dir.join(REDO).exists();
+                if !undone && !dir.join(CHANGES).exists() {
+                    return None;
+                }
+                Some(Run {
+                    id: dir.file_name()?.to_str()?.to_string(),
+                    info: serde_json::from_str(&info).ok()?,
+                    dir,
+                    undone,
+                })
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    runs.sort_by_key(|run| run_order(&run.id));
+    runs
+}
+
+/// Sort key for run IDs, which look like `<time>-<n>`
+fn run_order(id: &str) -> (u64, u64) {
+    let mut parts = id.splitn(2, '-').map(|part| part.parse().unwrap_or(0));
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// The run with ID `id`, or the most recent run that is or isn't undone
+fn find_run(root: &Path, id: Option<&str>, undone: bool) -> Result<Run, String> {
+    let mut runs = runs(root);
+    match id {
+        Some(id) => {
+            let run = runs
+                .into_iter()
+                .find(|run| run.id == id)
+                .ok_or_else(|| format!("there is no run {}", id))?;
+            match (run.undone, undone) {
+                (false, true) => Err(format!("run {} hasn't been undone", id)),
+                (true, false) => Err(format!("run {} has already been undone", id)),
+                _ => Ok(run),
+            }
+        }
+        None => {
+            runs.retain(|run| run.undone == undone);
+            if undone {
+                // Redo whatever was undone last
+                runs.sort_by_key(|run| fs::metadata(run.log()).and_then(|m| m.modified()).ok());
+            }
+            runs.pop().ok_or_else(|| {
+                if undone {
+                    "there are no undone runs to redo".to_string()
+                } else {
+                    "there are no runs to undo".to_string()
+                }
+            })
+        }
+    }
+}
+
+/// The journal directory in `root`, created if there isn't one yet
+pub fn create_journal_dir(root: &Path) -> io::Result<PathBuf> {
+    let journal_dir = root.join(JOURNAL_DIR);
+    fs::create_dir_all(&journal_dir)?;
+    // Keep the journal out of version control
+    let gitignore = journal_dir.join(".gitignore");
+    if !gitignore.exists() {
+        fs::write(gitignore, "*\n")?;
+    }
+    Ok(journal_dir)
+}
+
+/// Lock `root` against other runs, so two runs can't interleave their changes
+/// or their journals. If another run holds the lock, wait for it to finish
+/// when `wait` is set, or fail straight away.
+///
+/// The lock is held until the returned file is closed, which happens at the
+/// latest when the process exits.
+pub fn lock(root: &Path, wait: bool) -> Result<File, String> {
+    let path = create_journal_dir(root)
+        .map(|dir| dir.join(LOCK))
+        .map_err(|e| e.to_string())?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+
+    match file.try_lock() {
+        Ok(()) => return Ok(file),
+        Err(TryLockError::WouldBlock) if wait => {}
+        Err(TryLockError::WouldBlock) => {
+            return Err(format!(
+                "another newtext run is changing files in {}",
+                root.display()
+            ))
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(format!("Could not lock {}: {}", path.display(), e))
+        }
+    }
+    info!("Waiting for another newtext run in this directory to finish");
+    file.lock()
+        .map_err(|e| format!("Could not lock {}: {}", path.display(), e))?;
+    Ok(file)
+}
+
+/// Revert a run in `root`, by default the most recent one still in effect.
+/// Returns the ID of the run.
+pub fn undo(root: &Path, id: Option<&str>) -> Result<String, String> {
+    let run = find_run(root, id, false)?;
+    let reverted = revert_log(&run.dir, CHANGES, REDO)?;
+    info!(
+        "Undid run {}, reverting {}",
+        run.id,
+        plural(reverted, "change")
+    );
+    Ok(run.id)
+}
+
+/// Reapply a run in `root` that was undone, by default the most recent one
+pub fn redo(root: &Path, id: Option<&str>) -> Result<(), String> {
+    let run = find_run(root, id, true)?;
+    let reverted = revert_log(&run.dir, REDO, CHANGES)?;
+    info!(
+        "Redid run {}, reapplying {}",
+        run.id,
+        plural(reverted, "change")
+    );
+    Ok(())
+}
+
+/// Revert every change in the log `from`, latest first, recording what's
+/// done in the log `to` so that it can be reverted in turn
+///
+/// Afterwards the old log and its copies of files are removed, as the changes
+/// they describe are no longer in effect. Returns how many changes were
+/// reverted.
+fn revert_log(dir: &Path, from: &str, to: &str) -> Result<usize, String> {
+    let changes = fs::read_to_string(dir.join(from)).map_err(|e| e.to_string())?;
+    let changes: Vec<Value> = changes
+        .lines()
+        // A run that was interrupted mid-write can end with a partial line
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+
+    let mut log = Log::create(dir, to).map_err(|e| e.to_string())?;
+    let mut reverted = 0;
+    for change in changes.iter().rev() {
+        match revert(dir, change, &mut log) {
+            Ok(()) => reverted += 1,
+            Err(e) => warn!("{}", e),
+        }
+    }
+
+    for change in &changes {
+        if let Some(backup) = change["backup"].as_str() {
+            let _ = fs::remove_file(dir.join(backup));
+        }
+    }
+    fs::remove_file(dir.join(from)).map_err(|e| e.to_string())?;
+
+    Ok(reverted)
+}
+
+fn revert(dir: &Path, change: &Value, log: &mut Log) -> Result<(), String> {
+    let path_field = |name: &str| -> Result<PathBuf, String> {
+        change[name]
+            .as_str()
+            .map(PathBuf::from)
+            .ok_or_else(|| format!("journal entry is missing {}", name))
+    };
+
+    match change["op"].as_str() {
+        Some("write") => {
+            let path = path_field("path")?;
+            let recorded = change["size"].as_u64().zip(change["hash"].as_u64());
+            if stamp(&path) != recorded {
+                return Err(format!(
+                    "Not restoring {}: it has changed since",
+                    path.display()
+                ));
+            }
+
+            let restore = || match change["backup"].as_str() {
+                Some(backup) => {
+                    let original = fs::read(dir.join(backup))?;
+                    if recorded.is_some() {
+                        write::write_atomic(&path, &original, &restore_options())
+                    } else {
+                        // The change deleted this file
+                        fs::write(&path, original)
+                    }
+                }
+                // The change created this file
+                None if recorded.is_some() => fs::remove_file(&path),
+                None => Ok(()),
+            };
+            log.record_write(&path, restore)
+                .map_err(|e| format!("Could not restore {}: {}", path.display(), e))
+        }
+        Some("symlink") => {
+            let path = path_field("path")?;
+            let target = path_field("target")?;
+            let new_target = path_field("new_target")?;
+            if fs::read_link(&path).ok().as_ref() != Some(&new_target) {
+                return Err(format!(
+                    "Not restoring {}: it has changed since",
+                    path.display()
+                ));
+            }
+            write::replace_symlink(&path, &target, &WriteOptions::default())
+                .and_then(|()| log.record_symlink(&path, &new_target, &target))
+                .map_err(|e| format!("Could not restore {}: {}", path.display(), e))
+        }
+        Some("rename") => {
+            let from = path_field("from")?;
+            let to = path_field("to")?;
+            if fs::symlink_metadata(&from).is_ok() {
+                return Err(format!(
+                    "Not renaming {} back: {} exists",
+                    to.display(),
+                    from.display()
+                ));
+            }
+            crate::rename::rename_path(&to, &from)
+                .and_then(|()| log.record_rename(&to, &from))
+                .map_err(|e| format!("Could not rename {} back: {}", to.display(), e))
+        }
+        _ => Err(format!("unknown journal entry {}", change)),
+    }
+}
+
+/// Restored files keep the attributes they have now, as the run did
+fn restore_options() -> WriteOptions {
+    WriteOptions {
+        preserve: vec![Attribute::Mode, Attribute::Ownership, Attribute::Xattr],
+        ..WriteOptions::default()
+    }
+}
+
+/// Print the runs recorded in `root`, oldest first
+pub fn print_history(root: &Path) {
+    let mut stdout = io::stdout().lock();
+    for run in runs(root) {
+        let changes = fs::read_to_string(run.log()).unwrap_or_default();
+        let files = changes
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter(|change| change["op"] == "write")
+            .count();
+
+        // Show the command as it was typed, rather than the binary's full path
+        let args: Vec<&str> = run.info["args"]
+            .as_array()
+            .map(|args| args.iter().skip(1).filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let printed = writeln!(
+            stdout,
+            "{}  {}  {:>5} {}  {:<11}  newtext {}",
+            run.id,
+            format_time(run.info["time"].as_u64().unwrap_or(0)),
+            files,
+            if files == 1 { "file " } else { "files" },
+            if run.undone {
+                "undone"
+            } else if run.info["interrupted"] == true {
+                "interrupted"
+            } else {
+                ""
+            },
+            args.join(" ")
+        );
+        // Stop quietly if the output is piped to something like head
+        if printed.is_err() {
+            return;
+        }
+    }
+}
+
+/// Format seconds since the epoch as a UTC date and time
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Days since 1970-01-01 to a calendar date, from Howard Hinnant's
+    // civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
@@ -0,0 +1,231 @@
+//! --json-path, which limits replacements to the string values at some paths
+//! in JSON files
+//!
+//! Documents are scanned rather than parsed into values, so that everything
+//! outside the values that change is written back exactly as it was. Comments
+//! and trailing commas, as in tsconfig.json and VS Code's settings, are
+//! allowed.
+
+use crate::scope::{Escape, Key, KeyPath, Segment, Step};
+use std::ops::Range;
+
+/// Parse a JSONPath such as `$.dependencies.*`, `$..url` or
+/// `$['scripts'][0]`
+///
+/// Only the parts of JSONPath that name values are understood: child
+/// names, indexes, `*` and `..` for any depth, not filters or slices.
+pub fn parse(path: &str) -> Result<KeyPath, String> {
+    let invalid = |why: &str| format!("invalid JSONPath '{}': {}", path, why);
+    let rest = path
+        .strip_prefix('$')
+        .ok_or_else(|| invalid("it should start with $"))?;
+
+    let mut steps = Vec::new();
+    let mut i = 0;
+    while i < rest.len() {
+        let at = &rest[i..];
+        if at.starts_with("..") {
+            steps.push(Step::AnyDepth);
+            i += 2;
+            // `..name` and `..*` go straight on to the name
+            if rest[i..].starts_with('[') {
+                continue;
+            }
+        } else if at.starts_with('.') {
+            i += 1;
+        } else if at.starts_with('[') {
+            let end = at.find(']').ok_or_else(|| invalid("a [ isn't closed"))?;
+            let inside = at[1..end].trim();
+            let quoted = inside.len() >= 2
+                && (inside.starts_with('\'') && inside.ends_with('\'')
+                    || inside.starts_with('"') && inside.ends_with('"'));
+            steps.push(if inside == "*" {
+                Step::Any
+            } else if let Ok(index) = inside.parse() {
+                Step::Index(index)
+            } else if quoted {
+                Step::Key(inside[1..inside.len() - 1].to_string())
+            } else {
+                return Err(invalid(&format!(
+                    "[{}] isn't a name, an index or *",
+                    inside
+                )));
+            });
+            i += end + 1;
+            continue;
+        } else {
+            return Err(invalid(&format!("expected . or [ before '{}'", at)));
+        }
+
+        let name = &rest[i..];
+        let name = &name[..name.find(['.', '[']).unwrap_or(name.len())];
+        if name.is_empty() {
+            return Err(invalid("a . should be followed by a name"));
+        }
+        steps.push(match name {
+            "*" => Step::Any,
+            name => Step::Key(name.to_string()),
+        });
+        i += name.len();
+    }
+    Ok(KeyPath(steps))
+}
+
+/// The string values in the JSON document `text` that any of `paths` cover
+pub fn segments(text: &str, paths: &[KeyPath]) -> Result<Vec<Segment>, String> {
+    let mut scanner = Scanner {
+        text,
+        at: 0,
+        path: Vec::new(),
+        paths,
+        segments: Vec::new(),
+    };
+    scanner.skip_space()?;
+    scanner.value()?;
+    scanner.skip_space()?;
+    if scanner.at < text.len() {
+        return Err(scanner.error("there's more after the document"));
+    }
+    Ok(scanner.segments)
+}
+
+struct Scanner<'a> {
+    text: &'a str,
+    at: usize,
+    /// The path to the value being scanned
+    path: Vec<Key>,
+    paths: &'a [KeyPath],
+    segments: Vec<Segment>,
+}
+
+impl Scanner<'_> {
+    fn value(&mut self) -> Result<(), String> {
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => {
+                let (range, value) = self.string()?;
+                if self.paths.iter().any(|path| path.covers(&self.path)) {
+                    self.segments.push(Segment {
+                        range,
+                        text: value,
+                        escape: Escape::Json,
+                    });
+                }
+                Ok(())
+            }
+            Some(_) => {
+                // Numbers, true, false and null are left as they are
+                let end = self.text[self.at..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c)))
+                    .map_or(self.text.len(), |end| self.at + end);
+                if end == self.at {
+                    return Err(self.error("expected a value"));
+                }
+                self.at = end;
+                Ok(())
+            }
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn object(&mut self) -> Result<(), String> {
+        self.at += 1;
+        loop {
+            self.skip_space()?;
+            if self.eat(b'}') {
+                return Ok(());
+            }
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a key in double quotes"));
+            }
+            let (_, key) = self.string()?;
+            self.skip_space()?;
+            if !self.eat(b':') {
+                return Err(self.error("expected a : after the key"));
+            }
+            self.skip_space()?;
+            self.path.push(Key::Name(key));
+            self.value()?;
+            self.path.pop();
+            self.skip_space()?;
+            if !self.eat(b',') && self.peek() != Some(b'}') {
+                return Err(self.error("expected a , or }"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<(), String> {
+        self.at += 1;
+        for index in 0.. {
+            self.skip_space()?;
+            if self.eat(b']') {
+                break;
+            }
+            self.path.push(Key::Index(index));
+            self.value()?;
+            self.path.pop();
+            self.skip_space()?;
+            if !self.eat(b',') && self.peek() != Some(b']') {
+                return Err(self.error("expected a , or ]"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The string starting here, with its quotes, and its value
+    fn string(&mut self) -> Result<(Range<usize>, String), String> {
+        let start = self.at;
+        let bytes = self.text.as_bytes();
+        let mut end = start + 1;
+        loop {
+            match bytes.get(end) {
+                Some(b'"') => break,
+                Some(b'\\') => end += 2,
+                Some(_) => end += 1,
+                None => return Err(self.error("a string isn't closed")),
+            }
+        }
+        let range = start..end + 1;
+        let value = serde_json::from_str(&self.text[range.clone()])
+            .map_err(|e| self.error(&format!("invalid string: {}", e)))?;
+        self.at = range.end;
+        Ok((range, value))
+    }
+
+    /// Skip whitespace and comments
+    fn skip_space(&mut self) -> Result<(), String> {
+        loop {
+            let rest = &self.text[self.at..];
+            let trimmed = rest.trim_start();
+            self.at += rest.len() - trimmed.len();
+            if trimmed.starts_with("//") {
+                self.at += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else if trimmed.starts_with("/*") {
+                let end = trimmed
+                    .find("*/")
+                    .ok_or_else(|| self.error("a comment isn't closed"))?;
+                self.at += end + 2;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.at).copied()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let found = self.peek() == Some(byte);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn error(&self, why: &str) -> String {
+        let line = self.text[..self.at].matches('\n').count() + 1;
+        format!("it isn't valid JSON, {} on line {}", why, line)
+    }
+}
@@ -0,0 +1,462 @@
+//! --key, which limits replacements to the values of some keys in `.env`,
+//! INI and `.properties` files, and with --ensure adds the keys that are
+//! missing
+//!
+//! Files are read a line at a time, and only the values that change are
+//! written back, so comments, blank lines and the order of keys stay as they
+//! were.
+
+use crate::scope::{Escape, Segment};
+use std::path::Path;
+
+/// The keys to replace within, with --key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keys {
+    /// The keys, written `section.key` for keys in INI sections
+    pub names: Vec<String>,
+    /// The value a key is added with to the files that don't have it
+    pub ensure: Option<String>,
+}
+
+/// The kinds of files with keys and values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// `KEY=value` lines, with optional `export` and quotes, as read by
+    /// shells and dotenv libraries
+    Env,
+    /// `key = value` lines in `[section]`s
+    Ini,
+    /// Java's `key=value`, `key: value` or `key value` lines
+    Properties,
+}
+
+impl Format {
+    /// The kind of file at `path`, if it's one
+    pub fn of(path: &Path) -> Option<Format> {
+        let name = path.file_name()?.to_str()?;
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        if name == ".env" || name.starts_with(".env.") || extension == Some("env") {
+            Some(Format::Env)
+        } else if matches!(extension, Some("ini" | "cfg")) || name == ".editorconfig" {
+            Some(Format::Ini)
+        } else if extension == Some("properties") {
+            Some(Format::Properties)
+        } else {
+            None
+        }
+    }
+}
+
+/// A key's value found on a line
+struct Entry {
+    /// The section it's in, for INI files
+    section: Option<String>,
+    key: String,
+    /// Where the key starts, after any `export`
+    key_start: usize,
+    value: Segment,
+    /// What's between the key and the value, such as ` = `
+    separator: String,
+    /// Where the line ends, after any line ending
+    line_end: usize,
+}
+
+/// The values of `keys` in `text`, a file in `format`, with a segment adding
+/// each key that's missing if the keys are ensured
+pub fn segments(text: &str, format: Format, keys: &Keys) -> Vec<Segment> {
+    let (entries, sections) = read(text, format);
+    let mut segments = Vec::new();
+    for name in &keys.names {
+        let mut found = false;
+        for entry in &entries {
+            if entry_is(entry, name) {
+                found = true;
+                segments.push(Segment {
+                    range: entry.value.range.clone(),
+                    text: entry.value.text.clone(),
+                    escape: entry.value.escape,
+                });
+            }
+        }
+        if let (false, Some(value)) = (found, &keys.ensure) {
+            segments.push(addition(text, format, &entries, &sections, name, value));
+        }
+    }
+    segments.sort_by_key(|segment| segment.range.start);
+    segments
+}
+
+fn entry_is(entry: &Entry, name: &str) -> bool {
+    match &entry.section {
+        Some(section) => name
+            .strip_prefix(section.as_str())
+            .and_then(|rest| rest.strip_prefix('.'))
+            .is_some_and(|key| key == entry.key),
+        None => entry.key == name,
+    }
+}
+
+/// A segment adding the key `name` with `value` to `text`
+fn addition(
+    text: &str,
+    format: Format,
+    entries: &[Entry],
+    sections: &[Section],
+    name: &str,
+    value: &str,
+) -> Segment {
+    // The section the key goes in, if it's there already, or the one to add
+    let existing = sections
+        .iter()
+        .filter(|section| name.starts_with(&format!("{}.", section.name)))
+        .max_by_key(|section| section.name.len());
+    let (existing, new_section, key) = match (format, existing) {
+        (Format::Ini, Some(section)) => (Some(section), None, &name[section.name.len() + 1..]),
+        (Format::Ini, None) => match name.rsplit_once('.') {
+            Some((section, key)) => (None, Some(section), key),
+            None => (None, None, name),
+        },
+        (Format::Env | Format::Properties, _) => (None, None, name),
+    };
+    // Added after the last of the other keys in the same section, so new
+    // sections go at the end and keys outside any go before the first
+    let in_section = |entry: &&Entry| match existing {
+        Some(section) => entry.section.as_deref() == Some(section.name.as_str()),
+        None => entry.section.is_none(),
+    };
+    let last_entry = entries.iter().rfind(in_section);
+    let last = last_entry.map(|entry| entry.line_end);
+
+    // Written the way the keys around it are
+    let separator = last_entry.or(entries.last()).map_or(
+        match format {
+            Format::Ini => " = ",
+            Format::Env | Format::Properties => "=",
+        },
+        |entry| entry.separator.as_str(),
+    );
+    let (key, value) = match format {
+        Format::Env => (key.to_string(), Escape::Env(None).escape(value)),
+        Format::Ini => (key.to_string(), value.replace('\n', " ")),
+        Format::Properties => {
+            let unicode = text.is_ascii();
+            (
+                Escape::PropertiesKey { unicode }.escape(key),
+                Escape::Properties { unicode }.escape(value),
+            )
+        }
+    };
+    let line = format!("{}{}{}\n", key, separator, value);
+
+    let at = match (existing, new_section) {
+        (Some(section), _) => last.unwrap_or(section.line_end),
+        (None, Some(_)) => text.len(),
+        (None, None) => last.unwrap_or(sections.first().map_or(text.len(), |first| first.start)),
+    };
+
+    let mut added = String::new();
+    if at > 0 && !text[..at].ends_with('\n') {
+        added.push('\n');
+    }
+    if let Some(section) = new_section {
+        if !text.trim().is_empty() && !text[..at].ends_with("\n\n") {
+            added.push('\n');
+        }
+        added.push_str(&format!("[{}]\n", section));
+    }
+    added.push_str(&line);
+    Segment {
+        range: at..at,
+        text: added,
+        escape: Escape::Added,
+    }
+}
+
+/// An INI section's header
+struct Section {
+    name: String,
+    /// Where its header line starts
+    start: usize,
+    /// Where its header line ends, after any line ending
+    line_end: usize,
+}
+
+/// The keys and values in `text`, and the INI sections
+fn read(text: &str, format: Format) -> (Vec<Entry>, Vec<Section>) {
+    let mut entries = Vec::new();
+    let mut sections = Vec::new();
+    // Non-ASCII characters are written as \u escapes in files that have
+    // no others
+    let unicode = text.is_ascii();
+    let mut at = 0;
+    while at < text.len() {
+        let line_end = text[at..].find('\n').map_or(text.len(), |end| at + end + 1);
+        let line = text[at..line_end].trim_end_matches(['\n', '\r']);
+        let indent = line.len() - line.trim_start().len();
+        let start = at + indent;
+        let trimmed = line.trim();
+        let parsed = match format {
+            _ if trimmed.is_empty() => None,
+            Format::Env if trimmed.starts_with('#') => None,
+            Format::Ini if trimmed.starts_with([';', '#']) => None,
+            Format::Properties if trimmed.starts_with(['#', '!']) => None,
+            Format::Ini if trimmed.starts_with('[') => {
+                let name = trimmed[1..].trim_end().trim_end_matches(']').trim();
+                sections.push(Section {
+                    name: name.to_string(),
+                    start: at,
+                    line_end,
+                });
+                None
+            }
+            Format::Env => env_entry(text, start, line_end),
+            Format::Ini => ini_entry(text, start, line_end),
+            Format::Properties => properties_entry(text, start, unicode),
+        };
+        at = line_end;
+        if let Some(mut entry) = parsed {
+            entry.section = sections.last().map(|section| section.name.clone());
+            // A quoted value can run on over several lines
+            at = at.max(entry.line_end);
+            entries.push(entry);
+        }
+    }
+    (entries, sections)
+}
+
+fn env_entry(text: &str, start: usize, line_end: usize) -> Option<Entry> {
+    let line = &text[start..line_end];
+    let after_export = line
+        .strip_prefix("export")
+        .filter(|rest| rest.starts_with([' ', '\t']))
+        .map_or(0, |rest| line.len() - rest.trim_start().len());
+    let key_start = start + after_export;
+    let equals = key_start + text[key_start..line_end].find('=')?;
+    let key = text[key_start..equals].trim();
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return None;
+    }
+    let rest = &text[equals + 1..];
+    let value_start = equals + 1 + (rest.len() - rest.trim_start_matches([' ', '\t']).len());
+    let separator = text[key_start + key.len()..value_start].to_string();
+
+    let (value, line_end) = match text[value_start..].chars().next() {
+        Some(quote @ ('"' | '\'')) => {
+            // The closing quote, which can be on a later line
+            let mut end = value_start + 1;
+            let mut decoded = String::new();
+            loop {
+                let c = text[end..].chars().next()?;
+                end += c.len_utf8();
+                match c {
+                    '\\' if quote == '"' => {
+                        let escaped = text[end..].chars().next()?;
+                        end += escaped.len_utf8();
+                        match escaped {
+                            'n' => decoded.push('\n'),
+                            'r' => decoded.push('\r'),
+                            't' => decoded.push('\t'),
+                            '"' | '\\' | '$' => decoded.push(escaped),
+                            // Other backslashes are left as they are
+                            _ => {
+                                decoded.push('\\');
+                                decoded.push(escaped);
+                            }
+                        }
+                    }
+                    c if c == quote => break,
+                    c => decoded.push(c),
+                }
+            }
+            let line_end = text[end..]
+                .find('\n')
+                .map_or(text.len(), |found| end + found + 1);
+            let value = Segment {
+                range: value_start..end,
+                text: decoded,
+                escape: Escape::Env(Some(quote)),
+            };
+            (value, line_end)
+        }
+        _ => {
+            let line = text[value_start..line_end].trim_end_matches(['\n', '\r']);
+            // A comment needs a space before it
+            let end = line
+                .find(" #")
+                .or_else(|| line.find("\t#"))
+                .unwrap_or(line.len());
+            let value = line[..end].trim_end();
+            let value = Segment {
+                range: value_start..value_start + value.len(),
+                text: value.to_string(),
+                escape: Escape::Env(None),
+            };
+            (value, line_end)
+        }
+    };
+    Some(Entry {
+        section: None,
+        key: key.to_string(),
+        key_start,
+        value,
+        separator,
+        line_end,
+    })
+}
+
+fn ini_entry(text: &str, start: usize, line_end: usize) -> Option<Entry> {
+    let line = text[start..line_end].trim_end_matches(['\n', '\r']);
+    let split = line.find(['=', ':'])?;
+    let key = line[..split].trim_end();
+    if key.is_empty() {
+        return None;
+    }
+    let rest = &line[split + 1..];
+    let value = rest.trim();
+    let value_start = start + split + 1 + (rest.len() - rest.trim_start().len());
+    Some(Entry {
+        section: None,
+        key: key.to_string(),
+        key_start: start,
+        separator: text[start + key.len()..value_start].to_string(),
+        value: Segment {
+            range: value_start..value_start + value.len(),
+            text: value.to_string(),
+            escape: Escape::None,
+        },
+        line_end,
+    })
+}
+
+fn properties_entry(text: &str, start: usize, unicode: bool) -> Option<Entry> {
+    // The key ends at the first =, : or space that isn't escaped
+    let mut key = String::new();
+    let mut at = start;
+    while let Some((c, escaped, next)) = properties_char(text, at) {
+        if !escaped && matches!(c, '=' | ':' | ' ' | '\t' | '\x0c') {
+            break;
+        }
+        key.push(c);
+        at = next;
+    }
+    let key_end = at;
+    // Spaces, then at most one = or :, then spaces again
+    let mut separated = false;
+    while let Some((c, false, next)) = properties_char(text, at) {
+        match c {
+            ' ' | '\t' | '\x0c' => {}
+            '=' | ':' if !separated => separated = true,
+            _ => break,
+        }
+        at = next;
+    }
+    let value_start = at;
+    let mut value = String::new();
+    while let Some((c, _, next)) = properties_char(text, at) {
+        value.push(c);
+        at = next;
+    }
+    let value_end = at;
+    let line_end = text[at..].find('\n').map_or(text.len(), |end| at + end + 1);
+    Some(Entry {
+        section: None,
+        key,
+        key_start: start,
+        separator: text[key_end..value_start].to_string(),
+        value: Segment {
+            range: value_start..value_end,
+            text: value,
+            escape: Escape::Properties { unicode },
+        },
+        line_end,
+    })
+}
+
+/// The character of a `.properties` line at `at`, whether it was escaped,
+/// and where the next one is, or None at the end of the line. A backslash
+/// at the end of a line carries it on past the next line's indentation.
+fn properties_char(text: &str, at: usize) -> Option<(char, bool, usize)> {
+    let mut chars = text[at..].chars();
+    match chars.next()? {
+        '\n' => None,
+        '\r' if text[at + 1..].starts_with('\n') || at + 1 == text.len() => None,
+        '\\' => {
+            let escaped = chars.next()?;
+            let next = at + 1 + escaped.len_utf8();
+            let c = match escaped {
+                '\r' | '\n' => {
+                    let next =
+                        next + usize::from(escaped == '\r' && text[next..].starts_with('\n'));
+                    let rest = &text[next..];
+                    let indent = rest.len() - rest.trim_start_matches([' ', '\t', '\x0c']).len();
+                    return properties_char(text, next + indent);
+                }
+                'u' => {
+                    let (c, len) = unicode_escape(&text[at..])?;
+                    return Some((c, true, at + len));
+                }
+                't' => '\t',
+                'n' => '\n',
+                'r' => '\r',
+                'f' => '\x0c',
+                c => c,
+            };
+            Some((c, true, next))
+        }
+        c => Some((c, false, at + c.len_utf8())),
+    }
+}
+
+/// The character a `\uXXXX` escape at the start of `text` stands for, with
+/// a pair of them for the characters outside the Basic Multilingual Plane,
+/// and how long it's written
+fn unicode_escape(text: &str) -> Option<(char, usize)> {
+    let unit = |escape: &str| {
+        let hex = escape.strip_prefix("\\u")?.get(..4)?;
+        u16::from_str_radix(hex, 16).ok()
+    };
+    let high = unit(text)?;
+    if let Some(c) = char::from_u32(u32::from(high)) {
+        return Some((c, 6));
+    }
+    let low = unit(text.get(6..)?)?;
+    let c = char::decode_utf16([high, low]).next()?.ok()?;
+    Some((c, 12))
+}
+
+/// Every part of the `.properties` file `text`, with its keys and values
+/// read with their escapes and continuations, and the rest as it is
+pub fn properties_segments(text: &str) -> Vec<Segment> {
+    let (entries, _) = read(text, Format::Properties);
+    let unicode = text.is_ascii();
+    let mut segments = Vec::new();
+    let mut at = 0;
+    let between = |segments: &mut Vec<Segment>, from: usize, to: usize| {
+        if to > from {
+            segments.push(Segment {
+                range: from..to,
+                text: text[from..to].to_string(),
+                escape: Escape::None,
+            });
+        }
+    };
+    for entry in entries {
+        let value = entry.value.range.clone();
+        let (key_start, key_end) = (entry.key_start, value.start - entry.separator.len());
+        between(&mut segments, at, key_start);
+        if key_end > key_start {
+            segments.push(Segment {
+                range: key_start..key_end,
+                text: entry.key,
+                escape: Escape::PropertiesKey { unicode },
+            });
+        }
+        between(&mut segments, key_end, value.start);
+        if !value.is_empty() {
+            segments.push(entry.value);
+        }
+        at = value.end;
+    }
+    between(&mut segments, at, text.len());
+    segments
+}
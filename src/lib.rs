@@ -0,0 +1,127 @@
+//! Find and replace text throughout trees of files
+//!
+//! [`Engine`] is the way in for other programs: build one from [`Options`],
+//! then replace within text with it, or work out the changes to a tree of
+//! files as a [`ChangeSet`] to look over and apply. The modules underneath
+//! are what the `newtext` command line tool is built from.
+//!
+//! Without the default `files` feature, only the modules that replace within
+//! text already in memory are built, such as [`text`] and [`matcher`], which
+//! don't touch the filesystem and so build for WebAssembly.
+
+pub mod case;
+pub mod encoding;
+pub mod eol;
+pub mod matcher;
+pub mod sed;
+pub mod text;
+
+#[cfg(feature = "files")]
+pub mod added;
+#[cfg(feature = "files")]
+pub mod amplify;
+#[cfg(feature = "files")]
+pub mod archive;
+#[cfg(feature = "files")]
+pub mod binary;
+#[cfg(feature = "files")]
+pub mod branch;
+#[cfg(feature = "files")]
+pub mod cache;
+#[cfg(feature = "files")]
+pub mod color;
+#[cfg(feature = "files")]
+pub mod commit;
+#[cfg(feature = "files")]
+pub mod contents;
+#[cfg(feature = "files")]
+pub mod csv_column;
+#[cfg(feature = "files")]
+pub mod diff;
+#[cfg(feature = "files")]
+pub mod edits;
+#[cfg(feature = "files")]
+mod engine;
+#[cfg(feature = "files")]
+pub mod explain;
+#[cfg(feature = "files")]
+mod ffi;
+#[cfg(feature = "files")]
+mod files;
+#[cfg(feature = "files")]
+pub mod filters;
+#[cfg(feature = "files")]
+pub mod fix;
+#[cfg(feature = "files")]
+pub mod gzip;
+#[cfg(feature = "files")]
+pub mod hook;
+#[cfg(feature = "files")]
+pub mod interrupt;
+#[cfg(feature = "files")]
+pub mod journal;
+#[cfg(feature = "files")]
+pub mod json_path;
+#[cfg(feature = "files")]
+pub mod key_value;
+#[cfg(feature = "files")]
+pub mod logging;
+#[cfg(feature = "files")]
+pub mod lsp;
+#[cfg(feature = "files")]
+pub mod map;
+#[cfg(feature = "files")]
+pub mod markdown;
+#[cfg(feature = "files")]
+pub mod markup;
+#[cfg(feature = "files")]
+pub mod memory;
+#[cfg(feature = "files")]
+pub mod notebook;
+#[cfg(feature = "files")]
+pub mod office;
+#[cfg(feature = "files")]
+pub mod patch;
+#[cfg(feature = "files")]
+pub mod plan;
+#[cfg(feature = "files")]
+pub mod plugin;
+#[cfg(feature = "files")]
+pub mod po;
+#[cfg(feature = "files")]
+pub mod pool;
+#[cfg(feature = "files")]
+pub mod prefilter;
+#[cfg(feature = "files")]
+pub mod progress;
+#[cfg(feature = "files")]
+pub mod rdjson;
+#[cfg(feature = "files")]
+pub mod rename;
+#[cfg(feature = "files")]
+pub mod report;
+#[cfg(feature = "files")]
+pub mod scope;
+#[cfg(feature = "files")]
+pub mod script;
+#[cfg(feature = "files")]
+pub mod selector;
+#[cfg(feature = "files")]
+pub mod stream;
+#[cfg(feature = "files")]
+pub mod throttle;
+#[cfg(feature = "files")]
+pub mod timings;
+#[cfg(feature = "files")]
+pub mod toml_key;
+#[cfg(feature = "files")]
+pub mod write;
+#[cfg(feature = "files")]
+pub mod yaml_path;
+
+#[cfg(feature = "files")]
+pub use engine::{Change, ChangeSet, Engine, Options};
+#[cfg(feature = "files")]
+use files::Replacer;
+#[cfg(feature = "files")]
+pub use files::*;
@@ -0,0 +1,150 @@
+//! What a run does and decides, logged through tracing
+//!
+//! Errors, warnings and what came of the run are always shown on stderr.
+//! With -v, so are the decisions made about each file, and with -vv each
+//! file as it's looked at. A --log-file records all of them as JSON lines, so
+//! that a long unattended run leaves a record to search afterwards.
+
+use crate::progress;
+use serde_json::{json, Map, Value};
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::Registry;
+
+/// How many times -v was given
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// The --log-file, once it's open
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Whether there's a --log-file, checked without taking the lock
+static LOGGING_TO_FILE: AtomicBool = AtomicBool::new(false);
+
+/// The problems found with files are printed together at the end by
+/// `report::print`, so they're only shown on stderr from there
+const REPORT_TARGET: &str = "newtext::report";
+
+/// Log from here on, to stderr until a --log-file is given as well
+pub fn init() {
+    let subscriber = Registry::default().with(Logger);
+    // Only fails if something else has already set one up
+    let _ = tracing::subscriber::set_global_default(subscriber);
+}
+
+/// Show more on stderr, for each time -v was given
+pub fn set_verbosity(verbosity: u8) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
+
+/// Record everything logged from here on in `path` too, after anything
+/// already there
+pub fn set_file(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *FILE.lock().unwrap() = Some(file);
+    LOGGING_TO_FILE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The most detailed level shown on stderr
+fn stderr_level() -> Level {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    }
+}
+
+struct Logger;
+
+impl<S: Subscriber> Layer<S> for Logger {
+    // The level shown changes once the options are parsed, so whether each
+    // event is logged is decided as it happens
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if metadata.target().starts_with("newtext") {
+            Interest::sometimes()
+        } else {
+            Interest::never()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _: Context<'_, S>) -> bool {
+        LOGGING_TO_FILE.load(Ordering::Relaxed) || *metadata.level() <= stderr_level()
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+
+        if *metadata.level() <= stderr_level() && metadata.target() != REPORT_TARGET {
+            let prefix = match *metadata.level() {
+                Level::ERROR => "Error: ",
+                Level::WARN => "Warning: ",
+                _ => "",
+            };
+            // In place of the progress bar, if it's showing
+            eprintln!("{}{}{}", progress::clear_line(), prefix, fields.message);
+        }
+
+        if !LOGGING_TO_FILE.load(Ordering::Relaxed) {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut line = json!({
+            "time": time,
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "message": fields.message,
+        });
+        line.as_object_mut().unwrap().append(&mut fields.values);
+        if let Some(file) = FILE.lock().unwrap().as_mut() {
+            // Logging failing isn't worth stopping the run for
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+/// The message of an event and its other fields
+#[derive(Default)]
+struct Fields {
+    message: String,
+    values: Map<String, Value>,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.values.insert(field.name().to_string(), json!(value));
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.values.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.values.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.values.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
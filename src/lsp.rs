@@ -0,0 +1,164 @@
+use crate::edits::{self, Edit};
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+
+/// An LSP `WorkspaceEdit` making the changes to each file, given with its
+/// old and new text
+///
+/// Positions count UTF-16 code units, as LSP's do by default.
+pub fn workspace_edit(files: &[(&Path, &str, &str)]) -> Value {
+    let mut changes = Map::new();
+    for (path, old, new) in files {
+        let edits: Vec<Value> = edits::edits(old, new)
+            .iter()
+            .map(|edit| text_edit(old, edit))
+            .collect();
+        changes.insert(uri(path), Value::Array(edits));
+    }
+    json!({ "changes": changes })
+}
+
+fn text_edit(old: &str, edit: &Edit) -> Value {
+    json!({
+        "range": {
+            "start": position(old, edit.start),
+            "end": position(old, edit.end),
+        },
+        "newText": edit.text,
+    })
+}
+
+/// The LSP position of a byte offset in `text`
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": before.matches('\n').count(),
+        "character": before[line_start..].encode_utf16().count(),
+    })
+}
+
+/// The TextEdits a WorkspaceEdit makes to each file, from its `changes` or
+/// its `documentChanges`
+pub fn parse(edit: &Value) -> Result<Vec<(PathBuf, Vec<Value>)>, String> {
+    let text_edits = |edits: &Value| {
+        edits
+            .as_array()
+            .cloned()
+            .ok_or_else(|| "the edits to a file aren't a list".to_string())
+    };
+    let mut files = Vec::new();
+    if let Some(changes) = edit["changes"].as_object() {
+        for (uri, edits) in changes {
+            files.push((path(uri)?, text_edits(edits)?));
+        }
+    }
+    if let Some(changes) = edit["documentChanges"].as_array() {
+        for change in changes {
+            // Files can only be edited, not created, renamed or deleted
+            let uri = change["textDocument"]["uri"]
+                .as_str()
+                .ok_or("only edits to the text of files are supported")?;
+            files.push((path(uri)?, text_edits(&change["edits"])?));
+        }
+    }
+    if !(edit["changes"].is_object() || edit["documentChanges"].is_array()) {
+        return Err("not a WorkspaceEdit".to_string());
+    }
+    Ok(files)
+}
+
+/// The edits to `text` that LSP TextEdits make
+pub fn edits(text: &str, text_edits: &[Value]) -> Result<Vec<Edit>, String> {
+    text_edits
+        .iter()
+        .map(|edit| {
+            Ok(Edit {
+                start: offset(text, &edit["range"]["start"])?,
+                end: offset(text, &edit["range"]["end"])?,
+                text: edit["newText"]
+                    .as_str()
+                    .ok_or("a TextEdit is missing newText")?
+                    .to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The byte offset in `text` of an LSP position
+fn offset(text: &str, position: &Value) -> Result<usize, String> {
+    let (Some(line), Some(character)) = (position["line"].as_u64(), position["character"].as_u64())
+    else {
+        return Err("a TextEdit has an invalid range".to_string());
+    };
+    let line_start = match line {
+        0 => 0,
+        _ => text
+            .match_indices('\n')
+            .nth(line as usize - 1)
+            .map(|(i, _)| i + 1)
+            .ok_or_else(|| format!("line {} is past the end of the file", line + 1))?,
+    };
+    let line_text = &text[line_start..];
+    let line_text = &line_text[..line_text.find('\n').unwrap_or(line_text.len())];
+    let mut units = 0;
+    for (i, c) in line_text.char_indices().chain([(line_text.len(), '\n')]) {
+        if units == character {
+            return Ok(line_start + i);
+        }
+        units += c.len_utf16() as u64;
+    }
+    Err(format!(
+        "character {} is past the end of line {}",
+        character + 1,
+        line + 1
+    ))
+}
+
+/// The path of a `file:` URI
+pub fn path(uri: &str) -> Result<PathBuf, String> {
+    let invalid = || format!("{} isn't the URI of a local file", uri);
+    let encoded = uri.strip_prefix("file://").ok_or_else(invalid)?;
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = rest.get(..2).and_then(|hex| std::str::from_utf8(hex).ok());
+        bytes.push(
+            hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(invalid)?,
+        );
+        rest = &rest[2..];
+    }
+    let mut path = String::from_utf8(bytes).map_err(|_| invalid())?;
+    // Windows drive letters come after a /
+    if path.as_bytes().get(2) == Some(&b':') {
+        path.remove(0);
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// The `file:` URI of an absolute path
+pub fn uri(path: &Path) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file://");
+    // Windows paths start with a drive letter rather than /
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for (i, byte) in path.bytes().enumerate() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(byte as char)
+            }
+            // Drive letters keep their colon, as editors write them
+            b':' if i == 1 => uri.push(':'),
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
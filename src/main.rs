@@ -1,24 +1,106 @@
-use clap::Parser;
-use ignore::WalkBuilder;
-use regex::Regex;
+mod config;
+mod man;
+mod precommit;
+#[cfg(unix)]
+mod serve;
+mod watch;
+
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand};
+use newtext::added::AddedLines;
+use newtext::cache::Cache;
+use newtext::color;
+use newtext::contents::Contents;
+use newtext::csv_column::{self, Column, Csv};
+use newtext::diff;
+use newtext::edits;
+use newtext::encoding::Encoding;
+use newtext::eol::Eol;
+use newtext::fix::{self, Fix};
+use newtext::journal::{self, Journal};
+use newtext::key_value::Keys;
+use newtext::map::MissingKey;
+use newtext::notebook::Cells;
+use newtext::plan::{self, Plan};
+use newtext::progress::{self, Progress};
+use newtext::report::{self, Code};
+use newtext::scope::{KeyPath, Scope};
+use newtext::selector::{self, Selector};
+use newtext::timings::{self, Phase};
+use newtext::write::{self, Attribute, OutputDir};
+use newtext::{
+    amplify, branch, commit, display_path, explain, filters, hook, interrupt, is_walked, json_path,
+    logging, lsp, markdown, matching_lines, memory, patch, plural, po, pool, process_file,
+    process_symlink, rdjson, rename, rewrite_input, throttle, walk, Replacement,
+    FILES_AHEAD_PER_JOB,
+};
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::ffi::OsString;
 use std::fs;
-use std::io;
-use std::path::Path;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info, trace, warn};
 
 /// A simple find and replace tool that processes all text files in the current directory
 #[derive(Parser)]
 #[command(name = "newtext")]
 #[command(version)]
-#[command(about = "Find and replace text in all files in the current directory", long_about = None)]
+#[command(
+    about = "Find and replace text in all files in the current directory, or in the given paths",
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    // So that options on the command line override those from config files
+    args_override_self = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print the options that would be used and where each one's value came
+    /// from, the config files, the environment or the command line, then exit
+    #[arg(long = "print-config", global = true)]
+    print_config: bool,
+
+    /// Show more of what's done on stderr: with -v, what's decided about
+    /// each file, and with -vv each file as it's looked at
+    #[arg(short = 'v', long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Record everything that's done as JSON lines, added to the end of FILE
+    #[arg(long = "log-file", value_name = "FILE", global = true)]
+    log_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    options: Options,
+}
+
+/// What to replace and how, for a run or a plan
+#[derive(Args)]
+#[command(group(ArgGroup::new("committing").args(["commit", "branch"]).multiple(true)))]
+#[command(group(ArgGroup::new("sed_script").args(["sed", "sed_file"])))]
+struct Options {
     /// The text to search for
-    #[arg(value_name = "OLD")]
-    old: String,
+    #[arg(
+        value_name = "OLD",
+        required_unless_present_any = ["sed", "sed_file", "fix", "print_config"]
+    )]
+    old: Option<String>,
 
     /// The text to replace with
-    #[arg(value_name = "NEW")]
-    new: String,
+    #[arg(
+        value_name = "NEW",
+        required_unless_present_any = ["script", "map", "plugin", "sed", "sed_file", "fix", "print_config"]
+    )]
+    new: Option<String>,
+
+    /// Files and directories to process instead of the current directory, or
+    /// - to filter stdin to stdout
+    #[arg(value_name = "PATH")]
+    paths: Vec<PathBuf>,
 
     /// Treat the find string as a regular expression pattern
     #[arg(short = 'p', long = "pattern")]
@@ -27,224 +109,2418 @@ struct Cli {
     /// Case-insensitive matching with case-preserving replacement
     #[arg(short = 'i', long = "ignore-case")]
     ignore_case: bool,
+
+    /// Compute each replacement with the replace() function in a Rhai script
+    #[arg(long = "script", value_name = "FILE")]
+    script: Option<PathBuf>,
+
+    /// Replace the key captured by the pattern with its value from a JSON or TOML dictionary
+    #[arg(
+        long = "map",
+        value_name = "FILE",
+        requires = "pattern",
+        conflicts_with = "script"
+    )]
+    map: Option<PathBuf>,
+
+    /// Compute each replacement by asking CMD, a program run by the shell
+    /// that's sent each match as a line of JSON and answers with another
+    #[arg(long = "plugin", value_name = "CMD", conflicts_with_all = ["script", "map"])]
+    plugin: Option<String>,
+
+    /// What to do when a captured key is not in the --map dictionary
+    #[arg(
+        long = "map-missing",
+        value_name = "ACTION",
+        default_value = "skip",
+        requires = "map"
+    )]
+    map_missing: MissingKey,
+
+    /// Swap the direction of every pair in the --map dictionary, mapping values back to keys
+    #[arg(long = "reverse", requires = "map")]
+    reverse: bool,
+
+    /// Apply sed substitution commands, e.g. 's/foo/bar/g; s/baz/qux/'
+    #[arg(long = "sed", value_name = "SCRIPT", conflicts_with_all = ["pattern", "script", "map", "plugin", "sed_file"])]
+    sed: Option<String>,
+
+    /// Read sed substitution commands from a file
+    #[arg(short = 'f', long = "sed-file", value_name = "FILE", conflicts_with_all = ["pattern", "script", "map", "plugin"])]
+    sed_file: Option<PathBuf>,
+
+    /// Use extended regular expressions in sed commands, like sed -E
+    #[arg(short = 'E', long = "sed-extended", requires = "sed_script")]
+    sed_extended: bool,
+
+    /// Make the built-in transform NAME to each file, after the replacement
+    /// or instead of one: normalize, which makes smart quotes, odd spaces,
+    /// zero-width characters and lookalike letters plain ASCII,
+    /// trailing-whitespace, tabs-to-spaces=N or final-newline
+    #[arg(long, value_name = "NAME", value_delimiter = ',', value_parser = fix::parse)]
+    fix: Vec<Fix>,
+
+    /// Line endings to write in modified files
+    #[arg(long = "eol", value_name = "STYLE", default_value = "preserve")]
+    eol: Eol,
+
+    /// Always end modified files with a newline, instead of keeping the original ending
+    #[arg(long = "final-newline")]
+    final_newline: bool,
+
+    /// Remove the byte order mark from files that have one
+    #[arg(long = "strip-bom")]
+    strip_bom: bool,
+
+    /// Decode files that aren't valid UTF-8 with this encoding, e.g. latin1, windows-1252, shift_jis
+    #[arg(long = "encoding", value_name = "ENC", value_parser = parse_encoding)]
+    encoding: Option<Encoding>,
+
+    /// Guess the encoding of each file that isn't valid UTF-8
+    #[arg(long = "detect-encoding", conflicts_with = "encoding")]
+    detect_encoding: bool,
+
+    /// File attributes to keep when rewriting files (comma separated).
+    /// Ownership and extended attributes are kept where possible anyway, but
+    /// only fail a write when given here.
+    #[arg(
+        long = "preserve",
+        value_name = "ATTRS",
+        value_delimiter = ',',
+        default_value = "mode"
+    )]
+    preserve: Vec<Attribute>,
+
+    /// File attributes not to keep when rewriting files (comma separated)
+    #[arg(long = "no-preserve", value_name = "ATTRS", value_delimiter = ',')]
+    no_preserve: Vec<Attribute>,
+
+    /// Keep the original modification time of rewritten files, like adding times to --preserve
+    #[arg(long = "preserve-mtime")]
+    preserve_mtime: bool,
+
+    /// Leave out files and directories matching GLOB, a gitignore-style glob
+    /// relative to the current directory. Can be given more than once.
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Don't leave out the files that .gitignore and .ignore files ignore
+    #[arg(long = "no-ignore")]
+    no_ignore: bool,
+
+    /// Only replace within the string values at PATH in JSON files, a
+    /// JSONPath such as '$.dependencies.*', leaving other files alone. Can
+    /// be given more than once.
+    #[arg(long = "json-path", value_name = "PATH", value_parser = json_path::parse)]
+    json_path: Vec<KeyPath>,
+
+    /// Only replace within the values at PATH in YAML files, a dotted path
+    /// such as 'spec.template.**' with * for any one key and ** for any
+    /// number, leaving other files alone. Can be given more than once.
+    #[arg(long = "yaml-path", value_name = "PATH", value_parser = KeyPath::parse, conflicts_with = "json_path")]
+    yaml_path: Vec<KeyPath>,
+
+    /// Only replace within the string values at KEY in TOML files, a dotted
+    /// key such as 'dependencies.*.version' with * for any one key and **
+    /// for any number, leaving other files alone. Can be given more than
+    /// once.
+    #[arg(long = "toml-key", value_name = "KEY", value_parser = KeyPath::parse, conflicts_with_all = ["json_path", "yaml_path"])]
+    toml_key: Vec<KeyPath>,
+
+    /// Only replace within the elements a CSS SELECTOR such as 'div.note'
+    /// matches in XML and HTML files, or within the values of the
+    /// attributes it names last, as in 'a[href]'. Can be given more than
+    /// once.
+    #[arg(long, value_name = "SELECTOR", value_parser = selector::parse_css, conflicts_with_all = ["json_path", "yaml_path", "toml_key"])]
+    selector: Vec<Selector>,
+
+    /// Only replace within the elements or attributes an XPATH such as
+    /// '//dependency/version' or '//a/@href' matches in XML and HTML files.
+    /// Can be given more than once, and with --selector.
+    #[arg(long, value_name = "XPATH", value_parser = selector::parse_xpath, conflicts_with_all = ["json_path", "yaml_path", "toml_key"])]
+    xpath: Vec<Selector>,
+
+    /// Only replace within the text of XML and HTML files, such as the
+    /// labels in SVG icons, leaving element and attribute names, comments
+    /// and other files alone, and within the values of ATTRIBUTES, a
+    /// comma-separated list, if they're given
+    #[arg(
+        long = "xml-text",
+        value_name = "ATTRIBUTES",
+        num_args = 0..=1,
+        require_equals = true,
+        value_delimiter = ',',
+        conflicts_with_all = ["json_path", "yaml_path", "toml_key", "selector", "xpath"]
+    )]
+    xml_text: Option<Vec<String>>,
+
+    /// Only replace within the fields in COLUMN of CSV and TSV files, a
+    /// name from the header or a number counting from 1, leaving other
+    /// files alone. Can be given more than once.
+    #[arg(long = "csv-column", value_name = "COLUMN", value_parser = csv_column::parse_column, conflicts_with_all = ["json_path", "yaml_path", "toml_key", "selector", "xpath", "xml_text"])]
+    csv_column: Vec<Column>,
+
+    /// The character between fields for --csv-column, instead of a tab in
+    /// .tsv files and a comma in others
+    #[arg(long = "csv-delimiter", value_name = "CHAR", value_parser = csv_column::parse_char, requires = "csv_column")]
+    csv_delimiter: Option<char>,
+
+    /// The character that quotes fields for --csv-column
+    #[arg(long = "csv-quote", value_name = "CHAR", value_parser = csv_column::parse_char, default_value = "\"", requires = "csv_column")]
+    csv_quote: char,
+
+    /// Don't take the first row as the names of the columns for
+    /// --csv-column, and replace within it as well
+    #[arg(long = "csv-no-header", requires = "csv_column")]
+    csv_no_header: bool,
+
+    /// Only replace within the prose of Markdown files, leaving code blocks
+    /// and inline code alone, or only within the code, leaving other files
+    /// alone. The default is all of it.
+    #[arg(long, value_name = "PART", conflicts_with_all = ["json_path", "yaml_path", "toml_key", "selector", "xpath", "xml_text", "csv_column"])]
+    markdown: Option<markdown::Part>,
+
+    /// Only replace within the YAML or TOML front matter at the top of
+    /// Markdown files, between --- or +++ lines, leaving the rest of them
+    /// and other files alone
+    #[arg(long = "front-matter", conflicts_with_all = ["json_path", "yaml_path", "toml_key", "selector", "xpath", "xml_text", "csv_column", "markdown"])]
+    front_matter: bool,
+
+    /// Only replace within the value of KEY in .env, INI and .properties
+    /// files, written SECTION.KEY for a key in an INI section, leaving other
+    /// files alone. Can be given more than once.
+    #[arg(long, value_name = "KEY", conflicts_with_all = ["json_path", "yaml_path", "toml_key", "selector", "xpath", "xml_text", "csv_column", "markdown", "front_matter"])]
+    key: Vec<String>,
+
+    /// Add each --key that a file doesn't have, with NEW as its value
+    #[arg(long, requires = "key", conflicts_with_all = ["script", "map", "plugin", "sed", "sed_file"])]
+    ensure: bool,
+
+    /// Only replace within the code cells of Jupyter notebooks, or only
+    /// within the Markdown cells, leaving other files alone. Notebooks are
+    /// only ever replaced within in their cells, and the default is all of
+    /// them.
+    #[arg(long = "notebook-cells", value_name = "CELLS", conflicts_with_all = ["json_path", "yaml_path", "toml_key", "selector", "xpath", "xml_text", "csv_column", "markdown", "front_matter", "key"])]
+    notebook_cells: Option<Cells>,
+
+    /// Only replace within the source strings of gettext PO and POT files,
+    /// or only within their translations, or both, leaving comments,
+    /// contexts and other files alone
+    #[arg(long, value_name = "STRINGS", conflicts_with_all = ["json_path", "yaml_path", "toml_key", "selector", "xpath", "xml_text", "csv_column", "markdown", "front_matter", "key", "notebook_cells"])]
+    po: Option<po::Part>,
+
+    /// Only replace within the lines that the current branch added or
+    /// changed since it forked from REF, by default HEAD, as git diff shows
+    /// them, including uncommitted changes and untracked files
+    #[arg(
+        long = "only-added",
+        value_name = "REF",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "HEAD"
+    )]
+    only_added: Option<String>,
+
+    /// Also search and patch binary files, matching on raw bytes
+    #[arg(long = "binary", conflicts_with_all = ["script", "map", "plugin", "sed", "sed_file", "json_path", "yaml_path", "toml_key", "selector", "xpath", "xml_text", "csv_column", "markdown", "front_matter", "key", "notebook_cells", "po", "only_added"])]
+    binary: bool,
+
+    /// Also rewrite the targets of symlinks whose target path matches
+    #[arg(long = "symlink-targets")]
+    symlink_targets: bool,
+
+    /// Also replace within the text files in zip and tar archives
+    #[arg(long)]
+    archives: bool,
+
+    /// Also replace within gzip compressed text files, such as rotated logs
+    #[arg(long)]
+    compressed: bool,
+
+    /// Also replace within the text of Word, Excel and PowerPoint documents
+    /// (.docx, .xlsx and .pptx files), leaving their formatting alone
+    #[arg(long, conflicts_with_all = ["binary", "json_path", "yaml_path", "toml_key", "selector", "xpath", "xml_text", "csv_column", "markdown", "front_matter", "key", "notebook_cells", "po"])]
+    office: bool,
+
+    /// Also rename files and directories whose names match
+    #[arg(long)]
+    rename: bool,
+
+    /// Leave files in place and write the rewritten ones to DIR instead, at
+    /// the same relative paths
+    #[arg(long, value_name = "DIR", conflicts_with = "rename")]
+    output: Option<PathBuf>,
+
+    /// Flush each rewritten file, and directories after renames, to disk
+    #[arg(long)]
+    fsync: bool,
+
+    /// Don't record changes in the .newtext journal, so the run can't be undone
+    #[arg(long = "no-journal")]
+    no_journal: bool,
+
+    /// How to report problems with files at the end of the run: as text, or
+    /// as JSON lines with an error code for each problem
+    #[arg(long = "report", value_name = "FORMAT", default_value = "text")]
+    report: report::Format,
+
+    /// How to show progress: as a bar, or as JSON lines for other programs
+    /// to read
+    #[arg(long = "progress", value_name = "FORMAT", default_value = "bar")]
+    progress: progress::Format,
+
+    /// Don't show progress while the run is going, only the counts at the end
+    #[arg(long = "no-progress", conflicts_with = "progress")]
+    no_progress: bool,
+
+    /// When to color the lines that `newtext check` and --diff print
+    #[arg(long, value_name = "WHEN", default_value = "auto")]
+    color: color::When,
+
+    /// Stop at the first file that can't be processed, instead of carrying on
+    /// with the rest
+    #[arg(long)]
+    strict: bool,
+
+    /// Only change anything if every file can be processed, rolling back
+    /// everything if a change can't be made
+    #[arg(long, conflicts_with = "no_journal")]
+    atomic: bool,
+
+    /// Run CMD on each modified file once it's written, with {} for its path,
+    /// e.g. 'rustfmt {}'
+    #[arg(long = "format-cmd", value_name = "CMD")]
+    format_cmd: Option<String>,
+
+    /// Run CMD before changing anything, stopping if it fails
+    #[arg(long = "pre-hook", value_name = "CMD")]
+    pre_hook: Option<String>,
+
+    /// Run CMD after the changes have been made, with the modified files'
+    /// paths on its stdin
+    #[arg(long = "post-hook", value_name = "CMD")]
+    post_hook: Option<String>,
+
+    /// Once the changes have been made, commit the files that changed, and
+    /// nothing else, to the git repository they're in
+    #[arg(long, conflicts_with_all = ["watch", "output"])]
+    commit: bool,
+
+    /// Make the changes on a new branch NAME off HEAD, in a worktree of its
+    /// own, and commit them there, leaving the current branch and working
+    /// tree alone
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["watch", "output"])]
+    branch: Option<String>,
+
+    /// The message for --commit and --branch, instead of one naming OLD and
+    /// NEW
+    #[arg(short = 'm', long, value_name = "MSG", requires = "committing")]
+    message: Option<String>,
+
+    /// Run CMD once the changes have been made, and roll them back if it fails,
+    /// e.g. 'cargo check'
+    #[arg(long, value_name = "CMD", conflicts_with = "no_journal")]
+    validate: Option<String>,
+
+    /// Change nothing, but print the changes that would be made in FORMAT
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        conflicts_with_all = ["check", "check_idempotent", "watch", "rename", "output"]
+    )]
+    format: Option<edits::Format>,
+
+    /// Change nothing, but write the changes that would be made to FILE as a
+    /// patch for `git apply`, or to stdout if FILE is -
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["format", "check", "check_idempotent", "watch", "rename", "output"]
+    )]
+    patch: Option<PathBuf>,
+
+    /// Change nothing, but print a diff of the changes that would be made, in
+    /// STYLE
+    #[arg(
+        long,
+        value_name = "STYLE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "lines",
+        conflicts_with_all = ["format", "patch", "check", "check_idempotent", "watch", "rename", "output"]
+    )]
+    diff: Option<diff::Style>,
+
+    /// As `newtext check`
+    #[arg(long, conflicts_with = "check_idempotent", hide = true)]
+    check: bool,
+
+    /// As `newtext check --idempotent`
+    #[arg(long = "check-idempotent", hide = true)]
+    check_idempotent: bool,
+
+    /// Change nothing and fail unless OLD matches exactly N times in the
+    /// files' contents
+    #[arg(long, value_name = "N", conflicts_with_all = ["sed", "sed_file", "resume"])]
+    expect: Option<u64>,
+
+    /// Change nothing and fail unless exactly N files would change
+    #[arg(long = "expect-files", value_name = "N", conflicts_with = "resume")]
+    expect_files: Option<u64>,
+
+    /// Don't warn about replacements whose output they would match again
+    #[arg(long = "allow-recursive")]
+    allow_recursive: bool,
+
+    /// Don't ask for confirmation before changing many files
+    #[arg(short = 'y', long)]
+    yes: bool,
+
+    /// Ask for confirmation first when more than N files would be changed,
+    /// if run from a terminal
+    #[arg(long = "confirm-over", value_name = "N", default_value_t = 100)]
+    confirm_over: usize,
+
+    /// Exit with status 1 if nothing matched, instead of 0
+    #[arg(long = "fail-if-no-matches")]
+    fail_if_no_matches: bool,
+
+    /// Fail straight away if another run is changing files in this
+    /// directory, instead of waiting for it to finish
+    #[arg(long = "no-wait")]
+    no_wait: bool,
+
+    /// Carry on from where the last unfinished run of the same command stopped
+    #[arg(long, conflicts_with_all = ["no_journal", "atomic"])]
+    resume: bool,
+
+    /// Keep running once the files have been replaced within, and replace
+    /// within files as they're created or modified
+    #[arg(
+        long,
+        conflicts_with_all = ["check", "check_idempotent", "expect", "expect_files", "atomic", "resume", "validate", "rename"]
+    )]
+    watch: bool,
+
+    /// Skip files that an earlier run of the same replacement found needed no
+    /// changes, if they haven't been modified since
+    #[arg(long, conflicts_with = "expect")]
+    cache: bool,
+
+    /// Read and write files at no more than this many megabytes a second, to
+    /// leave disk bandwidth for other programs
+    #[arg(long, value_name = "MB/S", value_parser = parse_rate)]
+    throttle: Option<u64>,
+
+    /// Run at a low priority for the CPU and, on Linux, for the disk
+    #[arg(long)]
+    nice: bool,
+
+    /// Hold no more than SIZE of file content in memory at once across the
+    /// threads, e.g. 512M. Larger files are streamed where possible.
+    #[arg(long = "max-memory", value_name = "SIZE", value_parser = parse_size)]
+    max_memory: Option<u64>,
+
+    /// Print how long the walk, reading, matching, replacing and writing took,
+    /// and which files were slowest
+    #[arg(long)]
+    timings: bool,
+
+    /// How many files to process at once, by default one per CPU
+    #[arg(short = 'j', long, value_name = "N", visible_alias = "threads")]
+    jobs: Option<usize>,
+
+    /// Process files at least this large in chunks rather than reading them whole, e.g. 64M
+    #[arg(
+        long = "stream-threshold",
+        value_name = "SIZE",
+        default_value = "64M",
+        value_parser = parse_size
+    )]
+    stream_threshold: u64,
 }
 
-fn main() {
-    let cli = Cli::parse();
+/// The exit status when nothing matched, with --fail-if-no-matches
+const EXIT_NO_MATCHES: i32 = 1;
 
-    if cli.old.is_empty() {
-        eprintln!("Error: old string cannot be empty");
-        std::process::exit(1);
+/// The exit status when anything went wrong, as for invalid arguments
+const EXIT_ERROR: i32 = 2;
+
+/// The exit status when --check or --check-idempotent found files to change
+const EXIT_CHECK_FAILED: i32 = 3;
+
+/// The exit status when the confirmation was declined
+const EXIT_DECLINED: i32 = 4;
+
+fn parse_encoding(label: &str) -> Result<Encoding, String> {
+    Encoding::for_label(label).ok_or_else(|| format!("unknown encoding '{}'", label))
+}
+
+/// Parse a rate in megabytes a second, as bytes a second
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    match rate.trim().parse::<f64>() {
+        Ok(megabytes) if megabytes > 0.0 => Ok((megabytes * (1 << 20) as f64).max(1.0) as u64),
+        _ => Err(format!("invalid rate '{}'", rate)),
     }
+}
 
-    // If using regex mode, compile the regex pattern
-    let regex = if cli.pattern {
-        let pattern = if cli.ignore_case {
-            format!("(?i){}", cli.old)
-        } else {
-            cli.old.clone()
-        };
-        match Regex::new(&pattern) {
-            Ok(re) => Some(re),
-            Err(e) => {
-                eprintln!("Error: Invalid regex pattern: {}", e);
-                std::process::exit(1);
-            }
-        }
-    } else {
-        None
+/// Parse a byte count with an optional K, M or G suffix (powers of 1024)
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let upper = size.to_ascii_uppercase();
+    let digits = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+
+    let (digits, multiplier) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 1 << 10),
+        Some('M') => (&digits[..digits.len() - 1], 1 << 20),
+        Some('G') => (&digits[..digits.len() - 1], 1 << 30),
+        _ => (digits, 1),
     };
 
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size '{}'", size))
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Replace OLD with NEW throughout the files, as `newtext OLD NEW` does
+    Replace {
+        #[command(flatten)]
+        options: Box<Options>,
+    },
+    /// Print the lines that OLD matches, without changing anything
+    Find {
+        /// The text to search for
+        #[arg(value_name = "OLD")]
+        old: String,
+
+        /// Files and directories to search instead of the current directory, or
+        /// - to search stdin
+        #[arg(value_name = "PATH")]
+        paths: Vec<PathBuf>,
+
+        /// Treat the find string as a regular expression pattern
+        #[arg(short = 'p', long = "pattern")]
+        pattern: bool,
+
+        /// Match ignoring case
+        #[arg(short = 'i', long = "ignore-case")]
+        ignore_case: bool,
+
+        /// How many files to search at once, by default one per CPU
+        #[arg(short = 'j', long, value_name = "N", visible_alias = "threads")]
+        jobs: Option<usize>,
+
+        /// Leave out files and directories matching GLOB, a gitignore-style
+        /// glob relative to the current directory
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Don't leave out the files that .gitignore and .ignore files ignore
+        #[arg(long = "no-ignore")]
+        no_ignore: bool,
+
+        /// When to color the paths and line numbers
+        #[arg(long, value_name = "WHEN", default_value = "auto")]
+        color: color::When,
+    },
+    /// Replace OLD with NEW in sample text from stdin, and print the result
+    /// with what changed marked, to try out a pattern before replacing in files
+    Try {
+        /// The text to search for
+        #[arg(value_name = "OLD")]
+        old: String,
+
+        /// The text to replace with
+        #[arg(value_name = "NEW")]
+        new: String,
+
+        /// Read the sample text from FILE instead of stdin
+        #[arg(long, value_name = "FILE")]
+        sample: Option<PathBuf>,
+
+        /// Treat the find string as a regular expression pattern
+        #[arg(short = 'p', long = "pattern")]
+        pattern: bool,
+
+        /// Match ignoring case, keeping the case of each match
+        #[arg(short = 'i', long = "ignore-case")]
+        ignore_case: bool,
+
+        /// When to color what changed, which is otherwise marked as {+text+}
+        #[arg(long, value_name = "WHEN", default_value = "auto")]
+        color: color::When,
+    },
+    /// Check a regex pattern, print what each part of it matches, and warn
+    /// about the mistakes that make it match more or less than meant
+    Explain {
+        /// The pattern, as it would be given as OLD with -p
+        #[arg(short = 'p', long = "pattern", value_name = "PATTERN")]
+        pattern: String,
+
+        /// Match ignoring case, as with -i
+        #[arg(short = 'i', long = "ignore-case")]
+        ignore_case: bool,
+    },
+    /// Change nothing, but print where the replacement would change files, and
+    /// fail if it would change any
+    Check {
+        #[command(flatten)]
+        options: Box<Options>,
+
+        /// Instead report files that the replacement would change again if it
+        /// were run a second time
+        #[arg(long)]
+        idempotent: bool,
+    },
+    /// Make the replacements in a recipe from the config files, one run after
+    /// another, or list the recipes
+    Run {
+        /// The name of the recipe
+        recipe: Option<String>,
+
+        /// Options for each run, such as -y or --check
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            requires = "recipe"
+        )]
+        options: Vec<OsString>,
+    },
+    /// Work out the changes a run would make and save them to FILE, without
+    /// changing anything
+    Plan {
+        #[command(flatten)]
+        options: Box<Options>,
+
+        /// Where to save the plan
+        #[arg(short = 'o', long = "plan", value_name = "FILE")]
+        plan: PathBuf,
+    },
+    /// Make the changes saved by `newtext plan`
+    Apply {
+        /// The plan to apply
+        plan: PathBuf,
+
+        /// Don't record changes in the .newtext journal, so they can't be undone
+        #[arg(long = "no-journal")]
+        no_journal: bool,
+    },
+    /// Make the changes in a patch written by --patch, or in a WorkspaceEdit
+    /// printed by --format lsp
+    ApplyPatch {
+        /// The patch to apply
+        patch: PathBuf,
+
+        /// How many lines of context at each end of a hunk can fail to match
+        #[arg(long, value_name = "N", default_value_t = 2)]
+        fuzz: usize,
+
+        /// Don't record changes in the .newtext journal, so they can't be undone
+        #[arg(long = "no-journal")]
+        no_journal: bool,
+    },
+    /// Check the files about to be committed against the rules in the config
+    /// files, fixing them or exiting with status 1, for pre-commit
+    Hook {
+        /// The files to check, by default those staged in git
+        paths: Vec<PathBuf>,
+
+        /// Read the rules from FILE instead of the config files
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+    },
+    /// Revert the most recent run in the current directory, or the given run
+    Undo {
+        /// The ID of the run, as shown by `newtext history`
+        run: Option<String>,
+    },
+    /// Reapply the most recently undone run, or the given run
+    Redo {
+        /// The ID of the run, as shown by `newtext history`
+        run: Option<String>,
+    },
+    /// List the runs recorded in the current directory
+    History,
+    /// Print the man page, as roff
+    Man,
+    /// Answer requests to find and replace from editors and other tools, as
+    /// JSON-RPC over a unix socket, keeping track of the files in between
+    Serve {
+        /// The socket to listen on, by default .newtext/serve.sock
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// Leave out files and directories matching GLOB, a gitignore-style
+        /// glob relative to the current directory
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Don't leave out the files that .gitignore and .ignore files ignore
+        #[arg(long = "no-ignore")]
+        no_ignore: bool,
+    },
+}
+
+fn main() {
+    logging::init();
     let current_dir = match env::current_dir() {
         Ok(dir) => dir,
         Err(e) => {
-            eprintln!("Error getting current directory: {}", e);
-            std::process::exit(1);
+            error!("Could not get the current directory: {}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+    let config = match config::load(&current_dir) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+    let given: Vec<OsString> = env::args_os().collect();
+    let args = match config::with_defaults(given.clone(), &config.settings) {
+        Ok(args) => args,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
         }
     };
 
-    let mut files_processed = 0;
-    let mut files_modified = 0;
-    let mut directories_traversed = 0;
+    let cli = Cli::parse_from(&args);
+    logging::set_verbosity(cli.verbose);
+    if let Some(file) = &cli.log_file {
+        if let Err(e) = logging::set_file(file) {
+            error!("Could not open {}: {}", file.display(), e);
+            exit(EXIT_ERROR);
+        }
+    }
+    let command: Vec<_> = args
+        .iter()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy())
+        .collect();
+    debug!(
+        args = %command.join(" "),
+        "Running in {}",
+        current_dir.display()
+    );
+    if cli.print_config {
+        config::print(&given, &args, &config);
+        return;
+    }
+    match cli.command {
+        Some(command) => run_command(command),
+        None => run(cli.options, None),
+    }
+}
 
-    for result in WalkBuilder::new(&current_dir)
-        .hidden(false) // Don't automatically skip hidden files/dirs
-        .standard_filters(true) // Use standard VCS filters (ignores .git, etc)
-        .build()
-    {
-        let entry = match result {
-            Ok(entry) => entry,
-            Err(_) => continue,
-        };
+/// Replace throughout the files, or only record the changes in a plan saved
+/// to `plan_file`
+fn run(mut cli: Options, plan_file: Option<PathBuf>) {
+    let started = Instant::now();
+    shift_positionals(&mut cli);
+    if let Some(name) = cli.branch.as_deref().filter(|_| plan_file.is_none()) {
+        run_on_branch(&cli, name);
+    }
+    report::set_format(cli.report);
+    if let Some(rate) = cli.throttle {
+        throttle::set_rate(rate);
+    }
+    if let Some(limit) = cli.max_memory {
+        memory::set_limit(limit);
+    }
+    if cli.timings {
+        timings::enable();
+    }
+    // Before any threads are started, so they're low priority too
+    if cli.nice {
+        if let Err(e) = throttle::be_nice() {
+            warn!("Could not lower the priority: {}", e);
+        }
+    }
 
-        // Track directories
-        if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
-            directories_traversed += 1;
-            continue;
+    let mut replacement = match build_replacement(&cli) {
+        Ok(replacement) => replacement,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
         }
+    };
 
-        // Only process files
-        if !entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
-            continue;
+    let current_dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            error!("Could not get the current directory: {}", e);
+            exit(EXIT_ERROR);
         }
+    };
 
-        let path = entry.path();
+    if let Err(e) = filters::set(&current_dir, &cli.exclude, cli.no_ignore) {
+        error!("{}", e);
+        exit(EXIT_ERROR);
+    }
+    color::set(cli.color);
 
-        match process_file(path, &cli.old, &cli.new, regex.as_ref(), cli.ignore_case) {
-            Ok(true) => {
-                files_modified += 1;
-                files_processed += 1;
-            }
-            Ok(false) => {
-                files_processed += 1;
+    let filter = match cli.paths.as_slice() {
+        // Plans are always of files
+        [] => plan_file.is_none() && stdin_is_pipe(),
+        [path] => path.as_os_str() == "-",
+        paths => {
+            if paths.iter().any(|path| path.as_os_str() == "-") {
+                error!("- can't be combined with other paths");
+                exit(EXIT_ERROR);
             }
+            false
+        }
+    };
+    if plan_file.is_some() && (filter || cli.output.is_some()) {
+        error!("Plans can only be made of changes to files in place");
+        exit(EXIT_ERROR);
+    }
+    if cli.check && filter {
+        error!("newtext check only checks files, not stdin");
+        exit(EXIT_ERROR);
+    }
+    if cli.format.is_some() && filter {
+        error!("--format only prints changes to files, not to stdin");
+        exit(EXIT_ERROR);
+    }
+    if cli.patch.is_some() && filter {
+        error!("--patch only writes changes to files, not to stdin");
+        exit(EXIT_ERROR);
+    }
+    if cli.diff.is_some() && filter {
+        error!("--diff only prints changes to files, not to stdin");
+        exit(EXIT_ERROR);
+    }
+    if cli.watch && (filter || plan_file.is_some()) {
+        error!("--watch only watches files, and can't make a plan");
+        exit(EXIT_ERROR);
+    }
+    // Changes are planned before any are made for atomic runs, and for runs
+    // that only report on the changes, which leave everything as it is
+    let dry_run = plan_file.is_some()
+        || cli.check
+        || cli.check_idempotent
+        || cli.format.is_some()
+        || cli.patch.is_some()
+        || cli.diff.is_some();
+    let confirm = !cli.yes && !cli.resume && !filter && !dry_run && io::stdin().is_terminal();
+    let expects = cli.expect.is_some() || cli.expect_files.is_some();
+    let plan = (dry_run || cli.atomic || expects).then(|| Arc::new(Plan::new(&current_dir)));
+
+    let mut roots = if cli.paths.is_empty() {
+        vec![current_dir.clone()]
+    } else {
+        cli.paths
+            .iter()
+            .map(|path| current_dir.join(path))
+            .collect()
+    };
+    // Walking in sorted order lets a resumed run skip everything up to where
+    // it stopped
+    roots.sort();
+
+    // Held until the run exits
+    let _lock = (!filter && !dry_run).then(|| lock(&current_dir, !cli.no_wait));
+
+    let (journal, checkpoint) = if cli.resume && !dry_run {
+        let args = args().into_iter().filter(|arg| arg != "--resume").collect();
+        match Journal::resume(&current_dir, args) {
+            Ok((journal, checkpoint)) => (Some(Arc::new(journal)), Some(checkpoint)),
             Err(e) => {
-                eprintln!("Warning: Could not process {}: {}", path.display(), e);
+                error!("Could not resume: {}", e);
+                exit(EXIT_ERROR);
             }
         }
+    } else {
+        let journal = (!cli.no_journal && !filter && !dry_run)
+            .then(|| Arc::new(Journal::new(&current_dir, args())));
+        (journal, None)
+    };
 
-        // Print progress update (clear line and overwrite)
-        eprint!(
-            "\x1b[2K\rFiles: {}, Dirs: {}, Modified: {}",
-            files_processed, directories_traversed, files_modified
-        );
-    }
+    let output = cli.output.as_ref().map(|dir| {
+        if let Err(e) = fs::create_dir_all(dir) {
+            error!("Could not create {}: {}", dir.display(), e);
+            exit(EXIT_ERROR);
+        }
+        OutputDir {
+            source: current_dir.clone(),
+            dir: dir.clone(),
+        }
+    });
 
-    // Print newline after progress updates
-    eprintln!();
-}
+    replacement.write.output = output;
+    replacement.write.journal = journal;
+    replacement.write.plan = plan.clone();
+    // What a file needs also depends on the lines git shows as added, which
+    // can change when the file doesn't
+    let cache = cli.cache && !filter && cli.only_added.is_none();
+    replacement.cache = cache.then(|| Cache::load(&current_dir, &cache_key(&cli)));
 
-/// Apply the case pattern from the matched text to the replacement text
-fn apply_case_pattern(matched: &str, replacement: &str) -> String {
-    // If the matched text has no letters, just return the replacement as-is
-    if !matched.chars().any(|c| c.is_alphabetic()) {
-        return replacement.to_string();
+    if !cli.allow_recursive && !filter {
+        let problems = amplify::check(&replacement);
+        for problem in &problems {
+            warn!("{}", problem);
+        }
+        if !problems.is_empty() {
+            warn!(
+                "Replacements that match their own output can give surprising results and change files again on every run, pass --allow-recursive if this is intended"
+            );
+        }
     }
 
-    let matched_chars: Vec<char> = matched.chars().collect();
-
-    // Determine case pattern of matched text
-    let alphabetic_chars: Vec<char> = matched_chars
-        .iter()
-        .filter(|c| c.is_alphabetic())
-        .copied()
-        .collect();
+    let mut progress = checkpoint
+        .as_ref()
+        .map_or_else(Progress::default, Progress::from_json);
+    let resume_after = progress.last.clone();
+    // Atomic runs change nothing until the end, so there's nothing to resume
+    let checkpoints = replacement.write.journal.clone().filter(|_| plan.is_none());
+    let mut last_checkpoint = Instant::now();
+    let mut modified = Vec::new();
+    // The directories walked, for --watch to tell whether new files are in
+    // the walk
+    let mut walked_dirs = HashSet::new();
 
-    if alphabetic_chars.is_empty() {
-        return replacement.to_string();
-    }
-
-    let all_upper = alphabetic_chars.iter().all(|c| c.is_uppercase());
-    let all_lower = alphabetic_chars.iter().all(|c| c.is_lowercase());
-    let first_upper = alphabetic_chars[0].is_uppercase()
-        && alphabetic_chars[1..].iter().all(|c| c.is_lowercase());
-
-    if all_upper {
-        // All uppercase: BAR -> BAR
-        replacement.to_uppercase()
-    } else if all_lower {
-        // All lowercase: bar -> bar
-        replacement.to_lowercase()
-    } else if first_upper {
-        // Title case: Bar -> Bar
-        let mut result = String::new();
-        let mut first_letter = true;
-        for c in replacement.chars() {
-            if c.is_alphabetic() {
-                if first_letter {
-                    result.push_str(&c.to_uppercase().to_string());
-                    first_letter = false;
-                } else {
-                    result.push_str(&c.to_lowercase().to_string());
-                }
-            } else {
-                result.push(c);
+    if filter {
+        match filter_stdin(&replacement) {
+            Ok(false) if cli.fail_if_no_matches => exit(EXIT_NO_MATCHES),
+            Ok(_) => {}
+            Err(e) => {
+                error!("{}", e);
+                exit(EXIT_ERROR);
             }
         }
-        result
+        return;
+    }
+    if let Some(command) = cli.pre_hook.as_deref().filter(|_| !dry_run) {
+        if let Err(e) = hook::pre(command) {
+            error!("{}, so nothing was changed", e);
+            exit(EXIT_ERROR);
+        }
+    }
+    if cli.commit && !dry_run {
+        if let Err(e) = commit::check() {
+            error!(
+                "Could not commit the changes: {}, so nothing was changed",
+                e
+            );
+            exit(EXIT_ERROR);
+        }
+    }
+    interrupt::install();
+
+    // Renames are worked out before anything changes, so that collisions
+    // can stop the run while the tree is still untouched
+    let renames = if cli.rename {
+        plan_renames(&roots, &current_dir, &replacement)
     } else {
-        // Mixed case (including camelCase): try to preserve pattern character by character
-        let mut result = String::new();
-        let mut matched_alpha_iter = alphabetic_chars.iter();
-
-        for c in replacement.chars() {
-            if c.is_alphabetic() {
-                if let Some(&matched_c) = matched_alpha_iter.next() {
-                    if matched_c.is_uppercase() {
-                        result.push_str(&c.to_uppercase().to_string());
-                    } else {
-                        result.push_str(&c.to_lowercase().to_string());
+        Vec::new()
+    };
+    // Runs without a plan are confirmed after a first pass that only notes
+    // the paths that would change, so the run itself can still stream files
+    // and save checkpoints
+    if confirm && plan.is_none() {
+        let planned = plan_paths(&roots, &current_dir, &cli, &mut replacement);
+        for rename in &renames {
+            planned.record_rename(&rename.from, &rename.to);
+        }
+        if interrupt::is_interrupted() {
+            info!("Stopped before changing anything");
+            exit(interrupt::EXIT_STATUS);
+        }
+        if !confirmed(&planned, cli.confirm_over) {
+            info!("Nothing was changed");
+            exit(EXIT_DECLINED);
+        }
+    }
+
+    // Paths that couldn't be read count as failures, as files that can't be
+    // read do
+    let walk_failures = std::cell::Cell::new(0);
+    let entries = timings::time_iter(Phase::Walk, walk(&roots, cli.output.as_deref()))
+        .filter_map(|entry| {
+            entry
+                .map_err(|e| {
+                    if report::add_walk_error(None, &e) {
+                        walk_failures.set(walk_failures.get() + 1);
                     }
+                })
+                .ok()
+        })
+        .filter(|entry| {
+            resume_after
+                .as_deref()
+                .is_none_or(|last| entry.path() > last)
+        })
+        .take_while(|_| !interrupt::is_interrupted());
+    let process = |entry: &ignore::DirEntry| {
+        let is_symlink = entry.file_type().is_some_and(|ft| ft.is_symlink());
+        let is_file = entry.file_type().is_some_and(|ft| ft.is_file());
+        if entry.file_type().is_some_and(|ft| ft.is_dir()) {
+            Processed::Dir
+        } else if is_symlink && cli.symlink_targets {
+            Processed::File(process_symlink(entry.path(), &replacement))
+        } else if is_file {
+            let path = entry.path();
+            Processed::File(timings::time_file(path, || {
+                process_file(path, &replacement)
+            }))
+        } else {
+            // Only files are processed, and symlinks when their targets are
+            // rewritten
+            Processed::Skipped
+        }
+    };
+
+    let mut indicator =
+        progress::Indicator::new(&progress, (!cli.no_progress).then_some(cli.progress));
+    let (count_roots, count_output) = (roots.clone(), cli.output.clone());
+    let (count_after, symlink_targets) = (resume_after.clone(), cli.symlink_targets);
+    indicator.count(move || {
+        walk(&count_roots, count_output.as_deref())
+            .filter_map(Result::ok)
+            .filter(move |entry| {
+                count_after
+                    .as_deref()
+                    .is_none_or(|last| entry.path() > last)
+            })
+            .filter(move |entry| {
+                entry
+                    .file_type()
+                    .is_some_and(|ft| ft.is_file() || (ft.is_symlink() && symlink_targets))
+            })
+    });
+
+    let jobs = replacement.jobs;
+    let window = jobs * FILES_AHEAD_PER_JOB;
+    pool::run(jobs, window, entries, process, |entry, processed| {
+        let path = entry.path();
+        // Results come back in the order of the walk, so everything up to
+        // here is done
+        progress.last = Some(path.to_path_buf());
+        if let Some(journal) = &checkpoints {
+            if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                save_checkpoint(journal, &progress);
+                last_checkpoint = Instant::now();
+            }
+        }
+
+        let result = match processed {
+            Processed::Dir => {
+                if path != current_dir {
+                    trace!(path = %path.display(), "Walking {}", display_path(path, &current_dir));
+                }
+                progress.directories_traversed += 1;
+                if cli.watch {
+                    walked_dirs.insert(path.to_path_buf());
+                }
+                return true;
+            }
+            Processed::Skipped => {
+                trace!(path = %path.display(), "Skipped {}, as it isn't a file", display_path(path, &current_dir));
+                return true;
+            }
+            Processed::File(result) => result,
+        };
+        match result {
+            Ok(true) => {
+                debug!(path = %path.display(), "Replaced in {}", display_path(path, &current_dir));
+                progress.files_modified += 1;
+                progress.files_processed += 1;
+                if cli.post_hook.is_some() || cli.commit {
+                    modified.push(display_path(path, &current_dir));
+                }
+            }
+            Ok(false) => {
+                trace!(path = %path.display(), "Nothing to replace in {}", display_path(path, &current_dir));
+                progress.files_processed += 1;
+            }
+            Err(e) => {
+                let code = if write::is_write_error(&e) {
+                    Code::Write
                 } else {
-                    // If we run out of matched characters, keep the original case
-                    result.push(c);
+                    Code::Process
+                };
+                report::add(code, path, e);
+                progress.failures += 1;
+                if cli.strict {
+                    return false;
                 }
-            } else {
-                result.push(c);
             }
         }
-        result
-    }
-}
 
-fn process_file(
-    path: &Path,
-    old: &str,
-    new: &str,
-    regex: Option<&Regex>,
-    ignore_case: bool,
-) -> io::Result<bool> {
-    // Try to read the file as text
-    let content = match fs::read_to_string(path) {
-        Ok(c) => c,
-        Err(_) => {
-            // If we can't read it as text, it's probably binary, skip it
-            return Ok(false);
-        }
-    };
-
-    // Perform replacement based on mode
-    let new_content = if let Some(re) = regex {
-        // Regex mode (ignore_case is already handled in regex compilation)
-        if !re.is_match(&content) {
-            return Ok(false);
-        }
-        re.replace_all(&content, new).to_string()
-    } else if ignore_case {
-        // Literal mode with case-insensitive matching and case-preserving replacement
-        // Use regex for safe case-insensitive matching
-        let pattern = format!("(?i){}", regex::escape(old));
-        let re = match Regex::new(&pattern) {
-            Ok(r) => r,
-            Err(_) => return Ok(false),
-        };
+        indicator.update(&progress, || entry.metadata().map_or(0, |m| m.len()));
+        true
+    });
+    progress.failures += walk_failures.get();
+    let matches = replacement
+        .matches
+        .as_ref()
+        .map_or(0, |m| m.load(Ordering::Relaxed));
+    let expect_failed = cli.expect.is_some_and(|expected| matches != expected)
+        || cli
+            .expect_files
+            .is_some_and(|expected| progress.files_modified != expected);
+    // Planned changes aren't made if the run stops or finds what wasn't
+    // expected, so the files are only those that would have been modified
+    let stopped = interrupt::is_interrupted() || (cli.strict && progress.failures > 0);
+    indicator.finish(
+        &progress,
+        dry_run || (plan.is_some() && (stopped || expect_failed)),
+    );
 
-        if !re.is_match(&content) {
-            return Ok(false);
+    // What was found holds even if the run goes no further
+    if let Some(cache) = &replacement.cache {
+        if let Err(e) = cache.save(&current_dir) {
+            warn!("Could not save the cache: {}", e);
         }
+    }
+    timings::print(started.elapsed(), &current_dir);
 
-        // Replace all matches with case-preserved versions
-        let result = re.replace_all(&content, |caps: &regex::Captures| {
-            let matched = caps.get(0).unwrap().as_str();
-            apply_case_pattern(matched, new)
-        });
+    if interrupt::is_interrupted() {
+        if plan.is_some() {
+            info!("Stopped before changing anything");
+        } else {
+            info!("Stopped after modifying {} files", progress.files_modified);
+            if let Some(journal) = &replacement.write.journal {
+                save_checkpoint(journal, &progress);
+                if let Err(e) = journal.record_interrupted() {
+                    warn!("Could not update the journal: {}", e);
+                }
+                info!(
+                    "Run `newtext undo` to revert them, or the same command with --resume to carry on"
+                );
+            }
+        }
+        exit(interrupt::EXIT_STATUS);
+    }
 
-        result.to_string()
-    } else {
-        // Literal mode (case-sensitive)
-        if !content.contains(old) {
-            return Ok(false);
+    if cli.strict && progress.failures > 0 {
+        if plan.is_some() || progress.files_modified == 0 {
+            error!("Stopped at the first error, so nothing was changed");
+        } else {
+            error!(
+                "Stopped at the first error, after modifying {} files",
+                progress.files_modified
+            );
+            if let Some(journal) = &replacement.write.journal {
+                if let Err(e) = journal.finish() {
+                    warn!("Could not update the journal: {}", e);
+                }
+                info!("Run `newtext undo` to revert them");
+            }
         }
-        content.replace(old, new)
-    };
+        exit(EXIT_ERROR);
+    }
 
-    // Only write if content actually changed
-    if new_content == content {
-        return Ok(false);
+    if let Some(expected) = cli.expect {
+        if matches != expected {
+            error!(
+                "Expected {} matches but found {}, so nothing was changed",
+                expected, matches
+            );
+            exit(EXIT_ERROR);
+        }
+    }
+    if let Some(expected) = cli.expect_files {
+        if progress.files_modified != expected {
+            error!(
+                "Expected {} files to change but {} would, so nothing was changed",
+                expected, progress.files_modified
+            );
+            exit(EXIT_ERROR);
+        }
     }
 
-    // Write back to the file
-    fs::write(path, new_content)?;
+    let matched = progress.files_modified > 0 || !renames.is_empty();
+    if cli.post_hook.is_some() || cli.commit {
+        modified.extend(
+            renames
+                .iter()
+                .map(|rename| display_path(&rename.to, &current_dir)),
+        );
+    }
+    // The paths renamed files had, which are committed as removed
+    let renamed_from: Vec<String> = renames
+        .iter()
+        .filter(|_| cli.commit)
+        .map(|rename| display_path(&rename.from, &current_dir))
+        .collect();
+    let mut failures = progress.failures as usize;
+    match (&plan, &plan_file) {
+        (Some(plan), Some(plan_file)) => {
+            for rename in &renames {
+                plan.record_rename(&rename.from, &rename.to);
+            }
+            if let Err(e) = plan.save(plan_file, args(), &replacement.write) {
+                error!("Could not save {}: {}", plan_file.display(), e);
+                exit(EXIT_ERROR);
+            }
+            info!(
+                "Planned {}, saved to {}",
+                plural(plan.len(), "change"),
+                plan_file.display()
+            );
+        }
+        (Some(plan), None) if cli.format.is_some() => {
+            let changed = print_changes(plan, &current_dir, cli.format.unwrap());
+            if cli.check && changed > 0 {
+                info!("{} would be changed", plural(changed, "file"));
+                exit(EXIT_CHECK_FAILED);
+            }
+        }
+        (Some(plan), None) if cli.diff.is_some() => {
+            print_diff(plan, &current_dir, cli.diff.unwrap())
+        }
+        (Some(plan), None) if cli.patch.is_some() => {
+            let patch = cli.patch.as_ref().unwrap();
+            if let Err(e) = write_patch(plan, &current_dir, patch) {
+                error!("Could not write {}: {}", patch.display(), e);
+                exit(EXIT_ERROR);
+            }
+        }
+        (Some(plan), None) if cli.check => {
+            for rename in &renames {
+                plan.record_rename(&rename.from, &rename.to);
+            }
+            let changed = print_locations(plan, &current_dir, &replacement);
+            if changed > 0 {
+                info!("{} would be changed", plural(changed, "file"));
+                exit(EXIT_CHECK_FAILED);
+            }
+            info!("Nothing would be changed");
+        }
+        (Some(plan), None) if cli.check_idempotent => {
+            let unstable = check_idempotent(plan, &renames, &current_dir, &replacement);
+            if unstable > 0 {
+                info!("{} files would change again on a second run", unstable);
+                exit(EXIT_CHECK_FAILED);
+            }
+            info!("A second run would change nothing");
+        }
+        (Some(plan), None) => {
+            if cli.atomic && progress.failures > 0 {
+                error!(
+                    "{} files could not be processed, so nothing was changed",
+                    progress.failures
+                );
+                exit(EXIT_ERROR);
+            }
+            for rename in &renames {
+                plan.record_rename(&rename.from, &rename.to);
+            }
+            if confirm && !confirmed(plan, cli.confirm_over) {
+                info!("Nothing was changed");
+                exit(EXIT_DECLINED);
+            }
+
+            if cli.atomic {
+                if let Err(e) = plan.commit(&replacement.write) {
+                    error!("{}", e);
+                    error!("Nothing was changed");
+                    if interrupt::is_interrupted() {
+                        exit(interrupt::EXIT_STATUS);
+                    }
+                    exit(EXIT_ERROR);
+                }
+            } else {
+                let (applied, stale, failed) = plan.apply(&replacement.write);
+                failures += stale + failed;
+                if stale > 0 {
+                    info!(
+                        "Skipped {} changes to files that changed during the run",
+                        stale
+                    );
+                }
+                if interrupt::is_interrupted() {
+                    info!("Stopped after making {} changes", applied);
+                    if let Some(journal) = &replacement.write.journal {
+                        if let Err(e) = journal.record_interrupted() {
+                            warn!("Could not update the journal: {}", e);
+                        }
+                        info!("Run `newtext undo` to revert them");
+                    }
+                    exit(interrupt::EXIT_STATUS);
+                }
+            }
+        }
+        _ => {
+            let expected = renames.len();
+            failures += expected - rename::apply(renames, &current_dir, &replacement.write);
+        }
+    }
+
+    if let (Some(command), Some(journal)) = (&cli.validate, &replacement.write.journal) {
+        if matched && !hook::validate(command, journal) {
+            exit(EXIT_ERROR);
+        }
+    }
+
+    if let Some(journal) = &replacement.write.journal {
+        if let Err(e) = journal.finish() {
+            warn!("Could not update the journal: {}", e);
+        }
+    }
+
+    if let Some(command) = cli.post_hook.as_deref().filter(|_| !dry_run) {
+        if let Err(e) = hook::post(command, &modified) {
+            error!("{}", e);
+            failures += 1;
+        }
+    }
+
+    if cli.commit && !dry_run && matched {
+        if failures > 0 {
+            warn!("Nothing was committed, as {} changes failed", failures);
+        } else {
+            let message = cli.message.clone().unwrap_or_else(|| {
+                commit_message(&cli, progress.files_modified, renamed_from.len())
+            });
+            let paths: Vec<String> = modified.iter().chain(&renamed_from).cloned().collect();
+            match commit::commit(&paths, &message) {
+                Ok(hash) => info!("Committed the changes as {}", hash),
+                Err(e) => {
+                    error!("Could not commit the changes: {}", e);
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    report::print();
+    if cli.watch {
+        // Other runs can go between the batches of changes
+        drop(_lock);
+        let mut replacement = replacement;
+        replacement.write.plan = None;
+        watch_files(&roots, &current_dir, &cli, replacement, walked_dirs);
+        return;
+    }
+    if failures > 0 {
+        exit(EXIT_ERROR);
+    }
+    if !matched && cli.fail_if_no_matches {
+        exit(EXIT_NO_MATCHES);
+    }
+}
+
+/// The message --commit uses without --message, naming the replacement and
+/// how much it changed
+fn commit_message(cli: &Options, files: u64, renamed: usize) -> String {
+    let replaced = match (&cli.old, &cli.new) {
+        (Some(old), Some(new)) if !old.is_empty() => format!("Replace {} → {}", old, new),
+        _ if !cli.fix.is_empty() => {
+            let fixes: Vec<String> = cli.fix.iter().map(Fix::to_string).collect();
+            format!("Make the fixes {}", fixes.join(", "))
+        }
+        _ => "Replace with newtext".to_string(),
+    };
+    let files_changed = match files {
+        1 => "1 file".to_string(),
+        files => format!("{} files", files),
+    };
+    let paths_renamed = match renamed {
+        1 => "the name of 1 path".to_string(),
+        renamed => format!("the names of {} paths", renamed),
+    };
+    match (files, renamed) {
+        (_, 0) => format!("{} in {}", replaced, files_changed),
+        (0, _) => format!("{} in {}", replaced, paths_renamed),
+        _ => format!("{} in {} and {}", replaced, files_changed, paths_renamed),
+    }
+}
+
+/// Make the run's changes on the new branch `name` instead, by running
+/// newtext again in a worktree for it, and exit as that run does
+fn run_on_branch(cli: &Options, name: &str) -> ! {
+    let worktree = match branch::Worktree::create(name) {
+        Ok(worktree) => worktree,
+        Err(e) => {
+            error!(
+                "Could not make the branch {}: {}, so nothing was changed",
+                name, e
+            );
+            exit(EXIT_ERROR);
+        }
+    };
+    let dir = env::current_dir()
+        .ok()
+        .and_then(|dir| worktree.path_for(&dir))
+        .unwrap_or_else(|| worktree.path.clone());
+
+    // The same arguments, but for --branch, and with paths into the tree
+    // made paths into the worktree
+    let moved: HashMap<String, PathBuf> = cli
+        .paths
+        .iter()
+        .filter(|path| path.is_absolute())
+        .filter_map(|path| {
+            Some((
+                path.to_string_lossy().into_owned(),
+                worktree.path_for(path)?,
+            ))
+        })
+        .collect();
+    let mut branch_args = Vec::new();
+    let mut given = args().into_iter().skip(1);
+    while let Some(arg) = given.next() {
+        if arg == "--branch" {
+            given.next();
+        } else if !arg.starts_with("--branch=") {
+            branch_args.push(
+                moved
+                    .get(&arg)
+                    .map_or(arg, |path| path.display().to_string()),
+            );
+        }
+    }
+    if !cli.commit {
+        branch_args.insert(0, "--commit".to_string());
+    }
+
+    debug!("Running in the worktree {}", worktree.path.display());
+    let status = env::current_exe().and_then(|exe| {
+        std::process::Command::new(exe)
+            .args(&branch_args)
+            .current_dir(&dir)
+            .status()
+    });
+    let path = worktree.path.clone();
+    match worktree.finish() {
+        Ok(true) => info!("Committed the changes to the branch {}", name),
+        Ok(false) => info!("Nothing was committed, so the branch {} was removed", name),
+        Err(e) => warn!("Could not remove the worktree {}: {}", path.display(), e),
+    }
+    match status {
+        Ok(status) => exit(status.code().unwrap_or(EXIT_ERROR)),
+        Err(e) => {
+            error!("Could not run newtext in the worktree: {}", e);
+            exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// The replacement `cli` asks for, without the output directory, journal,
+/// plan or cache, which depend on the run
+fn build_replacement(cli: &Options) -> Result<Replacement, String> {
+    Replacement::new(&newtext::Options {
+        old: cli.old.clone().unwrap_or_default(),
+        new: cli.new.clone().unwrap_or_default(),
+        pattern: cli.pattern,
+        ignore_case: cli.ignore_case,
+        script: cli.script.clone(),
+        map: cli.map.clone(),
+        plugin: cli.plugin.clone(),
+        scope: scope(cli),
+        only_added: cli
+            .only_added
+            .as_deref()
+            .map(AddedLines::since)
+            .transpose()?,
+        map_missing: cli.map_missing,
+        reverse: cli.reverse,
+        sed: cli.sed.clone(),
+        sed_file: cli.sed_file.clone(),
+        sed_extended: cli.sed_extended,
+        fixes: cli.fix.clone(),
+        eol: cli.eol,
+        final_newline: cli.final_newline,
+        strip_bom: cli.strip_bom,
+        encoding: cli.encoding,
+        detect_encoding: cli.detect_encoding,
+        binary: cli.binary,
+        archives: cli.archives,
+        compressed: cli.compressed,
+        office: cli.office,
+        preserve: cli
+            .preserve
+            .iter()
+            .copied()
+            .chain(cli.preserve_mtime.then_some(Attribute::Times))
+            .filter(|attribute| !cli.no_preserve.contains(attribute))
+            .collect(),
+        // Ownership and extended attributes are kept where they can be,
+        // unless --preserve asks for them outright
+        preserve_if_possible: [Attribute::Ownership, Attribute::Xattr]
+            .into_iter()
+            .filter(|attribute| {
+                !cli.preserve.contains(attribute) && !cli.no_preserve.contains(attribute)
+            })
+            .collect(),
+        fsync: cli.fsync,
+        format_cmd: cli.format_cmd.clone(),
+        count_matches: cli.expect.is_some(),
+        jobs: cli.jobs,
+        stream_threshold: Some(cli.max_memory.map_or(cli.stream_threshold, |limit| {
+            limit.min(cli.stream_threshold)
+        })),
+    })
+}
+
+/// What the replacements are limited to, if anything
+fn scope(cli: &Options) -> Option<Scope> {
+    if !cli.json_path.is_empty() {
+        Some(Scope::JsonPath(cli.json_path.clone()))
+    } else if !cli.yaml_path.is_empty() {
+        Some(Scope::YamlPath(cli.yaml_path.clone()))
+    } else if !cli.toml_key.is_empty() {
+        Some(Scope::TomlKey(cli.toml_key.clone()))
+    } else if let Some(attributes) = &cli.xml_text {
+        Some(Scope::Markup(vec![selector::text_nodes(attributes)]))
+    } else if !cli.selector.is_empty() || !cli.xpath.is_empty() {
+        Some(Scope::Markup(
+            cli.selector.iter().chain(&cli.xpath).cloned().collect(),
+        ))
+    } else if !cli.csv_column.is_empty() {
+        Some(Scope::CsvColumn(Csv {
+            columns: cli.csv_column.clone(),
+            delimiter: cli.csv_delimiter,
+            quote: cli.csv_quote,
+            header: !cli.csv_no_header,
+        }))
+    } else if cli.front_matter {
+        Some(Scope::FrontMatter)
+    } else if !cli.key.is_empty() {
+        Some(Scope::Key(Keys {
+            names: cli.key.clone(),
+            ensure: cli.ensure.then(|| cli.new.clone().unwrap_or_default()),
+        }))
+    } else if let Some(part) = cli.po {
+        Some(Scope::Po(part))
+    } else if let Some(part) = cli.markdown.filter(|part| *part != markdown::Part::All) {
+        Some(Scope::Markdown(part))
+    } else {
+        cli.notebook_cells
+            .filter(|cells| *cells != Cells::All)
+            .map(Scope::Notebook)
+    }
+}
+
+/// Keep the replacement applied to the files under `roots` as they're created
+/// or modified, until interrupted
+///
+/// Each batch of changes is a run of its own in the journal, so it can be
+/// undone.
+fn watch_files(
+    roots: &[PathBuf],
+    current_dir: &Path,
+    cli: &Options,
+    mut replacement: Replacement,
+    mut walked_dirs: HashSet<PathBuf>,
+) {
+    info!("Watching for changes, press Ctrl-C to stop");
+    // The size and modification time of each file as it was rewritten, to
+    // tell the changes made here from others
+    let mut rewritten = HashMap::new();
+    let result = watch::watch(roots, |paths| {
+        let _lock = lock(current_dir, true);
+        let journal = (!cli.no_journal).then(|| Arc::new(Journal::new(current_dir, args())));
+        replacement.write.journal = journal.clone();
+
+        let mut modified = Vec::new();
+        for path in paths {
+            let stamp = match file_stamp(&path) {
+                Some(stamp) if rewritten.get(&path) != Some(&stamp) => stamp,
+                _ => continue,
+            };
+            if !is_walked(&path, roots, cli.output.as_deref(), &mut walked_dirs) {
+                continue;
+            }
+            match process_file(&path, &replacement) {
+                Ok(true) => {
+                    info!("Replaced in {}", display_path(&path, current_dir));
+                    modified.push(display_path(&path, current_dir));
+                    rewritten.insert(path.clone(), file_stamp(&path).unwrap_or(stamp));
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    let code = if write::is_write_error(&e) {
+                        Code::Write
+                    } else {
+                        Code::Process
+                    };
+                    report::add(code, &path, e);
+                }
+            }
+        }
+
+        if let Some(journal) = &journal {
+            if let Err(e) = journal.finish() {
+                warn!("Could not update the journal: {}", e);
+            }
+        }
+        if let Some(command) = cli.post_hook.as_deref().filter(|_| !modified.is_empty()) {
+            if let Err(e) = hook::post(command, &modified) {
+                error!("{}", e);
+            }
+        }
+        report::print();
+    });
+    if let Err(e) = result {
+        error!("Could not watch for changes: {}", e);
+        exit(EXIT_ERROR);
+    }
+    info!("Stopped watching");
+}
+
+/// The size and modification time of the file at `path`, or None if it isn't
+/// a file
+fn file_stamp(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// Apply the replacement to the planned content of each file and the new
+/// name of each renamed path, printing those it would change again. Returns
+/// how many there are.
+fn check_idempotent(
+    plan: &Plan,
+    renames: &[rename::Rename],
+    root: &Path,
+    replacement: &Replacement,
+) -> usize {
+    let mut stdout = io::stdout().lock();
+    let mut unstable = 0;
+    // Paths are shown as --check shows them, relative to the current
+    // directory
+    let mut report = |path: &Path| {
+        unstable += 1;
+        let _ = writeln!(stdout, "{}", color::path(&display_path(path, root)));
+    };
+
+    for (path, content) in plan.writes() {
+        let changed = match rewrite_input(&content, &path, replacement) {
+            Ok(new_content) => new_content.is_some_and(|new_content| new_content != content),
+            Err(e) => {
+                report::add(Code::Check, &path, e);
+                false
+            }
+        };
+        if changed {
+            report(&path);
+        }
+    }
+
+    for rename in renames {
+        if let Ok(Some(_)) = rename::renamed(&rename.to, replacement) {
+            report(&rename.from);
+        }
+    }
+
+    unstable
+}
+
+/// Print each line that the plan would change, as `path:line: text`, or just
+/// the path if the change isn't to lines of text. Returns how many paths would
+/// be changed.
+fn print_locations(plan: &Plan, root: &Path, replacement: &Replacement) -> usize {
+    let mut stdout = io::stdout().lock();
+    let mut paths = plan.paths();
+    // A file can be both rewritten and renamed
+    let mut seen = HashSet::new();
+    paths.retain(|path| seen.insert(path.clone()));
+
+    for path in &paths {
+        let content = fs::read(root.join(path)).unwrap_or_default();
+        let content = String::from_utf8_lossy(&content);
+        let lines = matching_lines(&content, &root.join(path), replacement);
+        if lines.is_empty() {
+            let _ = writeln!(stdout, "{}", color::path(path));
+        }
+        for (number, text) in lines {
+            let _ = writeln!(stdout, "{} {}", color::location(path, number), text);
+        }
+    }
+    paths.len()
+}
+
+/// The files a plan writes, with their content before and after, in order
+/// of their paths
+///
+/// Files that aren't UTF-8 are left out, as their changes can't be given as
+/// text.
+fn text_changes(plan: &Plan) -> Vec<(PathBuf, String, String)> {
+    let mut files = Vec::new();
+    let mut writes = plan.writes();
+    writes.sort();
+    for (path, new) in writes {
+        let old = fs::read(&path).map_err(|e| e.to_string());
+        let texts = old.and_then(|old| {
+            let old = String::from_utf8(old).map_err(|_| "it isn't UTF-8".to_string())?;
+            let new = String::from_utf8(new).map_err(|_| "it isn't UTF-8".to_string())?;
+            Ok((old, new))
+        });
+        match texts {
+            Ok((old, new)) => files.push((path, old, new)),
+            Err(e) => report::add(
+                Code::Encoding,
+                &path,
+                format!("Its changes were left out: {}", e),
+            ),
+        }
+    }
+    files
+}
+
+/// Print the changes in a plan in `format`, returning how many files they're
+/// to
+fn print_changes(plan: &Plan, root: &Path, format: edits::Format) -> usize {
+    let files = text_changes(plan);
+    let printed = match format {
+        edits::Format::Lsp => {
+            let files: Vec<_> = files
+                .iter()
+                .map(|(path, old, new)| (path.as_path(), old.as_str(), new.as_str()))
+                .collect();
+            lsp::workspace_edit(&files)
+        }
+        edits::Format::Rdjson => {
+            let paths: Vec<_> = files
+                .iter()
+                .map(|(path, _, _)| display_path(path, root).replace('\\', "/"))
+                .collect();
+            let files: Vec<_> = files
+                .iter()
+                .zip(&paths)
+                .map(|((_, old, new), path)| (path.as_str(), old.as_str(), new.as_str()))
+                .collect();
+            rdjson::diagnostics(&files)
+        }
+    };
+    println!("{}", printed);
+    report::print();
+    files.len()
+}
+
+/// Print a diff of the changes in a plan, in `style`
+fn print_diff(plan: &Plan, root: &Path, style: diff::Style) {
+    let mut stdout = io::stdout().lock();
+    for (path, old, new) in text_changes(plan) {
+        let path = display_path(&path, root);
+        let _ = write!(stdout, "{}", diff::diff(style, &path, &old, &new));
+    }
+    report::print();
+}
+
+/// Write the changes in a plan to `to` as a patch, with paths relative to
+/// `root`, or to stdout if `to` is -
+fn write_patch(plan: &Plan, root: &Path, to: &Path) -> io::Result<()> {
+    let files = text_changes(plan);
+    let mut patch = String::new();
+    for (path, old, new) in &files {
+        let path = display_path(path, root).replace('\\', "/");
+        patch.push_str(&patch::diff(&path, old, new));
+    }
+    if to.as_os_str() == "-" {
+        io::stdout().write_all(patch.as_bytes())?;
+    } else {
+        fs::write(to, patch)?;
+        info!(
+            "Wrote the changes to {} files to {}",
+            files.len(),
+            to.display()
+        );
+    }
+    report::print();
+    Ok(())
+}
+
+/// Print the lines that OLD matches in the files under `paths`, or in stdin,
+/// exiting with EXIT_NO_MATCHES if there are none
+fn find(old: String, paths: Vec<PathBuf>, pattern: bool, ignore_case: bool, jobs: Option<usize>) {
+    let replacement = newtext::Options {
+        old,
+        pattern,
+        ignore_case,
+        jobs,
+        ..newtext::Options::default()
+    };
+    let replacement = match Replacement::new(&replacement) {
+        Ok(replacement) => replacement,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+    let current_dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            error!("Could not get the current directory: {}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+
+    let filter = match paths.as_slice() {
+        [] => stdin_is_pipe(),
+        [path] => path.as_os_str() == "-",
+        paths => {
+            if paths.iter().any(|path| path.as_os_str() == "-") {
+                error!("- can't be combined with other paths");
+                exit(EXIT_ERROR);
+            }
+            false
+        }
+    };
+    let found = if filter {
+        let mut input = Vec::new();
+        if let Err(e) = io::stdin().lock().read_to_end(&mut input) {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+        let content = String::from_utf8_lossy(&input);
+        let lines = matching_lines(&content, Path::new("-"), &replacement);
+        let mut stdout = io::stdout().lock();
+        for (number, text) in &lines {
+            let _ = writeln!(stdout, "{}: {}", number, text);
+        }
+        !lines.is_empty()
+    } else {
+        let roots = if paths.is_empty() {
+            vec![current_dir.clone()]
+        } else {
+            paths.iter().map(|path| current_dir.join(path)).collect()
+        };
+        let found = print_matches(&roots, &current_dir, &replacement);
+        report::print();
+        found
+    };
+    if !found {
+        exit(EXIT_NO_MATCHES);
+    }
+}
+
+/// Replace OLD with NEW in the sample text, from `sample` or stdin, and
+/// print the result with what changed marked
+fn try_out(old: String, new: String, sample: Option<PathBuf>, pattern: bool, ignore_case: bool) {
+    let replacement = newtext::Options {
+        old,
+        new,
+        pattern,
+        ignore_case,
+        count_matches: true,
+        ..newtext::Options::default()
+    };
+    let replacement = match Replacement::new(&replacement) {
+        Ok(replacement) => replacement,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+    let input = match &sample {
+        Some(sample) => {
+            fs::read(sample).map_err(|e| format!("Could not read {}: {}", sample.display(), e))
+        }
+        None => {
+            let mut input = Vec::new();
+            io::stdin()
+                .lock()
+                .read_to_end(&mut input)
+                .map(|_| input)
+                .map_err(|e| format!("Could not read stdin: {}", e))
+        }
+    };
+    let input = match input {
+        Ok(input) => input,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+
+    let output = match rewrite_input(&input, Path::new("-"), &replacement) {
+        Ok(output) => output.unwrap_or_else(|| input.clone()),
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+    let shown = diff::highlighted(
+        &String::from_utf8_lossy(&input),
+        &String::from_utf8_lossy(&output),
+    );
+    let _ = io::stdout().lock().write_all(shown.as_bytes());
+
+    let matches = replacement
+        .matches
+        .as_ref()
+        .map_or(0, |matches| matches.load(Ordering::Relaxed));
+    let counted = format!(
+        "{} {}",
+        matches,
+        if matches == 1 { "match" } else { "matches" }
+    );
+    if matches == 0 {
+        info!("Nothing matched");
+        exit(EXIT_NO_MATCHES);
+    } else if output == input {
+        info!("Found {}, but replacing them changes nothing", counted);
+    } else {
+        info!("Replaced {}", counted);
+    }
+}
+
+/// Print what each part of `pattern` matches, and warn about its likely
+/// mistakes
+fn explain(pattern: &str, ignore_case: bool) {
+    let explanation = match explain::explain(pattern, ignore_case) {
+        Ok(explanation) => explanation,
+        Err(e) => {
+            error!("Invalid regex pattern: {}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+    // The descriptions line up, unless a part is too long for them to
+    let shown = |part: &explain::Part| 2 * part.depth + part.source.chars().count();
+    let width = explanation
+        .parts
+        .iter()
+        .map(shown)
+        .filter(|&width| width <= 24)
+        .max()
+        .unwrap_or(0);
+    let mut stdout = io::stdout().lock();
+    for part in &explanation.parts {
+        let _ = writeln!(
+            stdout,
+            "{}{}{}  {}",
+            "  ".repeat(part.depth),
+            part.source,
+            " ".repeat(width.saturating_sub(shown(part))),
+            part.description
+        );
+    }
+    for warning in &explanation.warnings {
+        warn!("{}", warning);
+    }
+}
+
+/// Print the matching lines in the files under `roots`, returning whether
+/// there were any
+fn print_matches(roots: &[PathBuf], root: &Path, replacement: &Replacement) -> bool {
+    let files = walk(roots, None)
+        .filter_map(|entry| entry.map_err(|e| report::add_walk_error(None, &e)).ok())
+        .filter(|entry| entry.file_type().is_some_and(|ft| ft.is_file()));
+    let mut stdout = io::stdout().lock();
+    let mut found = false;
+    pool::run(
+        replacement.jobs,
+        replacement.jobs * FILES_AHEAD_PER_JOB,
+        files,
+        |entry| {
+            // Binary files are left out, as they are when replacing
+            let contents = Contents::read_unless_binary(entry.path())?;
+            let content = contents.as_deref().map(String::from_utf8_lossy);
+            let lines = content.map_or_else(Vec::new, |content| {
+                matching_lines(&content, entry.path(), replacement)
+                    .into_iter()
+                    .map(|(number, text)| (number, text.to_string()))
+                    .collect()
+            });
+            io::Result::Ok(lines)
+        },
+        |entry, result| {
+            match result {
+                Ok(lines) => {
+                    let path = display_path(entry.path(), root);
+                    for (number, text) in &lines {
+                        let _ = writeln!(stdout, "{} {}", color::location(&path, *number), text);
+                    }
+                    found |= !lines.is_empty();
+                }
+                Err(e) => report::add(Code::Read, entry.path(), e),
+            }
+            true
+        },
+    );
+    found
+}
+
+/// How many of the paths to be changed are listed when asking for confirmation
+const CONFIRM_SAMPLE: usize = 10;
+
+/// The paths that processing the files under `roots` would change, found
+/// without changing them. Problems are left for the run itself to report.
+fn plan_paths(
+    roots: &[PathBuf],
+    root: &Path,
+    cli: &Options,
+    replacement: &mut Replacement,
+) -> Plan {
+    let plan = Arc::new(Plan::paths_only(root));
+    replacement.write.plan = Some(Arc::clone(&plan));
+    replacement.write.problems = Some(Arc::default());
+    let entries = walk(roots, cli.output.as_deref())
+        .flatten()
+        .take_while(|_| !interrupt::is_interrupted());
+    let jobs = replacement.jobs;
+    pool::run(
+        jobs,
+        jobs * FILES_AHEAD_PER_JOB,
+        entries,
+        |entry| match entry.file_type() {
+            Some(ft) if ft.is_symlink() && cli.symlink_targets => {
+                let _ = process_symlink(entry.path(), replacement);
+            }
+            Some(ft) if ft.is_file() => {
+                let _ = process_file(entry.path(), replacement);
+            }
+            _ => {}
+        },
+        |_, ()| true,
+    );
+    replacement.write.plan = None;
+    replacement.write.problems = None;
+    Arc::try_unwrap(plan).expect("the pass is done with the plan")
+}
+
+/// Whether the planned changes can go ahead, asking first if they're to more
+/// than `threshold` files
+fn confirmed(plan: &Plan, threshold: usize) -> bool {
+    let paths = plan.paths();
+    if paths.len() <= threshold {
+        return true;
+    }
+
+    eprintln!("This will change {} files, including:", paths.len());
+    for path in paths.iter().take(CONFIRM_SAMPLE) {
+        eprintln!("  {}", path);
+    }
+    if paths.len() > CONFIRM_SAMPLE {
+        eprintln!("  and {} more", paths.len() - CONFIRM_SAMPLE);
+    }
+    eprint!("Continue? [y/N] ");
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// What processing an entry of the walk came to
+enum Processed {
+    Dir,
+    /// Neither a file nor a symlink to be processed
+    Skipped,
+    /// Whether the file was modified
+    File(io::Result<bool>),
+}
+
+/// How often an unfinished run saves how far it has got
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+fn save_checkpoint(journal: &Journal, progress: &Progress) {
+    if let Err(e) = journal.checkpoint(&progress.to_json()) {
+        warn!("Could not save a checkpoint: {}", e);
+    }
+}
+
+/// Report the problems found so far, then exit with `status`
+fn exit(status: i32) -> ! {
+    report::print();
+    std::process::exit(status)
+}
+
+/// Lock `root` against other runs, exiting if it can't be locked
+fn lock(root: &Path, wait: bool) -> fs::File {
+    match journal::lock(root, wait) {
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Everything that decides whether a file needs changing, so that a cache is
+/// only shared by runs that would find the same
+fn cache_key(cli: &Options) -> String {
+    let stamp = |path: &Option<PathBuf>| path.as_deref().and_then(journal::stamp);
+    let debug = |value: &dyn std::fmt::Debug| format!("{:?}", value);
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "old": cli.old,
+        "new": cli.new,
+        "pattern": cli.pattern,
+        "ignore_case": cli.ignore_case,
+        "script": stamp(&cli.script),
+        "map": stamp(&cli.map),
+        "plugin": cli.plugin,
+        "map_missing": debug(&cli.map_missing),
+        "reverse": cli.reverse,
+        "sed": cli.sed,
+        "sed_file": stamp(&cli.sed_file),
+        "sed_extended": cli.sed_extended,
+        "fix": debug(&cli.fix),
+        "eol": debug(&cli.eol),
+        "final_newline": cli.final_newline,
+        "strip_bom": cli.strip_bom,
+        "encoding": debug(&cli.encoding),
+        "detect_encoding": cli.detect_encoding,
+        "binary": cli.binary,
+        "archives": cli.archives,
+        "compressed": cli.compressed,
+        "office": cli.office,
+        "scope": debug(&scope(cli)),
+    })
+    .to_string()
+}
+
+/// Make each replacement in `recipe` with a run of its own, or list the
+/// recipes if none is given
+///
+/// Each run is a newtext process of its own, so it's a run of its own in the
+/// journal. Runs that find changes with --check still let the rest go, but
+/// one that fails stops the recipe.
+fn run_recipe(current_dir: &Path, recipe: Option<String>, options: Vec<OsString>) {
+    let config = match config::load(current_dir) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+    let Some(name) = recipe else {
+        for (name, description) in config.recipes() {
+            match description {
+                Some(description) => println!("{}  {}", name, description),
+                None => println!("{}", name),
+            }
+        }
+        return;
+    };
+    let recipe = match config.recipe(&name) {
+        Ok(recipe) => recipe,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            error!("Could not find newtext itself: {}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+
+    if let Some(description) = &recipe.description {
+        info!("{}", description);
+    }
+    let mut status = 0;
+    for (i, step) in recipe.steps.iter().enumerate() {
+        // The options go before the -- that ends the step's own
+        let split = step
+            .iter()
+            .position(|arg| arg == "--")
+            .unwrap_or(step.len());
+        let args: Vec<&OsString> = step[..split]
+            .iter()
+            .chain(&options)
+            .chain(&step[split..])
+            .collect();
+        info!(
+            "Step {} of {}: newtext replace {}",
+            i + 1,
+            recipe.steps.len(),
+            args.iter()
+                .filter(|arg| **arg != "--")
+                .map(|arg| arg.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        let code = match std::process::Command::new(&exe)
+            .arg("replace")
+            .args(args)
+            .status()
+        {
+            Ok(exit_status) => exit_status.code().unwrap_or(interrupt::EXIT_STATUS),
+            Err(e) => {
+                error!("Could not run newtext: {}", e);
+                exit(EXIT_ERROR);
+            }
+        };
+        match code {
+            0 => {}
+            EXIT_NO_MATCHES => status = EXIT_NO_MATCHES,
+            code => exit(code),
+        }
+    }
+    if status != 0 {
+        exit(status);
+    }
+}
+
+/// The command line, for recording in the journal
+fn args() -> Vec<String> {
+    env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+fn run_command(command: Command) {
+    let current_dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            error!("Could not get the current directory: {}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+
+    let _lock = match command {
+        // A server locks the directory for each change it makes
+        // Runs take the lock themselves, unless they only look at the files
+        Command::Replace { .. }
+        | Command::Find { .. }
+        | Command::Try { .. }
+        | Command::Explain { .. }
+        | Command::Check { .. }
+        | Command::History
+        | Command::Man
+        | Command::Run { .. }
+        | Command::Plan { .. }
+        | Command::Serve { .. } => None,
+        _ => Some(lock(&current_dir, true)),
+    };
+    match command {
+        Command::Replace { options } => run(*options, None),
+        Command::Find {
+            old,
+            paths,
+            pattern,
+            ignore_case,
+            jobs,
+            exclude,
+            no_ignore,
+            color,
+        } => {
+            if let Err(e) = filters::set(&current_dir, &exclude, no_ignore) {
+                error!("{}", e);
+                exit(EXIT_ERROR);
+            }
+            color::set(color);
+            find(old, paths, pattern, ignore_case, jobs)
+        }
+        Command::Try {
+            old,
+            new,
+            sample,
+            pattern,
+            ignore_case,
+            color,
+        } => {
+            color::set(color);
+            try_out(old, new, sample, pattern, ignore_case)
+        }
+        Command::Explain {
+            pattern,
+            ignore_case,
+        } => explain(&pattern, ignore_case),
+        Command::Check {
+            mut options,
+            idempotent,
+        } => {
+            options.check = !idempotent;
+            options.check_idempotent = idempotent;
+            run(*options, None)
+        }
+        Command::Undo { run } => {
+            if let Err(e) = journal::undo(&current_dir, run.as_deref()) {
+                error!("Could not undo: {}", e);
+                exit(EXIT_ERROR);
+            }
+        }
+        Command::Redo { run } => {
+            if let Err(e) = journal::redo(&current_dir, run.as_deref()) {
+                error!("Could not redo: {}", e);
+                exit(EXIT_ERROR);
+            }
+        }
+        Command::History => journal::print_history(&current_dir),
+        Command::Run { recipe, options } => run_recipe(&current_dir, recipe, options),
+        Command::Hook { paths, config } => precommit::run(&current_dir, paths, config),
+        Command::Man => {
+            if let Err(e) = man::render(&mut io::stdout().lock()) {
+                error!("Could not print the man page: {}", e);
+                exit(EXIT_ERROR);
+            }
+        }
+        Command::Plan { options, plan } => run(*options, Some(plan)),
+        #[cfg(unix)]
+        Command::Serve {
+            socket,
+            exclude,
+            no_ignore,
+        } => {
+            if let Err(e) = filters::set(&current_dir, &exclude, no_ignore) {
+                error!("{}", e);
+                exit(EXIT_ERROR);
+            }
+            let socket = match socket {
+                Some(socket) => socket,
+                None => match journal::create_journal_dir(&current_dir) {
+                    Ok(dir) => dir.join(serve::SOCKET),
+                    Err(e) => {
+                        error!("Could not create the journal directory: {}", e);
+                        exit(EXIT_ERROR);
+                    }
+                },
+            };
+            if let Err(e) = serve::serve(&current_dir, &socket) {
+                error!("{}", e);
+                exit(EXIT_ERROR);
+            }
+        }
+        #[cfg(not(unix))]
+        Command::Serve { .. } => {
+            error!("newtext serve needs unix sockets, which aren't available here");
+            exit(EXIT_ERROR);
+        }
+        Command::Apply { plan, no_journal } => {
+            let journal = (!no_journal).then(|| Arc::new(Journal::new(&current_dir, args())));
+            interrupt::install();
+            match plan::apply(&current_dir, &plan, journal) {
+                Ok(_) if interrupt::is_interrupted() => exit(interrupt::EXIT_STATUS),
+                Ok(0) => report::print(),
+                // Some of the plan wasn't applied
+                Ok(_) => exit(EXIT_ERROR),
+                Err(e) => {
+                    error!("Could not apply {}: {}", plan.display(), e);
+                    exit(EXIT_ERROR);
+                }
+            }
+        }
+        Command::ApplyPatch {
+            patch,
+            fuzz,
+            no_journal,
+        } => {
+            let journal = (!no_journal).then(|| Arc::new(Journal::new(&current_dir, args())));
+            interrupt::install();
+            match patch::apply(&current_dir, &patch, fuzz, journal) {
+                Ok(_) if interrupt::is_interrupted() => exit(interrupt::EXIT_STATUS),
+                Ok(0) => report::print(),
+                // Some files weren't patched
+                Ok(_) => {
+                    report::print();
+                    exit(EXIT_ERROR);
+                }
+                Err(e) => {
+                    error!("Could not apply {}: {}", patch.display(), e);
+                    exit(EXIT_ERROR);
+                }
+            }
+        }
+    }
+}
+
+/// Move positional arguments that clap took as OLD or NEW into the paths
+/// when the replacement comes from somewhere else
+///
+/// sed scripts stand in for both OLD and NEW, and scripts, maps and plugins
+/// for NEW. Fixes are made instead of a replacement when nothing gives NEW,
+/// so a single positional argument is a path.
+fn shift_positionals(cli: &mut Options) {
+    let mut shifted = Vec::new();
+    let computed = cli.script.is_some() || cli.map.is_some() || cli.plugin.is_some();
+    let only_fixes = !cli.fix.is_empty() && cli.new.is_none() && !computed;
+    let instead = cli.sed.is_some() || cli.sed_file.is_some() || only_fixes;
+    if instead {
+        shifted.extend(cli.old.take().map(PathBuf::from));
+    }
+    if instead || computed {
+        shifted.extend(cli.new.take().map(PathBuf::from));
+    }
+    shifted.append(&mut cli.paths);
+    cli.paths = shifted;
+}
+
+/// Whether stdin is a pipe, so newtext should act as a filter when given no
+/// paths
+fn stdin_is_pipe() -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+        use std::os::unix::fs::FileTypeExt;
+
+        // Only pipes count, so redirects from /dev/null as in cron jobs still
+        // process the current directory
+        io::stdin()
+            .as_fd()
+            .try_clone_to_owned()
+            .map(fs::File::from)
+            .and_then(|stdin| stdin.metadata())
+            .is_ok_and(|metadata| metadata.file_type().is_fifo())
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// Apply the replacement to stdin and write the result to stdout, like sed
+fn filter_stdin(replacement: &Replacement) -> io::Result<bool> {
+    let mut input = Vec::new();
+    io::stdin().lock().read_to_end(&mut input)?;
+
+    let output = rewrite_input(&input, Path::new("-"), replacement)?;
+
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(output.as_deref().unwrap_or(&input))?;
+    stdout.flush()?;
+    Ok(output.is_some())
+}
+
+/// Find the files and directories whose names match, exiting if any of the
+/// renames would collide
+fn plan_renames(
+    roots: &[PathBuf],
+    current_dir: &Path,
+    replacement: &Replacement,
+) -> Vec<rename::Rename> {
+    let mut renames = Vec::new();
+    for entry in walk(roots, None) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                report::add_walk_error(None, &e);
+                continue;
+            }
+        };
+        // The directories being processed keep their names, but files given
+        // as paths can be renamed
+        if entry.depth() == 0 && entry.file_type().is_some_and(|ft| ft.is_dir()) {
+            continue;
+        }
+        match rename::renamed(entry.path(), replacement) {
+            Ok(Some(to)) => renames.push(rename::Rename {
+                from: entry.path().to_path_buf(),
+                to,
+            }),
+            Ok(None) => {}
+            Err(e) => report::add(Code::Process, entry.path(), e),
+        }
+    }
+
+    let collisions = rename::collisions(&renames, rename::is_case_insensitive(current_dir));
+    if !collisions.is_empty() {
+        for collision in &collisions {
+            error!("{}", collision);
+        }
+        error!("Renames would collide, so nothing was changed");
+        exit(EXIT_ERROR);
+    }
 
-    Ok(true)
+    renames
 }
@@ -0,0 +1,159 @@
+//! The man page, generated from the command line definition so that it
+//! covers every option
+
+use crate::Cli;
+use clap::{Arg, CommandFactory};
+use clap_mangen::Man;
+use roff::{bold, italic, roman, Roff};
+use std::io::{self, Write};
+
+const MATCHING: &[&str] = &[
+    "OLD is matched literally, unless -p makes it a regular expression in the syntax of the Rust regex crate. NEW can then refer to capture groups as $1 or ${name}.",
+    "With -i, OLD matches ignoring case, and each replacement takes on the case of the text it replaces: all lowercase, ALL UPPERCASE, Title case, or letter by letter for mixed case such as camelCase.",
+    "Files ignored by .gitignore, .ignore and similar files are left out unless --no-ignore is given, as are hidden files, the .newtext journal and files matching --exclude. Binary files are skipped unless --binary is given.",
+];
+
+const EXIT_STATUS: &[(&str, &str)] = &[
+    ("0", "The run finished without problems."),
+    (
+        "1",
+        "Nothing matched with --fail-if-no-matches, newtext find or newtext try.",
+    ),
+    (
+        "2",
+        "Something went wrong, such as invalid arguments or a file that couldn't be processed.",
+    ),
+    ("3", "newtext check found files to change."),
+    ("4", "The confirmation was declined."),
+    ("130", "The run was stopped by Ctrl-C."),
+];
+
+const ENVIRONMENT: &[(&str, &str)] = &[
+    (
+        "NEWTEXT_*",
+        "A default for the option named by the rest of the variable's name, in lowercase with _ as -, as in NEWTEXT_JOBS=4 or NEWTEXT_NO_IGNORE=1. They take precedence over the config files, and options on the command line over them.",
+    ),
+    ("NO_COLOR", "Don't color output unless --color=always is given."),
+];
+
+const FILES: &[(&str, &str)] = &[
+    (
+        ".newtext/",
+        "The journal of runs in the current directory, with a copy of each file as it was before, for newtext undo. It also holds the lock that stops two runs changing the same files at once.",
+    ),
+    (
+        ".newtext.toml",
+        "Defaults for options for a project, in the current directory or one above it. Each setting is an option's long name, as in jobs = 4. Recipes for newtext run are under [recipes.NAME], and rules for newtext hook under [rules.NAME].",
+    ),
+    (
+        "~/.config/newtext/config.toml",
+        "Defaults for options for the user, which the project's take precedence over. It's under $XDG_CONFIG_HOME instead when that's set.",
+    ),
+];
+
+/// Write the man page, as roff
+pub fn render(w: &mut dyn Write) -> io::Result<()> {
+    let command = Cli::command();
+    let man = Man::new(command.clone());
+    man.render_title(w)?;
+    man.render_name_section(w)?;
+    man.render_synopsis_section(w)?;
+    man.render_description_section(w)?;
+    man.render_options_section(w)?;
+
+    let mut roff = Roff::new();
+    roff.control("SH", ["COMMANDS"]);
+    let shared: Vec<_> = command
+        .get_arguments()
+        .filter(|arg| !arg.is_global_set())
+        .map(Arg::get_id)
+        .collect();
+    for subcommand in command.get_subcommands() {
+        if subcommand.get_name() == "help" {
+            continue;
+        }
+        roff.control(
+            "SS",
+            [format!("newtext {}", subcommand.get_name()).as_str()],
+        );
+        if let Some(about) = subcommand.get_about() {
+            roff.text([roman(about.to_string())]);
+        }
+        // Commands that make a run take all the options of one
+        let runs = shared
+            .iter()
+            .all(|id| subcommand.get_arguments().any(|arg| arg.get_id() == *id));
+        if runs {
+            roff.control("PP", []);
+            roff.text([
+                roman("It takes the same arguments and options as "),
+                bold("newtext"),
+                roman("."),
+            ]);
+        }
+        for arg in subcommand.get_arguments() {
+            if arg.is_hide_set() || (runs && shared.contains(&arg.get_id())) {
+                continue;
+            }
+            roff.control("TP", []);
+            roff.text(arg_names(arg));
+            if let Some(help) = arg.get_help() {
+                roff.text([roman(help.to_string())]);
+            }
+        }
+    }
+
+    roff.control("SH", ["MATCHING"]);
+    for (i, paragraph) in MATCHING.iter().enumerate() {
+        if i > 0 {
+            roff.control("PP", []);
+        }
+        roff.text([roman(*paragraph)]);
+    }
+    roff.control("SH", ["EXIT STATUS"]);
+    for (status, meaning) in EXIT_STATUS {
+        roff.control("TP", []);
+        roff.text([bold(*status)]);
+        roff.text([roman(*meaning)]);
+    }
+    roff.control("SH", ["ENVIRONMENT"]);
+    for (var, meaning) in ENVIRONMENT {
+        roff.control("TP", []);
+        roff.text([bold(*var)]);
+        roff.text([roman(*meaning)]);
+    }
+    roff.control("SH", ["FILES"]);
+    for (path, meaning) in FILES {
+        roff.control("TP", []);
+        roff.text([italic(*path)]);
+        roff.text([roman(*meaning)]);
+    }
+    roff.to_writer(w)?;
+
+    man.render_version_section(w)
+}
+
+/// How an argument is given, as the options section shows it
+fn arg_names(arg: &Arg) -> Vec<roff::Inline> {
+    let mut names = Vec::new();
+    if let Some(short) = arg.get_short() {
+        names.push(bold(format!("-{}", short)));
+    }
+    if let Some(long) = arg.get_long() {
+        if !names.is_empty() {
+            names.push(roman(", "));
+        }
+        names.push(bold(format!("--{}", long)));
+    }
+    let value = arg
+        .get_value_names()
+        .and_then(|names| names.first())
+        .map_or_else(|| arg.get_id().as_str().to_uppercase(), ToString::to_string);
+    if names.is_empty() {
+        names.push(italic(format!("<{}>", value)));
+    } else if arg.get_action().takes_values() {
+        names.push(roman(" "));
+        names.push(italic(format!("<{}>", value)));
+    }
+    names
+}
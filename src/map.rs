@@ -0,0 +1,137 @@
+use clap::ValueEnum;
+use regex::Captures;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// What to do when a matched key has no entry in the map
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MissingKey {
+    /// Leave the match unchanged
+    Skip,
+    /// Report an error and leave the file untouched
+    Error,
+}
+
+/// A dictionary of replacement values loaded from a JSON or TOML file
+pub struct ValueMap {
+    entries: HashMap<String, String>,
+    missing: MissingKey,
+}
+
+impl ValueMap {
+    /// Load a flat key/value table. TOML is used for `.toml` files and JSON
+    /// for everything else. Values must be strings, numbers or booleans.
+    ///
+    /// With `reverse`, the table is inverted so values map back to their keys.
+    pub fn load(path: &Path, missing: MissingKey, reverse: bool) -> Result<ValueMap, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        let is_toml = path
+            .extension()
+            .map(|ext| ext.eq_ignore_ascii_case("toml"))
+            .unwrap_or(false);
+
+        let entries = if is_toml {
+            let table: toml::Table = toml::from_str(&text).map_err(|e| e.to_string())?;
+            table
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        toml::Value::String(s) => s,
+                        toml::Value::Integer(i) => i.to_string(),
+                        toml::Value::Float(f) => f.to_string(),
+                        toml::Value::Boolean(b) => b.to_string(),
+                        _ => return Err(format!("value for key '{}' must be a scalar", key)),
+                    };
+                    Ok((key, value))
+                })
+                .collect::<Result<HashMap<_, _>, _>>()?
+        } else {
+            let value: serde_json::Value =
+                serde_json::from_str(&text).map_err(|e| e.to_string())?;
+            let object = match value {
+                serde_json::Value::Object(object) => object,
+                _ => return Err("top level must be an object".to_string()),
+            };
+            object
+                .into_iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        serde_json::Value::String(s) => s,
+                        serde_json::Value::Number(n) => n.to_string(),
+                        serde_json::Value::Bool(b) => b.to_string(),
+                        _ => return Err(format!("value for key '{}' must be a scalar", key)),
+                    };
+                    Ok((key, value))
+                })
+                .collect::<Result<HashMap<_, _>, _>>()?
+        };
+
+        let entries = if reverse { invert(entries)? } else { entries };
+
+        Ok(ValueMap { entries, missing })
+    }
+
+    /// Look up the replacement for a match
+    ///
+    /// The key is the capture group named `key` if the pattern has one,
+    /// otherwise the first capture group, otherwise the whole match. The key
+    /// text is substituted in place, so the rest of the match is kept.
+    pub fn replace(&self, caps: &Captures) -> Result<Option<String>, String> {
+        let whole = caps.get(0).unwrap();
+        let key = caps.name("key").or_else(|| caps.get(1)).unwrap_or(whole);
+
+        let value = match self.entries.get(key.as_str()) {
+            Some(value) => value,
+            None => {
+                return match self.missing {
+                    MissingKey::Skip => Ok(None),
+                    MissingKey::Error => Err(format!("no mapping for key '{}'", key.as_str())),
+                }
+            }
+        };
+
+        let start = key.start() - whole.start();
+        let end = key.end() - whole.start();
+        let text = whole.as_str();
+        Ok(Some(format!("{}{}{}", &text[..start], value, &text[end..])))
+    }
+
+    /// Every key whose value is itself a key, with the value that maps to
+    pub fn chains(&self) -> Vec<(&str, &str, &str)> {
+        let mut chains: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(key, value)| key != value)
+            .filter_map(|(key, value)| {
+                let next = self.entries.get(value)?;
+                Some((key.as_str(), value.as_str(), next.as_str()))
+            })
+            .collect();
+        chains.sort();
+        chains
+    }
+}
+
+/// Swap keys and values, failing if two keys share a value
+///
+/// Keys are taken in order, so that the same map always reports the same
+/// keys when several values are shared.
+fn invert(entries: HashMap<String, String>) -> Result<HashMap<String, String>, String> {
+    let mut inverted: HashMap<String, String> = HashMap::with_capacity(entries.len());
+    let mut entries: Vec<(String, String)> = entries.into_iter().collect();
+    entries.sort_unstable();
+
+    for (key, value) in entries {
+        if let Some(other) = inverted.get(&value) {
+            return Err(format!(
+                "cannot reverse: value '{}' is mapped from both '{}' and '{}'",
+                value, other, key
+            ));
+        }
+        inverted.insert(value, key);
+    }
+
+    Ok(inverted)
+}
@@ -0,0 +1,107 @@
+//! --markdown, which limits replacements in Markdown files to their prose or
+//! to their code, and --front-matter, which limits them to the metadata at
+//! the top
+//!
+//! Code blocks, fenced or indented, and inline code spans are found with
+//! pulldown-cmark, and the rest of a file is its prose: headings, links,
+//! tables, HTML and all. Front matter isn't prose or code.
+
+use crate::scope::{Escape, Segment};
+use clap::ValueEnum;
+use pulldown_cmark::{Event, Options, Parser, Tag, TagEnd};
+use std::ops::Range;
+
+/// Which parts of Markdown files are replaced within, with --markdown
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Part {
+    /// Everything but code blocks and inline code
+    Prose,
+    /// Only what's inside code blocks and inline code
+    Code,
+    /// The whole file, as for any other file
+    All,
+}
+
+/// The parts of the Markdown in `text` to replace within
+pub fn segments(text: &str, part: Part) -> Vec<Segment> {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_YAML_STYLE_METADATA_BLOCKS
+        | Options::ENABLE_PLUSES_DELIMITED_METADATA_BLOCKS;
+    // The code with its fences and backticks, and the code inside them, and
+    // the front matter, which is left out of the prose along with the code
+    let mut outer: Vec<Range<usize>> = Vec::new();
+    let mut inner: Vec<Range<usize>> = Vec::new();
+    let mut in_code_block = false;
+    for (event, range) in Parser::new_ext(text, options).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => {
+                outer.push(range);
+                in_code_block = true;
+            }
+            Event::End(TagEnd::CodeBlock) => in_code_block = false,
+            Event::Start(Tag::MetadataBlock(_)) => outer.push(range),
+            Event::Text(_) if in_code_block => inner.push(range),
+            Event::Code(_) => {
+                let code = &text[range.clone()];
+                let start = range.start + (code.len() - code.trim_start_matches('`').len());
+                let end = range.end - (code.len() - code.trim_end_matches('`').len());
+                outer.push(range);
+                inner.push(start..end.max(start));
+            }
+            _ => {}
+        }
+    }
+
+    let ranges = match part {
+        Part::Code => inner,
+        Part::Prose => {
+            let mut prose = Vec::new();
+            let mut at = 0;
+            for code in outer {
+                if code.start > at {
+                    prose.push(at..code.start);
+                }
+                at = at.max(code.end);
+            }
+            prose.push(at..text.len());
+            prose
+        }
+        Part::All => std::iter::once(0..text.len()).collect(),
+    };
+    ranges
+        .into_iter()
+        .filter(|range| !range.is_empty())
+        .map(|range| Segment {
+            text: text[range.clone()].to_string(),
+            range,
+            escape: Escape::None,
+        })
+        .collect()
+}
+
+/// Where the YAML or TOML front matter of the Markdown in `text` is, between
+/// `---` or `+++` lines at the very top, if it has any
+pub fn front_matter(text: &str) -> Option<Range<usize>> {
+    let fence = ["---", "+++"]
+        .into_iter()
+        .find(|fence| first_line(text) == *fence)?;
+    let start = text.find('\n')? + 1;
+    let mut at = start;
+    while at < text.len() {
+        let line = first_line(&text[at..]);
+        // YAML can end a document with ... as well
+        if line == fence || fence == "---" && line == "..." {
+            return Some(start..at);
+        }
+        at += text[at..].find('\n').map_or(text.len() - at, |end| end + 1);
+    }
+    None
+}
+
+/// The first line of `text`, without its line ending or trailing spaces
+fn first_line(text: &str) -> &str {
+    text[..text.find('\n').unwrap_or(text.len())].trim_end()
+}
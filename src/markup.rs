@@ -0,0 +1,309 @@
+//! A forgiving reader of XML and HTML, for where each element, attribute and
+//! piece of text is in a document
+//!
+//! Nothing is decoded: text and attribute values are where and as they're
+//! written, entities and all, so that what's replaced within them can be
+//! written back without touching anything else. HTML's void elements,
+//! elements whose text isn't markup, such as `<script>`, and closing tags
+//! that are left out are understood when `html` is set.
+
+use std::ops::Range;
+
+/// The elements of a document and the text in them
+#[derive(Debug, Default)]
+pub struct Document {
+    pub elements: Vec<Element>,
+    pub texts: Vec<Text>,
+}
+
+#[derive(Debug)]
+pub struct Element {
+    /// Lowercased in HTML
+    pub name: String,
+    /// The index of the element this one's in, or None at the top level
+    pub parent: Option<usize>,
+    /// How many elements with the same name come before this one in its
+    /// parent, for XPath's `[N]`
+    pub position: usize,
+    /// How many elements of any name come before this one in its parent
+    pub index: usize,
+    pub attributes: Vec<Attribute>,
+}
+
+#[derive(Debug)]
+pub struct Attribute {
+    /// Lowercased in HTML
+    pub name: String,
+    /// Where the value is, inside any quotes, or None if there's no value
+    pub value: Option<Range<usize>>,
+    /// The quote around the value, if it has one
+    pub quote: Option<char>,
+}
+
+/// A piece of text directly inside an element, or at the top level
+#[derive(Debug)]
+pub struct Text {
+    pub range: Range<usize>,
+    pub parent: Option<usize>,
+    pub kind: TextKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextKind {
+    /// Text between tags, where `<` starts a tag
+    Markup,
+    /// The inside of a `<![CDATA[...]]>` section
+    Cdata,
+    /// The inside of an HTML `<script>`, `<style>`, `<textarea>` or `<title>`
+    Raw,
+}
+
+/// HTML elements that never have anything in them or a closing tag
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "keygen", "link", "meta", "param",
+    "source", "track", "wbr",
+];
+
+/// HTML elements whose text is read up to their closing tag, as it is
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "textarea", "title"];
+
+/// Read the document in `text`, as HTML if `html` is set and as XML
+/// otherwise
+pub fn parse(text: &str, html: bool) -> Result<Document, String> {
+    let mut reader = Reader {
+        text,
+        html,
+        at: 0,
+        open: Vec::new(),
+        children: vec![Vec::new()],
+        document: Document::default(),
+    };
+    reader.read()?;
+    Ok(reader.document)
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    html: bool,
+    at: usize,
+    /// The elements that are open, innermost last
+    open: Vec<usize>,
+    /// The names of the elements seen so far in each open element, with the
+    /// top level first
+    children: Vec<Vec<String>>,
+    document: Document,
+}
+
+impl Reader<'_> {
+    fn read(&mut self) -> Result<(), String> {
+        while self.at < self.text.len() {
+            let rest = &self.text[self.at..];
+            if rest.starts_with("<!--") {
+                self.skip_past("-->", "a comment isn't closed")?;
+            } else if rest.starts_with("<![CDATA[") {
+                let start = self.at + "<![CDATA[".len();
+                self.skip_past("]]>", "a CDATA section isn't closed")?;
+                self.push_text(start..self.at - "]]>".len(), TextKind::Cdata);
+            } else if rest.starts_with("<!") {
+                self.skip_past(">", "a declaration isn't closed")?;
+            } else if rest.starts_with("<?") {
+                self.skip_past("?>", "a processing instruction isn't closed")?;
+            } else if rest.starts_with("</") {
+                self.close_tag()?;
+            } else if rest.starts_with('<')
+                && rest[1..].starts_with(|c: char| c.is_alphabetic() || c == '_' || c == ':')
+            {
+                self.open_tag()?;
+            } else {
+                // Text, up to the next tag. A `<` that doesn't start one is
+                // part of the text.
+                let start = self.at;
+                let first = rest.chars().next().map_or(1, char::len_utf8);
+                let end = rest[first..]
+                    .find('<')
+                    .map_or(self.text.len(), |end| start + first + end);
+                self.at = end;
+                self.push_text(start..end, TextKind::Markup);
+            }
+        }
+        Ok(())
+    }
+
+    fn open_tag(&mut self) -> Result<(), String> {
+        let tag_start = self.at;
+        self.at += 1;
+        let name = self.name();
+        let mut attributes = Vec::new();
+        let self_closing = loop {
+            self.skip_space();
+            let rest = &self.text[self.at..];
+            if rest.starts_with("/>") {
+                self.at += 2;
+                break true;
+            } else if rest.starts_with('>') {
+                self.at += 1;
+                break false;
+            } else if rest.is_empty() {
+                self.at = tag_start;
+                return Err(self.error("a tag isn't closed"));
+            } else if rest.starts_with('/') {
+                self.at += 1;
+                continue;
+            }
+            attributes.push(self.attribute()?);
+        };
+
+        let parent = self.open.last().copied();
+        let siblings = self
+            .children
+            .last_mut()
+            .expect("the top level is never closed");
+        let position = siblings.iter().filter(|sibling| **sibling == name).count();
+        let index = siblings.len();
+        siblings.push(name.clone());
+        let id = self.document.elements.len();
+        self.document.elements.push(Element {
+            name: name.clone(),
+            parent,
+            position,
+            index,
+            attributes,
+        });
+
+        let void = self.html && VOID_ELEMENTS.contains(&name.as_str());
+        if self_closing || void {
+            return Ok(());
+        }
+        if self.html && RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+            let start = self.at;
+            let end = find_ignoring_case(&self.text[start..], &format!("</{}", name))
+                .map_or(self.text.len(), |end| start + end);
+            self.at = end;
+            self.open.push(id);
+            self.children.push(Vec::new());
+            self.push_text(start..end, TextKind::Raw);
+            return Ok(());
+        }
+        self.open.push(id);
+        self.children.push(Vec::new());
+        Ok(())
+    }
+
+    fn close_tag(&mut self) -> Result<(), String> {
+        self.at += 2;
+        let name = self.name();
+        self.skip_past(">", "a closing tag isn't closed")?;
+        // Closing an element closes any left open inside it, and a closing
+        // tag for an element that isn't open is passed over
+        let elements = &self.document.elements;
+        if let Some(depth) = self
+            .open
+            .iter()
+            .rposition(|&open| elements[open].name == name)
+        {
+            self.open.truncate(depth);
+            self.children.truncate(depth + 1);
+        }
+        Ok(())
+    }
+
+    fn attribute(&mut self) -> Result<Attribute, String> {
+        let start = self.at;
+        let end = self.text[start..]
+            .find(|c: char| c.is_whitespace() || "=/>".contains(c))
+            .map_or(self.text.len(), |end| start + end.max(1));
+        self.at = end;
+        let name = self.fold(&self.text[start..end]);
+        self.skip_space();
+        if !self.text[self.at..].starts_with('=') {
+            return Ok(Attribute {
+                name,
+                value: None,
+                quote: None,
+            });
+        }
+        self.at += 1;
+        self.skip_space();
+        let (value, quote) = match self.text[self.at..].chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let start = self.at + 1;
+                let end = self.text[start..]
+                    .find(quote)
+                    .map(|end| start + end)
+                    .ok_or_else(|| self.error("an attribute's quote isn't closed"))?;
+                self.at = end + 1;
+                (start..end, Some(quote))
+            }
+            _ => {
+                let start = self.at;
+                let end = self.text[start..]
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .map_or(self.text.len(), |end| start + end);
+                self.at = end;
+                (start..end, None)
+            }
+        };
+        Ok(Attribute {
+            name,
+            value: Some(value),
+            quote,
+        })
+    }
+
+    /// The name of a tag starting here
+    fn name(&mut self) -> String {
+        let start = self.at;
+        let end = self.text[start..]
+            .find(|c: char| c.is_whitespace() || c == '/' || c == '>')
+            .map_or(self.text.len(), |end| start + end);
+        self.at = end;
+        self.fold(&self.text[start..end])
+    }
+
+    /// `name`, lowercased in HTML
+    fn fold(&self, name: &str) -> String {
+        if self.html {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn push_text(&mut self, range: Range<usize>, kind: TextKind) {
+        if !range.is_empty() {
+            self.document.texts.push(Text {
+                range,
+                parent: self.open.last().copied(),
+                kind,
+            });
+        }
+    }
+
+    fn skip_space(&mut self) {
+        let rest = &self.text[self.at..];
+        self.at += rest.len() - rest.trim_start().len();
+    }
+
+    fn skip_past(&mut self, end: &str, why: &str) -> Result<(), String> {
+        match self.text[self.at..].find(end) {
+            Some(found) => {
+                self.at += found + end.len();
+                Ok(())
+            }
+            None => Err(self.error(why)),
+        }
+    }
+
+    fn error(&self, why: &str) -> String {
+        let line = self.text[..self.at].matches('\n').count() + 1;
+        format!("it isn't valid markup, {} on line {}", why, line)
+    }
+}
+
+/// Where `needle`, which is ASCII, first is in `haystack`, ignoring case
+fn find_ignoring_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
@@ -0,0 +1,72 @@
+use memchr::memmem::Finder;
+use regex::{Regex, RegexBuilder};
+
+/// How OLD is found, built once for the whole run
+pub enum Matcher {
+    /// OLD itself
+    Literal(Box<Finder<'static>>),
+    /// OLD ignoring case, with the case of each match kept when it's replaced
+    IgnoreCase(Regex),
+    /// A pattern, or OLD for a script or map to be handed its matches
+    Regex(Regex),
+}
+
+impl Matcher {
+    /// A matcher for OLD, as a regex if it's a `pattern`
+    pub fn new(old: &str, pattern: bool, ignore_case: bool) -> Result<Matcher, regex::Error> {
+        if pattern {
+            compile(old, ignore_case).map(Matcher::Regex)
+        } else {
+            Matcher::literal(old, ignore_case)
+        }
+    }
+
+    /// A matcher for OLD in literal mode
+    pub fn literal(old: &str, ignore_case: bool) -> Result<Matcher, regex::Error> {
+        if ignore_case {
+            // The flag is kept in the pattern, for the prefilter to read
+            let re = Regex::new(&format!("(?i){}", regex::escape(old)))?;
+            Ok(Matcher::IgnoreCase(re))
+        } else {
+            Ok(Matcher::Literal(Box::new(
+                Finder::new(old.as_bytes()).into_owned(),
+            )))
+        }
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        match self {
+            Matcher::Literal(finder) => finder.find(text.as_bytes()).is_some(),
+            Matcher::IgnoreCase(re) | Matcher::Regex(re) => re.is_match(text),
+        }
+    }
+
+    /// The byte offsets in `text` where each match starts, in order
+    pub fn starts(&self, text: &str) -> Vec<usize> {
+        match self {
+            Matcher::Literal(finder) => finder.find_iter(text.as_bytes()).collect(),
+            Matcher::IgnoreCase(re) | Matcher::Regex(re) => {
+                re.find_iter(text).map(|m| m.start()).collect()
+            }
+        }
+    }
+
+    /// The regex, when OLD is a pattern or is handed to a script or map
+    pub fn regex(&self) -> Option<&Regex> {
+        match self {
+            Matcher::Regex(re) => Some(re),
+            _ => None,
+        }
+    }
+}
+
+/// The regex for `pattern`, matching ignoring case if asked
+pub fn compile(pattern: &str, ignore_case: bool) -> Result<Regex, regex::Error> {
+    let pattern = if ignore_case {
+        format!("(?i){}", pattern)
+    } else {
+        pattern.to_string()
+    };
+    // CRLF mode lets `$` in multi-line patterns match before "\r\n"
+    RegexBuilder::new(&pattern).crlf(true).build()
+}
@@ -0,0 +1,51 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// The most bytes of file content held at once across all threads, with
+/// --max-memory, or 0 for no limit
+static LIMIT: AtomicU64 = AtomicU64::new(0);
+
+/// How many bytes are reserved, by the files being worked on
+static RESERVED: Mutex<u64> = Mutex::new(0);
+
+/// Signalled when a reservation is given back
+static RELEASED: Condvar = Condvar::new();
+
+/// Hold no more than `limit` bytes of file content at once
+pub fn set_limit(limit: u64) {
+    LIMIT.store(limit, Ordering::Relaxed);
+}
+
+/// Room for the content of the file at `path`, given back when dropped
+///
+/// Threads wait here until there's room, which holds up the walk in turn. A
+/// file larger than the limit waits until nothing else is reserved, so that
+/// it still gets done.
+pub fn reserve_for(path: &Path) -> Reservation {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    if limit == 0 {
+        return Reservation(0);
+    }
+    let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+
+    let mut reserved = RESERVED.lock().unwrap();
+    while *reserved > 0 && *reserved + size > limit {
+        reserved = RELEASED.wait(reserved).unwrap();
+    }
+    *reserved += size;
+    Reservation(size)
+}
+
+pub struct Reservation(u64);
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.0 == 0 {
+            return;
+        }
+        *RESERVED.lock().unwrap() -= self.0;
+        RELEASED.notify_all();
+    }
+}
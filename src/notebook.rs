@@ -0,0 +1,64 @@
+//! Jupyter notebooks, which are replaced within only in the sources of their
+//! cells, and with --notebook-cells only in their code or their Markdown
+//!
+//! Notebooks are JSON, and their outputs, metadata and execution counts are
+//! as much a part of them as their cells are, so the cells are found with
+//! serde_json and their sources are replaced within with the --json-path
+//! scanner, which writes everything else back exactly as it was.
+
+use crate::json_path;
+use crate::scope::{KeyPath, Segment, Step};
+use clap::ValueEnum;
+use serde_json::Value;
+use std::path::Path;
+
+/// Which cells of notebooks are replaced within, with --notebook-cells
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Cells {
+    /// Only code cells
+    Code,
+    /// Only Markdown cells
+    Markdown,
+    /// Every cell, raw ones too, as notebooks always are
+    All,
+}
+
+/// Whether the file at `path` is a notebook
+pub fn is_notebook(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("ipynb"))
+}
+
+/// The sources of the `cells` in the notebook `text`, a segment for each
+/// line where a source is written as a list of lines
+pub fn segments(text: &str, cells: Cells) -> Result<Vec<Segment>, String> {
+    let notebook: Value =
+        serde_json::from_str(text).map_err(|e| format!("it isn't a valid notebook, {}", e))?;
+    let Some(found) = notebook.get("cells").and_then(Value::as_array) else {
+        return Err("it isn't a valid notebook, it has no list of cells".to_string());
+    };
+    let paths: Vec<KeyPath> = found
+        .iter()
+        .enumerate()
+        .filter(|(_, cell)| {
+            let kind = cell.get("cell_type").and_then(Value::as_str);
+            match cells {
+                Cells::Code => kind == Some("code"),
+                Cells::Markdown => kind == Some("markdown"),
+                Cells::All => true,
+            }
+        })
+        .map(|(index, _)| {
+            KeyPath(vec![
+                Step::Key("cells".to_string()),
+                Step::Index(index),
+                Step::Key("source".to_string()),
+            ])
+        })
+        .collect();
+    if paths.is_empty() {
+        return Ok(Vec::new());
+    }
+    json_path::segments(text, &paths)
+}
@@ -0,0 +1,155 @@
+//! --office, which replaces within the text of Word, Excel and PowerPoint
+//! documents
+//!
+//! Office Open XML documents are zip archives of XML parts. They're repacked
+//! the way other archives are, and only the text runs of the parts that hold
+//! a document's text are replaced within, so styles, relationships and
+//! everything else in them stay as they were. Text is matched as it reads,
+//! with entities such as `&amp;` decoded. Word splits text into runs
+//! wherever its formatting or spell checking changes, so text that spans
+//! runs isn't matched.
+
+use crate::markup;
+use crate::scope::{Escape, Segment};
+use std::path::Path;
+
+/// The extensions of the documents --office opens, including templates and
+/// those with macros
+const EXTENSIONS: &[&str] = &[
+    "docx", "docm", "dotx", "dotm", "xlsx", "xlsm", "xltx", "xltm", "pptx", "pptm", "potx", "potm",
+];
+
+/// The elements that text runs are in: `w:t` in Word, `a:t` in PowerPoint
+/// and charts, and `t` in Excel
+const RUN_ELEMENTS: &[&str] = &["w:t", "a:t", "t"];
+
+/// Whether the file at `path` is an Office Open XML document
+pub fn is_document(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            EXTENSIONS
+                .iter()
+                .any(|wanted| extension.eq_ignore_ascii_case(wanted))
+        })
+}
+
+/// Whether `path` is a part inside a document, such as
+/// `report.docx/word/document.xml`
+pub fn is_part(path: &Path) -> bool {
+    path.ancestors().skip(1).any(is_document)
+}
+
+/// Whether the part at `path` holds the document's text, rather than its
+/// styles, settings or relationships
+fn holds_text(path: &Path) -> bool {
+    let Some(document) = path
+        .ancestors()
+        .skip(1)
+        .find(|&ancestor| is_document(ancestor))
+    else {
+        return false;
+    };
+    let Some(name) = path
+        .strip_prefix(document)
+        .ok()
+        .and_then(|name| name.to_str())
+    else {
+        return false;
+    };
+    let name = name.replace('\\', "/");
+    let Some((directory, file)) = name.rsplit_once('/') else {
+        return false;
+    };
+    let numbered = |prefix: &str| {
+        file.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(".xml"))
+            .is_some_and(|number| number.chars().all(|c| c.is_ascii_digit()))
+    };
+    match directory {
+        "word" => {
+            matches!(
+                file,
+                "document.xml" | "footnotes.xml" | "endnotes.xml" | "comments.xml"
+            ) || numbered("header")
+                || numbered("footer")
+        }
+        "xl" => file == "sharedStrings.xml" || numbered("comments"),
+        "xl/worksheets" => numbered("sheet"),
+        "ppt/slides" => numbered("slide"),
+        "ppt/notesSlides" => numbered("notesSlide"),
+        "word/charts" | "xl/charts" | "ppt/charts" => numbered("chart"),
+        _ => false,
+    }
+}
+
+/// The text runs of the part at `path` holding `text`, or None if it isn't
+/// one of the parts that hold a document's text
+pub fn segments(path: &Path, text: &str) -> Result<Option<Vec<Segment>>, String> {
+    if !holds_text(path) {
+        return Ok(None);
+    }
+    let document = markup::parse(text, false)?;
+    let segments = document
+        .texts
+        .iter()
+        .filter(|run| {
+            run.parent.is_some_and(|parent| {
+                RUN_ELEMENTS.contains(&document.elements[parent].name.as_str())
+            })
+        })
+        .map(|run| {
+            let raw = &text[run.range.clone()];
+            let (text, escape) = match run.kind {
+                markup::TextKind::Cdata => (raw.to_string(), Escape::Cdata),
+                markup::TextKind::Markup | markup::TextKind::Raw => (decode(raw), Escape::XmlText),
+            };
+            Segment {
+                range: run.range.clone(),
+                text,
+                escape,
+            }
+        })
+        .collect();
+    Ok(Some(segments))
+}
+
+/// `text` with its entities and character references replaced by what they
+/// stand for, so that `R&D` is matched as it reads in the document
+fn decode(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let c = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => {
+                let number = entity.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (c, entity) {
+            (Some(c), Some(entity)) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            // An `&` that doesn't start an entity is left as it is
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
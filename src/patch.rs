@@ -0,0 +1,488 @@
+//! Patches in the unified diff format that `diff -u` and `git diff` write,
+//! for changes to be reviewed or applied later, without needing git
+
+use crate::edits;
+use crate::interrupt;
+use crate::journal::Journal;
+use crate::lsp;
+use crate::report::{self, Code};
+use crate::write::{self, WriteOptions};
+use serde_json::Value;
+use similar::{capture_diff_slices, Algorithm};
+use std::fs;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tracing::info;
+
+/// Lines of unchanged context around each hunk, as `diff -u` gives
+const CONTEXT: usize = 3;
+
+/// The diff from `old` to `new` of the file at `path`, relative to the root
+/// of the tree, with the `a/` and `b/` prefixes that `git apply` expects
+pub fn diff(path: &str, old: &str, new: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let ops = capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines);
+    let runs = edits::changed_runs(edits::ranges(&ops));
+
+    // Runs whose context would meet go in the same hunk
+    let mut hunks: Vec<Vec<(Range<usize>, Range<usize>)>> = Vec::new();
+    for run in runs {
+        match hunks.last_mut() {
+            Some(hunk) if run.0.start <= hunk[hunk.len() - 1].0.end + 2 * CONTEXT => hunk.push(run),
+            _ => hunks.push(vec![run]),
+        }
+    }
+
+    let mut diff = format!("diff --git a/{0} b/{0}\n--- a/{0}\n+++ b/{0}\n", path);
+    for hunk in hunks {
+        let (first, last) = (&hunk[0], &hunk[hunk.len() - 1]);
+        let before = first.0.start.min(CONTEXT);
+        let after = (old_lines.len() - last.0.end).min(CONTEXT);
+        let old_range = first.0.start - before..last.0.end + after;
+        let new_range = first.1.start - before..last.1.end + after;
+        diff.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(&old_range),
+            hunk_range(&new_range)
+        ));
+        let mut at = old_range.start;
+        for (old_run, new_run) in &hunk {
+            push_lines(&mut diff, ' ', &old_lines[at..old_run.start]);
+            push_lines(&mut diff, '-', &old_lines[old_run.clone()]);
+            push_lines(&mut diff, '+', &new_lines[new_run.clone()]);
+            at = old_run.end;
+        }
+        push_lines(&mut diff, ' ', &old_lines[at..old_range.end]);
+    }
+    diff
+}
+
+/// A range of lines as a hunk header gives it: the line it starts at,
+/// counting from 1, and how many lines it has unless that's 1
+fn hunk_range(range: &Range<usize>) -> String {
+    match range.len() {
+        // An empty range gives the line before it
+        0 => format!("{},0", range.start),
+        1 => format!("{}", range.start + 1),
+        len => format!("{},{}", range.start + 1, len),
+    }
+}
+
+fn push_lines(diff: &mut String, kind: char, lines: &[&str]) {
+    for line in lines {
+        diff.push(kind);
+        diff.push_str(line);
+        if !line.ends_with('\n') {
+            diff.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+/// The changes a patch makes to one file
+#[derive(Debug)]
+pub struct FilePatch {
+    /// The file's path before and after, without the `a/` or `b/` prefix, or
+    /// None if the patch creates or deletes it
+    pub old_path: Option<String>,
+    pub new_path: Option<String>,
+    hunks: Vec<Hunk>,
+}
+
+/// One hunk of a patch, with each of its lines as they are in the file,
+/// including the line ending
+#[derive(Debug)]
+struct Hunk {
+    /// The line the hunk starts at in the old file, counting from 1
+    old_start: usize,
+    lines: Vec<Line>,
+}
+
+#[derive(Debug)]
+enum Line {
+    Context(String),
+    Removed(String),
+    Added(String),
+}
+
+/// A file with a patch applied
+pub struct Patched {
+    pub content: String,
+    /// How many hunks were applied with fuzz or away from where the patch
+    /// has them
+    pub inexact: usize,
+}
+
+/// The changes to each file in a unified diff
+///
+/// Anything between the changes to files, such as `diff --git` and `index`
+/// lines or the message of a mailed patch, is ignored.
+pub fn parse(patch: &str) -> Result<Vec<FilePatch>, String> {
+    let mut files = Vec::new();
+    let mut lines = patch.split_inclusive('\n').enumerate().peekable();
+    while let Some((_, line)) = lines.next() {
+        let Some(old_path) = line.strip_prefix("--- ") else {
+            continue;
+        };
+        let Some((_, new_path)) = lines.next_if(|(_, line)| line.starts_with("+++ ")) else {
+            continue;
+        };
+        let mut file = FilePatch {
+            old_path: header_path(old_path, "a/"),
+            new_path: header_path(&new_path[4..], "b/"),
+            hunks: Vec::new(),
+        };
+        while let Some((n, header)) = lines.next_if(|(_, line)| line.starts_with("@@ ")) {
+            let (old_start, mut old_left, mut new_left) = hunk_header(header)
+                .ok_or_else(|| format!("line {} isn't a valid hunk header", n + 1))?;
+            let mut hunk = Hunk {
+                old_start,
+                lines: Vec::new(),
+            };
+            while old_left > 0 || new_left > 0 {
+                let Some((n, line)) = lines.next() else {
+                    return Err("the patch ends in the middle of a hunk".to_string());
+                };
+                let (kind, text) = match line.as_bytes()[0] {
+                    // Some editors strip the space from empty context lines
+                    b'\n' | b'\r' => (b' ', line),
+                    kind => (kind, &line[1..]),
+                };
+                let text = text.to_string();
+                let line = match kind {
+                    b' ' if old_left > 0 && new_left > 0 => {
+                        (old_left, new_left) = (old_left - 1, new_left - 1);
+                        Line::Context(text)
+                    }
+                    b'-' if old_left > 0 => {
+                        old_left -= 1;
+                        Line::Removed(text)
+                    }
+                    b'+' if new_left > 0 => {
+                        new_left -= 1;
+                        Line::Added(text)
+                    }
+                    _ => return Err(format!("line {} doesn't fit in its hunk", n + 1)),
+                };
+                hunk.lines.push(line);
+                // The line before "\ No newline at end of file" has no line
+                // ending
+                if lines.next_if(|(_, line)| line.starts_with('\\')).is_some() {
+                    if let Some(Line::Context(text) | Line::Removed(text) | Line::Added(text)) =
+                        hunk.lines.last_mut()
+                    {
+                        let end = text.trim_end_matches(['\n', '\r']).len();
+                        text.truncate(end);
+                    }
+                }
+            }
+            file.hunks.push(hunk);
+        }
+        files.push(file);
+    }
+    if files.is_empty() {
+        return Err("there are no changes to files in it".to_string());
+    }
+    Ok(files)
+}
+
+/// The path in a `---` or `+++` line, without `prefix`
+fn header_path(header: &str, prefix: &str) -> Option<String> {
+    let path = header.trim_end_matches(['\n', '\r']);
+    // A timestamp can follow the path
+    let path = path.split('\t').next().unwrap_or_default();
+    if path == "/dev/null" {
+        return None;
+    }
+    Some(path.strip_prefix(prefix).unwrap_or(path).to_string())
+}
+
+/// The line the hunk starts at in the old file and how many lines it has in
+/// the old and the new, from its `@@ -1,4 +1,5 @@` header
+fn hunk_header(header: &str) -> Option<(usize, usize, usize)> {
+    let mut ranges = header
+        .strip_prefix("@@ -")?
+        .split(" @@")
+        .next()?
+        .split(" +");
+    let range = |range: &str| -> Option<(usize, usize)> {
+        match range.split_once(',') {
+            Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+    let (old_start, old_len) = range(ranges.next()?)?;
+    let (_, new_len) = range(ranges.next()?)?;
+    Some((old_start, old_len, new_len))
+}
+
+impl FilePatch {
+    /// The path of the file the patch changes, if it only changes its content
+    pub fn path(&self) -> Result<&str, String> {
+        match (&self.old_path, &self.new_path) {
+            (Some(old), Some(new)) if old == new => Ok(new),
+            (Some(_), Some(_)) => Err("patches that rename files aren't supported".to_string()),
+            _ => Err("patches that create or delete files aren't supported".to_string()),
+        }
+    }
+
+    /// `content` with the patch applied
+    ///
+    /// A hunk that doesn't match where the patch has it is looked for
+    /// elsewhere, nearest first, then with up to `fuzz` lines of context at
+    /// each end left out, as `patch` does. If a hunk can't be placed at all,
+    /// nothing is changed.
+    pub fn apply(&self, content: &str, fuzz: usize) -> Result<Patched, String> {
+        let lines: Vec<&str> = content.split_inclusive('\n').collect();
+        let mut new = String::with_capacity(content.len());
+        let mut done = 0;
+        // How far the hunks so far were from where the patch has them
+        let mut offset = 0;
+        let mut inexact = 0;
+        for (i, hunk) in self.hunks.iter().enumerate() {
+            let expected = hunk.start().saturating_add_signed(offset);
+            let placed = (0..=fuzz).find_map(|fuzz| hunk.place(&lines, done, expected, fuzz));
+            let Some(placed) = placed else {
+                let applied = hunk.new_lines();
+                let applied = find(&lines, &applied, done, expected).is_some();
+                return Err(match applied {
+                    true => format!("hunk {} looks to have been applied already", i + 1),
+                    false => format!(
+                        "hunk {} doesn't match the file around line {}",
+                        i + 1,
+                        expected + 1
+                    ),
+                });
+            };
+            if placed.start != expected || placed.fuzz > 0 {
+                inexact += 1;
+            }
+            lines[done..placed.at]
+                .iter()
+                .for_each(|line| new.push_str(line));
+            placed.new.iter().for_each(|line| new.push_str(line));
+            done = placed.at + placed.old.len();
+            offset = placed.start as isize - hunk.start() as isize;
+        }
+        lines[done..].iter().for_each(|line| new.push_str(line));
+        Ok(Patched {
+            content: new,
+            inexact,
+        })
+    }
+}
+
+impl Hunk {
+    /// The index of the line the hunk starts at in the old file
+    fn start(&self) -> usize {
+        // Hunks that only add lines give the line they add them after
+        match self.old_lines().is_empty() {
+            true => self.old_start,
+            false => self.old_start.saturating_sub(1),
+        }
+    }
+
+    /// The lines the hunk replaces
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Context(text) | Line::Removed(text) => Some(text.as_str()),
+                Line::Added(_) => None,
+            })
+            .collect()
+    }
+
+    /// The lines the hunk replaces them with
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                Line::Context(text) | Line::Added(text) => Some(text.as_str()),
+                Line::Removed(_) => None,
+            })
+            .collect()
+    }
+
+    /// Where in `lines` the hunk goes, at `from` or after it, with up to
+    /// `fuzz` lines of context left out at each end
+    fn place(
+        &self,
+        lines: &[&str],
+        from: usize,
+        expected: usize,
+        fuzz: usize,
+    ) -> Option<Placed<'_>> {
+        let context = |lines: &mut dyn Iterator<Item = &Line>| {
+            lines
+                .take_while(|line| matches!(line, Line::Context(_)))
+                .count()
+                .min(fuzz)
+        };
+        let leading = context(&mut self.lines.iter());
+        let trailing = context(&mut self.lines.iter().rev()).min(self.lines.len() - leading);
+        let (old, new) = (self.old_lines(), self.new_lines());
+        let old = old[leading..old.len() - trailing].to_vec();
+        let new = new[leading..new.len() - trailing].to_vec();
+        let at = find(lines, &old, from, expected + leading)?;
+        Some(Placed {
+            at,
+            start: at - leading,
+            fuzz: leading.max(trailing),
+            old,
+            new,
+        })
+    }
+}
+
+/// Where a hunk goes in a file
+struct Placed<'a> {
+    /// The index of the first line it replaces, once any context that's
+    /// left out is
+    at: usize,
+    /// The index its first line of context would be at
+    start: usize,
+    /// How much context is left out
+    fuzz: usize,
+    /// The lines it replaces and the lines it adds in their place
+    old: Vec<&'a str>,
+    new: Vec<&'a str>,
+}
+
+/// The index in `lines`, at `from` or after it, that `want` is found at
+/// nearest to `expected`
+fn find(lines: &[&str], want: &[&str], from: usize, expected: usize) -> Option<usize> {
+    let last = lines.len().checked_sub(want.len())?;
+    if from > last {
+        return None;
+    }
+    let expected = expected.clamp(from, last);
+    let matches = |at: &usize| lines[*at..*at + want.len()] == *want;
+    (0..=last - from)
+        .flat_map(|distance| [expected.checked_sub(distance), Some(expected + distance)])
+        .flatten()
+        .filter(|at| (from..=last).contains(at))
+        .find(matches)
+}
+
+/// Make the changes in the patch `file` to the files under `root`, allowing
+/// up to `fuzz` lines of each hunk's context not to match
+///
+/// `file` can be a unified diff, as `--patch` writes, or a WorkspaceEdit, as
+/// `--format lsp` prints. Files it doesn't apply to cleanly are left alone.
+/// Returns how many files weren't changed.
+pub fn apply(
+    root: &Path,
+    file: &Path,
+    fuzz: usize,
+    journal: Option<Arc<Journal>>,
+) -> Result<usize, String> {
+    let patch = fs::read_to_string(file).map_err(|e| e.to_string())?;
+    let changes: Vec<(PathBuf, Change)> = match serde_json::from_str::<Value>(&patch) {
+        Ok(edit) => lsp::parse(&edit)?
+            .into_iter()
+            .map(|(path, edits)| {
+                let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+                (relative, Change::Edits(edits))
+            })
+            .collect(),
+        Err(_) => parse(&patch)?
+            .into_iter()
+            .map(|patch| {
+                let path = patch.new_path.clone().or(patch.old_path.clone());
+                (
+                    PathBuf::from(path.unwrap_or_default()),
+                    Change::Patch(patch),
+                )
+            })
+            .collect(),
+    };
+
+    let options = WriteOptions {
+        journal,
+        ..WriteOptions::default()
+    };
+    let (mut applied, mut failed, mut inexact) = (0, 0, 0);
+    for (relative, change) in &changes {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        let path = root.join(relative);
+        match patch_file(&path, relative, change, fuzz, &options) {
+            Ok(hunks) => {
+                applied += 1;
+                inexact += hunks;
+            }
+            Err((code, message)) => {
+                report::add(code, &path, message);
+                failed += 1;
+            }
+        }
+    }
+    if interrupt::is_interrupted() {
+        info!(
+            "Stopped after patching {} files from {}",
+            applied,
+            file.display()
+        );
+        if let Some(journal) = &options.journal {
+            journal.record_interrupted().map_err(|e| e.to_string())?;
+        }
+        return Ok(changes.len() - applied);
+    }
+
+    info!("Patched {} files from {}", applied, file.display());
+    if inexact > 0 {
+        info!(
+            "{} hunks didn't match exactly, and were applied with fuzz or at an offset",
+            inexact
+        );
+    }
+    Ok(failed)
+}
+
+/// What a patch does to one file
+enum Change {
+    Patch(FilePatch),
+    /// LSP TextEdits
+    Edits(Vec<Value>),
+}
+
+/// Make the change to the file at `path`, returning how many hunks didn't
+/// match exactly
+fn patch_file(
+    path: &Path,
+    relative: &Path,
+    change: &Change,
+    fuzz: usize,
+    options: &WriteOptions,
+) -> Result<usize, (Code, String)> {
+    if let Change::Patch(patch) = change {
+        patch.path().map_err(|e| (Code::Process, e))?;
+    }
+    // Patches can't reach outside the tree they're applied to
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err((
+            Code::Process,
+            "it's outside the current directory".to_string(),
+        ));
+    }
+    let content = fs::read_to_string(path).map_err(|e| (Code::Read, e.to_string()))?;
+    let patched = match change {
+        Change::Patch(patch) => patch.apply(&content, fuzz),
+        Change::Edits(edits) => lsp::edits(&content, edits)
+            .and_then(|edits| edits::apply(&content, edits))
+            .map(|content| Patched {
+                content,
+                inexact: 0,
+            }),
+    };
+    let patched = patched.map_err(|e| (Code::Conflict, e))?;
+    write::write_atomic(path, patched.content.as_bytes(), options)
+        .map_err(|e| (Code::Write, e.to_string()))?;
+    Ok(patched.inexact)
+}
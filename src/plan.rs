@@ -0,0 +1,430 @@
+use crate::journal::{self, Journal};
+use crate::rename::{self, Rename};
+use crate::report::{self, Code};
+use crate::write::{self, AtomicFile, Attribute, WriteOptions};
+use crate::{interrupt, plural};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// The version of the plan format, for rejecting plans it can't apply
+const VERSION: u64 = 1;
+
+/// The changes a run would make, saved by `newtext plan` so they can be
+/// reviewed and made later by `newtext apply`
+///
+/// A plan is a JSON file listing every change, with the full new content of
+/// each file and a hash of the content it replaces. Paths are relative to the
+/// directory the plan was made in, so a plan can be applied to another
+/// checkout of the same tree.
+#[derive(Debug)]
+pub struct Plan {
+    root: PathBuf,
+    changes: Mutex<Vec<Value>>,
+    /// Whether the new content of files is kept, rather than just their paths
+    content: bool,
+}
+
+impl Plan {
+    /// An empty plan for a run in `root`
+    pub fn new(root: &Path) -> Plan {
+        Plan {
+            root: root.to_path_buf(),
+            changes: Mutex::new(Vec::new()),
+            content: true,
+        }
+    }
+
+    /// An empty plan that only notes the paths that would change, to find
+    /// out what a run would do without holding every file's new content
+    ///
+    /// It can't be saved or applied.
+    pub fn paths_only(root: &Path) -> Plan {
+        Plan {
+            content: false,
+            ..Plan::new(root)
+        }
+    }
+
+    /// Record that the file at `path` would get `content`
+    pub fn record_write(&self, path: &Path, content: &[u8]) {
+        if !self.content {
+            self.push(json!({"op": "write", "path": self.relative(path)}));
+            return;
+        }
+        let (size, hash) = journal::stamp(path).unzip();
+        let mut change = json!({
+            "op": "write",
+            "path": self.relative(path),
+            "size": size,
+            "hash": hash,
+        });
+        // Text is kept readable for review, anything else is encoded
+        match std::str::from_utf8(content) {
+            Ok(text) => change["content"] = text.into(),
+            Err(_) => change["content_base64"] = BASE64.encode(content).into(),
+        }
+        self.push(change);
+    }
+
+    /// Record that the symlink at `path` would be repointed to `new_target`
+    pub fn record_symlink(&self, path: &Path, old_target: &Path, new_target: &Path) {
+        self.push(json!({
+            "op": "symlink",
+            "path": self.relative(path),
+            "target": old_target.to_string_lossy(),
+            "new_target": new_target.to_string_lossy(),
+        }));
+    }
+
+    /// Record that `from` would be renamed to `to`
+    pub fn record_rename(&self, from: &Path, to: &Path) {
+        self.push(json!({
+            "op": "rename",
+            "from": self.relative(from),
+            "to": self.relative(to),
+        }));
+    }
+
+    /// How many changes are planned
+    pub fn len(&self) -> usize {
+        self.changes.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.lock().unwrap().is_empty()
+    }
+
+    /// Write the plan to `file`, with the options needed to apply it
+    pub fn save(&self, file: &Path, args: Vec<String>, options: &WriteOptions) -> io::Result<()> {
+        let names = |attributes: &[Attribute]| -> Vec<String> {
+            attributes
+                .iter()
+                .filter_map(|attribute| Some(attribute.to_possible_value()?.get_name().to_string()))
+                .collect()
+        };
+        let plan = json!({
+            "newtext_plan": VERSION,
+            "args": args,
+            "dir": self.root.to_string_lossy(),
+            "preserve": names(&options.preserve),
+            "preserve_if_possible": names(&options.preserve_if_possible),
+            "fsync": options.fsync,
+            "changes": *self.changes.lock().unwrap(),
+        });
+        fs::write(file, format!("{:#}\n", plan))
+    }
+
+    /// Make the planned changes, once they've been confirmed. Returns how
+    /// many were made, how many were skipped as their files changed in the
+    /// meantime, and how many failed.
+    pub fn apply(&self, options: &WriteOptions) -> (usize, usize, usize) {
+        let options = WriteOptions {
+            plan: None,
+            ..options.clone()
+        };
+        apply_changes(&self.root, &self.changes.lock().unwrap(), &options)
+    }
+
+    /// The paths and planned content of the files the plan writes
+    pub fn writes(&self) -> Vec<(PathBuf, Vec<u8>)> {
+        let changes = self.changes.lock().unwrap();
+        changes
+            .iter()
+            .filter(|change| change["op"] == "write")
+            .filter_map(|change| {
+                let path = self.root.join(change["path"].as_str()?);
+                let content = content(change, &path).ok()?;
+                Some((path, content))
+            })
+            .collect()
+    }
+
+    /// Every path the plan changes, in the order they were planned
+    pub fn paths(&self) -> Vec<String> {
+        let changes = self.changes.lock().unwrap();
+        changes
+            .iter()
+            .filter_map(|change| change["path"].as_str().or(change["from"].as_str()))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Make every planned change, or none of them
+    ///
+    /// New content is written to temporary files first, so most problems are
+    /// found before anything changes. If a change still can't be made, the
+    /// ones already made are rolled back through the journal.
+    pub fn commit(&self, options: &WriteOptions) -> Result<(), String> {
+        let journal = options
+            .journal
+            .as_ref()
+            .ok_or("changes can't be rolled back without the journal")?;
+        let options = WriteOptions {
+            plan: None,
+            ..options.clone()
+        };
+        let changes = self.changes.lock().unwrap();
+
+        let mut staged = Vec::new();
+        for change in changes.iter() {
+            if let Some(path) = changed_since(&self.root, change) {
+                return Err(format!("{} changed during the run", path.display()));
+            }
+            if change["op"] == "write" {
+                let path = self.root.join(change["path"].as_str().unwrap_or_default());
+                let content = content(change, &path)?;
+                let file = AtomicFile::create(&path, &options)
+                    .and_then(|mut file| {
+                        file.write_all(&content)?;
+                        Ok(file)
+                    })
+                    .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+                staged.push((path, file));
+            }
+        }
+
+        let result = make_staged(&self.root, &changes, staged, &options);
+        if result.is_err() {
+            let reverted = journal.roll_back()?;
+            info!("Rolled back {}", plural(reverted, "change"));
+        }
+        result
+    }
+
+    fn push(&self, change: Value) {
+        self.changes.lock().unwrap().push(change);
+    }
+
+    /// `path` relative to the root, or as it is if it's outside the root
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Make the changes saved in the plan `file`, to the files under `root`
+///
+/// Files that have changed since the plan was made are skipped, as their
+/// planned content would undo the changes. Returns how many changes weren't
+/// made, whether they were skipped or failed.
+pub fn apply(root: &Path, file: &Path, journal: Option<Arc<Journal>>) -> Result<usize, String> {
+    let plan = fs::read_to_string(file).map_err(|e| e.to_string())?;
+    let plan: Value = serde_json::from_str(&plan).map_err(|e| e.to_string())?;
+    match plan["newtext_plan"].as_u64() {
+        Some(VERSION) => {}
+        Some(version) => return Err(format!("plan version {} isn't supported", version)),
+        None => return Err("not a newtext plan".to_string()),
+    }
+
+    let attributes = |key: &str| -> Vec<Attribute> {
+        plan[key]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|name| Attribute::from_str(name.as_str()?, false).ok())
+            .collect()
+    };
+    let options = WriteOptions {
+        preserve: attributes("preserve"),
+        preserve_if_possible: attributes("preserve_if_possible"),
+        fsync: plan["fsync"].as_bool().unwrap_or(false),
+        journal,
+        ..WriteOptions::default()
+    };
+
+    let changes = plan["changes"].as_array().map_or(&[][..], Vec::as_slice);
+    // Plans can't reach outside the tree they're applied to
+    let outside = changes
+        .iter()
+        .flat_map(|change| ["path", "from", "to"].map(|name| change[name].as_str()))
+        .flatten()
+        .find(|path| {
+            !Path::new(path)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        });
+    if let Some(path) = outside {
+        return Err(format!(
+            "{} is outside the directory the plan is applied to",
+            path
+        ));
+    }
+    let (applied, stale, failed) = apply_changes(root, changes, &options);
+    if interrupt::is_interrupted() {
+        info!(
+            "Stopped after applying {} from {}",
+            plural(applied, "change"),
+            file.display()
+        );
+        if let Some(journal) = &options.journal {
+            journal.record_interrupted().map_err(|e| e.to_string())?;
+        }
+        return Ok(stale + failed);
+    }
+
+    info!(
+        "Applied {} from {}",
+        plural(applied, "change"),
+        file.display()
+    );
+    if stale > 0 {
+        info!(
+            "Skipped {} to files that have changed since the plan was made",
+            plural(stale, "change")
+        );
+    }
+    Ok(stale + failed)
+}
+
+/// Make planned changes, skipping those to files that have changed since the
+/// plan was made. Returns how many were made, how many were skipped and how
+/// many failed.
+fn apply_changes(root: &Path, changes: &[Value], options: &WriteOptions) -> (usize, usize, usize) {
+    let mut applied = 0;
+    let mut stale = 0;
+    let mut failed = 0;
+    let mut renames = Vec::new();
+    for change in changes {
+        if interrupt::is_interrupted() {
+            return (applied, stale, failed);
+        }
+        if let Some(path) = changed_since(root, change) {
+            report::add_to(
+                options.problems.as_deref(),
+                Code::Stale,
+                &path,
+                "it has changed since the plan was made",
+            );
+            stale += 1;
+            continue;
+        }
+        match apply_change(root, change, options, &mut renames) {
+            Ok(true) => applied += 1,
+            Ok(false) => {}
+            Err(e) => {
+                warn!("{}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    // Renames go last, as the other changes are to paths from before them
+    let expected = renames.len();
+    let renamed = rename::apply(renames, root, options);
+    applied += renamed;
+    failed += expected - renamed;
+    (applied, stale, failed)
+}
+
+/// The path a planned change is to, if it's no longer as it was when the plan
+/// was made
+///
+/// Renames are checked when they're made, as they must not overwrite anything.
+fn changed_since(root: &Path, change: &Value) -> Option<PathBuf> {
+    let path = root.join(change["path"].as_str()?);
+    let unchanged = match change["op"].as_str()? {
+        "write" => {
+            let recorded = change["size"].as_u64().zip(change["hash"].as_u64());
+            journal::stamp(&path) == recorded
+        }
+        "symlink" => {
+            let target = change["target"].as_str()?;
+            fs::read_link(&path).is_ok_and(|current| current == Path::new(target))
+        }
+        _ => true,
+    };
+    (!unchanged).then_some(path)
+}
+
+/// Commit the staged writes, then make the rest of the changes in a plan,
+/// stopping at the first that fails
+fn make_staged(
+    root: &Path,
+    changes: &[Value],
+    staged: Vec<(PathBuf, AtomicFile)>,
+    options: &WriteOptions,
+) -> Result<(), String> {
+    for (path, file) in staged {
+        if interrupt::is_interrupted() {
+            return Err("Interrupted".to_string());
+        }
+        file.commit()
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    }
+
+    let mut renames = Vec::new();
+    for change in changes.iter().filter(|change| change["op"] != "write") {
+        apply_change(root, change, options, &mut renames)?;
+    }
+    let expected = renames.len();
+    if rename::apply(renames, root, options) < expected {
+        return Err("Could not make every rename".to_string());
+    }
+    Ok(())
+}
+
+/// Make one change from a plan, or add it to `renames` if it's a rename.
+/// Returns whether the change was made.
+fn apply_change(
+    root: &Path,
+    change: &Value,
+    options: &WriteOptions,
+    renames: &mut Vec<Rename>,
+) -> Result<bool, String> {
+    let path_field = |name: &str| -> Result<PathBuf, String> {
+        change[name]
+            .as_str()
+            .map(|path| root.join(path))
+            .ok_or_else(|| format!("plan entry is missing {}", name))
+    };
+
+    match change["op"].as_str() {
+        Some("write") => {
+            let path = path_field("path")?;
+            let content = content(change, &path)?;
+            write::write_atomic(&path, &content, options)
+                .map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+            Ok(true)
+        }
+        Some("symlink") => {
+            let path = path_field("path")?;
+            let new_target = change["new_target"]
+                .as_str()
+                .ok_or("plan entry is missing new_target")?;
+            write::replace_symlink(&path, Path::new(new_target), options)
+                .map_err(|e| format!("Could not repoint {}: {}", path.display(), e))?;
+            Ok(true)
+        }
+        Some("rename") => {
+            renames.push(Rename {
+                from: path_field("from")?,
+                to: path_field("to")?,
+            });
+            Ok(false)
+        }
+        _ => Err(format!("unknown plan entry {}", change)),
+    }
+}
+
+/// The new content of the file at `path` in a planned write
+fn content(change: &Value, path: &Path) -> Result<Vec<u8>, String> {
+    match (
+        change["content"].as_str(),
+        change["content_base64"].as_str(),
+    ) {
+        (Some(text), _) => Ok(text.as_bytes().to_vec()),
+        (None, Some(encoded)) => BASE64
+            .decode(encoded)
+            .map_err(|e| format!("Invalid content for {}: {}", path.display(), e)),
+        (None, None) => Err("plan entry is missing content".to_string()),
+    }
+}
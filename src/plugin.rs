@@ -0,0 +1,145 @@
+use crate::hook;
+use regex::Captures;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Stdio};
+use std::sync::{Mutex, OnceLock};
+
+/// A program in any language that computes replacements, run by the shell
+/// and spoken to in JSON lines over its stdin and stdout.
+///
+/// For each match, the plugin is sent a line such as
+/// `{"matched":"v1","captures":["v1","1"],"path":"/work/src/a.txt","line":3}`,
+/// with the file's absolute path, and answers with a line of its own: `{"replacement":"v2"}`,
+/// `{"replacement":null}` to leave the match unchanged, or `{"error":"..."}`
+/// to leave the whole file as it is. Leaving matches unchanged lets a plugin
+/// given a broad pattern decide what matches as well as what it's replaced
+/// with.
+///
+/// Files are processed on several threads at once, so each thread talks to
+/// a process of its own, started the first time one's needed.
+pub struct Plugin {
+    command: String,
+    /// The processes not in use by a thread
+    idle: Mutex<Vec<Process>>,
+    /// Why a process stopped answering, after which the plugin isn't started
+    /// again
+    failed: OnceLock<String>,
+}
+
+/// A running plugin
+struct Process {
+    child: Child,
+    /// Closed when the process is done with, telling the plugin to exit
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Plugin {
+    /// Start the plugin run by `command`, to find out straight away if it
+    /// can't be
+    pub fn start(command: &str) -> Result<Plugin, String> {
+        let plugin = Plugin {
+            command: command.to_string(),
+            idle: Mutex::new(Vec::new()),
+            failed: OnceLock::new(),
+        };
+        let process = plugin.spawn()?;
+        plugin.idle.lock().unwrap().push(process);
+        Ok(plugin)
+    }
+
+    /// Ask the plugin for the replacement of a single match
+    ///
+    /// `line` is the 1-based line number where the match starts. Capture
+    /// groups that did not participate in the match are sent as null.
+    pub fn replace(
+        &self,
+        caps: &Captures,
+        path: &Path,
+        line: usize,
+    ) -> Result<Option<String>, String> {
+        let request = json!({
+            "matched": caps.get(0).map_or("", |m| m.as_str()),
+            "captures": caps.iter().map(|c| c.map(|m| m.as_str())).collect::<Vec<_>>(),
+            "path": path.display().to_string(),
+            "line": line,
+        });
+
+        if let Some(e) = self.failed.get() {
+            return Err(e.clone());
+        }
+        let idle = self.idle.lock().unwrap().pop();
+        let mut process = match idle {
+            Some(process) => process,
+            None => self.spawn()?,
+        };
+        let response = match process.ask(&request) {
+            Ok(response) => response,
+            // A process that failed to answer is left to exit, as it could be
+            // part way through a line
+            Err(e) => {
+                let e = format!("plugin `{}` {}", self.command, e);
+                return Err(self.failed.get_or_init(|| e).clone());
+            }
+        };
+        self.idle.lock().unwrap().push(process);
+
+        if let Some(error) = response.get("error") {
+            return Err(format!(
+                "plugin error: {}",
+                error
+                    .as_str()
+                    .map_or_else(|| error.to_string(), str::to_string)
+            ));
+        }
+        match response.get("replacement") {
+            Some(Value::String(replacement)) => Ok(Some(replacement.clone())),
+            Some(Value::Null) => Ok(None),
+            _ => Err(format!(
+                "plugin `{}` should answer with a replacement that's a string or null, not {}",
+                self.command, response
+            )),
+        }
+    }
+
+    fn spawn(&self) -> Result<Process, String> {
+        let mut child = hook::shell(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Could not start plugin `{}`: {}", self.command, e))?;
+        let stdin = child.stdin.take();
+        let stdout = BufReader::new(child.stdout.take().expect("piped stdout"));
+        Ok(Process {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+impl Process {
+    /// Send `request` as a line, and read the line answering it
+    fn ask(&mut self, request: &Value) -> Result<Value, String> {
+        let stdin = self.stdin.as_mut().expect("open until dropped");
+        writeln!(stdin, "{}", request)
+            .and_then(|()| stdin.flush())
+            .map_err(|e| format!("could not be written to: {}", e))?;
+        let mut answer = String::new();
+        match self.stdout.read_line(&mut answer) {
+            Ok(0) => Err("exited without answering".to_string()),
+            Ok(_) => serde_json::from_str(&answer)
+                .map_err(|e| format!("answered with invalid JSON: {}", e)),
+            Err(e) => Err(format!("could not be read from: {}", e)),
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        drop(self.stdin.take());
+        let _ = self.child.wait();
+    }
+}
@@ -0,0 +1,167 @@
+//! --po, which limits replacements in gettext PO and POT files to their
+//! source strings or to their translations
+//!
+//! Files are scanned a line at a time, and only the strings that change are
+//! written back, so comments, fuzzy flags, contexts and plural forms stay as
+//! they were. A string wrapped over several lines is replaced within as a
+//! whole.
+
+use crate::scope::{Escape, Segment};
+use clap::ValueEnum;
+use std::ops::Range;
+
+/// Which strings of PO files are replaced within, with --po
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Part {
+    /// Only the source strings, `msgid` and `msgid_plural`
+    Msgid,
+    /// Only the translations, `msgstr` and `msgstr[N]`
+    Msgstr,
+    /// Both, but not contexts or comments
+    Both,
+}
+
+/// The keyword a string belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Context,
+    Id,
+    Plural,
+    Translation,
+}
+
+/// A string, which can be wrapped over several lines
+struct Message {
+    field: Field,
+    /// The quoted pieces of the string, with their quotes
+    pieces: Vec<Range<usize>>,
+    text: String,
+}
+
+/// The strings in the PO file `text` that `part` covers
+pub fn segments(text: &str, part: Part) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    // Whether the entry being read has a context, and whether it's the
+    // header, whose msgstr is the file's metadata rather than a translation
+    let (mut context, mut header) = (false, false);
+    for message in read(text)? {
+        match message.field {
+            Field::Context => context = true,
+            Field::Id => header = !context && message.text.is_empty(),
+            Field::Plural | Field::Translation => {}
+        }
+        let wanted = match message.field {
+            Field::Context => false,
+            Field::Id | Field::Plural => part != Part::Msgstr,
+            Field::Translation => part != Part::Msgid && !header,
+        };
+        if message.field == Field::Translation {
+            context = false;
+        }
+        if wanted {
+            let (first, last) = (
+                &message.pieces[0],
+                &message.pieces[message.pieces.len() - 1],
+            );
+            segments.push(Segment {
+                range: first.start..last.end,
+                text: message.text,
+                escape: Escape::Po {
+                    wrapped: message.pieces.len() > 1,
+                },
+            });
+        }
+    }
+    Ok(segments)
+}
+
+/// The strings in `text`, in order
+fn read(text: &str) -> Result<Vec<Message>, String> {
+    let mut messages: Vec<Message> = Vec::new();
+    // Whether the last line was part of a string, which a quoted line
+    // carries on
+    let mut in_message = false;
+    let (mut at, mut line) = (0, 1);
+    while at < text.len() {
+        let line_end = text[at..].find('\n').map_or(text.len(), |end| at + end + 1);
+        let content = text[at..line_end].trim_end_matches(['\n', '\r']);
+        let start = at + content.len() - content.trim_start().len();
+        let trimmed = content.trim();
+
+        if trimmed.starts_with('"') && in_message {
+            let message = messages.last_mut().expect("a string is being read");
+            let (range, decoded) = string(text, start, line)?;
+            message.pieces.push(range);
+            message.text.push_str(&decoded);
+        } else {
+            let keyword = trimmed
+                .split_once([' ', '\t'])
+                .map_or(trimmed, |(keyword, _)| keyword);
+            let field = match keyword {
+                "msgctxt" => Some(Field::Context),
+                "msgid" => Some(Field::Id),
+                "msgid_plural" => Some(Field::Plural),
+                _ if keyword == "msgstr" || is_plural_translation(keyword) => {
+                    Some(Field::Translation)
+                }
+                // Comments, flags, obsolete entries and blank lines
+                _ => None,
+            };
+            in_message = field.is_some();
+            if let Some(field) = field {
+                let after = start + keyword.len();
+                let quote = text[after..line_end]
+                    .find('"')
+                    .map(|quote| after + quote)
+                    .ok_or_else(|| error(&format!("{} has no string", keyword), line))?;
+                let (range, decoded) = string(text, quote, line)?;
+                messages.push(Message {
+                    field,
+                    pieces: vec![range],
+                    text: decoded,
+                });
+            }
+        }
+        at = line_end;
+        line += 1;
+    }
+    Ok(messages)
+}
+
+/// Whether `keyword` is `msgstr[N]`, one of the plural forms of a
+/// translation
+fn is_plural_translation(keyword: &str) -> bool {
+    keyword
+        .strip_prefix("msgstr[")
+        .and_then(|rest| rest.strip_suffix(']'))
+        .is_some_and(|index| !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// The quoted string starting at `start`, and what it says
+fn string(text: &str, start: usize, line: usize) -> Result<(Range<usize>, String), String> {
+    let mut decoded = String::new();
+    let mut chars = text[start + 1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((start..start + 1 + i + 1, decoded)),
+            '\\' => match chars.next() {
+                Some((_, 'n')) => decoded.push('\n'),
+                Some((_, 't')) => decoded.push('\t'),
+                Some((_, 'r')) => decoded.push('\r'),
+                Some((_, 'a')) => decoded.push('\x07'),
+                Some((_, 'b')) => decoded.push('\x08'),
+                Some((_, 'f')) => decoded.push('\x0c'),
+                Some((_, 'v')) => decoded.push('\x0b'),
+                Some((_, escaped)) if escaped != '\n' => decoded.push(escaped),
+                _ => break,
+            },
+            '\n' => break,
+            c => decoded.push(c),
+        }
+    }
+    Err(error("a string isn't closed", line))
+}
+
+fn error(why: &str, line: usize) -> String {
+    format!("it isn't a valid PO file, {} on line {}", why, line)
+}
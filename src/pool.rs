@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+/// Run `work` on each of `items` across `jobs` threads, passing the results
+/// to `done` on this thread in the order of the items
+///
+/// Handing results back in order keeps runs deterministic, as if the items
+/// were worked on one at a time. `done` returns whether to carry on: once it
+/// returns false no more items are started, but those already started are
+/// still passed to it.
+///
+/// A slow item holds up the results of the ones after it, so up to `window`
+/// items can be started after the oldest one that isn't done. That lets the
+/// other threads carry on meanwhile, while bounding how many results are
+/// held.
+pub fn run<T, R>(
+    jobs: usize,
+    window: usize,
+    items: impl Iterator<Item = T>,
+    work: impl Fn(&T) -> R + Sync,
+    mut done: impl FnMut(T, R) -> bool,
+) where
+    T: Send,
+    R: Send,
+{
+    if jobs <= 1 {
+        for item in items {
+            let result = work(&item);
+            if !done(item, result) {
+                break;
+            }
+        }
+        return;
+    }
+
+    let window = window.max(jobs);
+    let (job_tx, job_rx) = mpsc::sync_channel::<(usize, T)>(window);
+    let job_rx = Mutex::new(job_rx);
+    let (result_tx, result_rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let (job_rx, work, result_tx) = (&job_rx, &work, result_tx.clone());
+            scope.spawn(move || loop {
+                let job = job_rx.lock().unwrap().recv();
+                let (index, item) = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                // A panic is passed on to be raised again on the main thread,
+                // rather than leaving it waiting for the result
+                let result = panic::catch_unwind(AssertUnwindSafe(|| work(&item)));
+                if result_tx.send((index, item, result)).is_err() {
+                    break;
+                }
+            });
+        }
+
+        let mut items = items.fuse();
+        let mut started = 0;
+        let mut next = 0;
+        let mut finished = BTreeMap::new();
+        let mut carry_on = true;
+        loop {
+            while carry_on && started - next < window {
+                match items.next() {
+                    Some(item) => {
+                        // There's room in the channel for the whole window
+                        job_tx.send((started, item)).unwrap();
+                        started += 1;
+                    }
+                    None => break,
+                }
+            }
+            if next == started {
+                break;
+            }
+
+            let (index, item, result) = result_rx.recv().unwrap();
+            finished.insert(index, (item, result));
+            while let Some((item, result)) = finished.remove(&next) {
+                next += 1;
+                let result = result.unwrap_or_else(|payload| panic::resume_unwind(payload));
+                if !done(item, result) {
+                    carry_on = false;
+                }
+            }
+        }
+        // Lets the threads finish
+        drop(job_tx);
+    });
+}
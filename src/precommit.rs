@@ -0,0 +1,219 @@
+//! `newtext hook`, which checks the files about to be committed against the
+//! rules in the config files, for the pre-commit framework or git's own
+//! pre-commit hook
+
+use crate::config::{self, Rule};
+use crate::{args, EXIT_ERROR};
+use ignore::overrides::{Override, OverrideBuilder};
+use newtext::contents::Contents;
+use newtext::journal::Journal;
+use newtext::matcher::Matcher;
+use newtext::report::{self, Code};
+use newtext::write::{self, WriteOptions};
+use newtext::{display_path, text};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// A rule, ready to be checked
+struct Check {
+    rule: Rule,
+    matcher: Matcher,
+    /// The files it applies to, or None for every file
+    paths: Option<Override>,
+}
+
+/// Check `paths`, or the files staged in git if there are none, against the
+/// rules in `config_file`, or in the config files if it isn't given
+///
+/// Replace rules fix the files they match, and deny rules report where they
+/// match. Either way the hook exits with status 1, so that the commit stops
+/// to be looked at again.
+pub fn run(current_dir: &Path, paths: Vec<PathBuf>, config_file: Option<PathBuf>) {
+    let config = match &config_file {
+        Some(file) => config::load_file(file),
+        None => config::load(current_dir),
+    };
+    let rules = config.and_then(|config| config.rules());
+    let checks = rules.and_then(|rules| {
+        rules
+            .into_iter()
+            .map(|rule| check(current_dir, rule))
+            .collect::<Result<Vec<_>, _>>()
+    });
+    let checks = match checks {
+        Ok(checks) if checks.is_empty() => {
+            warn!("There are no [rules] in the config files to check");
+            return;
+        }
+        Ok(checks) => checks,
+        Err(e) => {
+            error!("{}", e);
+            exit(EXIT_ERROR);
+        }
+    };
+    let paths = if paths.is_empty() {
+        match staged() {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("Could not list the staged files: {}", e);
+                exit(EXIT_ERROR);
+            }
+        }
+    } else {
+        paths
+    };
+
+    let options = WriteOptions {
+        journal: Some(Arc::new(Journal::new(current_dir, args()))),
+        ..WriteOptions::default()
+    };
+    let github = env::var_os("GITHUB_ACTIONS").is_some_and(|var| var == "true");
+    let (mut denied, mut fixed) = (0, 0);
+    for path in &paths {
+        // Binary files are left out, as they are when replacing
+        let content = match Contents::read_unless_binary(path) {
+            Ok(Some(contents)) => String::from_utf8(contents.to_vec()),
+            Ok(None) => continue,
+            Err(e) => {
+                report::add(Code::Read, path, e);
+                continue;
+            }
+        };
+        let Ok(mut content) = content else {
+            report::add(Code::Encoding, path, "it isn't UTF-8");
+            continue;
+        };
+        let shown = display_path(path, current_dir);
+
+        let mut changed = false;
+        for check in checks.iter().filter(|check| check.applies_to(path)) {
+            let Some(new) = &check.rule.new else {
+                continue;
+            };
+            let replaced = text::replace_all(&content, &check.matcher, &check.rule.old, new);
+            if replaced == content {
+                continue;
+            }
+            for start in check.matcher.starts(&content) {
+                let message = check
+                    .rule
+                    .message
+                    .clone()
+                    .unwrap_or_else(|| format!("Replaced with {}", new));
+                annotate(&shown, &content, start, &check.rule.name, &message, github);
+            }
+            changed = true;
+            content = replaced;
+        }
+        for check in checks.iter().filter(|check| check.applies_to(path)) {
+            if check.rule.new.is_some() {
+                continue;
+            }
+            for start in check.matcher.starts(&content) {
+                let message = check
+                    .rule
+                    .message
+                    .clone()
+                    .unwrap_or_else(|| format!("{} isn't allowed", check.rule.old));
+                annotate(&shown, &content, start, &check.rule.name, &message, github);
+                denied += 1;
+            }
+        }
+
+        if changed {
+            match write::write_atomic(path, content.as_bytes(), &options) {
+                Ok(()) => fixed += 1,
+                Err(e) => report::add(Code::Write, path, e),
+            }
+        }
+    }
+
+    report::print();
+    if fixed > 0 {
+        info!("Fixed {} files, which need to be staged again", fixed);
+    }
+    if denied > 0 {
+        info!("Found {} matches of rules that deny them", denied);
+    }
+    if fixed > 0 || denied > 0 {
+        exit(1);
+    }
+}
+
+fn check(current_dir: &Path, rule: Rule) -> Result<Check, String> {
+    let matcher = Matcher::new(&rule.old, rule.pattern, rule.ignore_case)
+        .map_err(|e| format!("Invalid regex pattern in rule {}: {}", rule.name, e))?;
+    let paths = match rule.paths.is_empty() {
+        true => None,
+        false => {
+            let mut paths = OverrideBuilder::new(current_dir);
+            for glob in &rule.paths {
+                paths
+                    .add(glob)
+                    .map_err(|e| format!("Invalid glob '{}' in rule {}: {}", glob, rule.name, e))?;
+            }
+            Some(paths.build().map_err(|e| e.to_string())?)
+        }
+    };
+    Ok(Check {
+        rule,
+        matcher,
+        paths,
+    })
+}
+
+impl Check {
+    fn applies_to(&self, path: &Path) -> bool {
+        self.paths
+            .as_ref()
+            .is_none_or(|paths| paths.matched(path, false).is_whitelist())
+    }
+}
+
+/// The files staged in git, leaving out those being deleted
+fn staged() -> Result<Vec<PathBuf>, String> {
+    let output = Command::new("git")
+        .args([
+            "diff",
+            "--cached",
+            "--name-only",
+            "-z",
+            "--diff-filter=ACMR",
+        ])
+        .output()
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    // git gives the paths relative to the top of the working tree
+    let top = Command::new("git")
+        .args(["rev-parse", "--show-toplevel"])
+        .output()
+        .map_err(|e| e.to_string())?;
+    let top = PathBuf::from(String::from_utf8_lossy(&top.stdout).trim());
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .map(|path| top.join(path))
+        .collect())
+}
+
+/// Print where a rule matched, as `path:line:column: rule: message`, or as a
+/// workflow command that GitHub Actions shows on the line
+fn annotate(path: &str, content: &str, start: usize, rule: &str, message: &str, github: bool) {
+    let before = &content[..start];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let column = before[line_start..].chars().count() + 1;
+    if github {
+        println!(
+            "::error file={},line={},col={},title={}::{}",
+            path, line, column, rule, message
+        );
+    } else {
+        println!("{}:{}:{}: {}: {}", path, line, column, rule, message);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::encoding::{Encoding, Fallback, UTF16BE_BOM, UTF16LE_BOM};
+use aho_corasick::AhoCorasick;
+use memchr::memmem::Finder;
+use regex_syntax::hir::literal::{ExtractKind, Extractor, Seq};
+
+/// Beyond this many literals, scanning for them is hardly quicker than
+/// running the regex
+const MAX_LITERALS: usize = 64;
+
+/// A quick scan of a file's bytes for literals that every match contains,
+/// like ripgrep's, to rule out most files without decoding them or running
+/// the regex engine
+pub struct Prefilter {
+    search: Search,
+    /// Whether the literals are ASCII, so they're the same bytes in every
+    /// encoding that extends it
+    ascii: bool,
+    /// Whether content that isn't UTF-8 is read as UTF-16, which spreads the
+    /// literals' bytes out
+    utf16_fallback: bool,
+}
+
+enum Search {
+    /// OLD itself, in literal mode
+    Literal(Box<Finder<'static>>),
+    /// Each match starts (or ends) with one of these
+    AnyOf(AhoCorasick),
+}
+
+impl Prefilter {
+    /// For replacing `old` literally and case-sensitively
+    pub fn literal(old: &str, fallback: Fallback) -> Option<Prefilter> {
+        if old.is_empty() {
+            return None;
+        }
+        Some(Prefilter {
+            search: Search::Literal(Box::new(Finder::new(old.as_bytes()).into_owned())),
+            ascii: old.is_ascii(),
+            utf16_fallback: is_utf16(fallback),
+        })
+    }
+
+    /// For content that matches if any of `patterns` does, or None if some
+    /// pattern has no literals that its matches must contain
+    pub fn patterns<'a>(
+        patterns: impl IntoIterator<Item = &'a str>,
+        fallback: Fallback,
+    ) -> Option<Prefilter> {
+        let mut literals = Vec::new();
+        for pattern in patterns {
+            literals.extend(required_literals(pattern)?);
+        }
+        literals.sort_unstable();
+        literals.dedup();
+        if literals.is_empty() || literals.len() > MAX_LITERALS {
+            return None;
+        }
+
+        let search = AhoCorasick::new(&literals).ok()?;
+        Some(Prefilter {
+            search: Search::AnyOf(search),
+            ascii: literals.iter().all(|literal| literal.is_ascii()),
+            utf16_fallback: is_utf16(fallback),
+        })
+    }
+
+    /// Whether `bytes` certainly contain no match
+    pub fn rules_out(&self, bytes: &[u8]) -> bool {
+        // UTF-16, which might not have a BOM, but has NULs in ASCII text
+        if bytes.starts_with(UTF16LE_BOM)
+            || bytes.starts_with(UTF16BE_BOM)
+            || memchr::memchr(0, bytes).is_some()
+        {
+            return false;
+        }
+
+        let found = match &self.search {
+            Search::Literal(finder) => finder.find(bytes).is_some(),
+            Search::AnyOf(search) => search.is_match(bytes),
+        };
+        // Other literals are only known to be missing from UTF-8
+        !found && ((self.ascii && !self.utf16_fallback) || std::str::from_utf8(bytes).is_ok())
+    }
+}
+
+/// Literals one of which starts, or one of which ends, every match of
+/// `pattern`, whichever are longer
+fn required_literals(pattern: &str) -> Option<Vec<Vec<u8>>> {
+    let hir = regex_syntax::parse(pattern).ok()?;
+    let usable = |mut seq: Seq| {
+        seq.dedup();
+        // An empty literal is in every content
+        seq.min_literal_len()
+            .filter(|&len| len > 0)
+            .map(|len| (len, seq))
+    };
+    let prefixes = usable(Extractor::new().kind(ExtractKind::Prefix).extract(&hir));
+    let suffixes = usable(Extractor::new().kind(ExtractKind::Suffix).extract(&hir));
+    let (_, seq) = match (prefixes, suffixes) {
+        (Some(prefixes), Some(suffixes)) if suffixes.0 > prefixes.0 => suffixes,
+        (Some(prefixes), _) => prefixes,
+        (None, suffixes) => suffixes?,
+    };
+    let literals = seq.literals()?;
+    Some(literals.iter().map(|l| l.as_bytes().to_vec()).collect())
+}
+
+fn is_utf16(fallback: Fallback) -> bool {
+    matches!(
+        fallback,
+        Fallback::Fixed(Encoding::Utf16Le | Encoding::Utf16Be)
+    )
+}
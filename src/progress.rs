@@ -0,0 +1,204 @@
+use clap::ValueEnum;
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde_json::{json, Value};
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How far a run has got
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub files_processed: u64,
+    pub directories_traversed: u64,
+    pub files_modified: u64,
+    pub failures: u64,
+    /// The last path walked, in the sorted order of the walk
+    pub last: Option<PathBuf>,
+}
+
+impl Progress {
+    /// The counts, as printed once the walk is done. Dry runs only say
+    /// which files would be modified.
+    pub fn summary(&self, dry_run: bool) -> String {
+        format!(
+            "Files: {}, Dirs: {}, {}: {}",
+            self.files_processed,
+            self.directories_traversed,
+            if dry_run { "Would modify" } else { "Modified" },
+            self.files_modified
+        )
+    }
+
+    /// The progress as saved in a checkpoint
+    pub fn to_json(&self) -> Value {
+        json!({
+            "last": self.last.as_ref().map(|last| last.to_string_lossy()),
+            "files": self.files_processed,
+            "dirs": self.directories_traversed,
+            "modified": self.files_modified,
+            "failures": self.failures,
+        })
+    }
+
+    /// The progress saved in a checkpoint
+    pub fn from_json(checkpoint: &Value) -> Progress {
+        Progress {
+            files_processed: checkpoint["files"].as_u64().unwrap_or(0),
+            directories_traversed: checkpoint["dirs"].as_u64().unwrap_or(0),
+            files_modified: checkpoint["modified"].as_u64().unwrap_or(0),
+            failures: checkpoint["failures"].as_u64().unwrap_or(0),
+            last: checkpoint["last"].as_str().map(PathBuf::from),
+        }
+    }
+}
+
+/// How progress is shown while a run is going
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// A progress bar, when stderr is a terminal
+    Bar,
+    /// A JSON object on stderr every so often, and once the walk is done
+    Json,
+}
+
+/// How often progress is printed as JSON
+const JSON_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the bar is updated. Updating it for every file would slow down
+/// runs over many small files.
+const BAR_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Progress shown as the run goes: a bar on stderr with the files done out of
+/// those counted so far, how fast they're being read and how long is left, or
+/// JSON objects with the same counts for other programs to show
+///
+/// The bar is hidden when stderr isn't a terminal.
+pub struct Indicator {
+    bar: ProgressBar,
+    json: bool,
+    bytes: u64,
+    started: Instant,
+    last_update: Option<Instant>,
+}
+
+impl Indicator {
+    /// An indicator starting from `progress`, for a resumed run, or one
+    /// showing nothing if there's no `format`
+    pub fn new(progress: &Progress, format: Option<Format>) -> Indicator {
+        let json = format == Some(Format::Json);
+        let target = if format == Some(Format::Bar) {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        };
+        let bar = ProgressBar::with_draw_target(Some(progress.files_processed), target);
+        bar.set_style(
+            ProgressStyle::with_template("{wide_bar} {pos}/{len} files, {msg}, ETA {eta}").unwrap(),
+        );
+        bar.set_position(progress.files_processed);
+        bar.set_message(message(progress, 0));
+        Indicator {
+            bar,
+            json,
+            bytes: 0,
+            started: Instant::now(),
+            last_update: None,
+        }
+    }
+
+    /// Count the files left on another thread, for the total, as the
+    /// iterator made by `files` finds them
+    pub fn count<I: Iterator>(&self, files: impl FnOnce() -> I + Send + 'static) {
+        if !self.is_shown() {
+            return;
+        }
+        let bar = self.bar.clone();
+        thread::spawn(move || {
+            for _ in files() {
+                bar.inc_length(1);
+            }
+        });
+    }
+
+    /// Show `progress` once a file of `size` bytes is done, which is only
+    /// looked up when progress is shown
+    pub fn update(&mut self, progress: &Progress, size: impl FnOnce() -> u64) {
+        if !self.is_shown() {
+            return;
+        }
+        self.bytes += size();
+        let interval = if self.json {
+            JSON_INTERVAL
+        } else {
+            BAR_INTERVAL
+        };
+        if self
+            .last_update
+            .is_some_and(|last| last.elapsed() < interval)
+        {
+            return;
+        }
+        self.last_update = Some(Instant::now());
+
+        self.bar.set_position(progress.files_processed);
+        if self.json {
+            self.print_json(progress, false);
+            return;
+        }
+        let secs = self.started.elapsed().as_secs_f64().max(0.001);
+        let rate = (self.bytes as f64 / secs) as u64;
+        self.bar.set_message(message(progress, rate));
+    }
+
+    /// Take the bar down and print the final counts in its place
+    pub fn finish(&self, progress: &Progress, dry_run: bool) {
+        self.bar.set_position(progress.files_processed);
+        if self.json {
+            self.print_json(progress, true);
+            return;
+        }
+        self.bar.finish_and_clear();
+        eprintln!("{}", progress.summary(dry_run));
+    }
+
+    fn is_shown(&self) -> bool {
+        self.json || !self.bar.is_hidden()
+    }
+
+    /// Print the counts as a line of JSON. The total is of the files found so
+    /// far, until `done`.
+    fn print_json(&self, progress: &Progress, done: bool) {
+        let line = json!({
+            "files": progress.files_processed,
+            "total": self.bar.length(),
+            "dirs": progress.directories_traversed,
+            "modified": progress.files_modified,
+            "failures": progress.failures,
+            "bytes": self.bytes,
+            "elapsed_ms": self.started.elapsed().as_millis() as u64,
+            "done": done,
+        });
+        eprintln!("{}", line);
+    }
+}
+
+/// What to start a line on stderr with so it replaces the bar, left out when
+/// stderr isn't a terminal so logs don't fill with control characters
+pub fn clear_line() -> &'static str {
+    if io::stderr().is_terminal() {
+        "\x1b[2K\r"
+    } else {
+        ""
+    }
+}
+
+/// The counts shown after the bar, with `rate` in bytes a second
+fn message(progress: &Progress, rate: u64) -> String {
+    format!(
+        "{} dirs, {} modified, {}/s",
+        progress.directories_traversed,
+        progress.files_modified,
+        HumanBytes(rate)
+    )
+}
@@ -0,0 +1,52 @@
+//! Reviewdog's diagnostic format, rdjson, for CI to comment on the lines
+//! that a run would change
+
+use crate::edits::{self, Edit};
+use serde_json::{json, Value};
+
+/// A diagnostic result with a suggestion for each change to the files,
+/// given with their paths and their old and new text
+///
+/// Lines and columns count from 1, and columns count bytes, as rdjson's do.
+pub fn diagnostics(files: &[(&str, &str, &str)]) -> Value {
+    let mut diagnostics = Vec::new();
+    for (path, old, new) in files {
+        for edit in edits::edits(old, new) {
+            diagnostics.push(diagnostic(path, old, &edit));
+        }
+    }
+    json!({
+        "source": { "name": "newtext" },
+        "severity": "WARNING",
+        "diagnostics": diagnostics,
+    })
+}
+
+fn diagnostic(path: &str, old: &str, edit: &Edit) -> Value {
+    let removed = &old[edit.start..edit.end];
+    let message = match (removed.is_empty(), edit.text.is_empty()) {
+        (true, _) => format!("Insert \"{}\"", edit.text),
+        (false, true) => format!("Remove \"{}\"", removed),
+        (false, false) => format!("Replace \"{}\" with \"{}\"", removed, edit.text),
+    };
+    let range = json!({
+        "start": position(old, edit.start),
+        "end": position(old, edit.end),
+    });
+    json!({
+        "message": message,
+        "location": { "path": path, "range": range },
+        "severity": "WARNING",
+        "suggestions": [{ "range": range, "text": edit.text }],
+    })
+}
+
+/// The rdjson position of a byte offset in `text`
+fn position(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    json!({
+        "line": before.matches('\n').count() + 1,
+        "column": offset - line_start + 1,
+    })
+}
@@ -0,0 +1,205 @@
+use crate::report::{self, Code};
+use crate::write::{parent_dir, sync_dir, WriteOptions};
+use crate::{display_path, rewrite_content, Replacement};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::info;
+
+/// A file or directory whose name matched, and what to rename it to
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// The path `path` would be renamed to, if its name matches
+///
+/// Only the last component is replaced, parent directories get their own
+/// renames.
+pub fn renamed(path: &Path, replacement: &Replacement) -> io::Result<Option<PathBuf>> {
+    let name = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name,
+        // Only UTF-8 names can be matched
+        None => return Ok(None),
+    };
+
+    match rewrite_content(name, path, replacement)? {
+        Some(new_name) if new_name != name => {
+            if new_name.is_empty() || new_name.contains(['/', '\0']) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("can't rename {} to {:?}", name, new_name),
+                ));
+            }
+            Ok(Some(path.with_file_name(new_name)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Describe every rename that would overwrite an existing path, or the result
+/// of another rename
+///
+/// On case-insensitive filesystems, names that differ only by case count as
+/// the same. Renames that only change the case of a name are fine there.
+pub fn collisions(renames: &[Rename], case_insensitive: bool) -> Vec<String> {
+    let key = |path: &Path| {
+        if case_insensitive {
+            PathBuf::from(path.to_string_lossy().to_lowercase())
+        } else {
+            path.to_path_buf()
+        }
+    };
+
+    let mut targets = HashMap::new();
+    let mut collisions = Vec::new();
+    for rename in renames {
+        if let Some(other) = targets.insert(key(&rename.to), &rename.from) {
+            collisions.push(format!(
+                "{} and {} would both be renamed to {}",
+                other.display(),
+                rename.from.display(),
+                rename.to.display()
+            ));
+        } else if target_taken(rename) {
+            collisions.push(format!(
+                "{} would be renamed to {}, which already exists",
+                rename.from.display(),
+                rename.to.display()
+            ));
+        }
+    }
+    collisions
+}
+
+/// Whether the filesystem at `dir` treats names differing only by case as the
+/// same, found by creating a temporary file there
+pub fn is_case_insensitive(dir: &Path) -> bool {
+    let probe = tempfile::Builder::new()
+        .prefix(".newtext-case-")
+        .suffix(".tmp")
+        .tempfile_in(dir);
+    match probe {
+        Ok(probe) => {
+            let name = probe.path().file_name().unwrap().to_string_lossy();
+            fs::symlink_metadata(dir.join(name.to_uppercase())).is_ok()
+        }
+        // Assume the platform's usual filesystem
+        Err(_) => cfg!(any(target_os = "macos", target_os = "windows")),
+    }
+}
+
+/// Whether something other than the renamed file is already at the new path
+fn target_taken(rename: &Rename) -> bool {
+    match fs::symlink_metadata(&rename.to) {
+        // A case-insensitive filesystem finds the file itself when only the
+        // case of its name changes
+        Ok(_) => !is_same_file(&rename.from, &rename.to),
+        Err(_) => false,
+    }
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+}
+
+/// Carry out renames, deepest paths first so that the paths of later renames
+/// are still valid
+///
+/// The renames should already have been checked for collisions. Any path
+/// that has appeared since is still not overwritten. Returns how many
+/// renames were made, which are logged relative to `root`.
+pub fn apply(mut renames: Vec<Rename>, root: &Path, options: &WriteOptions) -> usize {
+    renames.sort_by_key(|rename| std::cmp::Reverse(rename.from.components().count()));
+
+    let mut renamed = 0;
+    for rename in renames {
+        if target_taken(&rename) {
+            let message = format!("{} already exists", display_path(&rename.to, root));
+            report::add_to(
+                options.problems.as_deref(),
+                Code::RenameTaken,
+                &rename.from,
+                message,
+            );
+            continue;
+        }
+
+        let result = rename_path(&rename.from, &rename.to).and_then(|()| {
+            if let Some(journal) = &options.journal {
+                journal.record_rename(&rename.from, &rename.to)?;
+            }
+            if options.fsync {
+                sync_dir(parent_dir(&rename.to))?;
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                info!(
+                    "Renamed {} to {}",
+                    display_path(&rename.from, root),
+                    display_path(&rename.to, root)
+                );
+                renamed += 1;
+            }
+            Err(e) => report::add_to(options.problems.as_deref(), Code::Rename, &rename.from, e),
+        }
+    }
+    renamed
+}
+
+/// Rename a path, through `git mv` if it's tracked so that the index follows
+/// the rename rather than showing a deletion and an untracked file
+pub fn rename_path(from: &Path, to: &Path) -> io::Result<()> {
+    if !is_tracked(from) {
+        return fs::rename(from, to);
+    }
+
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(parent_dir(from))
+        .args(["mv", "--"])
+        .arg(from.file_name().unwrap())
+        .arg(to.file_name().unwrap())
+        .stdin(Stdio::null())
+        .output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "git mv failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// Whether git tracks `path`, or any file under it for a directory
+///
+/// This is false outside a repository or when git isn't installed.
+fn is_tracked(path: &Path) -> bool {
+    Command::new("git")
+        .arg("-C")
+        .arg(parent_dir(path))
+        .args(["ls-files", "--error-unmatch", "--"])
+        .arg(path.file_name().unwrap())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
@@ -0,0 +1,279 @@
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tracing::warn;
+
+/// The problems with particular files found during a run, kept to be
+/// reported together at the end rather than scrolling away between progress
+/// updates
+static PROBLEMS: Mutex<Vec<Problem>> = Mutex::new(Vec::new());
+
+/// Whether the report is printed as JSON lines
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// How many of the paths with the same problem are listed
+const SAMPLE: usize = 10;
+
+/// How the report is printed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Warnings grouped by problem
+    Text,
+    /// One JSON object per line for each problem, with its code
+    Json,
+}
+
+/// The kinds of problem, each with a stable code for scripts to check
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Code {
+    /// The file couldn't be read
+    Read,
+    /// The new content couldn't be written
+    Write,
+    /// The replacement failed, e.g. a script error or a missing map key
+    Process,
+    /// The file isn't in a known encoding, so it was skipped
+    Encoding,
+    /// The file is binary, so it was skipped
+    BinarySkip,
+    /// The --format-cmd formatter failed on the file
+    Format,
+    /// The file couldn't be renamed
+    Rename,
+    /// The file wasn't renamed, as its new name is taken
+    RenameTaken,
+    /// The file has changed since the plan was made, so it wasn't changed
+    Stale,
+    /// The file couldn't be checked by `newtext check --idempotent`
+    Check,
+    /// The patch doesn't match the file, so it wasn't changed
+    Conflict,
+}
+
+impl Code {
+    pub fn name(self) -> &'static str {
+        match self {
+            Code::Read => "E_READ",
+            Code::Write => "E_WRITE",
+            Code::Process => "E_PROCESS",
+            Code::Encoding => "E_ENCODING",
+            Code::BinarySkip => "E_BINARY_SKIP",
+            Code::Format => "E_FORMAT",
+            Code::Rename => "E_RENAME",
+            Code::RenameTaken => "E_RENAME_TAKEN",
+            Code::Stale => "E_STALE",
+            Code::Check => "E_CHECK",
+            Code::Conflict => "E_CONFLICT",
+        }
+    }
+
+    /// What couldn't be done, to start the warning with
+    fn action(self) -> &'static str {
+        match self {
+            Code::Read => "Could not read",
+            Code::Write => "Could not write",
+            Code::Process => "Could not process",
+            Code::Encoding | Code::BinarySkip => "Skipped",
+            Code::Format => "Could not format",
+            Code::Rename => "Could not rename",
+            Code::RenameTaken => "Not renaming",
+            Code::Stale => "Not changing",
+            Code::Check => "Could not check",
+            Code::Conflict => "Not patching",
+        }
+    }
+}
+
+/// A problem with a file, which was left as it was
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Problem {
+    pub code: Code,
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl Problem {
+    fn to_json(&self, root: &Path) -> Value {
+        json!({
+            "code": self.code.name(),
+            "path": self.path.strip_prefix(root).unwrap_or(&self.path).display().to_string(),
+            "message": self.message,
+        })
+    }
+}
+
+/// Choose how the report is printed
+pub fn set_format(format: Format) {
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+/// Problems collected apart from the run's report, by a call of its own, so
+/// that calls made at the same time don't take each other's
+#[derive(Debug, Default)]
+pub struct Problems(Mutex<Vec<Problem>>);
+
+impl Problems {
+    /// Take the problems noted so far, as [`take`] does
+    pub fn take(&self) -> Vec<Problem> {
+        sorted(std::mem::take(&mut *self.0.lock().unwrap()))
+    }
+
+    /// Take the problems noted so far, as [`take_json`] does
+    pub fn take_json(&self, root: &Path) -> Vec<Value> {
+        self.take()
+            .iter()
+            .map(|problem| problem.to_json(root))
+            .collect()
+    }
+}
+
+/// Note a problem with `path`, for the report
+pub fn add(code: Code, path: &Path, message: impl Display) {
+    add_to(None, code, path, message);
+}
+
+/// Note a problem with `path` in `problems`, or for the report if there are
+/// none to note it in
+pub fn add_to(problems: Option<&Problems>, code: Code, path: &Path, message: impl Display) {
+    let message = message.to_string();
+    // Logged as it's found, though only printed with the rest
+    warn!(
+        code = code.name(),
+        path = %path.display(),
+        "{} {}: {}",
+        code.action(),
+        path.display(),
+        message
+    );
+    let problem = Problem {
+        code,
+        path: path.to_path_buf(),
+        message,
+    };
+    match problems {
+        Some(problems) => problems.0.lock().unwrap().push(problem),
+        None => PROBLEMS.lock().unwrap().push(problem),
+    }
+}
+
+/// Note the path in an error from walking the files as one that couldn't be
+/// read, in `problems` as [`add_to`] does, returning whether there was one.
+/// Errors about no path in particular, such as a bad ignore file, are only
+/// warned about.
+pub fn add_walk_error(problems: Option<&Problems>, e: &ignore::Error) -> bool {
+    match walk_problem(e) {
+        Some((path, message)) => {
+            add_to(problems, Code::Read, path, message);
+            true
+        }
+        None => {
+            warn!("{}", e);
+            false
+        }
+    }
+}
+
+/// The path that an error from walking the files couldn't read, and why
+pub fn walk_problem(e: &ignore::Error) -> Option<(&Path, String)> {
+    let io_error = e.io_error()?;
+    let path = walk_error_path(e)?;
+    // The walk's errors name the path again, so the error they wrap is
+    // reported where there is one
+    let message = match io_error.get_ref().and_then(|e| e.source()) {
+        Some(source) => source.to_string(),
+        None => io_error.to_string(),
+    };
+    Some((path, message))
+}
+
+/// The path that an error from the walk is about
+fn walk_error_path(e: &ignore::Error) -> Option<&Path> {
+    match e {
+        ignore::Error::WithPath { path, .. } => Some(path),
+        ignore::Error::WithDepth { err, .. } | ignore::Error::WithLineNumber { err, .. } => {
+            walk_error_path(err)
+        }
+        _ => None,
+    }
+}
+
+/// Print the problems noted so far, and forget them
+///
+/// As text, the paths that had the same problem are grouped, and skipped
+/// binary files are left out as skipping them is expected.
+pub fn print() {
+    let current_dir = env::current_dir().unwrap_or_default();
+    let display = |path: &Path| {
+        path.strip_prefix(&current_dir)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    };
+    let problems = take();
+
+    if JSON.load(Ordering::Relaxed) {
+        for problem in &problems {
+            eprintln!("{}", problem.to_json(&current_dir));
+        }
+        return;
+    }
+
+    // Groups are in the order of their first path
+    let mut groups: Vec<(Code, &str, Vec<&Path>)> = Vec::new();
+    let mut group_index = HashMap::new();
+    for problem in problems
+        .iter()
+        .filter(|problem| problem.code != Code::BinarySkip)
+    {
+        let key = (problem.code, problem.message.as_str());
+        let index = *group_index.entry(key).or_insert_with(|| {
+            groups.push((problem.code, &problem.message, Vec::new()));
+            groups.len() - 1
+        });
+        groups[index].2.push(&problem.path);
+    }
+
+    for (code, message, paths) in groups {
+        if let [path] = paths[..] {
+            eprintln!("Warning: {} {}: {}", code.action(), display(path), message);
+            continue;
+        }
+        eprintln!(
+            "Warning: {} {} files: {}",
+            code.action(),
+            paths.len(),
+            message
+        );
+        for path in paths.iter().take(SAMPLE) {
+            eprintln!("  {}", display(path));
+        }
+        if paths.len() > SAMPLE {
+            eprintln!("  and {} more", paths.len() - SAMPLE);
+        }
+    }
+}
+
+/// Take the problems noted so far as JSON objects, with their paths relative
+/// to `root`, and forget them
+pub fn take_json(root: &Path) -> Vec<Value> {
+    take().iter().map(|problem| problem.to_json(root)).collect()
+}
+
+/// Take the problems noted so far, in order of their paths and without
+/// repeats, and forget them
+pub fn take() -> Vec<Problem> {
+    sorted(std::mem::take(&mut *PROBLEMS.lock().unwrap()))
+}
+
+fn sorted(mut problems: Vec<Problem>) -> Vec<Problem> {
+    // Files are processed in parallel, so problems are found in any order
+    problems.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut seen = HashSet::new();
+    problems.retain(|problem| seen.insert(problem.clone()));
+    problems
+}
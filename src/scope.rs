@@ -0,0 +1,571 @@
+//! Scopes, which limit replacements to the parts of files that matter, such
+//! as the values at some paths in JSON documents, leaving the rest of each
+//! file exactly as it was
+
+use crate::csv_column::{self, Csv};
+use crate::key_value::{self, Keys};
+use crate::notebook::{self, Cells};
+use crate::po;
+use crate::selector::{self, Selector};
+use crate::{json_path, markdown, office, toml_key, yaml_path};
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+/// What a run's replacements are limited to
+#[derive(Clone, Debug)]
+pub enum Scope {
+    /// The string values at any of the paths in JSON files, with --json-path
+    JsonPath(Vec<KeyPath>),
+    /// The scalar values at any of the paths in YAML files, with --yaml-path
+    YamlPath(Vec<KeyPath>),
+    /// The string values at any of the keys in TOML files, with --toml-key
+    TomlKey(Vec<KeyPath>),
+    /// The text or attribute values of the elements any of the selectors
+    /// match in XML and HTML files, with --selector and --xpath
+    Markup(Vec<Selector>),
+    /// The fields in some columns of CSV and TSV files, with --csv-column
+    CsvColumn(Csv),
+    /// The prose or the code of Markdown files, with --markdown
+    Markdown(markdown::Part),
+    /// The front matter at the top of Markdown files, with --front-matter
+    FrontMatter,
+    /// The values of some keys in `.env`, INI and `.properties` files, with
+    /// --key
+    Key(Keys),
+    /// The sources of some cells of Jupyter notebooks, with
+    /// --notebook-cells, and of all of them without any other scope
+    Notebook(Cells),
+    /// The text runs of Word, Excel and PowerPoint documents, with --office
+    Office,
+    /// The source strings or the translations of gettext PO files, with --po
+    Po(po::Part),
+    /// All of `.properties` files, with the escapes in their keys and values
+    /// read, as they are without any other scope
+    Properties,
+}
+
+/// A part of a file that's replaced within
+pub struct Segment {
+    /// Where it is in the file, including any quotes around it
+    pub range: Range<usize>,
+    /// What it says, with any escapes taken out
+    pub text: String,
+    /// How new text is written in its place
+    pub escape: Escape,
+}
+
+/// How the text of a segment is written in a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Escape {
+    /// As it is
+    None,
+    /// As a JSON string, in double quotes, which YAML reads the same way
+    Json,
+    /// As a plain YAML scalar, or in double quotes if it can't be one
+    YamlPlain,
+    /// In single quotes, or in double quotes if it has several lines
+    YamlSingle,
+    /// As the lines of a YAML block scalar, indented by this many spaces
+    /// after the first, which is where the old first line was
+    YamlBlock(usize),
+    /// As a TOML literal string, in single quotes, or in double quotes if it
+    /// can't be one
+    TomlLiteral,
+    /// As a TOML multi-line string, with a newline after the opening quotes
+    /// if there was one
+    TomlMultiLine { literal: bool, newline: bool },
+    /// As text between XML or HTML tags, as it is but for `<`
+    MarkupText,
+    /// As the inside of a CDATA section
+    Cdata,
+    /// As XML text, with `&`, `<` and `>` written as entities
+    XmlText,
+    /// As an attribute's value, inside this quote, or in double quotes if it
+    /// had none and has to have them now
+    MarkupAttribute(Option<char>),
+    /// As a CSV field, in quotes if it was or if it has to be now
+    Csv {
+        delimiter: char,
+        quote: char,
+        quoted: bool,
+    },
+    /// As a `.env` value, inside this quote, or in double quotes if it had
+    /// none and has to have them now
+    Env(Option<char>),
+    /// As a `.properties` value, with line breaks, leading spaces and
+    /// backslashes escaped, and other characters as `\u` escapes if
+    /// `unicode` is set
+    Properties { unicode: bool },
+    /// As a `.properties` key, escaped as a value is, and with the
+    /// characters that would end it escaped too
+    PropertiesKey { unicode: bool },
+    /// As a PO string, in double quotes, or as one quoted line after another
+    /// if it was wrapped, a line break at a time
+    Po { wrapped: bool },
+    /// As it is, without being replaced within, as a line --ensure adds
+    Added,
+}
+
+impl Escape {
+    pub(crate) fn escape(self, text: &str) -> String {
+        match self {
+            Escape::None | Escape::Added => text.to_string(),
+            Escape::Json => json_string(text),
+            Escape::YamlPlain if is_plain_safe(text) => text.to_string(),
+            Escape::YamlSingle if !text.contains('\n') => format!("'{}'", text.replace('\'', "''")),
+            Escape::YamlPlain | Escape::YamlSingle => json_string(text),
+            Escape::YamlBlock(indent) => {
+                let indent = " ".repeat(indent);
+                text.split('\n')
+                    .enumerate()
+                    .map(|(i, line)| match i {
+                        0 => line.to_string(),
+                        _ if line.is_empty() => String::new(),
+                        _ => format!("{}{}", indent, line),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+            Escape::TomlLiteral if !text.contains(|c: char| c == '\'' || is_toml_control(c)) => {
+                format!("'{}'", text)
+            }
+            Escape::TomlLiteral => json_string(text),
+            Escape::TomlMultiLine { literal, newline } => {
+                let newline = if newline { "\n" } else { "" };
+                let literal = literal
+                    && !text.contains("'''")
+                    && !text.contains(|c| c != '\n' && is_toml_control(c));
+                if literal {
+                    format!("'''{}{}'''", newline, text)
+                } else {
+                    let mut escaped = String::with_capacity(text.len());
+                    for (i, c) in text.char_indices() {
+                        match c {
+                            '\\' => escaped.push_str("\\\\"),
+                            // So that no three quotes in a row end the string,
+                            // and no quote at the end runs into the closing ones
+                            '"' if escaped.ends_with("\"\"") || i + 1 == text.len() => {
+                                escaped.push_str("\\\"")
+                            }
+                            c if c != '\n' && is_toml_control(c) => {
+                                escaped.push_str(&format!("\\u{:04X}", c as u32))
+                            }
+                            c => escaped.push(c),
+                        }
+                    }
+                    format!("\"\"\"{}{}\"\"\"", newline, escaped)
+                }
+            }
+            Escape::MarkupText => text.replace('<', "&lt;"),
+            Escape::Cdata => text.replace("]]>", "]]]]><![CDATA[>"),
+            Escape::XmlText => text
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;"),
+            Escape::MarkupAttribute(Some('\'')) => text.replace('\'', "&#39;"),
+            Escape::MarkupAttribute(Some(_)) => text.replace('"', "&quot;"),
+            Escape::MarkupAttribute(None)
+                if text.is_empty()
+                    || text.contains(|c: char| c.is_whitespace() || "\"'=<>`".contains(c)) =>
+            {
+                format!("\"{}\"", text.replace('"', "&quot;"))
+            }
+            Escape::MarkupAttribute(None) => text.to_string(),
+            Escape::Env(Some('\'')) if !text.contains(['\'', '\n']) => format!("'{}'", text),
+            Escape::Env(None)
+                if !text.starts_with(char::is_whitespace)
+                    && !text.ends_with(char::is_whitespace)
+                    && !text.contains(['"', '\'', '\\', '#', '\n', '\r', '`']) =>
+            {
+                text.to_string()
+            }
+            // Line breaks can be in double quotes as they are
+            Escape::Env(Some('"')) => {
+                format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Escape::Env(_) => format!(
+                "\"{}\"",
+                text.replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+                    .replace('\r', "\\r")
+            ),
+            Escape::Properties { unicode } => properties_string(text, unicode, false),
+            Escape::PropertiesKey { unicode } => properties_string(text, unicode, true),
+            Escape::Po { wrapped: false } => po_string(text),
+            Escape::Po { wrapped: true } => {
+                let mut lines = String::from("\"\"");
+                for line in text.split_inclusive('\n') {
+                    lines.push('\n');
+                    lines.push_str(&po_string(line));
+                }
+                lines
+            }
+            Escape::Csv {
+                delimiter,
+                quote,
+                quoted,
+            } => {
+                if quoted || text.contains([delimiter, quote, '\n', '\r']) {
+                    let doubled = format!("{}{}", quote, quote);
+                    format!("{}{}{}", quote, text.replace(quote, &doubled), quote)
+                } else {
+                    text.to_string()
+                }
+            }
+        }
+    }
+}
+
+/// Whether `c` has to be escaped in TOML strings, other than tabs
+fn is_toml_control(c: char) -> bool {
+    c != '\t' && c.is_ascii_control()
+}
+
+/// `text` as a `.properties` value, or as a key if `key` is set
+fn properties_string(text: &str, unicode: bool, key: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for (i, c) in text.char_indices() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\x0c' => escaped.push_str("\\f"),
+            ' ' if i == 0 || key => escaped.push_str("\\ "),
+            '=' | ':' | '#' | '!' if key => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if unicode && !c.is_ascii() => {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    escaped.push_str(&format!("\\u{:04X}", unit));
+                }
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `text` as a PO string, with C's escapes
+fn po_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\x07' => escaped.push_str("\\a"),
+            '\x08' => escaped.push_str("\\b"),
+            '\x0c' => escaped.push_str("\\f"),
+            '\x0b' => escaped.push_str("\\v"),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_string(text: &str) -> String {
+    serde_json::to_string(text).expect("strings serialize")
+}
+
+/// Whether `text` reads the same as a plain YAML scalar, in block or flow
+/// context, as it was written
+fn is_plain_safe(text: &str) -> bool {
+    let mut chars = text.chars();
+    let Some(first) = chars.next() else {
+        return false;
+    };
+    // `-`, `?` and `:` only start something else when a space follows
+    let indicator = match first {
+        '-' | '?' | ':' => chars.next().is_none_or(char::is_whitespace),
+        _ => ",[]{}#&*!|>'\"%@`".contains(first) || first.is_whitespace(),
+    };
+    !indicator
+        && !text.ends_with(|c: char| c.is_whitespace() || c == ':')
+        && !text.contains(": ")
+        && !text.contains(" #")
+        && !text.contains(|c: char| ",[]{}\n\t".contains(c))
+}
+
+impl Scope {
+    /// The parts of the file at `path` that can be replaced within, or None
+    /// if the scope doesn't cover files like it, which are left as they are
+    pub fn segments(&self, path: &Path, text: &str) -> Result<Option<Vec<Segment>>, String> {
+        match self {
+            Scope::JsonPath(paths) if has_extension(path, &["json", "jsonc"]) => {
+                json_path::segments(text, paths).map(Some)
+            }
+            Scope::YamlPath(paths) if has_extension(path, &["yaml", "yml"]) => {
+                yaml_path::segments(text, paths).map(Some)
+            }
+            Scope::TomlKey(paths) if has_extension(path, &["toml"]) => {
+                toml_key::segments(text, paths).map(Some)
+            }
+            Scope::Markup(selectors) if has_extension(path, MARKUP_EXTENSIONS) => {
+                let html = has_extension(path, &["html", "htm"]);
+                selector::segments(text, html, selectors).map(Some)
+            }
+            Scope::CsvColumn(csv) if has_extension(path, &["csv", "tsv"]) => {
+                let delimiter = csv.delimiter.unwrap_or(if has_extension(path, &["tsv"]) {
+                    '\t'
+                } else {
+                    ','
+                });
+                csv_column::segments(text, csv, delimiter).map(Some)
+            }
+            Scope::Markdown(part) if has_extension(path, MARKDOWN_EXTENSIONS) => {
+                Ok(Some(markdown::segments(text, *part)))
+            }
+            Scope::FrontMatter if has_extension(path, MARKDOWN_EXTENSIONS) => Ok(Some(
+                markdown::front_matter(text)
+                    .map(|range| Segment {
+                        text: text[range.clone()].to_string(),
+                        range,
+                        escape: Escape::None,
+                    })
+                    .into_iter()
+                    .collect(),
+            )),
+            Scope::Po(part) if has_extension(path, &["po", "pot"]) => {
+                po::segments(text, *part).map(Some)
+            }
+            Scope::JsonPath(_)
+            | Scope::YamlPath(_)
+            | Scope::TomlKey(_)
+            | Scope::Markup(_)
+            | Scope::CsvColumn(_)
+            | Scope::Markdown(_)
+            | Scope::FrontMatter
+            | Scope::Po(_) => Ok(None),
+            Scope::Notebook(cells) => Ok(notebook::is_notebook(path)
+                .then(|| notebook::segments(text, *cells))
+                .transpose()?),
+            Scope::Office => office::segments(path, text),
+            Scope::Properties => Ok((key_value::Format::of(path)
+                == Some(key_value::Format::Properties))
+            .then(|| key_value::properties_segments(text))),
+            Scope::Key(keys) => {
+                Ok(key_value::Format::of(path)
+                    .map(|format| key_value::segments(text, format, keys)))
+            }
+        }
+    }
+
+    /// Whether the scope can add to files that nothing in them matches
+    pub fn adds(&self) -> bool {
+        matches!(self, Scope::Key(keys) if keys.ensure.is_some())
+    }
+
+    /// Whether the text the scope replaces within is decoded or joined, so
+    /// that what matches it might not be in the file as it's written
+    pub fn decodes(&self) -> bool {
+        matches!(
+            self,
+            Scope::Office | Scope::Po(_) | Scope::Key(_) | Scope::Properties
+        )
+    }
+}
+
+/// The files --markdown and --front-matter look in
+const MARKDOWN_EXTENSIONS: &[&str] = &["md", "markdown", "mdown", "mkd"];
+
+/// The files --selector and --xpath look in
+const MARKUP_EXTENSIONS: &[&str] = &[
+    "html", "htm", "xhtml", "xml", "svg", "xsd", "xsl", "xslt", "wsdl", "rss", "atom", "plist",
+    "xaml", "csproj", "fsproj", "vbproj", "props", "targets", "nuspec", "resx",
+];
+
+/// Whether the file at `path` has any of `extensions`, ignoring case
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extensions
+                .iter()
+                .any(|wanted| extension.eq_ignore_ascii_case(wanted))
+        })
+}
+
+/// Replace within each of `segments` of `content` with `rewrite`, which is
+/// given a segment's text and the 1-based line it starts on. Returns None if
+/// no segment changed.
+pub fn rewrite(
+    content: &str,
+    segments: &[Segment],
+    mut rewrite: impl FnMut(&str, usize) -> io::Result<Option<String>>,
+) -> io::Result<Option<String>> {
+    let mut new_content = String::with_capacity(content.len());
+    let (mut at, mut counted_to, mut line) = (0, 0, 1);
+    let mut changed = false;
+    for segment in segments {
+        // Counted as it goes, so that large files stay linear
+        line += content[counted_to..segment.range.start]
+            .matches('\n')
+            .count();
+        counted_to = segment.range.start;
+        if segment.escape == Escape::Added {
+            new_content.push_str(&content[at..segment.range.start]);
+            new_content.push_str(&segment.text);
+            at = segment.range.end;
+            changed = true;
+            continue;
+        }
+        let Some(new_text) = rewrite(&segment.text, line)? else {
+            continue;
+        };
+        if new_text == segment.text {
+            continue;
+        }
+        new_content.push_str(&content[at..segment.range.start]);
+        new_content.push_str(&segment.escape.escape(&new_text));
+        at = segment.range.end;
+        changed = true;
+    }
+    if !changed {
+        return Ok(None);
+    }
+    new_content.push_str(&content[at..]);
+    Ok(Some(new_content))
+}
+
+/// The byte offsets in `content` of the `starts` of matches within the text
+/// of `segments`, which are the starts of the segments themselves where
+/// escapes make the text differ from the file's
+pub fn starts(segments: &[Segment], mut find: impl FnMut(&str) -> Vec<usize>) -> Vec<usize> {
+    segments
+        .iter()
+        .filter(|segment| segment.escape != Escape::Added)
+        .flat_map(|segment| {
+            find(&segment.text)
+                .into_iter()
+                .map(move |start| match segment.escape {
+                    // Markup is replaced within as it's written, entities and
+                    // all
+                    Escape::None
+                    | Escape::MarkupText
+                    | Escape::Cdata
+                    | Escape::MarkupAttribute(_) => segment.range.start + start,
+                    Escape::Csv { quoted: false, .. } | Escape::Env(None) => {
+                        segment.range.start + start
+                    }
+                    _ => segment.range.start,
+                })
+        })
+        .collect()
+}
+
+/// A pattern for the paths to values in a document, such as
+/// `$.dependencies.*`
+///
+/// A path matches the values at the paths it matches and everything inside
+/// them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyPath(pub(crate) Vec<Step>);
+
+/// A step along a `KeyPath`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Step {
+    /// The value with this key in a table or object
+    Key(String),
+    /// The value at this index in an array
+    Index(usize),
+    /// Any one value
+    Any,
+    /// Any number of values, including none, as `**` or JSONPath's `..`
+    AnyDepth,
+}
+
+/// A step on the path to a value in a document
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Key {
+    Name(String),
+    Index(usize),
+}
+
+impl KeyPath {
+    /// Parse a dotted path such as `spec.template.**`, `jobs.*.steps[0]` or
+    /// `"example.com".url`, with `*` for any one key and `**` for any number
+    pub fn parse(path: &str) -> Result<KeyPath, String> {
+        let invalid = |why: &str| format!("invalid path '{}': {}", path, why);
+        let mut steps = Vec::new();
+        let mut rest = path;
+        loop {
+            // A key, quoted if it has dots or brackets in it
+            let (key, after) = match rest.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let end = rest[1..]
+                        .find(quote)
+                        .ok_or_else(|| invalid("a quote isn't closed"))?;
+                    (Step::Key(rest[1..end + 1].to_string()), &rest[end + 2..])
+                }
+                Some('[') => return Err(invalid("a [ should follow a key")),
+                _ => {
+                    let end = rest.find(['.', '[']).unwrap_or(rest.len());
+                    let step = match &rest[..end] {
+                        "" => return Err(invalid("a key is empty")),
+                        "*" => Step::Any,
+                        "**" => Step::AnyDepth,
+                        key => Step::Key(key.to_string()),
+                    };
+                    (step, &rest[end..])
+                }
+            };
+            steps.push(key);
+            rest = after;
+
+            while let Some(inside) = rest.strip_prefix('[') {
+                let end = inside
+                    .find(']')
+                    .ok_or_else(|| invalid("a [ isn't closed"))?;
+                steps.push(match inside[..end].trim() {
+                    "*" => Step::Any,
+                    index => Step::Index(
+                        index
+                            .parse()
+                            .map_err(|_| invalid(&format!("[{}] isn't an index or *", index)))?,
+                    ),
+                });
+                rest = &inside[end + 1..];
+            }
+            match rest.strip_prefix('.') {
+                Some(after) => rest = after,
+                None if rest.is_empty() => return Ok(KeyPath(steps)),
+                None => return Err(invalid(&format!("expected a . before '{}'", rest))),
+            }
+        }
+    }
+
+    /// Whether the value at `path` is matched, or is inside one that is
+    pub fn covers(&self, path: &[Key]) -> bool {
+        covers(&self.0, path)
+    }
+}
+
+fn covers(steps: &[Step], path: &[Key]) -> bool {
+    let Some((step, rest)) = steps.split_first() else {
+        return true;
+    };
+    match (step, path.split_first()) {
+        (Step::AnyDepth, _) if covers(rest, path) => true,
+        (Step::AnyDepth, Some((_, inner))) => covers(steps, inner),
+        (Step::AnyDepth, None) => false,
+        (step, Some((key, inner))) => step_matches(step, key) && covers(rest, inner),
+        (_, None) => false,
+    }
+}
+
+fn step_matches(step: &Step, key: &Key) -> bool {
+    match (step, key) {
+        (Step::Any | Step::AnyDepth, _) => true,
+        (Step::Key(wanted), Key::Name(name)) => wanted == name,
+        (Step::Index(wanted), Key::Index(index)) => wanted == index,
+        _ => false,
+    }
+}
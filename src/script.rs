@@ -0,0 +1,73 @@
+use regex::Captures;
+use rhai::{Array, Dynamic, Engine, Scope, AST};
+use std::path::Path;
+
+/// A user-supplied Rhai script that computes replacements.
+///
+/// The script must define a function with the signature
+/// `replace(matched, captures, path, line)`. It is called once per match and
+/// returns the replacement string, or `()` to leave the match unchanged.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    /// Compile the script at `path` and check that it defines `replace`
+    pub fn load(path: &Path) -> Result<Script, String> {
+        let engine = Engine::new();
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| e.to_string())?;
+
+        let has_replace = ast
+            .iter_functions()
+            .any(|f| f.name == "replace" && f.params.len() == 4);
+        if !has_replace {
+            return Err("script must define fn replace(matched, captures, path, line)".to_string());
+        }
+
+        Ok(Script { engine, ast })
+    }
+
+    /// Run the script's `replace` function for a single match
+    ///
+    /// `line` is the 1-based line number where the match starts. Capture
+    /// groups that did not participate in the match are passed as `()`.
+    pub fn replace(
+        &self,
+        caps: &Captures,
+        path: &Path,
+        line: usize,
+    ) -> Result<Option<String>, String> {
+        let matched = caps.get(0).map(|m| m.as_str()).unwrap_or("").to_string();
+        let captures: Array = caps
+            .iter()
+            .map(|c| match c {
+                Some(m) => Dynamic::from(m.as_str().to_string()),
+                None => Dynamic::UNIT,
+            })
+            .collect();
+
+        let result: Dynamic = self
+            .engine
+            .call_fn(
+                &mut Scope::new(),
+                &self.ast,
+                "replace",
+                (matched, captures, path.display().to_string(), line as i64),
+            )
+            .map_err(|e| format!("script error: {}", e))?;
+
+        if result.is_unit() {
+            Ok(None)
+        } else if result.is_string() {
+            Ok(Some(result.cast::<String>()))
+        } else {
+            Err(format!(
+                "replace() must return a string or (), got {}",
+                result.type_name()
+            ))
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use regex::{Captures, Regex};
+
+/// Which matches on a line a substitution replaces
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Occurrences {
+    /// Only the Nth match (1-based), the default when N is 1
+    Nth(usize),
+    /// The Nth match and every one after it (`g`, `Ng`)
+    From(usize),
+}
+
+/// A single `s/pattern/replacement/flags` command
+struct Substitution {
+    regex: Regex,
+    replacement: String,
+    occurrences: Occurrences,
+}
+
+/// A parsed sed script made up of substitution commands
+///
+/// Like sed, commands are applied line by line: `^` and `$` anchor to the
+/// start and end of each line, and without the `g` flag only the first match
+/// on each line is replaced.
+pub struct SedScript {
+    substitutions: Vec<Substitution>,
+}
+
+impl SedScript {
+    /// Parse a script of `s` commands separated by `;` or newlines
+    ///
+    /// Patterns use POSIX basic regular expression syntax (`\(...\)` for
+    /// groups) unless `extended` is set, as with `sed -E`.
+    pub fn parse(script: &str, extended: bool, ignore_case: bool) -> Result<SedScript, String> {
+        let mut substitutions = Vec::new();
+        let mut chars = script.chars().peekable();
+
+        loop {
+            // Skip separators, whitespace and comments between commands
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ';' {
+                    chars.next();
+                } else if c == '#' {
+                    for c in chars.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                } else {
+                    break;
+                }
+            }
+
+            let command = match chars.next() {
+                Some(c) => c,
+                None => break,
+            };
+            if command != 's' {
+                return Err(format!(
+                    "unsupported sed command '{}' (only s/// is supported)",
+                    command
+                ));
+            }
+
+            let delimiter = match chars.next() {
+                Some(c) if c != '\n' && c != '\\' => c,
+                _ => return Err("missing delimiter after 's'".to_string()),
+            };
+            let pattern = read_part(&mut chars, delimiter)?;
+            let replacement = read_part(&mut chars, delimiter)?;
+
+            let mut global = false;
+            let mut nth = None;
+            let mut case_insensitive = ignore_case;
+            while let Some(&c) = chars.peek() {
+                match c {
+                    'g' => global = true,
+                    'i' | 'I' => case_insensitive = true,
+                    '0'..='9' => {
+                        let digit = c.to_digit(10).unwrap() as usize;
+                        nth = Some(nth.unwrap_or(0) * 10 + digit);
+                    }
+                    ';' | '\n' | '}' | '#' => break,
+                    c if c.is_whitespace() => break,
+                    c => return Err(format!("unsupported flag '{}' in s command", c)),
+                }
+                chars.next();
+            }
+
+            let n = match nth {
+                Some(0) => return Err("number flag in s command must be positive".to_string()),
+                Some(n) => n,
+                None => 1,
+            };
+            let occurrences = if global {
+                Occurrences::From(n)
+            } else {
+                Occurrences::Nth(n)
+            };
+
+            let pattern = if extended {
+                pattern
+            } else {
+                translate_bre(&pattern)
+            };
+            if pattern.is_empty() {
+                return Err("empty pattern in s command".to_string());
+            }
+            // The flag is kept in the pattern, for the prefilter to read
+            let pattern = if case_insensitive {
+                format!("(?i){}", pattern)
+            } else {
+                pattern
+            };
+            let regex = Regex::new(&pattern).map_err(|e| format!("invalid pattern: {}", e))?;
+
+            substitutions.push(Substitution {
+                regex,
+                replacement: translate_replacement(&replacement),
+                occurrences,
+            });
+        }
+
+        if substitutions.is_empty() {
+            return Err("script contains no commands".to_string());
+        }
+
+        Ok(SedScript { substitutions })
+    }
+
+    /// The pattern and replacement of each substitution, in order
+    pub fn substitutions(&self) -> impl Iterator<Item = (&Regex, &str)> {
+        self.substitutions
+            .iter()
+            .map(|s| (&s.regex, s.replacement.as_str()))
+    }
+
+    /// Whether any substitution can put a line break into its output
+    pub fn inserts_line_breaks(&self) -> bool {
+        self.substitutions
+            .iter()
+            .any(|s| s.replacement.contains(['\n', '\r']))
+    }
+
+    /// Apply every substitution to each line of `content` in turn
+    pub fn apply(&self, content: &str) -> String {
+        let mut result = String::with_capacity(content.len());
+
+        for line in content.split_inclusive('\n') {
+            let (text, newline) = match line.strip_suffix('\n') {
+                Some(text) => (text, "\n"),
+                None => (line, ""),
+            };
+
+            let mut text = text.to_string();
+            for substitution in &self.substitutions {
+                text = substitution.apply(&text);
+            }
+
+            result.push_str(&text);
+            result.push_str(newline);
+        }
+
+        result
+    }
+}
+
+impl Substitution {
+    fn apply(&self, line: &str) -> String {
+        let mut seen = 0;
+        self.regex
+            .replace_all(line, |caps: &Captures| {
+                seen += 1;
+                let selected = match self.occurrences {
+                    Occurrences::Nth(n) => seen == n,
+                    Occurrences::From(n) => seen >= n,
+                };
+
+                let mut expanded = String::new();
+                if selected {
+                    caps.expand(&self.replacement, &mut expanded);
+                } else {
+                    expanded.push_str(&caps[0]);
+                }
+                expanded
+            })
+            .to_string()
+    }
+}
+
+/// Read up to the next unescaped `delimiter`, unescaping `\<delimiter>`
+fn read_part(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    delimiter: char,
+) -> Result<String, String> {
+    let mut part = String::new();
+
+    while let Some(c) = chars.next() {
+        if c == delimiter {
+            return Ok(part);
+        }
+        if c == '\\' {
+            match chars.next() {
+                Some(next) if next == delimiter => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            }
+        } else {
+            part.push(c);
+        }
+    }
+
+    Err(format!("unterminated s command (missing '{}')", delimiter))
+}
+
+/// Convert a POSIX basic regular expression into the regex crate's syntax
+///
+/// In basic syntax `\(`, `\)`, `\{`, `\}`, `\+`, `\?` and `\|` are the
+/// operators while the bare characters are literals, which is the reverse of
+/// extended syntax.
+fn translate_bre(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next @ ('(' | ')' | '{' | '}' | '+' | '?' | '|')) => result.push(next),
+                Some(next) => {
+                    result.push('\\');
+                    result.push(next);
+                }
+                None => result.push_str("\\\\"),
+            },
+            '(' | ')' | '{' | '}' | '+' | '?' | '|' => {
+                result.push('\\');
+                result.push(c);
+            }
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Convert a sed replacement into the template syntax used by `Captures::expand`
+///
+/// `&` is the whole match and `\1`..`\9` are groups. A literal `$` has to be
+/// doubled so it isn't read as a group reference.
+fn translate_replacement(replacement: &str) -> String {
+    let mut result = String::new();
+    let mut chars = replacement.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => result.push_str("${0}"),
+            '$' => result.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(d @ '0'..='9') => {
+                    result.push_str("${");
+                    result.push(d);
+                    result.push('}');
+                }
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('$') => result.push_str("$$"),
+                Some(other) => result.push(other),
+                None => result.push('\\'),
+            },
+            c => result.push(c),
+        }
+    }
+
+    result
+}
@@ -0,0 +1,570 @@
+//! --selector and --xpath, which limit replacements to the text or the
+//! attribute values of some elements in XML and HTML files
+//!
+//! Both are read into the same chains of steps, each an element's name and
+//! what else it has to have, and matched against the documents that
+//! `markup` reads.
+
+use crate::markup::{self, Attribute, Document, Element, TextKind};
+use crate::scope::{Escape, Segment};
+
+/// Elements to replace within, matched by any one of several chains
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selector(Vec<Chain>);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Chain {
+    steps: Vec<Step>,
+    target: Target,
+}
+
+/// What's replaced within in the elements a chain matches
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Target {
+    /// The text inside them, however deep
+    Text,
+    /// The values of their attributes with these names, or of all of them
+    /// if there are none
+    Attributes(Vec<String>),
+}
+
+/// An element, and how it's related to the one the previous step matched
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Step {
+    /// Whether the element can be anywhere inside the previous step's, rather
+    /// than directly inside it. On the first step, whether the element can
+    /// be anywhere rather than only at the top.
+    descendant: bool,
+    /// The name of the element, or None for any element
+    name: Option<String>,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Condition {
+    /// The element has the attribute
+    Has(String),
+    /// The attribute's value compares with a value as it's written
+    Compare(String, Op, String),
+    /// The element is the nth, from 0, of those with its name in its parent,
+    /// or of all of them for any element
+    Position(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Equals,
+    /// `~=`, one of its words
+    Word,
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+/// Parse a CSS selector such as `a[href]`, `div.note > p` or `#main, nav`
+///
+/// Type, class, id and attribute selectors are understood, with descendant
+/// and child combinators, not pseudo-classes or sibling combinators. When
+/// the last part of a selector has attribute selectors, the values of those
+/// attributes are replaced within, and otherwise the elements' text is.
+pub fn parse_css(selector: &str) -> Result<Selector, String> {
+    let invalid = |why: &str| format!("invalid selector '{}': {}", selector, why);
+    let mut cursor = Cursor::new(selector, false);
+    let mut chains = Vec::new();
+    loop {
+        let mut steps = Vec::new();
+        let mut named = Vec::new();
+        let mut descendant = true;
+        loop {
+            let skipped = cursor.skip_space();
+            match cursor.peek() {
+                None | Some(',') if steps.is_empty() => return Err(invalid("a selector is empty")),
+                None | Some(',') => break,
+                Some('>') if steps.is_empty() || !descendant => {
+                    return Err(invalid("a > should be between two selectors"))
+                }
+                Some('>') => {
+                    cursor.next();
+                    descendant = false;
+                    continue;
+                }
+                Some(c @ ('+' | '~')) => {
+                    return Err(invalid(&format!("the {} combinator isn't supported", c)))
+                }
+                _ if !steps.is_empty() && !skipped && descendant => {
+                    return Err(invalid(&format!("unexpected '{}'", cursor.rest())))
+                }
+                _ => {}
+            }
+
+            let star = cursor.eat('*');
+            let name = if star { None } else { cursor.ident() };
+            let mut conditions = Vec::new();
+            named.clear();
+            loop {
+                match cursor.peek() {
+                    Some('.') => {
+                        cursor.next();
+                        let class = cursor
+                            .ident()
+                            .ok_or_else(|| invalid("a . should be followed by a class"))?;
+                        conditions.push(Condition::Compare("class".to_string(), Op::Word, class));
+                    }
+                    Some('#') => {
+                        cursor.next();
+                        let id = cursor
+                            .ident()
+                            .ok_or_else(|| invalid("a # should be followed by an id"))?;
+                        conditions.push(Condition::Compare("id".to_string(), Op::Equals, id));
+                    }
+                    Some('[') => {
+                        cursor.next();
+                        let condition = css_attribute(&mut cursor).map_err(|why| invalid(&why))?;
+                        named.push(match &condition {
+                            Condition::Has(name) | Condition::Compare(name, ..) => name.clone(),
+                            Condition::Position(_) => unreachable!("not written in CSS"),
+                        });
+                        conditions.push(condition);
+                    }
+                    Some(':') => {
+                        return Err(invalid(&format!(
+                            "pseudo-classes such as '{}' aren't supported",
+                            cursor.rest()
+                        )))
+                    }
+                    _ => break,
+                }
+            }
+            if !star && name.is_none() && conditions.is_empty() {
+                return Err(invalid(&format!("unexpected '{}'", cursor.rest())));
+            }
+            steps.push(Step {
+                descendant,
+                name,
+                conditions,
+            });
+            descendant = true;
+        }
+        if !descendant {
+            return Err(invalid("a > should be between two selectors"));
+        }
+        let target = if named.is_empty() {
+            Target::Text
+        } else {
+            Target::Attributes(named)
+        };
+        chains.push(Chain { steps, target });
+        if !cursor.eat(',') {
+            return Ok(Selector(chains));
+        }
+    }
+}
+
+/// The inside of `[...]` in a CSS selector, after the `[`
+fn css_attribute(cursor: &mut Cursor) -> Result<Condition, String> {
+    cursor.skip_space();
+    let name = cursor
+        .ident()
+        .ok_or("a [ should be followed by an attribute's name")?;
+    cursor.skip_space();
+    if cursor.eat(']') {
+        return Ok(Condition::Has(name));
+    }
+    let op = match cursor.rest().get(..2) {
+        Some("~=") => Op::Word,
+        Some("^=") => Op::StartsWith,
+        Some("$=") => Op::EndsWith,
+        Some("*=") => Op::Contains,
+        _ if cursor.peek() == Some('=') => Op::Equals,
+        _ => return Err(format!("unexpected '{}' in [...]", cursor.rest())),
+    };
+    cursor.at += if op == Op::Equals { 1 } else { 2 };
+    cursor.skip_space();
+    let value = match cursor.quoted()? {
+        Some(value) => value,
+        None => cursor.ident().ok_or("an attribute's value is missing")?,
+    };
+    cursor.skip_space();
+    if !cursor.eat(']') {
+        return Err("a [ isn't closed".to_string());
+    }
+    Ok(Condition::Compare(name, op, value))
+}
+
+/// Parse an XPath such as `//dependency/version`, `//a/@href` or
+/// `/project/build/plugins/plugin[2]`
+///
+/// Only paths of element names or `*` are understood, with `[N]`, `[@name]`,
+/// `[@name='value']`, `[contains(@name, 'value')]` and
+/// `[starts-with(@name, 'value')]` predicates, ending with an element,
+/// `text()` or an attribute. Paths can be joined with `|`.
+pub fn parse_xpath(path: &str) -> Result<Selector, String> {
+    let invalid = |why: &str| format!("invalid XPath '{}': {}", path, why);
+    let mut cursor = Cursor::new(path, true);
+    let mut chains = Vec::new();
+    loop {
+        let mut steps = Vec::new();
+        let mut target = Target::Text;
+        cursor.skip_space();
+        if cursor.peek() != Some('/') {
+            return Err(invalid("it should start with / or //"));
+        }
+        while cursor.eat('/') {
+            let descendant = cursor.eat('/');
+            if cursor.eat('@') {
+                let name = if cursor.eat('*') {
+                    Vec::new()
+                } else {
+                    vec![cursor
+                        .ident()
+                        .ok_or_else(|| invalid("a @ should be followed by a name"))?]
+                };
+                if descendant {
+                    steps.push(Step {
+                        descendant,
+                        name: None,
+                        conditions: Vec::new(),
+                    });
+                }
+                target = Target::Attributes(name);
+                break;
+            }
+            if cursor.rest().starts_with("text()") {
+                cursor.at += "text()".len();
+                if descendant {
+                    steps.push(Step {
+                        descendant,
+                        name: None,
+                        conditions: Vec::new(),
+                    });
+                }
+                break;
+            }
+            let name = if cursor.eat('*') {
+                None
+            } else {
+                Some(cursor.ident().ok_or_else(|| {
+                    invalid(&format!("expected a name or * before '{}'", cursor.rest()))
+                })?)
+            };
+            let mut conditions = Vec::new();
+            while cursor.eat('[') {
+                cursor.skip_space();
+                conditions.push(xpath_predicate(&mut cursor).map_err(|why| invalid(&why))?);
+                cursor.skip_space();
+                if !cursor.eat(']') {
+                    return Err(invalid("a [ isn't closed"));
+                }
+            }
+            steps.push(Step {
+                descendant,
+                name,
+                conditions,
+            });
+        }
+        if steps.is_empty() {
+            return Err(invalid("it should name an element"));
+        }
+        chains.push(Chain { steps, target });
+        cursor.skip_space();
+        if cursor.eat('|') {
+            continue;
+        }
+        return match cursor.peek() {
+            None => Ok(Selector(chains)),
+            Some(_) => Err(invalid(&format!("unexpected '{}'", cursor.rest()))),
+        };
+    }
+}
+
+/// The inside of `[...]` in an XPath, after the `[`
+fn xpath_predicate(cursor: &mut Cursor) -> Result<Condition, String> {
+    if cursor.peek().is_some_and(|c| c.is_ascii_digit()) {
+        let digits = cursor.rest().find(|c: char| !c.is_ascii_digit());
+        let digits = &cursor.rest()[..digits.unwrap_or(cursor.rest().len())];
+        let position: usize = digits.parse().map_err(|_| "a position is too large")?;
+        cursor.at += digits.len();
+        return match position {
+            0 => Err("positions start from 1".to_string()),
+            position => Ok(Condition::Position(position - 1)),
+        };
+    }
+    for (function, op) in [
+        ("contains(", Op::Contains),
+        ("starts-with(", Op::StartsWith),
+    ] {
+        if cursor.rest().starts_with(function) {
+            cursor.at += function.len();
+            cursor.skip_space();
+            let name = xpath_attribute(cursor)?;
+            cursor.skip_space();
+            if !cursor.eat(',') {
+                return Err(format!("expected a , in {}...)", function));
+            }
+            cursor.skip_space();
+            let value = cursor
+                .quoted()?
+                .ok_or_else(|| format!("expected a quoted value in {}...)", function))?;
+            cursor.skip_space();
+            if !cursor.eat(')') {
+                return Err(format!("a {} isn't closed", function));
+            }
+            return Ok(Condition::Compare(name, op, value));
+        }
+    }
+    let name = xpath_attribute(cursor)?;
+    cursor.skip_space();
+    if !cursor.eat('=') {
+        return Ok(Condition::Has(name));
+    }
+    cursor.skip_space();
+    let value = cursor.quoted()?.ok_or("expected a quoted value after =")?;
+    Ok(Condition::Compare(name, Op::Equals, value))
+}
+
+fn xpath_attribute(cursor: &mut Cursor) -> Result<String, String> {
+    if !cursor.eat('@') {
+        return Err(format!(
+            "only positions and attributes can be tested, not '{}'",
+            cursor.rest()
+        ));
+    }
+    cursor
+        .ident()
+        .ok_or_else(|| "a @ should be followed by a name".to_string())
+}
+
+struct Cursor<'a> {
+    text: &'a str,
+    at: usize,
+    /// Whether names can have colons in them, as in XPath, rather than
+    /// colons starting CSS's pseudo-classes
+    colons: bool,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(text: &'a str, colons: bool) -> Cursor<'a> {
+        Cursor {
+            text,
+            at: 0,
+            colons,
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.at..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn next(&mut self) {
+        self.at += self.peek().map_or(0, char::len_utf8);
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    /// Skip whitespace, returning whether there was any
+    fn skip_space(&mut self) -> bool {
+        let rest = self.rest();
+        let skipped = rest.len() - rest.trim_start().len();
+        self.at += skipped;
+        skipped > 0
+    }
+
+    /// A name of an element or attribute, if there's one here
+    fn ident(&mut self) -> Option<String> {
+        let rest = self.rest();
+        let end = rest
+            .find(|c: char| {
+                !(c.is_alphanumeric() || c == '-' || c == '_' || self.colons && c == ':')
+            })
+            .unwrap_or(rest.len());
+        self.at += end;
+        (end > 0).then(|| rest[..end].to_string())
+    }
+
+    /// A string in single or double quotes, if there's one here
+    fn quoted(&mut self) -> Result<Option<String>, String> {
+        let Some(quote @ ('"' | '\'')) = self.peek() else {
+            return Ok(None);
+        };
+        let rest = &self.rest()[1..];
+        let end = rest.find(quote).ok_or("a quote isn't closed")?;
+        self.at += end + 2;
+        Ok(Some(rest[..end].to_string()))
+    }
+}
+
+/// A selector for the text of every element, and for the values of the
+/// attributes named `attributes`, as --xml-text limits replacements to
+pub fn text_nodes(attributes: &[String]) -> Selector {
+    let any = || Step {
+        descendant: true,
+        name: None,
+        conditions: Vec::new(),
+    };
+    let mut chains = vec![Chain {
+        steps: vec![any()],
+        target: Target::Text,
+    }];
+    if !attributes.is_empty() {
+        chains.push(Chain {
+            steps: vec![any()],
+            target: Target::Attributes(attributes.to_vec()),
+        });
+    }
+    Selector(chains)
+}
+
+/// The text and attribute values that `selectors` cover in the document in
+/// `text`, which is HTML if `html` is set and XML otherwise
+pub fn segments(text: &str, html: bool, selectors: &[Selector]) -> Result<Vec<Segment>, String> {
+    let document = &markup::parse(text, html)?;
+    let matcher = Matcher {
+        text,
+        document,
+        html,
+    };
+    let mut selected = vec![false; document.elements.len()];
+    let mut segments = Vec::new();
+    for chain in selectors.iter().flat_map(|selector| &selector.0) {
+        for (id, element) in document.elements.iter().enumerate() {
+            if !matcher.matches(id, &chain.steps) {
+                continue;
+            }
+            match &chain.target {
+                Target::Text => selected[id] = true,
+                Target::Attributes(names) => {
+                    let attributes = element.attributes.iter().filter(|attribute| {
+                        names.is_empty()
+                            || names
+                                .iter()
+                                .any(|name| matcher.same_name(name, &attribute.name))
+                    });
+                    for attribute in attributes {
+                        if let Some(range) = attribute.value.clone() {
+                            segments.push(Segment {
+                                text: text[range.clone()].to_string(),
+                                range,
+                                escape: Escape::MarkupAttribute(attribute.quote),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for piece in &document.texts {
+        let mut parent = piece.parent;
+        let covered = loop {
+            match parent {
+                Some(id) if selected[id] => break true,
+                Some(id) => parent = document.elements[id].parent,
+                None => break false,
+            }
+        };
+        if covered {
+            segments.push(Segment {
+                range: piece.range.clone(),
+                text: text[piece.range.clone()].to_string(),
+                escape: match piece.kind {
+                    TextKind::Markup => Escape::MarkupText,
+                    TextKind::Cdata => Escape::Cdata,
+                    TextKind::Raw => Escape::None,
+                },
+            });
+        }
+    }
+    segments.sort_by_key(|segment| segment.range.start);
+    // The same attribute can be selected more than once
+    segments.dedup_by_key(|segment| segment.range.start);
+    Ok(segments)
+}
+
+struct Matcher<'a> {
+    text: &'a str,
+    document: &'a Document,
+    html: bool,
+}
+
+impl Matcher<'_> {
+    /// Whether the element `id` is matched by the last of `steps`, with the
+    /// elements around it matched by those before
+    fn matches(&self, id: usize, steps: &[Step]) -> bool {
+        let Some((last, before)) = steps.split_last() else {
+            return true;
+        };
+        let element = &self.document.elements[id];
+        if !self.step_matches(last, element) {
+            return false;
+        }
+        match element.parent {
+            None => before.is_empty(),
+            Some(_) if before.is_empty() => last.descendant,
+            Some(parent) if !last.descendant => self.matches(parent, before),
+            Some(mut ancestor) => loop {
+                if self.matches(ancestor, before) {
+                    break true;
+                }
+                match self.document.elements[ancestor].parent {
+                    Some(parent) => ancestor = parent,
+                    None => break false,
+                }
+            },
+        }
+    }
+
+    fn step_matches(&self, step: &Step, element: &Element) -> bool {
+        if let Some(name) = &step.name {
+            if !self.same_name(name, &element.name) {
+                return false;
+            }
+        }
+        step.conditions.iter().all(|condition| match condition {
+            Condition::Has(name) => self.attribute(element, name).is_some(),
+            Condition::Compare(name, op, wanted) => {
+                let Some(value) = self.attribute(element, name).and_then(|a| a.value.clone())
+                else {
+                    return false;
+                };
+                let value = &self.text[value];
+                match op {
+                    Op::Equals => value == wanted,
+                    Op::Word => value.split_whitespace().any(|word| word == wanted),
+                    Op::StartsWith => value.starts_with(wanted.as_str()),
+                    Op::EndsWith => value.ends_with(wanted.as_str()),
+                    Op::Contains => value.contains(wanted.as_str()),
+                }
+            }
+            Condition::Position(position) => match step.name {
+                Some(_) => element.position == *position,
+                None => element.index == *position,
+            },
+        })
+    }
+
+    fn attribute<'e>(&self, element: &'e Element, name: &str) -> Option<&'e Attribute> {
+        element
+            .attributes
+            .iter()
+            .find(|attribute| self.same_name(name, &attribute.name))
+    }
+
+    /// Whether names are the same, ignoring case in HTML
+    fn same_name(&self, wanted: &str, name: &str) -> bool {
+        if self.html {
+            wanted.eq_ignore_ascii_case(name)
+        } else {
+            wanted == name
+        }
+    }
+}
@@ -0,0 +1,563 @@
+use crate::{build_replacement, shift_positionals, Cli, Command, Options};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use newtext::journal::{self, Journal};
+use newtext::plan::Plan;
+use newtext::report::{self, Code, Problems};
+use newtext::{
+    display_path, interrupt, is_walked, matching_lines, pool, process_file, walk, write,
+    Replacement, FILES_AHEAD_PER_JOB,
+};
+use notify::event::ModifyKind;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The socket's name in the journal directory, when no other is given
+pub const SOCKET: &str = "serve.sock";
+
+/// How often to check for an interrupt while no one is connecting
+const TICK: Duration = Duration::from_millis(100);
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// The options a request's args can give, which say what's replaced and how
+/// files are written. Options about the run as a whole, such as --check and
+/// hooks, aren't among them.
+const SERVED: &[&str] = &[
+    "old",
+    "new",
+    "paths",
+    "pattern",
+    "ignore_case",
+    "script",
+    "map",
+    "plugin",
+    "map_missing",
+    "reverse",
+    "sed",
+    "sed_file",
+    "sed_extended",
+    "fix",
+    "eol",
+    "final_newline",
+    "strip_bom",
+    "encoding",
+    "detect_encoding",
+    "preserve",
+    "no_preserve",
+    "preserve_mtime",
+    "json_path",
+    "yaml_path",
+    "toml_key",
+    "selector",
+    "xpath",
+    "xml_text",
+    "csv_column",
+    "csv_delimiter",
+    "csv_quote",
+    "csv_no_header",
+    "markdown",
+    "front_matter",
+    "key",
+    "ensure",
+    "notebook_cells",
+    "po",
+    "only_added",
+    "binary",
+    "archives",
+    "compressed",
+    "office",
+    "fsync",
+    "no_journal",
+    "format_cmd",
+    "jobs",
+    "max_memory",
+    "stream_threshold",
+];
+
+/// The files the walk of the root takes in, kept up to date as files are
+/// created, removed and renamed, so requests don't have to walk the tree
+struct Index {
+    root: PathBuf,
+    files: BTreeSet<PathBuf>,
+    /// The directories the walk takes in
+    dirs: HashSet<PathBuf>,
+    /// The paths the walk couldn't read, and why, noted as problems for each
+    /// request about them
+    unreadable: BTreeMap<PathBuf, String>,
+}
+
+impl Index {
+    fn new(root: &Path) -> Index {
+        let mut index = Index {
+            root: root.to_path_buf(),
+            files: BTreeSet::new(),
+            dirs: HashSet::new(),
+            unreadable: BTreeMap::new(),
+        };
+        index.add_tree(root);
+        index
+    }
+
+    /// Add the files and directories under `dir` that the walk takes in
+    fn add_tree(&mut self, dir: &Path) {
+        for entry in walk(&[dir.to_path_buf()], None) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    match report::walk_problem(&e) {
+                        Some((path, message)) => {
+                            self.unreadable.insert(path.to_path_buf(), message);
+                        }
+                        None => warn!("{}", e),
+                    }
+                    continue;
+                }
+            };
+            match entry.file_type() {
+                Some(ft) if ft.is_dir() => self.dirs.insert(entry.into_path()),
+                Some(ft) if ft.is_file() => self.files.insert(entry.into_path()),
+                _ => false,
+            };
+        }
+    }
+
+    /// Bring `paths` up to date, after they've been created or removed
+    fn update(&mut self, paths: Vec<PathBuf>) {
+        let roots = [self.root.clone()];
+        for path in paths {
+            // The journal is written to on every change
+            let in_journal = path.strip_prefix(&self.root).is_ok_and(|relative| {
+                relative
+                    .components()
+                    .any(|c| c == Component::Normal(journal::JOURNAL_DIR.as_ref()))
+            });
+            if in_journal {
+                continue;
+            }
+
+            // Whatever couldn't be read before is walked afresh
+            self.unreadable
+                .retain(|unreadable, _| !unreadable.starts_with(&path));
+            match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_file() => {
+                    if is_walked(&path, &roots, None, &mut self.dirs) {
+                        self.files.insert(path);
+                    }
+                }
+                Ok(metadata) if metadata.is_dir() => {
+                    if is_walked(&path, &roots, None, &mut self.dirs) {
+                        self.add_tree(&path);
+                    }
+                }
+                _ => {
+                    self.files.retain(|file| !file.starts_with(&path));
+                    self.dirs.retain(|dir| !dir.starts_with(&path));
+                }
+            }
+        }
+    }
+}
+
+struct Server {
+    root: PathBuf,
+    index: Arc<Mutex<Index>>,
+}
+
+/// An error to send back in place of a result
+struct Error {
+    code: i64,
+    message: String,
+}
+
+impl Error {
+    fn new(code: i64, message: impl Into<String>) -> Error {
+        Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Answer JSON-RPC requests on the unix socket at `socket`, one per line,
+/// for the files under `root`, until interrupted
+///
+/// The files are walked once and then kept track of as they change, so a
+/// request only has to read the files it's about.
+pub fn serve(root: &Path, socket: &Path) -> Result<(), String> {
+    let listener = bind(socket)?;
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| format!("Could not listen on {}: {}", socket.display(), e))?;
+
+    let index = Arc::new(Mutex::new(Index::new(root)));
+    let watched = Arc::clone(&index);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Could not watch for changes: {}", e);
+                return;
+            }
+        };
+        // Files only come and go from the index, their content is read
+        // afresh for each request
+        if matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))
+        ) {
+            watched.lock().unwrap().update(event.paths);
+        }
+    })
+    .map_err(|e| format!("Could not watch for changes: {}", e))?;
+    watcher
+        .watch(root, RecursiveMode::Recursive)
+        .map_err(|e| format!("Could not watch for changes: {}", e))?;
+
+    let server = Arc::new(Server {
+        root: root.to_path_buf(),
+        index,
+    });
+    let files = server.index.lock().unwrap().files.len();
+    info!(
+        "Serving {} files on {}, press Ctrl-C to stop",
+        files,
+        socket.display()
+    );
+
+    interrupt::install();
+    while !interrupt::is_interrupted() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let server = Arc::clone(&server);
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, &server) {
+                        warn!("Connection closed: {}", e);
+                    }
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(TICK),
+            Err(e) => warn!("Could not accept a connection: {}", e),
+        }
+    }
+
+    let _ = fs::remove_file(socket);
+    info!("Stopped serving");
+    Ok(())
+}
+
+/// Listen on `socket`, replacing a socket left behind by a server that's no
+/// longer running
+fn bind(socket: &Path) -> Result<UnixListener, String> {
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(format!(
+                "Another newtext serve is listening on {}",
+                socket.display()
+            ));
+        }
+        fs::remove_file(socket)
+            .map_err(|e| format!("Could not remove {}: {}", socket.display(), e))?;
+    }
+    UnixListener::bind(socket)
+        .map_err(|e| format!("Could not listen on {}: {}", socket.display(), e))
+}
+
+fn handle_connection(stream: UnixStream, server: &Server) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut writer = &stream;
+    for line in BufReader::new(&stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = respond(&line, server) {
+            writeln!(writer, "{}", response)?;
+        }
+    }
+    Ok(())
+}
+
+/// The response to a request, or None if it's a notification, which has no
+/// ID to respond to
+fn respond(line: &str, server: &Server) -> Option<Value> {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(error_response(
+                Value::Null,
+                Error::new(PARSE_ERROR, e.to_string()),
+            ))
+        }
+    };
+    let id = request.get("id").cloned();
+    let method = match request["method"].as_str() {
+        Some(method) => method,
+        None => {
+            let error = Error::new(INVALID_REQUEST, "the request has no method");
+            return Some(error_response(id.unwrap_or(Value::Null), error));
+        }
+    };
+
+    let params = &request["params"];
+    // Each connection's requests are answered on its own thread, with
+    // problems noted per request, and changes to the files wait for the
+    // journal's lock as runs do
+    let result = match method {
+        "scan" => scan(server, params),
+        "preview" => preview(server, params),
+        "apply" => apply(server, params),
+        "undo" => undo(server, params),
+        _ => Err(Error::new(
+            METHOD_NOT_FOUND,
+            format!("there is no method {}", method),
+        )),
+    };
+
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(error) => error_response(id, error),
+    })
+}
+
+fn error_response(id: Value, error: Error) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {"code": error.code, "message": error.message},
+    })
+}
+
+/// The files that would change, and the lines in them that match
+fn scan(server: &Server, params: &Value) -> Result<Value, Error> {
+    let (replacement, plan, problems) = dry_run(server, params)?;
+    let files: Vec<Value> = plan
+        .writes()
+        .into_iter()
+        .map(|(path, _)| {
+            let content = fs::read(&path).unwrap_or_default();
+            let content = String::from_utf8_lossy(&content);
+            let lines: Vec<Value> = matching_lines(&content, &path, &replacement)
+                .into_iter()
+                .map(|(number, text)| json!({"line": number, "text": text}))
+                .collect();
+            json!({"path": display_path(&path, &server.root), "lines": lines})
+        })
+        .collect();
+    Ok(json!({"files": files, "problems": problems}))
+}
+
+/// The files that would change, with their new content
+fn preview(server: &Server, params: &Value) -> Result<Value, Error> {
+    let (_, plan, problems) = dry_run(server, params)?;
+    let files: Vec<Value> = plan
+        .writes()
+        .into_iter()
+        .map(|(path, content)| {
+            json!({
+                "path": display_path(&path, &server.root),
+                "content": String::from_utf8_lossy(&content),
+            })
+        })
+        .collect();
+    Ok(json!({"files": files, "problems": problems}))
+}
+
+/// Make the changes, as a run in the journal unless `--no-journal` is given
+fn apply(server: &Server, params: &Value) -> Result<Value, Error> {
+    let (args, options) = options(params)?;
+    let mut replacement = replacement(&options)?;
+    let _lock = journal::lock(&server.root, true).map_err(|e| Error::new(SERVER_ERROR, e))?;
+    let journal = (!options.no_journal).then(|| {
+        let args = ["newtext".to_string()].into_iter().chain(args).collect();
+        Arc::new(Journal::new(&server.root, args))
+    });
+    replacement.write.journal = journal.clone();
+    // Requests are answered at the same time, so each collects its own
+    let problems = Arc::new(Problems::default());
+    replacement.write.problems = Some(Arc::clone(&problems));
+
+    let modified = process(server, &options, &replacement);
+    if let Some(journal) = &journal {
+        if let Err(e) = journal.finish() {
+            warn!("Could not update the journal: {}", e);
+        }
+    }
+    let files: Vec<String> = modified
+        .iter()
+        .map(|path| display_path(path, &server.root))
+        .collect();
+    Ok(json!({
+        "run": journal.and_then(|journal| journal.id()),
+        "files": files,
+        "problems": problems.take_json(&server.root),
+    }))
+}
+
+/// Revert the given run, or the most recent one
+fn undo(server: &Server, params: &Value) -> Result<Value, Error> {
+    let run = match &params["run"] {
+        Value::Null => None,
+        Value::String(run) => Some(run.as_str()),
+        _ => return Err(Error::new(INVALID_PARAMS, "run must be a string")),
+    };
+    let _lock = journal::lock(&server.root, true).map_err(|e| Error::new(SERVER_ERROR, e))?;
+    let id = journal::undo(&server.root, run)
+        .map_err(|e| Error::new(SERVER_ERROR, format!("Could not undo: {}", e)))?;
+    Ok(json!({"run": id}))
+}
+
+/// Work out the changes the request's options make, without making them
+fn dry_run(server: &Server, params: &Value) -> Result<(Replacement, Arc<Plan>, Vec<Value>), Error> {
+    let (_, options) = options(params)?;
+    let mut replacement = replacement(&options)?;
+    let plan = Arc::new(Plan::new(&server.root));
+    let problems = Arc::new(Problems::default());
+    replacement.write.plan = Some(Arc::clone(&plan));
+    replacement.write.problems = Some(Arc::clone(&problems));
+    process(server, &options, &replacement);
+    let problems = problems.take_json(&server.root);
+    Ok((replacement, plan, problems))
+}
+
+/// The command line arguments in the request's params, as given and as
+/// options
+fn options(params: &Value) -> Result<(Vec<String>, Options), Error> {
+    let args = params["args"]
+        .as_array()
+        .and_then(|args| {
+            args.iter()
+                .map(|arg| arg.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| Error::new(INVALID_PARAMS, "args must be an array of strings"))?;
+
+    let invalid = |e: clap::Error| {
+        // Just the error, without the usage that follows it
+        let message = e.to_string();
+        let message = message.lines().next().unwrap_or_default().to_string();
+        Error::new(INVALID_PARAMS, message.trim_start_matches("error: "))
+    };
+    let command = Cli::command();
+    let matches = command
+        .clone()
+        .try_get_matches_from(["newtext".to_string()].iter().chain(&args))
+        .map_err(invalid)?;
+    let cli = Cli::from_arg_matches(&matches).map_err(invalid)?;
+    let mut options = match cli.command {
+        None => cli.options,
+        Some(Command::Replace { options }) => *options,
+        Some(_) => return Err(Error::new(INVALID_PARAMS, "args can't be a command")),
+    };
+    let given = match matches.subcommand() {
+        Some((name, replace)) => vec![
+            (
+                command.find_subcommand(name).expect("parsed command"),
+                replace,
+            ),
+            (&command, &matches),
+        ],
+        None => vec![(&command, &matches)],
+    };
+    for (command, matches) in given {
+        if let Some(arg) = unserved(command, matches) {
+            return Err(Error::new(
+                INVALID_PARAMS,
+                format!("{} can't be used with serve", arg),
+            ));
+        }
+    }
+    shift_positionals(&mut options);
+    if options.paths.iter().any(|path| path.as_os_str() == "-") {
+        return Err(Error::new(INVALID_PARAMS, "- can't be used with serve"));
+    }
+    Ok((args, options))
+}
+
+/// The first option given in `matches` that isn't one of those served, by
+/// the name it's given with
+fn unserved(command: &clap::Command, matches: &ArgMatches) -> Option<String> {
+    let arg = command.get_arguments().find(|arg| {
+        let id = arg.get_id().as_str();
+        !SERVED.contains(&id) && matches.value_source(id) == Some(ValueSource::CommandLine)
+    })?;
+    Some(match arg.get_long() {
+        Some(long) => format!("--{}", long),
+        None => arg.get_id().to_string(),
+    })
+}
+
+fn replacement(options: &Options) -> Result<Replacement, Error> {
+    build_replacement(options).map_err(|e| Error::new(INVALID_PARAMS, e))
+}
+
+/// Process the indexed files under the options' paths, or all of them, and
+/// return those modified. Problems are noted with the replacement's own.
+fn process(server: &Server, options: &Options, replacement: &Replacement) -> Vec<PathBuf> {
+    let paths: Vec<PathBuf> = options
+        .paths
+        .iter()
+        .map(|path| server.root.join(path))
+        .collect();
+    let is_wanted =
+        |file: &Path| paths.is_empty() || paths.iter().any(|path| file.starts_with(path));
+    let index = server.index.lock().unwrap();
+    let files: Vec<PathBuf> = index
+        .files
+        .iter()
+        .filter(|file| is_wanted(file))
+        .cloned()
+        .collect();
+    for (path, message) in &index.unreadable {
+        if is_wanted(path) {
+            report::add_to(
+                replacement.write.problems.as_deref(),
+                Code::Read,
+                path,
+                message,
+            );
+        }
+    }
+    drop(index);
+
+    let mut modified = Vec::new();
+    let jobs = replacement.jobs;
+    let window = jobs * FILES_AHEAD_PER_JOB;
+    pool::run(
+        jobs,
+        window,
+        files.into_iter(),
+        |path| process_file(path, replacement),
+        |path, result| {
+            match result {
+                Ok(true) => modified.push(path),
+                Ok(false) => {}
+                Err(e) => {
+                    let code = if write::is_write_error(&e) {
+                        Code::Write
+                    } else {
+                        Code::Process
+                    };
+                    report::add_to(replacement.write.problems.as_deref(), code, &path, e);
+                }
+            }
+            true
+        },
+    );
+    modified
+}
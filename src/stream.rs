@@ -0,0 +1,232 @@
+use crate::eol::{self, Eol};
+use crate::matcher::Matcher;
+use crate::pool;
+use crate::throttle;
+use crate::write::AtomicFile;
+use crate::{count_matches, rewrite_content, Replacement};
+use regex_syntax::hir::{Class, Hir, HirKind, Look};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+/// Roughly how much content is held in memory at once when streaming
+const CHUNK_SIZE: usize = 1 << 20;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Whether a replacement gives the same result when applied to a file one
+/// chunk of whole lines at a time as it does on the whole file
+///
+/// That holds when no match can span a line break, the pattern doesn't
+/// anchor to the start or end of the whole text, and the replacement doesn't
+/// add line breaks that the line ending checks would need to see in context.
+pub fn is_streamable(replacement: &Replacement) -> bool {
+    let inserts_line_breaks = |text: &str| text.contains(['\n', '\r']);
+    let eol_ok = |new_has_breaks: bool| replacement.eol != Eol::Preserve || !new_has_breaks;
+
+    if replacement.replacer.is_some() || replacement.bytes_only || !replacement.fixes.is_empty() {
+        // Scripts are given line numbers, which chunks don't know
+        return false;
+    }
+    if replacement.scope.is_some() || replacement.only_added.is_some() {
+        // Scopes need the whole document to tell what's in them, and added
+        // lines are counted from the start
+        return false;
+    }
+
+    if let Some(sed) = &replacement.sed {
+        // sed already works one line at a time
+        return eol_ok(sed.inserts_line_breaks());
+    }
+
+    let line_local = match replacement.matcher.as_ref().and_then(Matcher::regex) {
+        Some(re) => is_line_local(re.as_str()),
+        None => !replacement.old.contains('\n'),
+    };
+    line_local && eol_ok(inserts_line_breaks(&replacement.new))
+}
+
+/// Whether every match of `pattern` stays within a single line
+fn is_line_local(pattern: &str) -> bool {
+    let hir = match regex_syntax::ParserBuilder::new()
+        .crlf(true)
+        .build()
+        .parse(pattern)
+    {
+        Ok(hir) => hir,
+        Err(_) => return false,
+    };
+
+    let properties = hir.properties();
+    let looks = properties.look_set();
+
+    // Empty matches could be found twice where two chunks meet
+    properties.minimum_len().unwrap_or(0) > 0
+        && !looks.contains(Look::Start)
+        && !looks.contains(Look::End)
+        && !can_match_newline(&hir)
+}
+
+fn can_match_newline(hir: &Hir) -> bool {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => false,
+        HirKind::Literal(literal) => literal.0.contains(&b'\n'),
+        HirKind::Class(Class::Unicode(class)) => class
+            .ranges()
+            .iter()
+            .any(|range| range.start() <= '\n' && '\n' <= range.end()),
+        HirKind::Class(Class::Bytes(class)) => class
+            .ranges()
+            .iter()
+            .any(|range| range.start() <= b'\n' && b'\n' <= range.end()),
+        HirKind::Repetition(repetition) => can_match_newline(&repetition.sub),
+        HirKind::Capture(capture) => can_match_newline(&capture.sub),
+        HirKind::Concat(subs) | HirKind::Alternation(subs) => subs.iter().any(can_match_newline),
+    }
+}
+
+/// Reads a file as UTF-8 text in chunks that end on line boundaries
+///
+/// Each chunk is None if it isn't plain UTF-8, in which case streaming has to
+/// give up.
+struct Chunks {
+    reader: BufReader<File>,
+    first: bool,
+    /// Whether the file starts with a byte order mark, which is left out of
+    /// the first chunk
+    bom: bool,
+}
+
+impl Chunks {
+    fn open(path: &Path) -> io::Result<Chunks> {
+        Ok(Chunks {
+            reader: BufReader::with_capacity(CHUNK_SIZE, throttle::open(path)?),
+            first: true,
+            bom: false,
+        })
+    }
+}
+
+impl Iterator for Chunks {
+    type Item = io::Result<Option<String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = Vec::new();
+        while buf.len() < CHUNK_SIZE {
+            match self.reader.read_until(b'\n', &mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        if buf.is_empty() {
+            return None;
+        }
+
+        if self.first {
+            self.first = false;
+            if buf.starts_with(UTF8_BOM) {
+                self.bom = true;
+                buf.drain(..UTF8_BOM.len());
+            }
+        }
+
+        // NULs suggest BOM-less UTF-16, which the whole-file path detects
+        let text = String::from_utf8(buf).ok();
+        Some(Ok(text.filter(|text| !text.contains('\0'))))
+    }
+}
+
+/// How many chunks each thread can get ahead of the oldest one still being
+/// replaced within, which bounds how much of the file is held in memory
+const CHUNKS_AHEAD_PER_JOB: usize = 2;
+
+/// Rewrite a large file without holding all of it in memory
+///
+/// Chunks are replaced within on several threads at once, so one large file
+/// doesn't hold up the run. Returns None if the file isn't plain UTF-8 text,
+/// in which case it should be processed whole so its encoding can be
+/// handled.
+pub fn process_file_streaming(path: &Path, replacement: &Replacement) -> io::Result<Option<bool>> {
+    let jobs = replacement.jobs;
+    let window = jobs * CHUNKS_AHEAD_PER_JOB;
+    let rewrite = |chunk: &io::Result<Option<String>>| match chunk {
+        Ok(Some(text)) => rewrite_content(text, path, replacement),
+        _ => Ok(None),
+    };
+
+    // First pass: find out whether anything changes, without writing. Most
+    // large files won't match, and those are only read once.
+    let mut chunks = Chunks::open(path)?;
+    let mut outcome = Ok(Some(false));
+    pool::run(jobs, window, chunks.by_ref(), rewrite, |chunk, new_text| {
+        match (chunk, new_text) {
+            (Err(e), _) | (_, Err(e)) => outcome = Err(e),
+            (Ok(None), _) => outcome = Ok(None),
+            (Ok(Some(text)), Ok(Some(new_text))) if new_text != text => outcome = Ok(Some(true)),
+            _ => return true,
+        }
+        false
+    });
+    let changed = match outcome? {
+        Some(changed) => changed,
+        None => return Ok(None),
+    };
+
+    let bom = chunks.bom;
+    let strip_bom = bom && replacement.strip_bom;
+    if !changed && !strip_bom {
+        return Ok(Some(false));
+    }
+
+    // Second pass: write the new content. Each chunk is held back until the
+    // next is read, so the last one can get the trailing newline fix.
+    let mut output = AtomicFile::create(path, &replacement.write)?;
+    if bom && !strip_bom {
+        output.write_all(UTF8_BOM)?;
+    }
+
+    let chunks = Chunks::open(path)?;
+    let mut previous: Option<(String, String)> = None;
+    let mut outcome = Ok(Some(()));
+    pool::run(jobs, window, chunks, rewrite, |chunk, new_text| {
+        let (text, new_text) = match (chunk, new_text) {
+            (Ok(Some(text)), Ok(new_text)) => {
+                let new_text = new_text.unwrap_or_else(|| text.clone());
+                (text, new_text)
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                outcome = Err(e);
+                return false;
+            }
+            // The file changed since the first pass; leave it alone
+            (Ok(None), _) => {
+                outcome = Ok(None);
+                return false;
+            }
+        };
+        // Only counted on this pass, which sees every chunk
+        count_matches(&text, path, replacement);
+
+        if let Some((previous_text, previous_new)) = previous.replace((text, new_text)) {
+            let previous_new = eol::apply(&previous_text, previous_new, replacement.eol);
+            if let Err(e) = output.write_all(previous_new.as_bytes()) {
+                outcome = Err(e);
+                return false;
+            }
+        }
+        true
+    });
+    if outcome?.is_none() {
+        return Ok(None);
+    }
+
+    if let Some((last_text, last_new)) = previous {
+        let last_new = eol::fix_final_newline(&last_text, last_new, replacement.final_newline);
+        let last_new = eol::apply(&last_text, last_new, replacement.eol);
+        output.write_all(last_new.as_bytes())?;
+    }
+
+    output.commit()?;
+    Ok(Some(true))
+}
@@ -0,0 +1,27 @@
+//! Replacing within text that's already in memory, which is all the rest of
+//! newtext does with the content of files once it has read them
+
+use crate::case::apply_case_pattern;
+use crate::matcher::Matcher;
+
+/// `content` with every match of `matcher`, which finds `old`, replaced with
+/// `new`
+///
+/// With a regex, `new` can refer to its captures, as `$1` or `${name}`.
+/// Ignoring case, each replacement takes on the case of the text it
+/// replaces.
+pub fn replace_all(content: &str, matcher: &Matcher, old: &str, new: &str) -> String {
+    match matcher {
+        // Regex mode (ignore_case is already handled in regex compilation)
+        Matcher::Regex(re) => re.replace_all(content, new).to_string(),
+        // Literal mode with case-insensitive matching and case-preserving
+        // replacement
+        Matcher::IgnoreCase(re) => re
+            .replace_all(content, |caps: &regex::Captures| {
+                let matched = caps.get(0).unwrap().as_str();
+                apply_case_pattern(matched, new)
+            })
+            .to_string(),
+        Matcher::Literal(_) => content.replace(old, new),
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The most bytes a second that files are read and written at, with
+/// --throttle, or 0 for no limit
+static RATE: AtomicU64 = AtomicU64::new(0);
+
+/// When the bytes that can go straight away were last worked out, and how
+/// many there were. It goes negative as threads take more than there is, and
+/// they each wait for their share to come.
+static AVAILABLE: Mutex<Option<(Instant, f64)>> = Mutex::new(None);
+
+/// Limit reading and writing files to `rate` bytes a second across all
+/// threads
+pub fn set_rate(rate: u64) {
+    RATE.store(rate, Ordering::Relaxed);
+}
+
+/// Wait until `bytes` more can be read or written without going over the
+/// limit
+///
+/// Up to a second's worth can go at once after a pause, rather than all
+/// that was saved up while nothing was read.
+pub fn wait(bytes: u64) {
+    let rate = RATE.load(Ordering::Relaxed);
+    if rate == 0 {
+        return;
+    }
+    let rate = rate as f64;
+
+    let delay = {
+        let mut available = AVAILABLE.lock().unwrap();
+        let now = Instant::now();
+        let (updated, before) = available.unwrap_or((now, rate));
+        let refilled = (before + now.duration_since(updated).as_secs_f64() * rate).min(rate);
+        let left = refilled - bytes as f64;
+        *available = Some((now, left));
+        Duration::from_secs_f64((-left).max(0.0) / rate)
+    };
+    thread::sleep(delay);
+}
+
+/// Open the file at `path` to read it all, once there's room under the limit
+pub fn open(path: &Path) -> io::Result<File> {
+    let file = File::open(path)?;
+    wait(file.metadata()?.len());
+    Ok(file)
+}
+
+/// Make this process yield to others for the CPU and, where it's supported,
+/// for the disk. Threads started afterwards are yielding too.
+#[cfg(unix)]
+pub fn be_nice() -> io::Result<()> {
+    // SAFETY: only changes the priority of this process, or thread on Linux
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        // The idle I/O class, which only gets the disk when no one else wants
+        // it. libc has no constants for these.
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+        // SAFETY: only changes the I/O priority of this thread
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn be_nice() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "lowering the priority isn't supported on this platform",
+    ))
+}
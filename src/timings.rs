@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Whether time is being kept, with --timings
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The nanoseconds spent in each phase, summed over the threads
+static SPENT: [AtomicU64; PHASES.len()] = [const { AtomicU64::new(0) }; PHASES.len()];
+
+/// The files that took longest, slowest first
+static SLOWEST: Mutex<Vec<(Duration, PathBuf)>> = Mutex::new(Vec::new());
+
+/// How many of the slowest files are listed
+const SLOWEST_LEN: usize = 10;
+
+/// Where the time of a run goes
+#[derive(Clone, Copy)]
+pub enum Phase {
+    /// Walking the directories for the files
+    Walk,
+    /// Reading files and decoding their content
+    Read,
+    /// Looking for matches
+    Match,
+    /// Working out the new content, including streamed files
+    Replace,
+    /// Writing the new content
+    Write,
+}
+
+const PHASES: [(Phase, &str); 5] = [
+    (Phase::Walk, "walk"),
+    (Phase::Read, "read"),
+    (Phase::Match, "match"),
+    (Phase::Replace, "replace"),
+    (Phase::Write, "write"),
+];
+
+/// Start keeping time
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Time `f` as part of `phase`
+pub fn time<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let started = Instant::now();
+    let result = f();
+    SPENT[phase as usize].fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    result
+}
+
+/// Time `f`, which does all the work on the file at `path`, to list it if
+/// it's one of the slowest
+pub fn time_file<T>(path: &Path, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let started = Instant::now();
+    let result = f();
+    let elapsed = started.elapsed();
+
+    let mut slowest = SLOWEST.lock().unwrap();
+    if slowest.len() < SLOWEST_LEN || slowest.last().is_some_and(|(last, _)| elapsed > *last) {
+        let index = slowest.partition_point(|(other, _)| *other >= elapsed);
+        slowest.insert(index, (elapsed, path.to_path_buf()));
+        slowest.truncate(SLOWEST_LEN);
+    }
+    result
+}
+
+/// Time each call to `next` on `items` as part of `phase`
+pub fn time_iter<I: Iterator>(phase: Phase, mut items: I) -> impl Iterator<Item = I::Item> {
+    std::iter::from_fn(move || time(phase, || items.next()))
+}
+
+/// Print where the time went, if it was kept, with a run that took `wall`
+pub fn print(wall: Duration, root: &Path) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    eprintln!("Timings, summed over the threads:");
+    for (phase, name) in PHASES {
+        let spent = Duration::from_nanos(SPENT[phase as usize].load(Ordering::Relaxed));
+        eprintln!("  {:<8} {:>9.3}s", name, spent.as_secs_f64());
+    }
+    eprintln!("  {:<8} {:>9.3}s wall clock", "total", wall.as_secs_f64());
+
+    let slowest = SLOWEST.lock().unwrap();
+    if slowest.is_empty() {
+        return;
+    }
+    eprintln!("Slowest files:");
+    for (elapsed, path) in slowest.iter() {
+        let path = path.strip_prefix(root).unwrap_or(path);
+        eprintln!("  {:>9.3}s  {}", elapsed.as_secs_f64(), path.display());
+    }
+}
@@ -0,0 +1,114 @@
+//! --toml-key, which limits replacements to the string values at some keys in
+//! TOML files
+//!
+//! The document is parsed with toml_edit for where each value is, and only
+//! the strings that change are written back, each in the kind of string it
+//! was, so comments and formatting elsewhere are left exactly as they were.
+
+use crate::scope::{Escape, Key, KeyPath, Segment};
+use toml_edit::{Document, Item, Value};
+
+/// The string values in the TOML `text` that any of `paths` cover
+pub fn segments(text: &str, paths: &[KeyPath]) -> Result<Vec<Segment>, String> {
+    let document = Document::parse(text).map_err(|e| {
+        let line = e
+            .span()
+            .map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+        format!(
+            "it isn't valid TOML, {} on line {}",
+            e.message().trim_end(),
+            line
+        )
+    })?;
+    let mut walk = Walk {
+        text,
+        path: Vec::new(),
+        paths,
+        segments: Vec::new(),
+    };
+    walk.item(document.as_item());
+    // Tables can be written in any order, and their keys with them
+    walk.segments.sort_by_key(|segment| segment.range.start);
+    Ok(walk.segments)
+}
+
+struct Walk<'a> {
+    text: &'a str,
+    /// The path to the value being walked
+    path: Vec<Key>,
+    paths: &'a [KeyPath],
+    segments: Vec<Segment>,
+}
+
+impl Walk<'_> {
+    fn item(&mut self, item: &Item) {
+        match item {
+            Item::Table(table) => {
+                for (key, item) in table.iter() {
+                    self.path.push(Key::Name(key.to_string()));
+                    self.item(item);
+                    self.path.pop();
+                }
+            }
+            Item::ArrayOfTables(tables) => {
+                for (index, table) in tables.iter().enumerate() {
+                    self.path.push(Key::Index(index));
+                    for (key, item) in table.iter() {
+                        self.path.push(Key::Name(key.to_string()));
+                        self.item(item);
+                        self.path.pop();
+                    }
+                    self.path.pop();
+                }
+            }
+            Item::Value(value) => self.value(value),
+            Item::None => {}
+        }
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Array(array) => {
+                for (index, value) in array.iter().enumerate() {
+                    self.path.push(Key::Index(index));
+                    self.value(value);
+                    self.path.pop();
+                }
+            }
+            Value::InlineTable(table) => {
+                for (key, value) in table.iter() {
+                    self.path.push(Key::Name(key.to_string()));
+                    self.value(value);
+                    self.path.pop();
+                }
+            }
+            Value::String(string) => {
+                let Some(range) = string.span() else {
+                    return;
+                };
+                if !self.paths.iter().any(|path| path.covers(&self.path)) {
+                    return;
+                }
+                let raw = &self.text[range.clone()];
+                let multi_line = raw.starts_with("\"\"\"") || raw.starts_with("'''");
+                let escape = if multi_line {
+                    Escape::TomlMultiLine {
+                        literal: raw.starts_with('\''),
+                        newline: raw[3..].starts_with(['\n', '\r']),
+                    }
+                } else if raw.starts_with('\'') {
+                    Escape::TomlLiteral
+                } else {
+                    Escape::Json
+                };
+                self.segments.push(Segment {
+                    range,
+                    text: string.value().clone(),
+                    escape,
+                });
+            }
+            // Numbers, booleans and dates are left as they are
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use newtext::interrupt;
+use notify::event::ModifyKind;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long the files have to be left alone before the changes to them are
+/// handled, so that a file being written in several goes is handled once
+const QUIET: Duration = Duration::from_millis(200);
+
+/// How often to check for an interrupt while nothing is happening
+const TICK: Duration = Duration::from_millis(100);
+
+/// Watch `roots` until interrupted, passing the paths of files created or
+/// modified under them to `changed` in batches, sorted
+///
+/// Changes come in bursts, e.g. from a code generator rewriting many files,
+/// so each batch is of the paths changed until they've been quiet for a
+/// moment.
+pub fn watch(roots: &[PathBuf], mut changed: impl FnMut(Vec<PathBuf>)) -> notify::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for root in roots {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+    }
+
+    let mut pending = BTreeSet::new();
+    let mut last_change = Instant::now();
+    while !interrupt::is_interrupted() {
+        match rx.recv_timeout(TICK) {
+            Ok(Ok(event)) => {
+                let is_change = match event.kind {
+                    EventKind::Create(_) => true,
+                    // Attributes are copied over to rewritten files
+                    EventKind::Modify(ModifyKind::Metadata(_)) => false,
+                    EventKind::Modify(_) => true,
+                    _ => false,
+                };
+                if is_change {
+                    pending.extend(event.paths);
+                    last_change = Instant::now();
+                }
+            }
+            Ok(Err(e)) => warn!("Could not watch for changes: {}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if !pending.is_empty() && last_change.elapsed() >= QUIET {
+            changed(std::mem::take(&mut pending).into_iter().collect());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,536 @@
+use crate::hook;
+use crate::journal::Journal;
+use crate::plan::Plan;
+use crate::report::{self, Code, Problems};
+use crate::throttle;
+use clap::ValueEnum;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+/// File attributes that can be carried over to a rewritten file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Attribute {
+    /// Permission bits, including the executable bit
+    Mode,
+    /// Access and modification times
+    Times,
+    /// Owning user and group (Unix only)
+    Ownership,
+    /// Extended attributes, including ACLs and SELinux labels (Unix only)
+    Xattr,
+}
+
+/// How rewritten files are written, and which attributes of the original
+/// file they keep
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    pub preserve: Vec<Attribute>,
+    /// Attributes kept where they can be, and otherwise left behind without
+    /// an error, as ownership is for users other than root
+    pub preserve_if_possible: Vec<Attribute>,
+    /// Write to a separate directory rather than replacing files in place
+    pub output: Option<OutputDir>,
+    /// Flush rewritten files and their directories to disk before moving on
+    pub fsync: bool,
+    /// Where to record changes so they can be undone
+    pub journal: Option<Arc<Journal>>,
+    /// Record changes in this plan instead of making them
+    pub plan: Option<Arc<Plan>>,
+    /// A command to run on each file once it's written, with `{}` for its path
+    pub format: Option<String>,
+    /// Where to note problems with files, when the caller collects its own
+    /// rather than leaving them for the report
+    pub problems: Option<Arc<Problems>>,
+}
+
+/// A directory that receives the rewritten files, so the originals stay as
+/// they are
+#[derive(Clone, Debug)]
+pub struct OutputDir {
+    /// The root of the tree being processed
+    pub source: PathBuf,
+    pub dir: PathBuf,
+}
+
+impl WriteOptions {
+    fn preserves(&self, attribute: Attribute) -> bool {
+        self.preserve.contains(&attribute)
+    }
+
+    fn tries_to_preserve(&self, attribute: Attribute) -> bool {
+        self.preserve_if_possible.contains(&attribute)
+    }
+
+    /// Where the rewritten version of `path` goes, keeping its path relative
+    /// to the source tree when there's an output directory
+    fn destination(&self, path: &Path) -> PathBuf {
+        match &self.output {
+            Some(output) => match path.strip_prefix(&output.source) {
+                Ok(relative) => output.dir.join(relative),
+                Err(_) => output.dir.join(path.file_name().unwrap_or_default()),
+            },
+            None => path.to_path_buf(),
+        }
+    }
+}
+
+/// Replace the content of `path` without ever leaving it half-written
+pub fn write_atomic(path: &Path, contents: &[u8], options: &WriteOptions) -> io::Result<()> {
+    let mut file = AtomicFile::create(path, options)?;
+    file.write_all(contents)?;
+    file.commit()
+}
+
+/// New content for an existing file that only replaces it once committed
+///
+/// The content goes to a temporary file in the same directory, which is then
+/// renamed over the original. Renames within a filesystem are atomic, so an
+/// interrupted run leaves either the old file or the new one. Dropping an
+/// `AtomicFile` without committing it leaves the original untouched.
+///
+/// Symlinks are written through to the files they point to. Files with more
+/// than one hard link are written in place instead, as a new file renamed
+/// over one of their names would split it from the others.
+///
+/// When only planning, the content is kept in memory and committing adds it
+/// to the plan.
+pub struct AtomicFile {
+    /// The original file, which extended attributes are copied from
+    source: PathBuf,
+    /// Where the new content goes, usually the original file
+    path: PathBuf,
+    metadata: fs::Metadata,
+    temp: Temp,
+    options: WriteOptions,
+}
+
+/// Where the new content of an `AtomicFile` is kept until it's committed
+enum Temp {
+    File(NamedTempFile),
+    Planned(Arc<Plan>, Cursor<Vec<u8>>),
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path, options: &WriteOptions) -> io::Result<AtomicFile> {
+        AtomicFile::open(path, options).map_err(write_error)
+    }
+
+    fn open(path: &Path, options: &WriteOptions) -> io::Result<AtomicFile> {
+        let destination = match &options.output {
+            Some(_) => options.destination(path),
+            None => fs::canonicalize(path)?,
+        };
+        let dir = parent_dir(&destination);
+        let metadata = fs::metadata(path)?;
+
+        if options.output.is_some() {
+            fs::create_dir_all(dir)?;
+        } else if metadata.permissions().readonly() {
+            // Renaming would sidestep the file's own permissions, so check them
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is read-only",
+            ));
+        }
+
+        if let Some(plan) = &options.plan {
+            return Ok(AtomicFile {
+                source: path.to_path_buf(),
+                path: destination,
+                metadata,
+                temp: Temp::Planned(plan.clone(), Cursor::new(Vec::new())),
+                options: options.clone(),
+            });
+        }
+
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(".newtext-").suffix(".tmp");
+        if !options.preserves(Attribute::Mode) {
+            // Without this temporary files are private, rather than getting the
+            // umask-based mode a freshly created file would have
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                builder.permissions(fs::Permissions::from_mode(0o666));
+            }
+        }
+        let temp = builder.tempfile_in(dir)?;
+
+        Ok(AtomicFile {
+            source: path.to_path_buf(),
+            path: destination,
+            metadata,
+            temp: Temp::File(temp),
+            options: options.clone(),
+        })
+    }
+
+    /// Copy over the preserved attributes and replace the original file
+    pub fn commit(self) -> io::Result<()> {
+        self.replace().map_err(write_error)
+    }
+
+    fn replace(self) -> io::Result<()> {
+        let temp = match self.temp {
+            Temp::File(temp) => temp,
+            Temp::Planned(plan, content) => {
+                // Applying the plan works out the destination again
+                plan.record_write(&self.source, content.get_ref());
+                return Ok(());
+            }
+        };
+        let options = &self.options;
+        let temp_path = temp.path();
+
+        // Ownership goes first, as changing it can clear setuid bits
+        #[cfg(unix)]
+        if options.preserves(Attribute::Ownership) {
+            copy_ownership(&self.metadata, temp_path)?;
+        } else if options.tries_to_preserve(Attribute::Ownership) {
+            // Only root can give a file to another user
+            let _ = copy_ownership(&self.metadata, temp_path);
+        }
+        if options.preserves(Attribute::Mode) {
+            fs::set_permissions(temp_path, self.metadata.permissions())?;
+        }
+        #[cfg(unix)]
+        if options.preserves(Attribute::Xattr) {
+            copy_xattrs(&self.source, temp_path, true)?;
+        } else if options.tries_to_preserve(Attribute::Xattr) {
+            copy_xattrs(&self.source, temp_path, false)?;
+        }
+        if options.preserves(Attribute::Times) {
+            // Renaming keeps the temporary file's times, so set them beforehand
+            let atime = filetime::FileTime::from_last_access_time(&self.metadata);
+            let mtime = filetime::FileTime::from_last_modification_time(&self.metadata);
+            filetime::set_file_times(temp_path, atime, mtime)?;
+        }
+
+        if options.fsync {
+            temp.as_file().sync_all()?;
+        }
+        let path = &self.path;
+        let in_place = options.output.is_none() && has_other_links(&self.metadata);
+        // The file is formatted before the journal records what it contains
+        let replace = || {
+            if in_place {
+                write_in_place(temp, path, options.fsync)?;
+                if options.preserves(Attribute::Times) {
+                    let atime = filetime::FileTime::from_last_access_time(&self.metadata);
+                    let mtime = filetime::FileTime::from_last_modification_time(&self.metadata);
+                    filetime::set_file_times(path, atime, mtime)?;
+                }
+            } else {
+                persist(temp, path)?;
+            }
+            if let Some(command) = &options.format {
+                if let Err(e) = hook::format(command, path) {
+                    report::add_to(options.problems.as_deref(), Code::Format, path, e);
+                }
+            }
+            Ok(())
+        };
+        match &options.journal {
+            Some(journal) => journal.record_write(path, replace)?,
+            None => replace()?,
+        }
+        if options.fsync {
+            sync_dir(parent_dir(&self.path))?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.temp {
+            Temp::File(temp) => {
+                throttle::wait(buf.len() as u64);
+                temp.write(buf).map_err(write_error)
+            }
+            Temp::Planned(_, content) => content.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.temp {
+            Temp::File(temp) => temp.flush().map_err(write_error),
+            Temp::Planned(_, content) => content.flush(),
+        }
+    }
+}
+
+/// An error from writing a file, as opposed to reading it or working out its
+/// new content
+#[derive(Debug)]
+struct WriteError(io::Error);
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Mark `e` as an error from writing a file
+fn write_error(e: io::Error) -> io::Error {
+    if is_write_error(&e) {
+        return e;
+    }
+    io::Error::new(e.kind(), WriteError(e))
+}
+
+/// Whether `e` came from writing a file
+pub fn is_write_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<WriteError>())
+}
+
+/// How many times to try replacing a file that another program has open
+const PERSIST_ATTEMPTS: u32 = 6;
+
+/// Rename the temporary file over `path`, retrying for a while if another
+/// program has the file open
+///
+/// On Windows editors, virus scanners and search indexers often hold files
+/// open briefly, and renaming over them fails until they let go.
+/// Whether the file has hard links other than the one being rewritten
+fn has_other_links(metadata: &fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.nlink() > 1
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        false
+    }
+}
+
+/// Copy the content of `temp` into the file at `path` itself, so that its
+/// other hard links keep sharing it
+fn write_in_place(mut temp: NamedTempFile, path: &Path, fsync: bool) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)?;
+    temp.seek(SeekFrom::Start(0))?;
+    io::copy(&mut temp, &mut file)?;
+    if fsync {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+fn persist(mut temp: NamedTempFile, path: &Path) -> io::Result<()> {
+    let mut delay = Duration::from_millis(50);
+    for attempt in 1.. {
+        let e = match temp.persist(path) {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        if !is_sharing_violation(&e.error) {
+            return Err(e.error);
+        }
+        if attempt == PERSIST_ATTEMPTS {
+            return Err(io::Error::new(
+                e.error.kind(),
+                format!(
+                    "{} (the file is open in another program, gave up after {} attempts)",
+                    e.error, attempt
+                ),
+            ));
+        }
+
+        temp = e.file;
+        thread::sleep(delay);
+        delay *= 2;
+    }
+    unreachable!()
+}
+
+#[cfg(windows)]
+fn is_sharing_violation(e: &io::Error) -> bool {
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    matches!(
+        e.raw_os_error(),
+        Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+    )
+}
+
+#[cfg(not(windows))]
+fn is_sharing_violation(_e: &io::Error) -> bool {
+    // Other platforms let files be replaced while they're open
+    false
+}
+
+// Needed for formats like zip that go back to fill in headers
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &mut self.temp {
+            Temp::File(temp) => temp.seek(pos),
+            Temp::Planned(_, content) => content.seek(pos),
+        }
+    }
+}
+
+/// Point the symlink at `path` to `target` instead, atomically
+///
+/// The new link is made under a temporary name and renamed over the old one,
+/// so the link never goes missing.
+pub fn replace_symlink(path: &Path, target: &Path, options: &WriteOptions) -> io::Result<()> {
+    relink(path, target, options).map_err(write_error)
+}
+
+fn relink(path: &Path, target: &Path, options: &WriteOptions) -> io::Result<()> {
+    if let Some(plan) = &options.plan {
+        plan.record_symlink(path, &fs::read_link(path)?, target);
+        return Ok(());
+    }
+
+    let destination = options.destination(path);
+    let dir = parent_dir(&destination);
+    if options.output.is_some() {
+        fs::create_dir_all(dir)?;
+    }
+
+    for attempt in 0.. {
+        let temp = dir.join(format!(".newtext-{}-{}.tmp", std::process::id(), attempt));
+        match make_symlink(path, target, &temp) {
+            Ok(()) => {
+                let old_target = fs::read_link(&destination).ok();
+                let replace = || fs::rename(&temp, &destination);
+                match (&options.journal, old_target) {
+                    (Some(journal), Some(old_target)) => {
+                        replace()?;
+                        journal.record_symlink(&destination, &old_target, target)?;
+                    }
+                    // A new link, as when writing to an output directory
+                    (Some(journal), None) => journal.record_write(&destination, replace)?,
+                    (None, _) => replace()?,
+                }
+                if options.fsync {
+                    sync_dir(dir)?;
+                }
+                return Ok(());
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(unix)]
+fn make_symlink(_original: &Path, target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn make_symlink(original: &Path, target: &Path, link: &Path) -> io::Result<()> {
+    use std::os::windows::fs::FileTypeExt;
+
+    // Windows links record whether they point to a directory
+    if fs::symlink_metadata(original)?.file_type().is_symlink_dir() {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// Flush a directory's entries to disk, so that files renamed into it stay
+/// renamed after a crash
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Flush a directory's entries to disk. Windows has no way of doing this, and
+/// relies on renames being journalled.
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// The directory containing `path`, for creating files next to it
+pub fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Give `target` the owner and group recorded in `metadata`
+#[cfg(unix)]
+fn copy_ownership(metadata: &fs::Metadata, target: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let current = fs::metadata(target)?;
+    if current.uid() == metadata.uid() && current.gid() == metadata.gid() {
+        return Ok(());
+    }
+
+    std::os::unix::fs::chown(target, Some(metadata.uid()), Some(metadata.gid())).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "could not preserve ownership ({}), try --no-preserve ownership",
+                e
+            ),
+        )
+    })
+}
+
+/// Copy every extended attribute of `source` onto `target`, or every one
+/// that can be unless `strict`
+#[cfg(unix)]
+fn copy_xattrs(source: &Path, target: &Path, strict: bool) -> io::Result<()> {
+    let names = match xattr::list(source) {
+        Ok(names) => names,
+        // Nothing to copy on filesystems without extended attributes
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(()),
+        Err(_) if !strict => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for name in names {
+        let value = match xattr::get(source, &name) {
+            Ok(value) => value,
+            Err(_) if !strict => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(value) = value {
+            // Some, such as SELinux labels, can only be set by root
+            let result = xattr::set(target, &name, &value);
+            if result.is_err() && !strict {
+                continue;
+            }
+            result.map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "could not preserve extended attribute {} ({})",
+                        name.to_string_lossy(),
+                        e
+                    ),
+                )
+            })?;
+        }
+    }
+
+    Ok(())
+}
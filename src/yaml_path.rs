@@ -0,0 +1,174 @@
+//! --yaml-path, which limits replacements to the scalar values at some paths
+//! in YAML files
+//!
+//! Only the scalars that change are written back, each in the style it was
+//! in, so that indentation, comments, anchors, tags and quoting everywhere
+//! else in a file are left exactly as they were.
+
+use crate::scope::{Escape, Key, KeyPath, Segment};
+use saphyr_parser::{Event, Parser, ScalarStyle, Span};
+
+/// The scalar values in the YAML `text` that any of `paths` cover, in any of
+/// its documents
+pub fn segments(text: &str, paths: &[KeyPath]) -> Result<Vec<Segment>, String> {
+    let offsets = Offsets::new(text);
+    let mut stack: Vec<Frame> = Vec::new();
+    // How deep inside a key that's a mapping or a sequence the parser is
+    let mut in_key = 0;
+    let mut segments = Vec::new();
+
+    for event in Parser::new_from_str(text) {
+        let (event, span) = event.map_err(|e| {
+            format!(
+                "it isn't valid YAML, {} on line {}",
+                e.info(),
+                e.marker().line()
+            )
+        })?;
+        match event {
+            Event::DocumentStart(_) => stack.clear(),
+            Event::MappingStart(..) | Event::SequenceStart(..) if in_key > 0 => in_key += 1,
+            Event::MappingEnd | Event::SequenceEnd if in_key > 0 => in_key -= 1,
+            Event::Scalar(..) | Event::Alias(_) if in_key > 0 => {}
+            Event::MappingStart(..) | Event::SequenceStart(..) if expecting_key(&stack) => {
+                // Nothing inside the value of a key like `? [a, b]` is
+                // covered, as paths can't name it
+                *stack.last_mut().unwrap() = Frame::Value(None);
+                in_key = 1;
+            }
+            Event::Scalar(key, ..) if expecting_key(&stack) => {
+                *stack.last_mut().unwrap() = Frame::Value(Some(key.into_owned()));
+            }
+            Event::Alias(_) if expecting_key(&stack) => {
+                *stack.last_mut().unwrap() = Frame::Value(None);
+            }
+            Event::MappingStart(..) => stack.push(Frame::Key),
+            Event::SequenceStart(..) => stack.push(Frame::Index(0)),
+            Event::MappingEnd | Event::SequenceEnd => {
+                stack.pop();
+                next(&mut stack);
+            }
+            Event::Scalar(value, style, ..) => {
+                let covered =
+                    path(&stack).is_some_and(|path| paths.iter().any(|paths| paths.covers(&path)));
+                if covered {
+                    segments.push(segment(text, &offsets, span, &value, style));
+                }
+                next(&mut stack);
+            }
+            Event::Alias(_) => next(&mut stack),
+            _ => {}
+        }
+    }
+    Ok(segments)
+}
+
+/// Where the parser is in a collection
+enum Frame {
+    /// In a mapping, before a key
+    Key,
+    /// In a mapping, in the value of a key, which is None if the key isn't a
+    /// scalar
+    Value(Option<String>),
+    /// In a sequence, at this index
+    Index(usize),
+}
+
+fn expecting_key(stack: &[Frame]) -> bool {
+    matches!(stack.last(), Some(Frame::Key))
+}
+
+/// Move on from a value that's ended
+fn next(stack: &mut [Frame]) {
+    match stack.last_mut() {
+        Some(frame @ Frame::Value(_)) => *frame = Frame::Key,
+        Some(Frame::Index(index)) => *index += 1,
+        Some(Frame::Key) | None => {}
+    }
+}
+
+/// The path to the value being parsed, if every key on the way is a scalar
+fn path(stack: &[Frame]) -> Option<Vec<Key>> {
+    stack
+        .iter()
+        .map(|frame| match frame {
+            Frame::Value(Some(key)) => Some(Key::Name(key.clone())),
+            Frame::Value(None) | Frame::Key => None,
+            Frame::Index(index) => Some(Key::Index(*index)),
+        })
+        .collect()
+}
+
+fn segment(text: &str, offsets: &Offsets, span: Span, value: &str, style: ScalarStyle) -> Segment {
+    let start = offsets.byte(span.start.index());
+    let end = offsets.byte(span.end.index());
+    match style {
+        ScalarStyle::Plain => Segment {
+            range: start..end,
+            text: value.to_string(),
+            escape: Escape::YamlPlain,
+        },
+        ScalarStyle::SingleQuoted => Segment {
+            range: start..end,
+            text: value.to_string(),
+            escape: Escape::YamlSingle,
+        },
+        ScalarStyle::DoubleQuoted => Segment {
+            range: start..end,
+            text: value.to_string(),
+            escape: Escape::Json,
+        },
+        // Block scalars are replaced within line by line as they're written,
+        // rather than as folded, so that their lines stay as they are. The
+        // span starts at the first line's content and runs on to whatever
+        // comes next, so trailing blank lines are left out.
+        ScalarStyle::Literal | ScalarStyle::Folded => {
+            let indent = span.start.col();
+            let raw = text[start..end].trim_end();
+            let lines: Vec<&str> = raw
+                .split('\n')
+                .enumerate()
+                .map(|(i, line)| match i {
+                    0 => line,
+                    _ => strip_indent(line, indent),
+                })
+                .collect();
+            Segment {
+                range: start..start + raw.len(),
+                text: lines.join("\n"),
+                escape: Escape::YamlBlock(indent),
+            }
+        }
+    }
+}
+
+/// `line` without up to `indent` spaces at its start
+fn strip_indent(line: &str, indent: usize) -> &str {
+    let spaces = line.bytes().take(indent).take_while(|&b| b == b' ').count();
+    &line[spaces..]
+}
+
+/// The byte offsets of the parser's character offsets
+enum Offsets {
+    Ascii,
+    Chars(Vec<usize>),
+}
+
+impl Offsets {
+    fn new(text: &str) -> Offsets {
+        if text.is_ascii() {
+            Offsets::Ascii
+        } else {
+            let mut offsets: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+            offsets.push(text.len());
+            Offsets::Chars(offsets)
+        }
+    }
+
+    fn byte(&self, char_index: usize) -> usize {
+        match self {
+            Offsets::Ascii => char_index,
+            Offsets::Chars(offsets) => offsets[char_index.min(offsets.len() - 1)],
+        }
+    }
+}
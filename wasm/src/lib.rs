@@ -0,0 +1,78 @@
+//! newtext's replacement within text, for JavaScript by way of wasm-bindgen
+//!
+//! This is the same matching and case-preserving replacement that the
+//! command line tool makes in files, for a web playground or an editor
+//! extension to make in the text it has.
+
+use newtext::matcher::Matcher;
+use newtext::sed::SedScript;
+use newtext::text;
+use wasm_bindgen::prelude::*;
+
+/// A replacement to make in text
+#[wasm_bindgen]
+pub struct Replacer {
+    kind: Kind,
+}
+
+enum Kind {
+    Text {
+        matcher: Matcher,
+        old: String,
+        new: String,
+    },
+    Sed(SedScript),
+}
+
+#[wasm_bindgen]
+impl Replacer {
+    /// Replace OLD with NEW, treating OLD as a regex if `pattern` is set
+    ///
+    /// Ignoring case, each replacement of a literal OLD takes on the case of
+    /// the text it replaces.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        old: &str,
+        new: &str,
+        pattern: bool,
+        ignore_case: bool,
+    ) -> Result<Replacer, JsError> {
+        if old.is_empty() {
+            return Err(JsError::new("old string cannot be empty"));
+        }
+        let matcher = Matcher::new(old, pattern, ignore_case)
+            .map_err(|e| JsError::new(&format!("Invalid regex pattern: {}", e)))?;
+        Ok(Replacer {
+            kind: Kind::Text {
+                matcher,
+                old: old.to_string(),
+                new: new.to_string(),
+            },
+        })
+    }
+
+    /// Make the substitutions in a sed script, with extended regexes if
+    /// `extended` is set
+    pub fn sed(script: &str, extended: bool, ignore_case: bool) -> Result<Replacer, JsError> {
+        let sed = SedScript::parse(script, extended, ignore_case)
+            .map_err(|e| JsError::new(&format!("Invalid sed script: {}", e)))?;
+        Ok(Replacer {
+            kind: Kind::Sed(sed),
+        })
+    }
+
+    /// `content` with the replacement made, or undefined if nothing in it
+    /// changes
+    pub fn replace(&self, content: &str) -> Option<String> {
+        let new_content = match &self.kind {
+            Kind::Text { matcher, old, new } => {
+                if !matcher.is_match(content) {
+                    return None;
+                }
+                text::replace_all(content, matcher, old, new)
+            }
+            Kind::Sed(sed) => sed.apply(content),
+        };
+        (new_content != content).then_some(new_content)
+    }
+}